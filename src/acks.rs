@@ -0,0 +1,242 @@
+use crate::server::{
+    get_connection_by_addr, send_error_response, send_response, send_to, ServerState,
+};
+use crate::transport::Writer;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+// a tagged message that has been relayed but not acknowledged yet
+pub struct InFlight {
+    pub sender: std::net::SocketAddr,
+    // the id the sender picked, which is what it gets back
+    pub sender_id: String,
+    pub recipient: std::net::SocketAddr,
+}
+
+// acknowledged messages waiting for READ, oldest first in `order`
+#[derive(Default)]
+struct Unread {
+    messages: HashMap<u64, InFlight>,
+    order: VecDeque<u64>,
+}
+
+/// Tracks tagged messages until the recipient ACKs them, and after that
+/// until it READs them. Recipients see server-assigned ids, so two senders
+/// picking the same id never clash.
+#[derive(Default)]
+pub struct Acks {
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+    unread: Mutex<Unread>,
+}
+
+impl Acks {
+    /// Starts tracking a message and returns the id the recipient sees.
+    pub async fn track(
+        &self,
+        sender: std::net::SocketAddr,
+        sender_id: &str,
+        recipient: std::net::SocketAddr,
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+        self.in_flight.lock().await.insert(
+            id,
+            InFlight {
+                sender,
+                sender_id: sender_id.to_string(),
+                recipient,
+            },
+        );
+
+        id
+    }
+
+    /// Stops tracking a message, returning it if it was still in flight.
+    pub async fn take(&self, id: u64) -> Option<InFlight> {
+        self.in_flight.lock().await.remove(&id)
+    }
+
+    // pulls out everything that can no longer be acked because `addr` left
+    async fn take_involving(&self, addr: std::net::SocketAddr) -> Vec<InFlight> {
+        let mut in_flight = self.in_flight.lock().await;
+
+        let ids: Vec<u64> = in_flight
+            .iter()
+            .filter(|(_, m)| m.sender == addr || m.recipient == addr)
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.iter().filter_map(|id| in_flight.remove(id)).collect()
+    }
+
+    // remembered for READ, forgetting the oldest past `max`
+    async fn delivered(&self, id: u64, message: InFlight, max: usize) {
+        let mut unread = self.unread.lock().await;
+
+        while unread.order.len() >= max.max(1) {
+            let Some(oldest) = unread.order.pop_front() else {
+                break;
+            };
+            unread.messages.remove(&oldest);
+        }
+
+        unread.messages.insert(id, message);
+        unread.order.push_back(id);
+    }
+
+    // the message `recipient` may mark read as `id`, acked or not yet,
+    // and whether it still needed its ACK
+    async fn take_for_read(
+        &self,
+        id: u64,
+        recipient: std::net::SocketAddr,
+    ) -> Option<(InFlight, bool)> {
+        {
+            let mut unread = self.unread.lock().await;
+
+            if unread
+                .messages
+                .get(&id)
+                .is_some_and(|m| m.recipient == recipient)
+            {
+                unread.order.retain(|o| *o != id);
+                return unread.messages.remove(&id).map(|m| (m, false));
+            }
+        }
+
+        let mut in_flight = self.in_flight.lock().await;
+
+        match in_flight.get(&id) {
+            Some(m) if m.recipient == recipient => in_flight.remove(&id).map(|m| (m, true)),
+            _ => None,
+        }
+    }
+
+    async fn forget_unread(&self, addr: std::net::SocketAddr) {
+        let mut unread = self.unread.lock().await;
+
+        unread
+            .messages
+            .retain(|_, m| m.sender != addr && m.recipient != addr);
+
+        let Unread { messages, order } = &mut *unread;
+        order.retain(|id| messages.contains_key(id));
+    }
+}
+
+async fn report_undelivered(state: Arc<ServerState>, message: InFlight) {
+    if let Some(sender) = get_connection_by_addr(message.sender, state.clone()).await {
+        let line = format!("ERR UNDELIVERED {}", message.sender_id);
+        let _ = send_to(state, &sender, &line).await;
+    }
+}
+
+/// Gives up on a message if it is still unacknowledged after the timeout.
+pub fn expire_after_timeout(state: Arc<ServerState>, id: u64) {
+    let timeout = Duration::from_secs(state.config.acks.timeout_seconds);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+
+        if let Some(message) = state.acks.take(id).await {
+            report_undelivered(state, message).await;
+        }
+    });
+}
+
+/// `ACK <id>` from the recipient is forwarded to the sender as
+/// `ACK <sender id> <recipient nick>`.
+pub async fn handle_ack(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Ok(id) = id.parse::<u64>() else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    // only the recipient may acknowledge
+    let message = {
+        let mut in_flight = state.acks.in_flight.lock().await;
+
+        match in_flight.get(&id) {
+            Some(m) if m.recipient == addr => in_flight.remove(&id),
+            _ => None,
+        }
+    };
+
+    let Some(message) = message else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    if let Some(sender) = get_connection_by_addr(message.sender, state.clone()).await {
+        let line = format!("ACK {} {}", message.sender_id, conn.nickname);
+        let _ = send_to(state.clone(), &sender, &line).await;
+    }
+
+    let max_unread = state.config.acks.max_unread;
+    state.acks.delivered(id, message, max_unread).await;
+
+    send_response(socket, "OK", true).await;
+}
+
+/// `READ <id>` from the recipient of a tracked message is forwarded to the
+/// sender as `READ <sender id> <recipient nick>`, once per message. A
+/// message read before it was acknowledged gets its `ACK` first.
+pub async fn handle_read(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Ok(id) = id.parse::<u64>() else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    let Some((message, unacked)) = state.acks.take_for_read(id, addr).await else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    if let Some(sender) = get_connection_by_addr(message.sender, state.clone()).await {
+        if unacked {
+            let line = format!("ACK {} {}", message.sender_id, conn.nickname);
+            let _ = send_to(state.clone(), &sender, &line).await;
+        }
+
+        let line = format!("READ {} {}", message.sender_id, conn.nickname);
+        let _ = send_to(state, &sender, &line).await;
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+/// Fails everything a departing connection was sending or receiving.
+pub async fn drop_connection(addr: std::net::SocketAddr, state: Arc<ServerState>) {
+    state.acks.forget_unread(addr).await;
+
+    for message in state.acks.take_involving(addr).await {
+        // nobody to tell when the sender itself is the one leaving
+        if message.sender != addr {
+            report_undelivered(state.clone(), message).await;
+        }
+    }
+}
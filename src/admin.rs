@@ -0,0 +1,236 @@
+use crate::audit::Event;
+use crate::auth;
+use crate::http::{Request, Response};
+use crate::reload;
+use crate::server::ServerState;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::watch;
+
+// a static page, the data comes from the api with the token it asks for
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+// the fields of PUT /limits, each one optional
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Limits {
+    relay_max_bytes: Option<u64>,
+    relay_bytes_per_second: Option<u64>,
+}
+
+fn not_found() -> Response {
+    Response::json("404 Not Found", json!({ "error": "not found" }))
+}
+
+async fn connections(state: &ServerState) -> Response {
+    let mut list: Vec<_> = state.connections.filter_map(|conn| {
+        let mut rooms: Vec<&str> = conn.rooms.iter().map(|r| r.as_str()).collect();
+        rooms.sort();
+
+        let registered = conn
+            .registered_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Some(json!({
+            "nickname": conn.nickname,
+            "addr": conn.addr.to_string(),
+            "status": conn.status.as_str(),
+            "status_text": conn.status_text,
+            "rooms": rooms,
+            "fingerprint": conn.fingerprint,
+            "registered": registered,
+            "idle": conn.last_activity.elapsed().as_secs(),
+        }))
+    });
+
+    list.sort_by(|a, b| a["nickname"].as_str().cmp(&b["nickname"].as_str()));
+    Response::json("200 OK", json!(list))
+}
+
+async fn rooms(state: &ServerState) -> Response {
+    // nicknames are looked up with the rooms lock released
+    let rooms: Vec<_> = {
+        let rooms = state.rooms.lock().await;
+
+        rooms
+            .iter()
+            .map(|(name, room)| {
+                let members: Vec<SocketAddr> = room.members.iter().copied().collect();
+                let operators: Vec<SocketAddr> = room.operators.iter().copied().collect();
+                (name.clone(), room.topic.clone(), members, operators)
+            })
+            .collect()
+    };
+
+    let nicknames = |addrs: &[SocketAddr]| {
+        let mut names: Vec<String> = addrs
+            .iter()
+            .filter_map(|addr| state.connections.get(*addr))
+            .map(|c| c.nickname)
+            .collect();
+        names.sort();
+        names
+    };
+
+    let mut list: Vec<_> = rooms
+        .iter()
+        .map(|(name, topic, members, operators)| {
+            json!({
+                "name": name,
+                "topic": topic,
+                "members": nicknames(members),
+                "operators": nicknames(operators),
+            })
+        })
+        .collect();
+
+    list.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Response::json("200 OK", json!(list))
+}
+
+async fn stats(state: &ServerState) -> Response {
+    let registered = state.connections.len();
+
+    Response::json("200 OK", json!(state.metrics.stats(registered)))
+}
+
+async fn links(state: &ServerState) -> Response {
+    Response::json(
+        "200 OK",
+        json!({
+            "name": state.federation.name(),
+            "links": state.federation.links().await,
+        }),
+    )
+}
+
+fn limits(state: &ServerState) -> Response {
+    let limits = &state.relay_limits;
+
+    Response::json(
+        "200 OK",
+        json!({
+            "relay_max_bytes": limits.max_bytes.load(Ordering::Relaxed),
+            "relay_bytes_per_second": limits.bytes_per_second.load(Ordering::Relaxed),
+        }),
+    )
+}
+
+fn set_limits(state: &ServerState, body: &[u8]) -> Response {
+    let changes: Limits = match serde_json::from_slice(body) {
+        Ok(changes) => changes,
+        Err(e) => return Response::json("400 Bad Request", json!({ "error": e.to_string() })),
+    };
+
+    let limits = &state.relay_limits;
+
+    if let Some(max_bytes) = changes.relay_max_bytes {
+        limits.max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    if let Some(bytes_per_second) = changes.relay_bytes_per_second {
+        limits
+            .bytes_per_second
+            .store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    tracing::info!(
+        component = "admin",
+        relay_max_bytes = limits.max_bytes.load(Ordering::Relaxed),
+        relay_bytes_per_second = limits.bytes_per_second.load(Ordering::Relaxed),
+        "Changed limits"
+    );
+    state.audit.record(Event::Admin {
+        action: "limits",
+        target: None,
+    });
+
+    self::limits(state)
+}
+
+/// The admin API `[admin]` serves, for a server running headless. `GET /`
+/// is a dashboard page that asks for the token; every other request needs
+/// `Authorization: Bearer <admin.token>`.
+///
+/// - `GET /stats` has the counters, uptime and recent joins and leaves
+/// - `GET /connections` lists registered connections
+/// - `DELETE /connections/<nick>` kicks one off, without a session to resume
+/// - `GET /rooms` lists rooms with their topic, members and operators
+/// - `GET /links` names this server and the servers linked to it
+/// - `GET /limits` and `PUT /limits` read and change the relay quotas
+/// - `POST /reload` rereads the config file, see [`reload`](crate::reload)
+/// - `POST /shutdown` stops the server the way [`Server::shutdown`] does
+///
+/// [`Server::shutdown`]: crate::Server::shutdown
+pub async fn handle(
+    request: Request,
+    state: Arc<ServerState>,
+    shutdown: watch::Sender<bool>,
+) -> Response {
+    if request.method == "GET" && matches!(request.path.as_str(), "/" | "/dashboard") {
+        return Response::text("200 OK", DASHBOARD).content_type("text/html; charset=utf-8");
+    }
+
+    let token = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let authorized = match (&state.config.admin.token, token) {
+        (Some(expected), Some(token)) => auth::tokens_match(expected, token),
+        _ => false,
+    };
+
+    if !authorized {
+        return Response::json("401 Unauthorized", json!({ "error": "unauthorized" }));
+    }
+
+    let path: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), path.as_slice()) {
+        ("GET", ["stats"]) => stats(&state).await,
+        ("GET", ["connections"]) => connections(&state).await,
+        ("DELETE", ["connections", nickname]) => {
+            if !state.kick(nickname).await {
+                return not_found();
+            }
+
+            tracing::info!(component = "admin", %nickname, "Kicked");
+            state.audit.record(Event::Admin {
+                action: "kick",
+                target: Some(nickname),
+            });
+            Response::json("200 OK", json!({ "kicked": nickname }))
+        }
+        ("GET", ["rooms"]) => rooms(&state).await,
+        ("GET", ["links"]) => links(&state).await,
+        ("GET", ["limits"]) => limits(&state),
+        ("PUT", ["limits"]) => set_limits(&state, &request.body),
+        ("POST", ["reload"]) => match reload::from_file(&state) {
+            Ok(changes) => {
+                state.audit.record(Event::Admin {
+                    action: "reload",
+                    target: None,
+                });
+                Response::json("200 OK", json!({ "reloaded": changes }))
+            }
+            Err(e) => Response::json("409 Conflict", json!({ "error": e.to_string() })),
+        },
+        ("POST", ["shutdown"]) => {
+            tracing::info!(component = "admin", "Shutting down");
+            state.audit.record(Event::Admin {
+                action: "shutdown",
+                target: None,
+            });
+            shutdown.send_replace(true);
+
+            Response::json("200 OK", json!({ "shutdown": true }))
+        }
+        _ => not_found(),
+    }
+}
@@ -0,0 +1,204 @@
+//! The `[audit]` log, for looking into abuse after the fact: one JSON object
+//! per line for every registration, disconnect, ban, admin action and
+//! finished relay session, appended to a file that starts over every day.
+//!
+//! ```text
+//! {"at":1760400000,"event":"register","nickname":"alice","addr":"192.0.2.1:50000"}
+//! {"at":1760400120,"event":"relay","id":3,"nickname":"alice","addr":"192.0.2.1:50004","bytes":1048576}
+//! ```
+//!
+//! Unlike the regular log nothing here is filtered by level, and it is
+//! written whatever `[log]` says. Lines are written by a thread of their
+//! own, so a slow disk never holds up a connection; should it fall more
+//! than 128k lines behind, the newest are dropped instead of waited on.
+
+use crate::config::AuditConfig;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+/// Something worth keeping a record of.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Register {
+        nickname: &'a str,
+        addr: SocketAddr,
+    },
+    Resume {
+        nickname: &'a str,
+        addr: SocketAddr,
+    },
+    Disconnect {
+        nickname: &'a str,
+        addr: SocketAddr,
+    },
+    /// A nickname taken from another connection, by GHOST or because that
+    /// one went idle; `addr` is the connection that took it.
+    Ghost {
+        nickname: &'a str,
+        addr: SocketAddr,
+    },
+    Ban {
+        ip: IpAddr,
+    },
+    /// A request to the admin API that changed something; `target` is what
+    /// it acted on, if anything.
+    Admin {
+        action: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<&'a str>,
+    },
+    /// One side of a relay session that ended, and what it sent through.
+    Relay {
+        id: u64,
+        nickname: &'a str,
+        addr: SocketAddr,
+        bytes: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    // unix seconds
+    at: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Where events go, nowhere when `[audit] path` is unset. Dropping it
+/// waits for the lines still queued to be written.
+pub struct Audit {
+    file: Option<(NonBlocking, WorkerGuard)>,
+}
+
+impl Audit {
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        let file = match &config.path {
+            Some(path) => Some(tracing_appender::non_blocking(appender(
+                path,
+                config.retention_days,
+            )?)),
+            None => None,
+        };
+
+        Ok(Audit { file })
+    }
+
+    /// Appends `event`. A failed write is logged, not returned, so that
+    /// nothing being audited has to care.
+    pub fn record(&self, event: Event) {
+        let Some((file, _)) = &self.file else {
+            return;
+        };
+
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut line = match serde_json::to_vec(&Entry { at, event: &event }) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to encode audit event");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // one write per line, so lines from different tasks never interleave
+        if let Err(e) = file.clone().write_all(&line) {
+            tracing::error!(error = %e, "Failed to write audit log");
+        }
+    }
+}
+
+fn appender(path: &Path, retention_days: usize) -> io::Result<RollingFileAppender> {
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("audit.path {} has no file name", path.display()),
+        )
+    })?;
+
+    let context =
+        |e: io::Error| io::Error::new(e.kind(), format!("audit.path {}: {}", path.display(), e));
+
+    // pruning old files reads the directory before anything is written
+    std::fs::create_dir_all(directory).map_err(context)?;
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy());
+
+    if retention_days > 0 {
+        builder = builder.max_log_files(retention_days);
+    }
+
+    builder
+        .build(directory)
+        .map_err(|e| context(io::Error::other(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_appended_as_json_lines() {
+        let directory = std::env::temp_dir().join(format!("p2p-audit-{}", std::process::id()));
+        let audit = Audit::open(&AuditConfig {
+            path: Some(directory.join("audit.jsonl")),
+            retention_days: 7,
+        })
+        .unwrap();
+
+        let addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        audit.record(Event::Register {
+            nickname: "alice",
+            addr,
+        });
+        audit.record(Event::Admin {
+            action: "kick",
+            target: Some("alice"),
+        });
+        drop(audit);
+
+        let file = std::fs::read_dir(&directory)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let contents = std::fs::read_to_string(file).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "register");
+        assert_eq!(lines[0]["nickname"], "alice");
+        assert_eq!(lines[0]["addr"], "192.0.2.1:5000");
+        assert!(lines[0]["at"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["event"], "admin");
+        assert_eq!(lines[1]["target"], "alice");
+    }
+
+    #[test]
+    fn nothing_is_written_without_a_path() {
+        let audit = Audit::open(&AuditConfig::default()).unwrap();
+        assert!(audit.file.is_none());
+
+        audit.record(Event::Ban {
+            ip: "192.0.2.1".parse().unwrap(),
+        });
+    }
+}
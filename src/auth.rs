@@ -0,0 +1,235 @@
+use crate::config::AuthConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+struct FailureRecord {
+    count: u32,
+    // when the first failure counted in `count` was
+    since: Instant,
+    banned_until: Option<Instant>,
+}
+
+pub struct Auth {
+    // swapped on reload, ongoing failure counts and bans stay
+    config: std::sync::RwLock<AuthConfig>,
+    failures: Mutex<HashMap<IpAddr, FailureRecord>>,
+}
+
+// compare without bailing out on the first mismatching byte
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    let expected = expected.as_bytes();
+    let given = given.as_bytes();
+
+    if expected.len() != given.len() {
+        return false;
+    }
+
+    expected
+        .iter()
+        .zip(given)
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Self {
+        Auth {
+            config: std::sync::RwLock::new(config),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn config(&self) -> std::sync::RwLockReadGuard<'_, AuthConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks later attempts against `config`.
+    pub fn set_config(&self, config: AuthConfig) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config().enabled
+    }
+
+    /// Whether `nickname` has a credential of its own under `users`, rather
+    /// than going by the shared token.
+    pub fn has_user(&self, nickname: &str) -> bool {
+        let config = self.config();
+
+        config.enabled && config.users.contains_key(nickname)
+    }
+
+    /// Checks the token presented with `REG`. A nickname listed under
+    /// `users` only accepts its own credential; everyone else needs the
+    /// shared token.
+    pub fn verify(&self, nickname: &str, token: Option<&str>) -> bool {
+        let config = self.config();
+
+        if !config.enabled {
+            return true;
+        }
+
+        let Some(token) = token else {
+            return false;
+        };
+
+        match config.users.get(nickname) {
+            Some(credential) => tokens_match(credential, token),
+            None => match &config.token {
+                Some(shared) => tokens_match(shared, token),
+                None => false,
+            },
+        }
+    }
+
+    pub async fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut failures = self.failures.lock().await;
+
+        let Some(record) = failures.get(&ip) else {
+            return false;
+        };
+
+        match record.banned_until {
+            Some(until) if until > Instant::now() => true,
+            // ban ran out, start over
+            Some(_) => {
+                failures.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed attempt and returns true if it got the ip banned.
+    pub async fn record_failure(&self, ip: IpAddr) -> bool {
+        let (max_failures, window, ban_seconds) = {
+            let config = self.config();
+            let window = Duration::from_secs(config.failure_window_seconds);
+            (config.max_failures, window, config.ban_seconds)
+        };
+
+        let mut failures = self.failures.lock().await;
+
+        // forget ips whose failures wore off and whose ban ran out, or a
+        // scan from many addresses would be remembered for good
+        let now = Instant::now();
+        failures.retain(|_, r| match r.banned_until {
+            Some(until) => until > now,
+            None => r.since.elapsed() <= window,
+        });
+
+        let record = failures.entry(ip).or_insert_with(|| FailureRecord {
+            count: 0,
+            since: Instant::now(),
+            banned_until: None,
+        });
+
+        // typos far enough apart don't add up to a ban
+        if record.since.elapsed() > window {
+            record.count = 0;
+            record.since = Instant::now();
+        }
+
+        record.count += 1;
+
+        if record.count >= max_failures {
+            record.banned_until = Some(Instant::now() + Duration::from_secs(ban_seconds));
+            return true;
+        }
+
+        false
+    }
+
+    pub async fn clear_failures(&self, ip: IpAddr) {
+        self.failures.lock().await.remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth::new(AuthConfig {
+            enabled: true,
+            token: Some("shared".to_string()),
+            users: HashMap::from([("alice".to_string(), "pw".to_string())]),
+            max_failures: 3,
+            ..AuthConfig::default()
+        })
+    }
+
+    #[test]
+    fn tokens_are_checked_per_user_then_shared() {
+        let auth = auth();
+
+        assert!(auth.verify("bob", Some("shared")));
+        assert!(!auth.verify("bob", Some("sharedd")));
+        assert!(!auth.verify("bob", None));
+
+        // a listed nickname only takes its own
+        assert!(auth.verify("alice", Some("pw")));
+        assert!(!auth.verify("alice", Some("shared")));
+
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abc", "abd"));
+        assert!(!tokens_match("abc", "ab"));
+    }
+
+    #[tokio::test]
+    async fn max_failures_bans_the_ip() {
+        let auth = auth();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(!auth.record_failure(ip).await);
+        assert!(!auth.record_failure(ip).await);
+        assert!(!auth.is_banned(ip).await);
+        assert!(auth.record_failure(ip).await);
+        assert!(auth.is_banned(ip).await);
+        assert!(!auth.is_banned("192.0.2.2".parse().unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn failures_wear_off() {
+        let auth = auth();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(!auth.record_failure(ip).await);
+        assert!(!auth.record_failure(ip).await);
+
+        // as if the first one was long ago
+        auth.failures.lock().await.get_mut(&ip).unwrap().since -= Duration::from_secs(601);
+        assert!(!auth.record_failure(ip).await);
+        assert!(!auth.record_failure(ip).await);
+        assert!(auth.record_failure(ip).await);
+
+        // and a success wipes the slate
+        auth.clear_failures(ip).await;
+        assert!(!auth.is_banned(ip).await);
+    }
+
+    #[tokio::test]
+    async fn worn_off_failures_are_forgotten() {
+        let auth = auth();
+        let scanner: IpAddr = "192.0.2.1".parse().unwrap();
+        let banned: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(!auth.record_failure(scanner).await);
+        for _ in 0..3 {
+            auth.record_failure(banned).await;
+        }
+
+        auth.failures.lock().await.get_mut(&scanner).unwrap().since -= Duration::from_secs(601);
+        auth.failures.lock().await.get_mut(&banned).unwrap().since -= Duration::from_secs(601);
+        auth.record_failure("192.0.2.3".parse().unwrap()).await;
+
+        // a ban outlives the window it was earned in
+        let failures = auth.failures.lock().await;
+        assert!(!failures.contains_key(&scanner));
+        assert!(failures.contains_key(&banned));
+        assert_eq!(failures.len(), 2);
+    }
+}
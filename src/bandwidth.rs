@@ -0,0 +1,239 @@
+//! Caps on how fast relayed data goes out, so one peer pushing a large file
+//! or relay session can't take the whole uplink.
+//!
+//! Every relayed byte is charged to the connection it came from, and to
+//! that connection's ip, each a leaky bucket: it drains at the configured
+//! rate, and once it holds more than `[bandwidth] burst_bytes` whatever
+//! poured in waits for it to drain back.
+//!
+//! File chunks wait on a writer task of their sender's, one per throttled
+//! connection, which passes them on in the order they came. The sender's
+//! own commands and replies never queue behind them, so `FILE_CANCEL` gets
+//! through however far behind its chunks are. A relay session waits in the
+//! task copying it, a data channel carries nothing else to hold up.
+
+use crate::config::BandwidthConfig;
+use crate::files::send_to_addr;
+use crate::server::ServerState;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+struct Bucket {
+    // bytes poured in and not drained yet
+    level: f64,
+    at: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket {
+            level: 0.0,
+            at: now,
+        }
+    }
+
+    // adds `bytes` and returns how long until the bucket is back under
+    // `burst`; a rate of 0 never fills
+    fn pour(&mut self, now: Instant, bytes: u64, rate: u64, burst: u64) -> Duration {
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let drained = now.saturating_duration_since(self.at).as_secs_f64() * rate as f64;
+        self.level = (self.level - drained).max(0.0) + bytes as f64;
+        self.at = now;
+
+        let over = self.level - burst as f64;
+
+        if over > 0.0 {
+            Duration::from_secs_f64(over / rate as f64)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+// a line waiting for its sender's buckets, then going to every one of `to`
+struct Throttled {
+    state: Arc<ServerState>,
+    to: Vec<SocketAddr>,
+    line: String,
+    bytes: usize,
+}
+
+pub struct Bandwidth {
+    config: BandwidthConfig,
+    connections: Mutex<HashMap<SocketAddr, Bucket>>,
+    ips: Mutex<HashMap<IpAddr, Bucket>>,
+    // the writer tasks of throttled senders, see `relay`
+    writers: Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Throttled>>>,
+}
+
+impl Bandwidth {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Bandwidth {
+            config: config.clone(),
+            connections: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.config.connection_bytes_per_second > 0 || self.config.ip_bytes_per_second > 0
+    }
+
+    // how long `addr` has to wait before relaying `bytes` more
+    fn charge(&self, addr: SocketAddr, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let burst = self.config.burst_bytes;
+
+        let connection = self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_insert_with(|| Bucket::new(now))
+            .pour(now, bytes, self.config.connection_bytes_per_second, burst);
+
+        let ip = self
+            .ips
+            .lock()
+            .unwrap()
+            .entry(addr.ip())
+            .or_insert_with(|| Bucket::new(now))
+            .pour(now, bytes, self.config.ip_bytes_per_second, burst);
+
+        connection.max(ip)
+    }
+
+    /// Waits until `addr` may relay `bytes` more, counting them against
+    /// both of its caps.
+    pub async fn wait(&self, addr: SocketAddr, bytes: usize) {
+        if !self.is_limited() {
+            return;
+        }
+
+        let wait = self.charge(addr, bytes as u64);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Sends `line` from `from` to each of `to`, once `from` may relay
+    /// `bytes` more. Without caps that is right away; with them the line
+    /// joins `from`'s writer task and this returns without waiting.
+    pub async fn relay(
+        &self,
+        state: &Arc<ServerState>,
+        from: SocketAddr,
+        to: Vec<SocketAddr>,
+        line: String,
+        bytes: usize,
+    ) {
+        if !self.is_limited() {
+            for addr in to {
+                send_to_addr(state.clone(), addr, &line).await;
+            }
+            return;
+        }
+
+        let throttled = Throttled {
+            state: state.clone(),
+            to,
+            line,
+            bytes,
+        };
+
+        self.writers
+            .lock()
+            .unwrap()
+            .entry(from)
+            .or_insert_with(|| {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(write_out(from, receiver));
+                sender
+            })
+            .send(throttled)
+            .ok();
+    }
+
+    /// Drops the bucket of a closed connection, and its ip's once no other
+    /// connection from there is left. Its writer task stops once it has
+    /// sent what is already queued.
+    pub fn forget(&self, addr: SocketAddr) {
+        self.writers.lock().unwrap().remove(&addr);
+
+        let mut connections = self.connections.lock().unwrap();
+
+        if connections.remove(&addr).is_none() {
+            return;
+        }
+
+        if !connections.keys().any(|a| a.ip() == addr.ip()) {
+            self.ips.lock().unwrap().remove(&addr.ip());
+        }
+    }
+}
+
+// the writer task of `from`, until its sender is forgotten
+async fn write_out(from: SocketAddr, mut queue: mpsc::UnboundedReceiver<Throttled>) {
+    while let Some(throttled) = queue.recv().await {
+        let state = throttled.state;
+        state.bandwidth.wait(from, throttled.bytes).await;
+
+        for addr in throttled.to {
+            send_to_addr(state.clone(), addr, &throttled.line).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_let_a_burst_through_then_drain_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(start);
+
+        assert_eq!(bucket.pour(start, 1000, 1000, 1000), Duration::ZERO);
+        assert_eq!(
+            bucket.pour(start, 500, 1000, 1000),
+            Duration::from_millis(500)
+        );
+
+        // half a second later that surplus is gone
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.pour(later, 0, 1000, 1000), Duration::ZERO);
+        assert_eq!(bucket.pour(later, 1000, 1000, 1000), Duration::from_secs(1));
+
+        assert_eq!(bucket.pour(start, u64::MAX, 0, 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn connections_from_one_ip_share_its_cap() {
+        let bandwidth = Bandwidth::new(&BandwidthConfig {
+            connection_bytes_per_second: 0,
+            ip_bytes_per_second: 1000,
+            burst_bytes: 1000,
+        });
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let c: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+
+        assert!(bandwidth.charge(a, 1000).is_zero());
+        assert!(!bandwidth.charge(b, 1000).is_zero());
+        assert!(bandwidth.charge(c, 1000).is_zero());
+
+        // the ip's bucket outlives one of its connections
+        bandwidth.forget(a);
+        assert!(bandwidth.ips.lock().unwrap().contains_key(&b.ip()));
+        bandwidth.forget(b);
+        assert!(!bandwidth.ips.lock().unwrap().contains_key(&b.ip()));
+    }
+}
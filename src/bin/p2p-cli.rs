@@ -0,0 +1,310 @@
+use colored::Colorize;
+use p2p_rs::client::{Client, ClientError, Event, Sent};
+use p2p_rs::proxy::Proxy;
+use p2p_rs::server::BIND_ADDR;
+use rustyline::error::ReadlineError;
+use rustyline::{DefaultEditor, ExternalPrinter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// seconds between them grow by one each time
+const RECONNECT_ATTEMPTS: u64 = 5;
+
+const HELP: &str = "\
+/list                 who is online
+/msg <nick> <text>    message someone, later lines go to them too
+/join <room>          join a room, later lines go to it
+/part <room>          leave a room
+/watch <nick>         get told when someone comes and goes
+/stats                how the server is doing
+/quit                 leave
+anything else is sent to whoever /msg or /join picked last";
+
+// where a line without a command goes
+enum Target {
+    Nobody,
+    Peer(String),
+    Room(String),
+}
+
+fn describe(event: Event) -> String {
+    match event {
+        Event::Message { from, payload, .. } => {
+            format!(
+                "{} {}",
+                format!("<{}>", from).bright_green().bold(),
+                payload
+            )
+        }
+        Event::RoomMessage {
+            room,
+            from,
+            payload,
+        }
+        | Event::History {
+            room,
+            from,
+            payload,
+            ..
+        } => format!(
+            "{} {} {}",
+            room.bright_cyan(),
+            format!("<{}>", from).bright_green().bold(),
+            payload
+        ),
+        Event::Joined { room, nickname } => {
+            format!("{} {} joined", room.bright_cyan(), nickname.bold())
+        }
+        Event::Parted { room, nickname } => {
+            format!("{} {} left", room.bright_cyan(), nickname.bold())
+        }
+        Event::Kicked { room, nickname, by } => format!(
+            "{} {} was kicked by {}",
+            room.bright_cyan(),
+            nickname.bold(),
+            by
+        ),
+        Event::Topic { room, topic } => format!("{} topic: {}", room.bright_cyan(), topic),
+        Event::Presence {
+            nickname,
+            status,
+            text,
+        } => format!(
+            "{} is {} {}",
+            nickname.bold(),
+            status.bright_yellow(),
+            text.unwrap_or_default()
+        ),
+        Event::Motd { line } => line.bright_blue().to_string(),
+        Event::Typing { from, room } => match room {
+            Some(room) => format!("{} is typing in {}", from, room),
+            None => format!("{} is typing", from),
+        }
+        .dimmed()
+        .to_string(),
+        other => format!("{:?}", other).dimmed().to_string(),
+    }
+}
+
+// runs one line from the prompt, false once it is time to leave
+async fn run_line(client: &Client, target: &mut Target, line: &str) -> Result<bool, ClientError> {
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    match command {
+        "/quit" => return Ok(false),
+        "/help" => println!("{}", HELP),
+        "/list" => {
+            for peer in client.list().await? {
+                println!(
+                    "{} {} {}",
+                    peer.nickname.bold(),
+                    peer.status.bright_yellow(),
+                    peer.status_text.unwrap_or_default()
+                );
+            }
+        }
+        "/msg" => {
+            let (nickname, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            *target = Target::Peer(nickname.to_string());
+
+            if !text.is_empty() {
+                send(client, target, text).await?;
+            }
+        }
+        "/join" => {
+            client.join(rest).await?;
+            *target = Target::Room(rest.to_string());
+        }
+        "/part" => {
+            client.part(rest).await?;
+
+            if matches!(target, Target::Room(room) if room == rest) {
+                *target = Target::Nobody;
+            }
+        }
+        "/watch" => client.watch(rest).await?,
+        "/stats" => {
+            let stats = client.stats().await?;
+
+            println!(
+                "p2p-rs {}, up {}s, {} online, {} messages",
+                stats.version, stats.uptime, stats.peers, stats.messages
+            );
+        }
+        _ if command.starts_with('/') => println!("{}", HELP),
+        _ => send(client, target, line).await?,
+    }
+
+    Ok(true)
+}
+
+async fn send(client: &Client, target: &Target, text: &str) -> Result<(), ClientError> {
+    match target {
+        Target::Nobody => println!(
+            "{}",
+            "Pick someone with /msg or /join first".bright_yellow()
+        ),
+        Target::Peer(nickname) => {
+            if client.send(nickname, text).await? == Sent::Queued {
+                println!("{}", format!("{} is offline, queued", nickname).dimmed());
+            }
+        }
+        Target::Room(room) => client.say(room, text).await?,
+    }
+
+    Ok(())
+}
+
+// a few tries at resuming the session, for servers with [resume] enabled
+async fn reconnect(client: &Client) -> Option<UnboundedReceiver<Event>> {
+    client.session_token()?;
+
+    for attempt in 1..=RECONNECT_ATTEMPTS {
+        tokio::time::sleep(Duration::from_secs(attempt)).await;
+
+        match client.reconnect().await {
+            Ok(events) => return Some(events),
+            // the session is gone, trying again won't bring it back
+            Err(ClientError::Server(_)) => return None,
+            Err(_) => {}
+        }
+    }
+
+    None
+}
+
+/// `p2p-cli [--proxy <url>] <nick> [addr]` connects, registers and chats
+/// until `/quit`. Without `--proxy`, `P2P_PROXY` is used when set.
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut proxy = std::env::var("P2P_PROXY").ok().filter(|p| !p.is_empty());
+
+    if let Some(at) = args.iter().position(|a| a == "--proxy") {
+        if at + 1 >= args.len() {
+            eprintln!("Usage: p2p-cli [--proxy <url>] <nick> [addr]");
+            std::process::exit(2);
+        }
+
+        proxy = Some(args.remove(at + 1));
+        args.remove(at);
+    }
+
+    let mut args = args.into_iter();
+
+    let Some(nickname) = args.next() else {
+        eprintln!("Usage: p2p-cli [--proxy <url>] <nick> [addr]");
+        std::process::exit(2);
+    };
+    let addr = args.next().unwrap_or_else(|| BIND_ADDR.to_string());
+
+    let connected = match proxy {
+        Some(url) => match Proxy::parse(&url) {
+            Ok(proxy) => Client::connect_via(&addr, proxy).await,
+            Err(_) => {
+                eprintln!("Not a socks5:// or http:// proxy: {url}");
+                std::process::exit(2);
+            }
+        },
+        None => Client::connect(&addr).await.map_err(ClientError::from),
+    };
+
+    let (client, mut events) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            eprintln!("Failed to connect to {addr}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = client.register(&nickname).await {
+        eprintln!("Failed to register as {nickname}: {e}");
+        std::process::exit(1);
+    }
+
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Failed to open the terminal: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // incoming lines are printed above the prompt, not through it
+    // when there is a terminal to draw it on
+    let mut printer = editor.create_external_printer().ok();
+    let client = Arc::new(client);
+
+    tokio::spawn({
+        let client = client.clone();
+
+        async move {
+            let mut print = |line: String| match printer.as_mut() {
+                Some(printer) => drop(printer.print(line)),
+                None => println!("{}", line),
+            };
+
+            loop {
+                while let Some(event) = events.recv().await {
+                    print(describe(event));
+                }
+
+                print("Disconnected, reconnecting".bright_red().to_string());
+
+                match reconnect(&client).await {
+                    Some(resumed) => {
+                        events = resumed;
+                        print("Reconnected".bright_green().to_string());
+                    }
+                    None => {
+                        print("Could not reconnect".bright_red().to_string());
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    });
+
+    println!(
+        "{}",
+        format!("Connected to {addr} as {nickname}, /help for help").dimmed()
+    );
+
+    let runtime = tokio::runtime::Handle::current();
+
+    // the prompt blocks, so it gets its own thread
+    let prompt = tokio::task::spawn_blocking(move || {
+        let mut target = Target::Nobody;
+
+        loop {
+            let line = match editor.readline("> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => return,
+                Err(e) => {
+                    eprintln!("Failed to read: {e}");
+                    return;
+                }
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(line);
+
+            match runtime.block_on(run_line(&client, &mut target, line)) {
+                Ok(true) => {}
+                Ok(false) => return,
+                // the event task is reconnecting, or gives up and exits
+                Err(ClientError::Closed) => {
+                    println!("{}", "Not connected right now".bright_red())
+                }
+                Err(e) => println!("{}", e.to_string().bright_red()),
+            }
+        }
+    });
+
+    let _ = prompt.await;
+}
@@ -0,0 +1,316 @@
+//! Optional features a connection can turn on and off with `CAP`, so the
+//! protocol can grow without minimal clients seeing what they don't
+//! understand:
+//!
+//! - `CAP LS` answers `CAP LS <cap>...` with what the server offers
+//! - `CAP LIST` answers `CAP LIST <cap>...` with what is on right now
+//! - `CAP REQ <cap>... [-<cap>...]` turns every one given on, or off with a
+//!   `-`, or none of them if one is unknown (`ERR BAD_CAP`)
+//!
+//! The capabilities are:
+//!
+//! - `compress=<zstd|gzip|none>`, see [`compression`](crate::compression);
+//!   `CAP compress=<name>` on its own still works too
+//! - `json`, every line both ways a JSON object, see [`to_json`]
+//! - `acks`, the `ACK` and `READ` notices for tracked messages
+//! - `presence`, the `PRESENCE` and `TYPING` notices
+//!
+//! `acks` and `presence` start out on, their notices only ever come after
+//! a command that asked for them. The `OK` to a `REQ` is the last thing
+//! sent the old way.
+
+use crate::compression::Compression;
+use crate::parser;
+use crate::server::{send_error_response, send_response, ServerState};
+use crate::transport::Writer;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const JSON: u8 = 1;
+const ACKS: u8 = 2;
+const PRESENCE: u8 = 4;
+
+const FLAGS: [(&str, u8); 3] = [("json", JSON), ("acks", ACKS), ("presence", PRESENCE)];
+
+// lines that end in free text, and how many words come before it
+const TEXT_AFTER: &[(&str, usize)] = &[
+    ("MSG", 1),
+    ("MSGID", 2),
+    ("RMSG", 2),
+    ("HISTORY", 3),
+    ("TOPIC", 1),
+    ("PRESENCE", 2),
+    ("LIST", 3),
+    ("WHOIS", 2),
+    ("MOTD", 0),
+];
+
+/// What a connection has turned on, shared by everything that writes to
+/// it.
+#[derive(Clone)]
+pub struct Caps(Arc<AtomicU8>);
+
+impl Default for Caps {
+    fn default() -> Self {
+        Caps(Arc::new(AtomicU8::new(ACKS | PRESENCE)))
+    }
+}
+
+impl Caps {
+    fn has(&self, flag: u8) -> bool {
+        self.0.load(Ordering::Relaxed) & flag != 0
+    }
+
+    pub fn json(&self) -> bool {
+        self.has(JSON)
+    }
+
+    // whether a line about to be written is one this connection takes
+    fn wants(&self, line: &str) -> bool {
+        match line.split(' ').next().unwrap_or_default() {
+            "ACK" | "READ" => self.has(ACKS),
+            "PRESENCE" | "TYPING" => self.has(PRESENCE),
+            _ => true,
+        }
+    }
+
+    /// `data`, one or more whole lines, the way this connection takes
+    /// them: without the notices it turned off, and as JSON if it asked.
+    pub fn render<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let bits = self.0.load(Ordering::Relaxed);
+
+        if bits == ACKS | PRESENCE {
+            return Cow::Borrowed(data);
+        }
+
+        let mut rendered = Vec::with_capacity(data.len());
+
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            let text = String::from_utf8_lossy(line);
+            let text = text.trim_end_matches(['\r', '\n']);
+
+            if !self.wants(text) {
+                continue;
+            }
+
+            if bits & JSON != 0 {
+                rendered.extend_from_slice(to_json(text).as_bytes());
+                rendered.push(b'\n');
+            } else {
+                rendered.extend_from_slice(line);
+            }
+        }
+
+        Cow::Owned(rendered)
+    }
+
+    fn names(&self) -> Vec<&'static str> {
+        FLAGS
+            .iter()
+            .filter(|(_, flag)| self.has(*flag))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// A line the server sends as `{"command": ..., "params": [...]}`, with a
+/// `"text"` for the lines that end in one, such as a message's payload.
+pub fn to_json(line: &str) -> String {
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    let count = TEXT_AFTER
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map(|(_, count)| *count);
+
+    let mut object = serde_json::json!({ "command": command });
+
+    match parser::leading_words(rest, count.unwrap_or(usize::MAX)) {
+        Ok((params, text)) => {
+            object["params"] = params.into();
+
+            if count.is_some() {
+                object["text"] = text.into();
+            }
+        }
+        // not something parse would read back, left as it was
+        Err(_) => object["text"] = rest.into(),
+    }
+
+    object.to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonLine {
+    command: String,
+    #[serde(default)]
+    params: Vec<String>,
+    #[serde(default)]
+    text: String,
+}
+
+/// The command line a client sent as JSON, in the same shape
+/// [`to_json`] writes, or the error code to answer with.
+pub fn from_json(line: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let line: JsonLine = serde_json::from_slice(line).map_err(|_| "BAD_JSON")?;
+
+    // one line in, one command out
+    let split = std::iter::once(&line.command)
+        .chain(&line.params)
+        .chain([&line.text])
+        .any(|f| f.contains(['\r', '\n']));
+
+    if split {
+        return Err("BAD_JSON");
+    }
+
+    if line.command.is_empty() || line.command.contains(char::is_whitespace) {
+        return Err("BAD_JSON");
+    }
+
+    let mut words = vec![line.command.clone()];
+    words.extend(line.params.iter().map(|p| parser::quote(p).into_owned()));
+
+    if !line.text.is_empty() {
+        words.push(line.text);
+    }
+
+    Ok(format!("{}\n", words.join(" ")).into_bytes())
+}
+
+// every capability `CAP LS` lists for this server
+fn offered(state: &ServerState) -> Vec<String> {
+    let mut caps = Vec::new();
+
+    if state.config.compression.enabled {
+        caps.push("compress=zstd,gzip".to_string());
+    }
+
+    caps.extend(FLAGS.iter().map(|(name, _)| name.to_string()));
+    caps
+}
+
+/// `CAP <LS|LIST|REQ> [cap...]`, or `CAP compress=<name>` on its own.
+pub async fn handle_cap(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>, args: &[&str]) {
+    match args {
+        ["LS"] => {
+            let line = format!("CAP LS {}", offered(&state).join(" "));
+            send_response(socket.clone(), &line, true).await;
+            send_response(socket, "OK", true).await;
+        }
+        ["LIST"] => {
+            let mut caps = Vec::new();
+            let writer = socket.lock().await;
+
+            if let Some(name) = writer.compression().name() {
+                caps.push(format!("compress={}", name));
+            }
+            caps.extend(writer.caps().names().into_iter().map(str::to_string));
+            drop(writer);
+
+            let line = format!("CAP LIST {}", caps.join(" "));
+            send_response(socket.clone(), line.trim_end(), true).await;
+            send_response(socket, "OK", true).await;
+        }
+        ["REQ", caps @ ..] if !caps.is_empty() => request(socket, state, caps).await,
+        [cap] if cap.starts_with("compress=") => request(socket, state, &[cap]).await,
+        _ => send_error_response(socket, "BAD_CAP").await,
+    }
+}
+
+async fn request(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>, caps: &[&str]) {
+    let (mut on, mut off, mut compression) = (0, 0, None);
+
+    for cap in caps {
+        if let Some(name) = cap.strip_prefix("compress=") {
+            let Some(wanted) = Compression::parse(name) else {
+                send_error_response(socket, "BAD_CAP").await;
+                return;
+            };
+
+            if !state.config.compression.enabled && wanted != Compression::None {
+                send_error_response(socket, "NO_COMPRESS").await;
+                return;
+            }
+
+            compression = Some(wanted);
+            continue;
+        }
+
+        let (name, removing) = match cap.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (*cap, false),
+        };
+
+        let Some((_, flag)) = FLAGS.iter().find(|(n, _)| *n == name) else {
+            send_error_response(socket, "BAD_CAP").await;
+            return;
+        };
+
+        if removing {
+            off |= flag;
+        } else {
+            on |= flag;
+        }
+    }
+
+    // held across both so nothing pushed to this connection gets between
+    let mut writer = socket.lock().await;
+
+    if writer.write_lines(b"OK\n").await.is_err() || writer.flush().await.is_err() {
+        return;
+    }
+
+    let caps = writer.caps();
+    caps.0.fetch_or(on, Ordering::Relaxed);
+    caps.0.fetch_and(!off, Ordering::Relaxed);
+
+    if let Some(compression) = compression {
+        writer.set_compression(compression, state.config.compression.min_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_round_trip_through_json() {
+        assert_eq!(
+            to_json("MSG alice hello  there"),
+            r#"{"command":"MSG","params":["alice"],"text":"hello  there"}"#
+        );
+        assert_eq!(
+            to_json(r#"FILE_OFFER 1 alice "my notes.txt" 5"#),
+            r#"{"command":"FILE_OFFER","params":["1","alice","my notes.txt","5"]}"#
+        );
+        assert_eq!(to_json("OK"), r#"{"command":"OK","params":[]}"#);
+
+        let line = r#"{"command":"FILE_OFFER","params":["bob","my notes.txt","5"]}"#;
+        assert_eq!(
+            from_json(line.as_bytes()).unwrap(),
+            b"FILE_OFFER bob \"my notes.txt\" 5\n"
+        );
+
+        let line = r#"{"command":"MSG","params":["bob"],"text":"hi\nREG x"}"#;
+        assert_eq!(from_json(line.as_bytes()), Err("BAD_JSON"));
+        assert_eq!(from_json(b"MSG bob hi"), Err("BAD_JSON"));
+    }
+
+    #[test]
+    fn turned_off_notices_are_left_out() {
+        let caps = Caps::default();
+        let data = b"ACK a1 bob\nPRESENCE bob away\nOK\n";
+        assert_eq!(caps.render(data).as_ref(), data);
+
+        caps.0.fetch_and(!ACKS, Ordering::Relaxed);
+        assert_eq!(
+            caps.render(data).as_ref(),
+            b"PRESENCE bob away\nOK\n".as_slice()
+        );
+    }
+}
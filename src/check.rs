@@ -0,0 +1,302 @@
+//! `p2p-rs check-config`: looks a config over before the server is started
+//! with it. Errors are settings the server can't work with, warnings ones
+//! that probably don't do what was meant. The checks on values alone also
+//! decide whether a reload is taken, see [`errors`].
+
+use crate::config::Config;
+use crate::offline::OfflineStore;
+use crate::transport::Noise;
+use colored::Colorize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UdpSocket};
+
+#[derive(Default)]
+struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    fn error(&mut self, message: impl Into<String>) {
+        self.errors.push(message.into());
+    }
+
+    fn warning(&mut self, message: impl Into<String>) {
+        self.warnings.push(message.into());
+    }
+}
+
+// settings that parse fine but can't work, or probably aren't what was meant
+fn check_values(config: &Config, report: &mut Report) {
+    let auth = &config.auth;
+
+    if auth.enabled && auth.token.is_none() && auth.users.is_empty() {
+        report.error(
+            "auth.enabled is set but neither auth.token nor auth.users is configured, \
+             so nobody could register",
+        );
+    }
+
+    if auth.enabled && auth.max_failures == 0 {
+        report.error("auth.max_failures must be at least 1");
+    }
+
+    if auth.enabled && auth.max_failures > 1 && auth.failure_window_seconds == 0 {
+        report.warning("auth.failure_window_seconds is 0, failures never add up to a ban");
+    }
+
+    if !auth.enabled && (auth.token.is_some() || !auth.users.is_empty()) {
+        report.warning("auth credentials are configured but auth.enabled is false");
+    }
+
+    if config.history.replay_on_join > config.history.size {
+        report.warning(format!(
+            "history.replay_on_join ({}) is larger than history.size ({}), only {} can be replayed",
+            config.history.replay_on_join, config.history.size, config.history.size
+        ));
+    }
+
+    if config.files.window == 0 {
+        report.error("files.window must be at least 1, otherwise no chunk can ever be sent");
+    }
+
+    if config.acks.timeout_seconds == 0 {
+        report.warning("acks.timeout_seconds is 0, every MSGID will be reported undelivered");
+    }
+
+    if !config.stealth.enabled && config.stealth.knock.is_some() {
+        report.warning("stealth.knock is set but stealth.enabled is false, it will be ignored");
+    }
+
+    if !config.federation.links.is_empty() && config.federation.secret.is_none() {
+        report.error("federation.links is set but federation.secret is not, no link can be made");
+    }
+
+    if config.mdns.enabled && config.stealth.enabled {
+        report.warning("mdns.enabled announces the server that stealth.enabled tries to hide");
+    }
+
+    // peers on the LAN can see the announcement but not connect
+    let announced = config.listen.addrs_or_default().remove(0);
+    let loopback = announced
+        .parse::<SocketAddr>()
+        .is_ok_and(|addr| addr.ip().is_loopback());
+
+    if config.mdns.enabled && loopback {
+        report.warning(format!(
+            "mdns.enabled is set but the server listens on {}, only this host can connect",
+            announced
+        ));
+    }
+
+    if config
+        .mdns
+        .name
+        .as_deref()
+        .is_some_and(|n| n.trim().is_empty())
+    {
+        report.error("mdns.name must not be empty, leave it unset for a random name");
+    }
+
+    if config.admin.enabled && config.admin.token.as_deref().is_none_or(str::is_empty) {
+        report.error("admin.enabled is set without an admin.token, every request would be refused");
+    }
+
+    let loopback = |bind: &str| {
+        bind.parse::<SocketAddr>()
+            .is_ok_and(|addr| addr.ip().is_loopback())
+    };
+
+    if config.admin.enabled && !loopback(&config.admin.bind) {
+        report.warning(format!(
+            "admin.bind {} is reachable from other hosts and the admin api is plain http",
+            config.admin.bind
+        ));
+    }
+
+    if config.relay.enabled && config.relay.open_timeout_seconds == 0 {
+        report.error("relay.open_timeout_seconds must be at least 1 when relaying is enabled");
+    }
+
+    if config.pex.max_ttl_seconds == 0 {
+        report.warning("pex.max_ttl_seconds is 0, every advert expires as soon as it is made");
+    }
+
+    if let Err(e) = Noise::load(&config.noise) {
+        report.error(format!("noise: {}", e));
+    }
+
+    if config.noise.enabled && config.noise.private_key.is_none() {
+        report.warning(
+            "noise.private_key is not set, clients will see a new server key on every start",
+        );
+    }
+
+    if let Err(e) = crate::logging::filter(&config.log) {
+        report.error(format!("log.level: {}", e));
+    }
+
+    if config.log.file.is_none() && config.log.max_files.is_some() {
+        report.warning("log.max_files is set but log.file is not, logs go to stdout");
+    }
+
+    if config.log.max_files == Some(0) {
+        report.error("log.max_files must be at least 1");
+    }
+
+    // read again on every REG, so a missing file is not fatal
+    if let Some(path) = &config.motd.file {
+        if let Err(e) = std::fs::read_to_string(path) {
+            report.warning(format!("motd.file {}: {}", path.display(), e));
+        }
+    }
+
+    let dht = &config.dht;
+
+    if dht.enabled && (dht.k == 0 || dht.alpha == 0) {
+        report.error("dht.k and dht.alpha must both be at least 1");
+    }
+
+    if dht.enabled && dht.record_ttl_seconds == 0 {
+        report.error("dht.record_ttl_seconds must be at least 1, records would expire unseen");
+    }
+
+    if dht.enabled && dht.bootstrap.is_empty() {
+        report.warning(
+            "dht.bootstrap is empty, this node is only reachable by nodes that bootstrap from it",
+        );
+    }
+
+    if dht.enabled && config.whois.hide_address {
+        report.warning(
+            "whois.hide_address is set but the dht publishes the address of every nickname",
+        );
+    }
+}
+
+// the offline store has to be readable now and writable later
+fn check_storage(config: &Config, report: &mut Report) {
+    let Some(path) = &config.offline.path else {
+        return;
+    };
+
+    if let Err(e) = OfflineStore::load(config.offline.clone()) {
+        report.error(format!("offline.path {}: {}", path.display(), e));
+        return;
+    }
+
+    if path.exists() {
+        // opening for append never creates or changes the file
+        if let Err(e) = std::fs::OpenOptions::new().append(true).open(path) {
+            report.error(format!(
+                "offline.path {} is not writable: {}",
+                path.display(),
+                e
+            ));
+        }
+        return;
+    }
+
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    match std::fs::metadata(parent) {
+        Ok(meta) if !meta.is_dir() => report.error(format!(
+            "offline.path {}: {} is not a directory",
+            path.display(),
+            parent.display()
+        )),
+        Ok(meta) if meta.permissions().readonly() => report.error(format!(
+            "offline.path {}: {} is read-only",
+            path.display(),
+            parent.display()
+        )),
+        Ok(_) => {}
+        Err(e) => report.error(format!(
+            "offline.path {}: cannot access {}: {}",
+            path.display(),
+            parent.display(),
+            e
+        )),
+    }
+}
+
+async fn check_listener(config: &Config, report: &mut Report) {
+    // bound and dropped straight away
+    for addr in config.listen.addrs_or_default() {
+        if let Err(e) = TcpListener::bind(&addr).await {
+            report.error(format!("cannot listen on {}: {}", addr, e));
+        }
+    }
+
+    if config.dht.enabled {
+        if let Err(e) = UdpSocket::bind(&config.dht.bind).await {
+            report.error(format!("dht.bind {}: {}", config.dht.bind, e));
+        }
+    }
+
+    if config.metrics.enabled {
+        if let Err(e) = TcpListener::bind(&config.metrics.bind).await {
+            report.error(format!("metrics.bind {}: {}", config.metrics.bind, e));
+        }
+    }
+
+    if config.admin.enabled {
+        if let Err(e) = TcpListener::bind(&config.admin.bind).await {
+            report.error(format!("admin.bind {}: {}", config.admin.bind, e));
+        }
+    }
+}
+
+/// What `check-config` would call an error in `config`, from its values
+/// alone: the `[noise]` keys are decoded, but the offline store isn't
+/// opened and nothing is bound. A reload refuses a config with any.
+pub fn errors(config: &Config) -> Vec<String> {
+    let mut report = Report::default();
+    check_values(config, &mut report);
+
+    report.errors
+}
+
+/// Runs every check for `p2p-rs check-config [path]` and prints the results.
+/// Returns false if anything would stop the server from working.
+pub async fn run(path: Option<String>) -> bool {
+    let path = path.map(PathBuf::from).or_else(Config::path);
+
+    let config = match &path {
+        Some(path) => match Config::from_file(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{} {}: {}", "error:".bright_red(), path.display(), e);
+                return false;
+            }
+        },
+        None => {
+            println!("no config file found, checking the defaults");
+            Config::default()
+        }
+    };
+
+    let mut report = Report::default();
+
+    check_values(&config, &mut report);
+    check_storage(&config, &mut report);
+    check_listener(&config, &mut report).await;
+
+    for warning in &report.warnings {
+        println!("{} {}", "warning:".bright_yellow(), warning);
+    }
+
+    for error in &report.errors {
+        println!("{} {}", "error:".bright_red(), error);
+    }
+
+    if report.errors.is_empty() {
+        println!("{}", "config ok".bright_green());
+    }
+
+    report.errors.is_empty()
+}
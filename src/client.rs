@@ -0,0 +1,1027 @@
+use crate::proxy::Proxy;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Mutex};
+
+/// Why a request didn't go through.
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// The server answered `ERR <code>`, such as `TKN` or `NO_NICK`.
+    Server(String),
+    /// Refused before sending, the argument can't be put on one line.
+    Invalid(&'static str),
+    /// The server answered with something this client doesn't understand.
+    Protocol(String),
+    /// The connection is closed.
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Server(code) => write!(f, "server error {}", code),
+            ClientError::Invalid(what) => write!(f, "invalid {}", what),
+            ClientError::Protocol(line) => write!(f, "unexpected reply {:?}", line),
+            ClientError::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Something the server pushed without being asked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A direct message. `id` is set for tracked messages, to
+    /// [`ack`](Client::ack).
+    Message {
+        from: String,
+        payload: String,
+        id: Option<String>,
+    },
+    /// A message sent with [`Client::send_tracked`] was delivered and
+    /// acknowledged by `by`.
+    Ack {
+        id: String,
+        by: String,
+    },
+    /// A tracked message was read by `by`, see [`Client::read`].
+    Read {
+        id: String,
+        by: String,
+    },
+    RoomMessage {
+        room: String,
+        from: String,
+        payload: String,
+    },
+    /// A message said in `room` before this connection joined it.
+    History {
+        room: String,
+        at: u64,
+        from: String,
+        payload: String,
+    },
+    Joined {
+        room: String,
+        nickname: String,
+    },
+    Parted {
+        room: String,
+        nickname: String,
+    },
+    Kicked {
+        room: String,
+        nickname: String,
+        by: String,
+    },
+    Op {
+        room: String,
+        nickname: String,
+    },
+    Topic {
+        room: String,
+        topic: String,
+    },
+    /// `from` is typing to this connection, or in `room` when set. Comes
+    /// again every few seconds for as long as they type.
+    Typing {
+        from: String,
+        room: Option<String>,
+    },
+    /// A watched nickname changed status, see [`Client::watch`].
+    Presence {
+        nickname: String,
+        status: String,
+        text: Option<String>,
+    },
+    /// Someone wants to connect directly, `PUNCH` follows.
+    Connect {
+        nickname: String,
+        addr: SocketAddr,
+    },
+    /// Time to start punching towards `nickname`.
+    Punch {
+        nickname: String,
+    },
+    /// A message sent with [`Client::send_tracked`] wasn't acknowledged in
+    /// time, or its recipient left first.
+    Undelivered {
+        id: String,
+    },
+    /// The server is about to hang up, for `reason`: `SLOW`, `GHOSTED`,
+    /// `SHUTDOWN` or `NICK_TAKEN`.
+    Disconnected {
+        reason: String,
+    },
+    /// One line of the message of the day, sent after registering.
+    Motd {
+        line: String,
+    },
+    /// `SDP_OFFER`, `SDP_ANSWER` or `ICE_CAND`, payload verbatim.
+    Signal {
+        kind: String,
+        from: String,
+        payload: Vec<u8>,
+    },
+    /// Any other pushed line, such as file transfer and relay notices.
+    Other(String),
+}
+
+/// A registered peer as `LIST` reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub nickname: String,
+    pub fingerprint: Option<String>,
+    pub status: String,
+    pub status_text: Option<String>,
+}
+
+/// The server's health as `STATS` reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Seconds since the server started.
+    pub uptime: u64,
+    /// Registered peers.
+    pub peers: u64,
+    /// Messages delivered directly or to a room.
+    pub messages: u64,
+    pub version: String,
+}
+
+/// Whether a message went straight through or waits in the offline queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sent {
+    Delivered,
+    Queued,
+}
+
+// lines that are only ever pushed, never part of a reply; everything
+// else belongs to whatever request is waiting
+const PUSHED: [&str; 17] = [
+    "MSG",
+    "MSGID",
+    "ACK",
+    "READ",
+    "RMSG",
+    "HISTORY",
+    "JOIN",
+    "PART",
+    "KICK",
+    "OP",
+    "TOPIC",
+    "TYPING",
+    "PRESENCE",
+    "CONNECT",
+    "PUNCH",
+    "RELAY_OPEN",
+    "MOTD",
+];
+// and every file transfer notice
+const PUSHED_FILE_PREFIX: &str = "FILE_";
+// ERR lines the server sends on its own, right before hanging up
+const FAREWELLS: [&str; 4] = ["SLOW", "GHOSTED", "SHUTDOWN", "NICK_TAKEN"];
+const SIGNALS: [&str; 3] = ["SDP_OFFER", "SDP_ANSWER", "ICE_CAND"];
+
+/// A connection to a p2p-rs server. Requests can be made from several
+/// tasks at once, each waits for its own reply.
+///
+/// ```no_run
+/// # async fn run() -> p2p_rs::client::Result<()> {
+/// let (client, mut events) = p2p_rs::client::Client::connect("127.0.0.1:4001").await?;
+/// client.register("alice").await?;
+/// client.send("bob", "hi").await?;
+///
+/// while let Some(event) = events.recv().await {
+///     println!("{:?}", event);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client {
+    // how the server was reached, for reconnecting
+    route: Route,
+    writer: Mutex<OwnedWriteHalf>,
+    replies: Mutex<mpsc::UnboundedReceiver<String>>,
+    // the session token from the last REG or RESUME, if the server gives them
+    token: std::sync::Mutex<Option<String>>,
+    // the last id handed out by `send_tracked`
+    last_id: AtomicU64,
+}
+
+type Events = mpsc::UnboundedReceiver<Event>;
+
+enum Route {
+    // the server as first resolved
+    Direct(SocketAddr),
+    // resolved by the proxy, every time
+    Proxied { proxy: Proxy, target: String },
+}
+
+// starts reading a fresh connection
+fn open(stream: TcpStream) -> (OwnedWriteHalf, mpsc::UnboundedReceiver<String>, Events) {
+    let (reader, writer) = stream.into_split();
+    let (replies_tx, replies) = mpsc::unbounded_channel();
+    let (events_tx, events) = mpsc::unbounded_channel();
+
+    tokio::spawn(read_lines(reader, replies_tx, events_tx));
+
+    (writer, replies, events)
+}
+
+impl Client {
+    /// Connects and starts reading. Pushed lines arrive on the returned
+    /// receiver, which ends when the connection does.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<(Client, Events)> {
+        let stream = TcpStream::connect(addr).await?;
+        let route = Route::Direct(stream.peer_addr()?);
+
+        Ok(Client::start(stream, route))
+    }
+
+    /// Like [`connect`](Client::connect), through `proxy`. `addr` is a
+    /// `host:port` the proxy resolves, an onion address through Tor.
+    pub async fn connect_via(addr: &str, proxy: Proxy) -> Result<(Client, Events)> {
+        let stream = proxy.connect(addr).await?;
+        let route = Route::Proxied {
+            proxy,
+            target: addr.to_string(),
+        };
+
+        Ok(Client::start(stream, route))
+    }
+
+    fn start(stream: TcpStream, route: Route) -> (Client, Events) {
+        let (writer, replies, events) = open(stream);
+
+        let client = Client {
+            route,
+            writer: Mutex::new(writer),
+            replies: Mutex::new(replies),
+            token: std::sync::Mutex::new(None),
+            last_id: AtomicU64::new(0),
+        };
+
+        (client, events)
+    }
+
+    /// Connects again after the connection dropped and takes the session
+    /// back with `RESUME`: same nickname, rooms and watches, and whatever
+    /// was sent meanwhile. Only works with `[resume]` enabled on the server
+    /// and within its grace window. Events continue on the returned
+    /// receiver.
+    pub async fn reconnect(&self) -> Result<Events> {
+        let token = self
+            .session_token()
+            .ok_or(ClientError::Invalid("session"))?;
+
+        // nothing else may write or wait for a reply while the halves change
+        let mut replies = self.replies.lock().await;
+        let mut writer = self.writer.lock().await;
+
+        let stream = match &self.route {
+            Route::Direct(addr) => TcpStream::connect(addr).await?,
+            Route::Proxied { proxy, target } => proxy.connect(target).await?,
+        };
+
+        let (new_writer, new_replies, events) = open(stream);
+        *writer = new_writer;
+        *replies = new_replies;
+        drop(writer);
+        drop(replies);
+
+        self.request(&format!("RESUME {}", token)).await?;
+
+        Ok(events)
+    }
+
+    /// The token to resume this session with, once registered on a server
+    /// with `[resume]` enabled.
+    pub fn session_token(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    /// Sends one command line and collects the reply lines up to the final
+    /// `OK`, which is returned last. Lines of the kinds listed in [`Event`]
+    /// always go to the event receiver instead.
+    pub async fn request(&self, line: &str) -> Result<Vec<String>> {
+        let mut replies = self.replies.lock().await;
+        self.write_line(line).await?;
+
+        let mut lines = Vec::new();
+
+        loop {
+            let reply = replies.recv().await.ok_or(ClientError::Closed)?;
+
+            if let Some(code) = reply.strip_prefix("ERR ") {
+                return Err(ClientError::Server(code.to_string()));
+            }
+
+            let done = reply == "OK" || reply.starts_with("OK ");
+
+            // REG and RESUME answer with the next token to use
+            if done && (line.starts_with("REG ") || line.starts_with("RESUME ")) {
+                if let Some(token) = reply.strip_prefix("OK ") {
+                    *self.token.lock().unwrap() = Some(token.to_string());
+                }
+            }
+
+            lines.push(reply);
+
+            if done {
+                return Ok(lines);
+            }
+        }
+    }
+
+    // for commands answered by one line that isn't OK
+    async fn query(&self, line: &str) -> Result<String> {
+        let mut replies = self.replies.lock().await;
+        self.write_line(line).await?;
+
+        let reply = replies.recv().await.ok_or(ClientError::Closed)?;
+
+        match reply.strip_prefix("ERR ") {
+            Some(code) => Err(ClientError::Server(code.to_string())),
+            None => Ok(reply),
+        }
+    }
+
+    async fn write_line(&self, line: &str) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+
+        writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// The server's version, from `HELLO p2p-rs <version>`.
+    pub async fn hello(&self) -> Result<String> {
+        let reply = self.query("HELLO").await?;
+
+        reply
+            .strip_prefix("HELLO p2p-rs ")
+            .map(str::to_string)
+            .ok_or(ClientError::Protocol(reply))
+    }
+
+    /// Our address as the server sees it.
+    pub async fn addr(&self) -> Result<SocketAddr> {
+        let reply = self.query("ADDR").await?;
+
+        reply
+            .strip_prefix("ADDR ")
+            .and_then(|a| a.parse().ok())
+            .ok_or(ClientError::Protocol(reply))
+    }
+
+    pub async fn register(&self, nickname: &str) -> Result<()> {
+        self.request(&format!("REG {}", word(nickname, "nickname")?))
+            .await
+            .map(drop)
+    }
+
+    /// Like [`register`](Client::register), with an `[auth]` token.
+    pub async fn register_with_token(&self, nickname: &str, token: &str) -> Result<()> {
+        let line = format!(
+            "REG {} {}",
+            word(nickname, "nickname")?,
+            word(token, "token")?
+        );
+
+        self.request(&line).await.map(drop)
+    }
+
+    /// Sends a direct message, queued by the server if `to` is offline.
+    pub async fn send(&self, to: &str, payload: &str) -> Result<Sent> {
+        let line = format!("MSG {} {}", word(to, "nickname")?, text(payload)?);
+        let reply = self.request(&line).await?;
+
+        match reply.last().map(String::as_str) {
+            Some("OK QUEUED") => Ok(Sent::Queued),
+            _ => Ok(Sent::Delivered),
+        }
+    }
+
+    /// Like [`send`](Client::send), as `MSGID` with an id of this client's
+    /// own, which is returned. [`Event::Ack`], [`Event::Read`] and
+    /// [`Event::Undelivered`] carry it back. A message queued for an
+    /// offline nickname is delivered plain and never acknowledged.
+    pub async fn send_tracked(&self, to: &str, payload: &str) -> Result<(String, Sent)> {
+        let id = (self.last_id.fetch_add(1, Ordering::Relaxed) + 1).to_string();
+        let line = format!("MSGID {} {} {}", id, word(to, "nickname")?, text(payload)?);
+        let reply = self.request(&line).await?;
+
+        match reply.last().map(String::as_str) {
+            Some("OK QUEUED") => Ok((id, Sent::Queued)),
+            _ => Ok((id, Sent::Delivered)),
+        }
+    }
+
+    /// Acknowledges a tracked [`Event::Message`].
+    pub async fn ack(&self, id: &str) -> Result<()> {
+        self.request(&format!("ACK {}", word(id, "id")?))
+            .await
+            .map(drop)
+    }
+
+    /// Tells the sender of a tracked [`Event::Message`] it was read.
+    pub async fn read(&self, id: &str) -> Result<()> {
+        self.request(&format!("READ {}", word(id, "id")?))
+            .await
+            .map(drop)
+    }
+
+    pub async fn join(&self, room: &str) -> Result<()> {
+        self.request(&format!("JOIN {}", word(room, "room")?))
+            .await
+            .map(drop)
+    }
+
+    pub async fn part(&self, room: &str) -> Result<()> {
+        self.request(&format!("PART {}", word(room, "room")?))
+            .await
+            .map(drop)
+    }
+
+    pub async fn say(&self, room: &str, payload: &str) -> Result<()> {
+        let line = format!("RMSG {} {}", word(room, "room")?, text(payload)?);
+
+        self.request(&line).await.map(drop)
+    }
+
+    /// Tells a peer, or a room when `to` starts with `#`, that this
+    /// connection is typing. Meant to be repeated while it is, the server
+    /// drops the ones that come too often.
+    pub async fn typing(&self, to: &str) -> Result<()> {
+        self.request(&format!("TYPING {}", word(to, "target")?))
+            .await
+            .map(drop)
+    }
+
+    /// Every registered peer, this connection included.
+    pub async fn list(&self) -> Result<Vec<Peer>> {
+        let mut lines = self.request("LIST").await?;
+        lines.pop();
+
+        lines.iter().map(|line| parse_peer(line)).collect()
+    }
+
+    /// What the server offers with `CAP LS`, such as `json` or
+    /// `compress=zstd,gzip`.
+    pub async fn capabilities(&self) -> Result<Vec<String>> {
+        let lines = self.request("CAP LS").await?;
+
+        let caps = lines
+            .first()
+            .and_then(|line| line.strip_prefix("CAP LS"))
+            .unwrap_or_default();
+
+        Ok(caps.split_whitespace().map(str::to_string).collect())
+    }
+
+    /// Works before registering too, for health checks.
+    pub async fn stats(&self) -> Result<Stats> {
+        let lines = self.request("STATS").await?;
+        parse_stats(&lines)
+    }
+
+    /// Asks for [`Event::Presence`] updates about `nickname`.
+    pub async fn watch(&self, nickname: &str) -> Result<()> {
+        self.request(&format!("WATCH {}", word(nickname, "nickname")?))
+            .await
+            .map(drop)
+    }
+
+    pub async fn unwatch(&self, nickname: &str) -> Result<()> {
+        self.request(&format!("UNWATCH {}", word(nickname, "nickname")?))
+            .await
+            .map(drop)
+    }
+}
+
+// a single word: nicknames, rooms, ids
+fn word<'a>(value: &'a str, what: &'static str) -> Result<&'a str> {
+    if value.is_empty() || value.contains(char::is_whitespace) {
+        return Err(ClientError::Invalid(what));
+    }
+
+    Ok(value)
+}
+
+// the rest of a line: spaces are fine, newlines are not
+fn text(value: &str) -> Result<&str> {
+    if value.trim().is_empty() || value.contains(['\n', '\r']) {
+        return Err(ClientError::Invalid("payload"));
+    }
+
+    Ok(value)
+}
+
+fn parse_peer(line: &str) -> Result<Peer> {
+    let protocol = || ClientError::Protocol(line.to_string());
+    let mut words = line.splitn(5, ' ');
+
+    if words.next() != Some("LIST") {
+        return Err(protocol());
+    }
+
+    let nickname = words.next().ok_or_else(protocol)?.to_string();
+    let fingerprint = words.next().ok_or_else(protocol)?;
+    let status = words.next().ok_or_else(protocol)?.to_string();
+
+    Ok(Peer {
+        nickname,
+        fingerprint: (fingerprint != "-").then(|| fingerprint.to_string()),
+        status,
+        status_text: words.next().map(str::to_string),
+    })
+}
+
+fn parse_stats(lines: &[String]) -> Result<Stats> {
+    let mut stats = Stats {
+        uptime: 0,
+        peers: 0,
+        messages: 0,
+        version: String::new(),
+    };
+
+    // the closing OK is not a field
+    for line in &lines[..lines.len().saturating_sub(1)] {
+        let protocol = || ClientError::Protocol(line.to_string());
+        let mut words = line.splitn(3, ' ');
+
+        if words.next() != Some("STATS") {
+            return Err(protocol());
+        }
+
+        let (Some(field), Some(value)) = (words.next(), words.next()) else {
+            return Err(protocol());
+        };
+        let number = || value.parse::<u64>().map_err(|_| protocol());
+
+        match field {
+            "uptime" => stats.uptime = number()?,
+            "peers" => stats.peers = number()?,
+            "messages" => stats.messages = number()?,
+            "version" => stats.version = value.to_string(),
+            // later servers may report more
+            _ => {}
+        }
+    }
+
+    Ok(stats)
+}
+
+// turns a pushed line into an event, Other when it doesn't look as expected
+fn parse_event(line: &str) -> Event {
+    let words: Vec<&str> = line.splitn(5, ' ').collect();
+    // the words from `from` on, joined back as they were
+    let rest = |from: usize| line.splitn(from + 1, ' ').nth(from).map(str::to_string);
+
+    let event = match words.as_slice() {
+        ["MSG", from, ..] => rest(2).map(|payload| Event::Message {
+            from: from.to_string(),
+            payload,
+            id: None,
+        }),
+        ["MSGID", id, from, ..] => rest(3).map(|payload| Event::Message {
+            from: from.to_string(),
+            payload,
+            id: Some(id.to_string()),
+        }),
+        ["ACK", id, by] => Some(Event::Ack {
+            id: id.to_string(),
+            by: by.to_string(),
+        }),
+        ["READ", id, by] => Some(Event::Read {
+            id: id.to_string(),
+            by: by.to_string(),
+        }),
+        ["RMSG", room, from, ..] => rest(3).map(|payload| Event::RoomMessage {
+            room: room.to_string(),
+            from: from.to_string(),
+            payload,
+        }),
+        ["HISTORY", room, at, from, ..] => match (at.parse(), rest(4)) {
+            (Ok(at), Some(payload)) => Some(Event::History {
+                room: room.to_string(),
+                at,
+                from: from.to_string(),
+                payload,
+            }),
+            _ => None,
+        },
+        ["JOIN", room, nickname] => Some(Event::Joined {
+            room: room.to_string(),
+            nickname: nickname.to_string(),
+        }),
+        ["PART", room, nickname] => Some(Event::Parted {
+            room: room.to_string(),
+            nickname: nickname.to_string(),
+        }),
+        ["KICK", room, nickname, by] => Some(Event::Kicked {
+            room: room.to_string(),
+            nickname: nickname.to_string(),
+            by: by.to_string(),
+        }),
+        ["OP", room, nickname] => Some(Event::Op {
+            room: room.to_string(),
+            nickname: nickname.to_string(),
+        }),
+        ["TOPIC", room, ..] => Some(Event::Topic {
+            room: room.to_string(),
+            topic: rest(2).unwrap_or_default(),
+        }),
+        ["TYPING", from] => Some(Event::Typing {
+            from: from.to_string(),
+            room: None,
+        }),
+        ["TYPING", room, from] => Some(Event::Typing {
+            from: from.to_string(),
+            room: Some(room.to_string()),
+        }),
+        ["PRESENCE", nickname, status, ..] => Some(Event::Presence {
+            nickname: nickname.to_string(),
+            status: status.to_string(),
+            text: rest(3),
+        }),
+        ["CONNECT", nickname, addr] => addr.parse().ok().map(|addr| Event::Connect {
+            nickname: nickname.to_string(),
+            addr,
+        }),
+        ["PUNCH", nickname] => Some(Event::Punch {
+            nickname: nickname.to_string(),
+        }),
+        ["MOTD", ..] => Some(Event::Motd {
+            line: rest(1).unwrap_or_default(),
+        }),
+        _ => None,
+    };
+
+    event.unwrap_or_else(|| Event::Other(line.to_string()))
+}
+
+// an ERR that answers nothing this client asked, see `FAREWELLS`
+fn unprompted(line: &str) -> Option<Event> {
+    let code = line.strip_prefix("ERR ")?;
+
+    if let Some(id) = code.strip_prefix("UNDELIVERED ") {
+        return Some(Event::Undelivered { id: id.to_string() });
+    }
+
+    FAREWELLS.contains(&code).then(|| Event::Disconnected {
+        reason: code.to_string(),
+    })
+}
+
+fn is_pushed(command: &str) -> bool {
+    PUSHED.contains(&command) || command.starts_with(PUSHED_FILE_PREFIX)
+}
+
+// splits what the server sends into replies and events until it hangs up
+async fn read_lines(
+    reader: OwnedReadHalf,
+    replies: mpsc::UnboundedSender<String>,
+    events: mpsc::UnboundedSender<Event>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\n', '\r']);
+        let command = text.split(' ').next().unwrap_or_default();
+
+        if SIGNALS.contains(&command) {
+            // `<kind> <from> <len>` and then the payload, newlines and all
+            let words: Vec<&str> = text.split(' ').collect();
+            let (Some(from), Some(Ok(len))) = (words.get(1), words.get(2).map(|l| l.parse()))
+            else {
+                let _ = events.send(Event::Other(text.to_string()));
+                continue;
+            };
+
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload).await?;
+
+            let _ = events.send(Event::Signal {
+                kind: command.to_string(),
+                from: from.to_string(),
+                payload,
+            });
+        } else if let Some(event) = unprompted(text) {
+            let _ = events.send(event);
+        } else if is_pushed(command) {
+            let _ = events.send(parse_event(text));
+        } else {
+            let _ = replies.send(text.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use std::sync::Arc;
+
+    async fn server() -> (Arc<Server>, SocketAddr) {
+        let mut config = crate::config::Config::default();
+        config.resume.enabled = true;
+
+        server_with(config).await
+    }
+
+    async fn server_with(config: crate::config::Config) -> (Arc<Server>, SocketAddr) {
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let server = Arc::new(server);
+
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+
+        (server, addr)
+    }
+
+    #[tokio::test]
+    async fn clients_talk_through_a_server() {
+        let (server, addr) = server().await;
+
+        let (alice, _) = Client::connect(addr).await.unwrap();
+        let (bob, mut bob_events) = Client::connect(addr).await.unwrap();
+
+        assert_eq!(alice.hello().await.unwrap(), env!("CARGO_PKG_VERSION"));
+        alice.register("alice").await.unwrap();
+        bob.register("bob").await.unwrap();
+
+        assert!(matches!(
+            bob.register("alice").await,
+            Err(ClientError::Server(code)) if code == "ALR_REG"
+        ));
+
+        assert_eq!(alice.send("bob", "hi bob").await.unwrap(), Sent::Delivered);
+        assert_eq!(
+            bob_events.recv().await.unwrap(),
+            Event::Message {
+                from: "alice".to_string(),
+                payload: "hi bob".to_string(),
+                id: None,
+            }
+        );
+
+        let nicknames: Vec<String> = alice
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|p| p.nickname)
+            .collect();
+        assert_eq!(nicknames, ["alice", "bob"]);
+
+        // health checks need no nickname
+        let (monitor, _) = Client::connect(addr).await.unwrap();
+        let stats = monitor.stats().await.unwrap();
+        assert_eq!(stats.peers, 2);
+        assert_eq!(stats.messages, 1);
+        assert_eq!(stats.version, env!("CARGO_PKG_VERSION"));
+
+        assert!(matches!(
+            alice.send("bob", "two\nlines").await,
+            Err(ClientError::Invalid(_))
+        ));
+
+        server.shutdown();
+    }
+
+    // just enough SOCKS5 for one tunnel, to wherever was asked
+    async fn socks5_proxy() -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0; 5];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3], 0x03, "hostnames go to the proxy unresolved");
+
+            let mut host = vec![0; request[4] as usize];
+            socket.read_exact(&mut host).await.unwrap();
+            let port = socket.read_u16().await.unwrap();
+            let host = String::from_utf8(host).unwrap();
+
+            let mut server = TcpStream::connect((host.as_str(), port)).await.unwrap();
+            socket
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut socket, &mut server).await;
+            });
+
+            host
+        });
+
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn clients_connect_through_a_proxy() {
+        let (server, addr) = server().await;
+        let (proxy_addr, proxy) = socks5_proxy().await;
+
+        let proxy_url = format!("socks5://{}", proxy_addr);
+        let target = format!("localhost:{}", addr.port());
+        let (alice, _) = Client::connect_via(&target, Proxy::parse(&proxy_url).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(proxy.await.unwrap(), "localhost");
+        assert_eq!(alice.hello().await.unwrap(), env!("CARGO_PKG_VERSION"));
+        alice.register("alice").await.unwrap();
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn pushed_lines_become_events() {
+        assert_eq!(
+            parse_event("MSG bob hello  there"),
+            Event::Message {
+                from: "bob".to_string(),
+                payload: "hello  there".to_string(),
+                id: None,
+            }
+        );
+        assert_eq!(
+            parse_event("PRESENCE bob away at lunch"),
+            Event::Presence {
+                nickname: "bob".to_string(),
+                status: "away".to_string(),
+                text: Some("at lunch".to_string()),
+            }
+        );
+        assert_eq!(
+            parse_event("MOTD"),
+            Event::Motd {
+                line: String::new()
+            }
+        );
+        assert_eq!(
+            parse_event("FILE_DONE 3"),
+            Event::Other("FILE_DONE 3".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_sessions_resume() {
+        let (server, addr) = server().await;
+
+        let (alice, _) = Client::connect(addr).await.unwrap();
+        let (bob, _) = Client::connect(addr).await.unwrap();
+        alice.register("alice").await.unwrap();
+        alice.join("#lobby").await.unwrap();
+        bob.register("bob").await.unwrap();
+
+        // the server sees alice hang up
+        alice.writer.lock().await.shutdown().await.unwrap();
+        while bob.list().await.unwrap().len() > 1 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(
+            bob.send("alice", "still there?").await.unwrap(),
+            Sent::Queued
+        );
+
+        let (mallory, _) = Client::connect(addr).await.unwrap();
+        assert!(matches!(
+            mallory.register("alice").await,
+            Err(ClientError::Server(code)) if code == "TKN"
+        ));
+
+        let mut events = alice.reconnect().await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            Event::Message {
+                from: "bob".to_string(),
+                payload: "still there?".to_string(),
+                id: None,
+            }
+        );
+
+        // back in the room, and the old token is spent
+        assert!(matches!(
+            alice.join("#lobby").await,
+            Err(ClientError::Server(code)) if code == "ALR_JOINED"
+        ));
+        assert!(alice.session_token().is_some());
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn unprompted_errors_never_answer_a_request() {
+        let mut config = crate::config::Config::default();
+        // given up on as soon as it is sent
+        config.acks.timeout_seconds = 0;
+        let (server, addr) = server_with(config).await;
+
+        let (alice, mut alice_events) = Client::connect(addr).await.unwrap();
+        let (bob, mut bob_events) = Client::connect(addr).await.unwrap();
+        alice.register("alice").await.unwrap();
+        bob.register("bob").await.unwrap();
+
+        let (id, sent) = alice.send_tracked("bob", "hi").await.unwrap();
+        assert_eq!(sent, Sent::Delivered);
+        assert!(matches!(
+            bob_events.recv().await.unwrap(),
+            Event::Message { id: Some(_), .. }
+        ));
+        assert_eq!(
+            alice_events.recv().await.unwrap(),
+            Event::Undelivered { id }
+        );
+
+        // and the next reply is the next request's
+        assert_eq!(alice.hello().await.unwrap(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(alice.send("bob", "again").await.unwrap(), Sent::Delivered);
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn tracked_messages_are_acked_by_their_own_id() {
+        let (server, addr) = server().await;
+
+        let (alice, mut alice_events) = Client::connect(addr).await.unwrap();
+        let (bob, mut bob_events) = Client::connect(addr).await.unwrap();
+        alice.register("alice").await.unwrap();
+        bob.register("bob").await.unwrap();
+
+        let (first, _) = alice.send_tracked("bob", "one").await.unwrap();
+        let (second, _) = alice.send_tracked("bob", "two").await.unwrap();
+        assert_ne!(first, second);
+
+        bob_events.recv().await.unwrap();
+        let Event::Message { id: Some(seen), .. } = bob_events.recv().await.unwrap() else {
+            panic!("expected a tracked message");
+        };
+        bob.ack(&seen).await.unwrap();
+
+        assert_eq!(
+            alice_events.recv().await.unwrap(),
+            Event::Ack {
+                id: second,
+                by: "bob".to_string(),
+            }
+        );
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn farewells_become_events() {
+        assert_eq!(
+            unprompted("ERR SLOW"),
+            Some(Event::Disconnected {
+                reason: "SLOW".to_string()
+            })
+        );
+        assert_eq!(unprompted("ERR NO_NICK"), None);
+        assert_eq!(unprompted("OK"), None);
+    }
+}
@@ -0,0 +1,467 @@
+//! Cluster mode, for several nodes behind a load balancer acting as one
+//! server.
+//!
+//! Who owns a nickname and what their status is live in a shared
+//! [`Store`], Redis unless the embedder sets another one. A node claims a
+//! nickname at REG and keeps the claim alive while the connection lasts;
+//! a node that dies without releasing its claims loses them after
+//! `[cluster] claim_seconds`. Messages for a nickname owned by another
+//! node are published on that node's channel, and room messages on a
+//! channel every node listens to, each handing them to its own members.
+
+use crate::rooms;
+use crate::server::{get_connection_by_nickname, send_to, ServerState};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+
+// the channel every node listens to besides its own
+const ALL: &str = "all";
+
+fn store_error(e: redis::RedisError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Where the nodes of a cluster keep nickname ownership and presence, and
+/// pass lines to each other. [`RedisStore`] is what a deployment uses,
+/// [`MemoryStore`] shares one in a single process.
+#[async_trait]
+pub trait Store: Send + Sync + 'static {
+    /// Claims `nickname` for `node` for `ttl`, or extends the claim if
+    /// `node` has it already. False if another node has it.
+    async fn claim(&self, nickname: &str, node: &str, ttl: Duration) -> io::Result<bool>;
+
+    /// Gives up the claim and the presence that goes with it, if `node`
+    /// still has it.
+    async fn release(&self, nickname: &str, node: &str) -> io::Result<()>;
+
+    /// The node with a live claim on `nickname`.
+    async fn owner(&self, nickname: &str) -> io::Result<Option<String>>;
+
+    async fn set_presence(&self, nickname: &str, presence: &str, ttl: Duration) -> io::Result<()>;
+
+    async fn presence(&self, nickname: &str) -> io::Result<Option<String>>;
+
+    async fn publish(&self, channel: &str, line: &str) -> io::Result<()>;
+
+    /// Lines published on any of `channels` from now on, until the
+    /// receiver is dropped or the store goes away.
+    async fn subscribe(&self, channels: &[String]) -> io::Result<mpsc::UnboundedReceiver<String>>;
+}
+
+// takes the key if it is free or ours already
+const CLAIM: &str = r"
+local owner = redis.call('GET', KEYS[1])
+if owner == false or owner == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+";
+
+const RELEASE: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1], KEYS[2])
+end
+return 0
+";
+
+/// A [`Store`] in Redis, every key and channel named `<prefix>:...`.
+pub struct RedisStore {
+    client: redis::Client,
+    // reconnects by itself when redis restarts
+    connection: redis::aio::ConnectionManager,
+    prefix: String,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str, prefix: &str) -> io::Result<Self> {
+        let client = redis::Client::open(url).map_err(store_error)?;
+        let connection = client.get_connection_manager().await.map_err(store_error)?;
+
+        Ok(RedisStore {
+            client,
+            connection,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn key(&self, kind: &str, name: &str) -> String {
+        format!("{}:{}:{}", self.prefix, kind, name)
+    }
+}
+
+#[async_trait]
+impl Store for RedisStore {
+    async fn claim(&self, nickname: &str, node: &str, ttl: Duration) -> io::Result<bool> {
+        let claimed: i64 = redis::Script::new(CLAIM)
+            .key(self.key("nick", nickname))
+            .arg(node)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(store_error)?;
+
+        Ok(claimed == 1)
+    }
+
+    async fn release(&self, nickname: &str, node: &str) -> io::Result<()> {
+        let _: i64 = redis::Script::new(RELEASE)
+            .key(self.key("nick", nickname))
+            .key(self.key("presence", nickname))
+            .arg(node)
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(store_error)?;
+
+        Ok(())
+    }
+
+    async fn owner(&self, nickname: &str) -> io::Result<Option<String>> {
+        redis::cmd("GET")
+            .arg(self.key("nick", nickname))
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(store_error)
+    }
+
+    async fn set_presence(&self, nickname: &str, presence: &str, ttl: Duration) -> io::Result<()> {
+        redis::cmd("SET")
+            .arg(self.key("presence", nickname))
+            .arg(presence)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(store_error)
+    }
+
+    async fn presence(&self, nickname: &str) -> io::Result<Option<String>> {
+        redis::cmd("GET")
+            .arg(self.key("presence", nickname))
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(store_error)
+    }
+
+    async fn publish(&self, channel: &str, line: &str) -> io::Result<()> {
+        redis::cmd("PUBLISH")
+            .arg(self.key("channel", channel))
+            .arg(line)
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(store_error)
+    }
+
+    async fn subscribe(&self, channels: &[String]) -> io::Result<mpsc::UnboundedReceiver<String>> {
+        // subscribing takes a connection of its own
+        let mut pubsub = self.client.get_async_pubsub().await.map_err(store_error)?;
+
+        for channel in channels {
+            pubsub
+                .subscribe(self.key("channel", channel))
+                .await
+                .map_err(store_error)?;
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+
+            while let Some(message) = messages.next().await {
+                let Ok(line) = message.get_payload::<String>() else {
+                    continue;
+                };
+
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+}
+
+#[derive(Default)]
+struct Memory {
+    claims: HashMap<String, (String, Instant)>,
+    presence: HashMap<String, (String, Instant)>,
+    subscribers: HashMap<String, Vec<mpsc::UnboundedSender<String>>>,
+}
+
+/// A [`Store`] in memory, shared by the nodes given clones of it. For
+/// running several nodes in one process, such as in tests.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    memory: Arc<Mutex<Memory>>,
+}
+
+// the value of a key that hasn't expired
+fn live(map: &HashMap<String, (String, Instant)>, key: &str) -> Option<String> {
+    map.get(key)
+        .filter(|(_, expires)| *expires > Instant::now())
+        .map(|(value, _)| value.clone())
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn claim(&self, nickname: &str, node: &str, ttl: Duration) -> io::Result<bool> {
+        let mut memory = self.memory.lock().await;
+
+        if live(&memory.claims, nickname).is_some_and(|owner| owner != node) {
+            return Ok(false);
+        }
+
+        let claim = (node.to_string(), Instant::now() + ttl);
+        memory.claims.insert(nickname.to_string(), claim);
+        Ok(true)
+    }
+
+    async fn release(&self, nickname: &str, node: &str) -> io::Result<()> {
+        let mut memory = self.memory.lock().await;
+
+        if live(&memory.claims, nickname).as_deref() == Some(node) {
+            memory.claims.remove(nickname);
+            memory.presence.remove(nickname);
+        }
+
+        Ok(())
+    }
+
+    async fn owner(&self, nickname: &str) -> io::Result<Option<String>> {
+        Ok(live(&self.memory.lock().await.claims, nickname))
+    }
+
+    async fn set_presence(&self, nickname: &str, presence: &str, ttl: Duration) -> io::Result<()> {
+        let presence = (presence.to_string(), Instant::now() + ttl);
+        self.memory
+            .lock()
+            .await
+            .presence
+            .insert(nickname.to_string(), presence);
+
+        Ok(())
+    }
+
+    async fn presence(&self, nickname: &str) -> io::Result<Option<String>> {
+        Ok(live(&self.memory.lock().await.presence, nickname))
+    }
+
+    async fn publish(&self, channel: &str, line: &str) -> io::Result<()> {
+        if let Some(subscribers) = self.memory.lock().await.subscribers.get_mut(channel) {
+            subscribers.retain(|s| s.send(line.to_string()).is_ok());
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, channels: &[String]) -> io::Result<mpsc::UnboundedReceiver<String>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut memory = self.memory.lock().await;
+
+        for channel in channels {
+            memory
+                .subscribers
+                .entry(channel.clone())
+                .or_default()
+                .push(sender.clone());
+        }
+
+        Ok(receiver)
+    }
+}
+
+/// This node's place in the cluster.
+pub struct Cluster {
+    node: String,
+    store: Arc<dyn Store>,
+    claim_for: Duration,
+}
+
+impl Cluster {
+    pub fn new(node: String, store: Arc<dyn Store>, claim_for: Duration) -> Self {
+        Cluster {
+            node,
+            store,
+            claim_for,
+        }
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Claims `nickname` for this node, false if another node has it.
+    pub async fn claim(&self, nickname: &str) -> io::Result<bool> {
+        self.store.claim(nickname, &self.node, self.claim_for).await
+    }
+
+    pub async fn release(&self, nickname: &str) {
+        if let Err(e) = self.store.release(nickname, &self.node).await {
+            tracing::warn!(component = "cluster", %nickname, error = %e, "Failed to release");
+        }
+    }
+
+    pub async fn set_presence(&self, nickname: &str, presence: &str) {
+        let result = self
+            .store
+            .set_presence(nickname, presence, self.claim_for)
+            .await;
+
+        if let Err(e) = result {
+            tracing::warn!(component = "cluster", %nickname, error = %e, "Failed to set presence");
+        }
+    }
+
+    /// The node `nickname` is registered on and its status, when that is
+    /// another node.
+    pub async fn locate(&self, nickname: &str) -> Option<(String, Option<String>)> {
+        let owner = self.store.owner(nickname).await.ok()??;
+
+        if owner == self.node {
+            return None;
+        }
+
+        let presence = self.store.presence(nickname).await.ok()?;
+        Some((owner, presence))
+    }
+
+    /// Publishes a message for `to` on the channel of the node that has
+    /// it. False if no other node does.
+    pub async fn route_message(&self, from: &str, to: &str, payload: &str) -> bool {
+        let Some((owner, _)) = self.locate(to).await else {
+            return false;
+        };
+
+        let line = format!("MSG {} {} {}", from, to, payload);
+        self.store.publish(&owner, &line).await.is_ok()
+    }
+
+    /// Publishes a room message for every other node.
+    pub async fn room_message(&self, room: &str, from: &str, payload: &str) {
+        let line = format!("RMSG {} {} {} {}", self.node, room, from, payload);
+
+        if let Err(e) = self.store.publish(ALL, &line).await {
+            tracing::warn!(component = "cluster", error = %e, "Failed to publish");
+        }
+    }
+}
+
+// one line from another node
+async fn handle_line(state: &Arc<ServerState>, cluster: &Cluster, line: &str) {
+    match line.split_once(' ') {
+        Some(("MSG", rest)) => {
+            let mut words = rest.splitn(3, ' ');
+            let (Some(from), Some(to), Some(payload)) = (words.next(), words.next(), words.next())
+            else {
+                return;
+            };
+
+            // it may have gone in the meantime, like a write to a dropped
+            // connection the message is lost
+            if let Some(target) = get_connection_by_nickname(to, state.clone()).await {
+                let line = format!("MSG {} {}", from, payload);
+
+                if send_to(state.clone(), &target, &line).await.is_ok() {
+                    state.metrics.direct_message();
+                }
+            }
+        }
+
+        Some(("RMSG", rest)) => {
+            let mut words = rest.splitn(4, ' ');
+            let (Some(node), Some(room), Some(from), Some(payload)) =
+                (words.next(), words.next(), words.next(), words.next())
+            else {
+                return;
+            };
+
+            if node != cluster.node {
+                rooms::deliver_linked(state.clone(), room, from, payload).await;
+            }
+        }
+
+        _ => {}
+    }
+}
+
+/// Hands this node whatever the others publish for it, subscribing again
+/// whenever the store drops the subscription.
+pub async fn listen(state: Arc<ServerState>, cluster: Arc<Cluster>) {
+    let channels = [cluster.node.clone(), ALL.to_string()];
+
+    loop {
+        match cluster.store.subscribe(&channels).await {
+            Ok(mut lines) => {
+                while let Some(line) = lines.recv().await {
+                    handle_line(&state, &cluster, &line).await;
+                }
+            }
+            Err(e) => {
+                tracing::warn!(component = "cluster", error = %e, "Failed to subscribe")
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Keeps this node's claims alive, renewing them three times per
+/// `claim_seconds`. A nickname whose claim another node took in the
+/// meantime is evicted here with `ERR NICK_TAKEN`, it belongs over there
+/// now.
+pub async fn renew(state: Arc<ServerState>, cluster: Arc<Cluster>) {
+    let interval = cluster.claim_for / 3;
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let registered: Vec<(String, &'static str)> = state
+            .connections
+            .filter_map(|c| Some((c.nickname.clone(), c.status.as_str())));
+
+        for (nickname, status) in registered {
+            match cluster.claim(&nickname).await {
+                Ok(true) => cluster.set_presence(&nickname, status).await,
+                // lapsed while the store was unreachable and taken since
+                Ok(false) => {
+                    tracing::warn!(component = "cluster", %nickname, "Lost nickname claim");
+                    state.evict(&nickname, "NICK_TAKEN").await;
+                }
+                Err(e) => {
+                    tracing::warn!(component = "cluster", %nickname, error = %e, "Failed to renew")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn claims_belong_to_one_node_until_they_lapse() {
+        let store = MemoryStore::default();
+        let ttl = Duration::from_millis(50);
+
+        assert!(store.claim("alice", "a", ttl).await.unwrap());
+        assert!(!store.claim("alice", "b", ttl).await.unwrap());
+        // renewing is claiming again
+        assert!(store.claim("alice", "a", ttl).await.unwrap());
+
+        // only the owner can release
+        store.release("alice", "b").await.unwrap();
+        assert_eq!(store.owner("alice").await.unwrap().as_deref(), Some("a"));
+
+        tokio::time::sleep(ttl * 2).await;
+        assert_eq!(store.owner("alice").await.unwrap(), None);
+        assert!(store.claim("alice", "b", ttl).await.unwrap());
+    }
+}
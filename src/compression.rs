@@ -0,0 +1,218 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+// a frame is a kind byte, a four byte big-endian length and the payload
+const HEADER_LEN: usize = 5;
+const KIND_RAW: u8 = 0;
+const KIND_COMPRESSED: u8 = 1;
+// the most a frame holds once decompressed, bigger writes are split; this
+// is also what stops a small frame from inflating without end
+const MAX_FRAME: usize = 64 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+/// How a connection compresses what goes over it, agreed on with
+/// `CAP compress=<name>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "gzip" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    /// What `CAP compress=` calls it, nothing for no compression.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zstd"),
+            Compression::Gzip => Some("gzip"),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let too_big = || io::Error::new(io::ErrorKind::InvalidData, "frame too big");
+
+        match self {
+            Compression::None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed frame without compression",
+            )),
+            Compression::Zstd => zstd::bulk::decompress(data, MAX_FRAME).map_err(|_| too_big()),
+            Compression::Gzip => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(data)
+                    .take(MAX_FRAME as u64 + 1)
+                    .read_to_end(&mut decoded)?;
+
+                if decoded.len() > MAX_FRAME {
+                    return Err(too_big());
+                }
+                Ok(decoded)
+            }
+        }
+    }
+}
+
+/// The compression a connection's reader and writer share, so switching
+/// it on the writer switches what the reader expects too.
+#[derive(Clone, Default)]
+pub struct Mode(Arc<AtomicU8>);
+
+impl Mode {
+    pub fn get(&self) -> Compression {
+        match self.0.load(Ordering::Relaxed) {
+            1 => Compression::Zstd,
+            2 => Compression::Gzip,
+            _ => Compression::None,
+        }
+    }
+
+    pub fn set(&self, compression: Compression) {
+        let value = match compression {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Gzip => 2,
+        };
+
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// `data` as frames, compressing the ones of at least `min_size` bytes
+/// when that makes them smaller.
+pub fn encode(compression: Compression, data: &[u8], min_size: usize) -> io::Result<Vec<u8>> {
+    let mut frames = Vec::with_capacity(data.len() + HEADER_LEN);
+
+    for chunk in data.chunks(MAX_FRAME) {
+        let compressed = match chunk.len() >= min_size {
+            true => Some(compression.compress(chunk)?).filter(|c| c.len() < chunk.len()),
+            false => None,
+        };
+
+        let (kind, payload) = match &compressed {
+            Some(compressed) => (KIND_COMPRESSED, compressed.as_slice()),
+            None => (KIND_RAW, chunk),
+        };
+
+        frames.push(kind);
+        frames.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frames.extend_from_slice(payload);
+    }
+
+    Ok(frames)
+}
+
+/// Takes every complete frame off the front of `encoded` and appends what
+/// it held to `decoded`, leaving a partial frame for the next read.
+pub fn decode(
+    compression: Compression,
+    encoded: &mut Vec<u8>,
+    decoded: &mut Vec<u8>,
+) -> io::Result<()> {
+    let mut start = 0;
+
+    while encoded.len() - start >= HEADER_LEN {
+        let header = &encoded[start..start + HEADER_LEN];
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+
+        if len > MAX_FRAME {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too big"));
+        }
+
+        if encoded.len() - start < HEADER_LEN + len {
+            break;
+        }
+
+        let payload = &encoded[start + HEADER_LEN..start + HEADER_LEN + len];
+
+        match header[0] {
+            KIND_RAW => decoded.extend_from_slice(payload),
+            KIND_COMPRESSED => decoded.extend_from_slice(&compression.decompress(payload)?),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad frame")),
+        }
+
+        start += HEADER_LEN + len;
+    }
+
+    encoded.drain(..start);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: Compression, data: &[u8], min_size: usize) -> Vec<u8> {
+        let mut encoded = encode(compression, data, min_size).unwrap();
+        let mut decoded = Vec::new();
+
+        // a frame at a time, the way they come off a socket
+        let mut pending = Vec::new();
+        for byte in encoded.drain(..) {
+            pending.push(byte);
+            decode(compression, &mut pending, &mut decoded).unwrap();
+        }
+
+        assert!(pending.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let big = b"FILE_CHUNK 1 aGVsbG8gd29ybGQ=\n".repeat(5000);
+
+        for compression in [Compression::Zstd, Compression::Gzip] {
+            assert_eq!(
+                round_trip(compression, b"MSG bob hi\n", 512),
+                b"MSG bob hi\n"
+            );
+            assert_eq!(round_trip(compression, &big, 512), big);
+
+            // big writes are worth it, small ones are sent as they are
+            assert!(encode(compression, &big, 512).unwrap().len() < big.len() / 10);
+            assert_eq!(
+                encode(compression, b"MSG bob hi\n", 512).unwrap().len(),
+                HEADER_LEN + 11
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut frame = vec![KIND_RAW];
+        frame.extend_from_slice(&(MAX_FRAME as u32 + 1).to_be_bytes());
+        assert!(decode(Compression::Zstd, &mut frame, &mut Vec::new()).is_err());
+
+        // and so are small ones that inflate past the limit
+        let bomb = vec![0; MAX_FRAME * 4];
+        let packed = Compression::Zstd.compress(&bomb).unwrap();
+        let mut frame = vec![KIND_COMPRESSED];
+        frame.extend_from_slice(&(packed.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&packed);
+        assert!(decode(Compression::Zstd, &mut frame, &mut Vec::new()).is_err());
+    }
+}
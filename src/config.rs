@@ -0,0 +1,732 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// used when P2P_CONFIG is not set
+const DEFAULT_CONFIG_PATH: &str = "p2p.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub auth: AuthConfig,
+    pub whois: WhoisConfig,
+    pub history: HistoryConfig,
+    pub offline: OfflineConfig,
+    pub acks: AckConfig,
+    pub files: FilesConfig,
+    pub stealth: StealthConfig,
+    pub punch: PunchConfig,
+    pub relay: RelayConfig,
+    pub signaling: SignalingConfig,
+    pub dht: DhtConfig,
+    pub pex: PexConfig,
+    pub noise: NoiseConfig,
+    pub identity: IdentityConfig,
+    pub resume: ResumeConfig,
+    pub log: LogConfig,
+    pub mdns: MdnsConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub motd: MotdConfig,
+    pub outbound: OutboundConfig,
+    pub compression: CompressionConfig,
+    pub federation: FederationConfig,
+    pub cluster: ClusterConfig,
+    pub daemon: DaemonConfig,
+    pub typing: TypingConfig,
+    pub bandwidth: BandwidthConfig,
+    pub tcp: TcpConfig,
+    pub listen: ListenConfig,
+    pub rdns: RdnsConfig,
+    pub nicknames: NicknamesConfig,
+    pub audit: AuditConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // when false, REG works without a token
+    pub enabled: bool,
+    // pre-shared token accepted for any nickname
+    pub token: Option<String>,
+    // per-nickname credentials, these take priority over the shared token
+    pub users: HashMap<String, String>,
+    // failed attempts from one ip before it gets banned
+    pub max_failures: u32,
+    // failures only add up within this long of the first one, after that
+    // the count starts over
+    pub failure_window_seconds: u64,
+    // how long a ban lasts
+    pub ban_seconds: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            enabled: false,
+            token: None,
+            users: HashMap::new(),
+            max_failures: 5,
+            failure_window_seconds: 600,
+            ban_seconds: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WhoisConfig {
+    // leave the observed address out of WHOIS replies
+    pub hide_address: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    // messages kept per room
+    pub size: usize,
+    // messages replayed to a member right after JOIN, 0 turns it off
+    pub replay_on_join: usize,
+    // rooms whose history is kept, emptied ones included; past this the
+    // one quiet the longest is forgotten
+    pub max_rooms: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            size: 100,
+            replay_on_join: 0,
+            max_rooms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OfflineConfig {
+    // queue MSG for registered nicknames that are offline
+    pub enabled: bool,
+    // where pending messages are kept, in memory only when unset
+    pub path: Option<PathBuf>,
+    pub max_per_nickname: usize,
+    // queued messages older than this are dropped
+    pub ttl_seconds: u64,
+    // nicknames not registered for this long are forgotten, messages can't
+    // be queued for them anymore; 0 remembers them for good
+    pub forget_after_days: u64,
+    // nicknames remembered at most, the longest gone are forgotten first;
+    // 0 for no limit
+    pub max_known: usize,
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        OfflineConfig {
+            enabled: true,
+            path: None,
+            max_per_nickname: 50,
+            ttl_seconds: 60 * 60 * 24,
+            forget_after_days: 90,
+            max_known: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AckConfig {
+    // how long a MSGID waits for its ACK before ERR UNDELIVERED
+    pub timeout_seconds: u64,
+    // acknowledged messages remembered until the recipient sends READ,
+    // the oldest are forgotten past this
+    pub max_unread: usize,
+}
+
+impl Default for AckConfig {
+    fn default() -> Self {
+        AckConfig {
+            timeout_seconds: 30,
+            max_unread: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    // largest file that can be offered, in bytes
+    pub max_size: u64,
+    // chunks a sender may have in flight before the recipient acks them
+    pub window: usize,
+    // how long an interrupted transfer waits for FILE_RESUME
+    pub resume_timeout_seconds: u64,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        FilesConfig {
+            max_size: 100 * 1024 * 1024,
+            window: 8,
+            resume_timeout_seconds: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StealthConfig {
+    // say nothing until the client opens with HELLO, drop it otherwise
+    pub enabled: bool,
+    // token the opening HELLO has to carry
+    pub knock: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PunchConfig {
+    // pause between sending CONNECT addresses and the PUNCH cue
+    pub delay_ms: u64,
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        PunchConfig { delay_ms: 250 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // tracing filter directives, such as "debug" or "info,p2p_rs::dht=debug";
+    // the P2P_LOG environment variable takes precedence
+    pub level: String,
+    // "pretty" for people, "json" for log collectors, one object per line
+    pub format: LogFormat,
+    // log to this file instead of stdout, rotated as set below
+    pub file: Option<PathBuf>,
+    // when to start a new file: "never", "minutely", "hourly" or "daily";
+    // rotated files get the date and time appended to their name
+    pub rotation: LogRotation,
+    // rotated files kept around, older ones are deleted; unlimited if unset
+    pub max_files: Option<usize>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+            file: None,
+            rotation: LogRotation::Daily,
+            max_files: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResumeConfig {
+    // hand out session tokens with REG and take RESUME
+    pub enabled: bool,
+    // how long a dropped nickname stays reserved for its token
+    pub grace_seconds: u64,
+    // messages held for a dropped nickname until it resumes
+    pub max_pending: usize,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        ResumeConfig {
+            enabled: false,
+            grace_seconds: 60,
+            max_pending: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    // allow RELAY_OPEN at all
+    pub enabled: bool,
+    // bytes a session may relay in total, both directions, 0 for no limit
+    pub max_bytes: u64,
+    // throughput cap per session, 0 for no limit
+    pub bytes_per_second: u64,
+    // how long both sides have to attach after RELAY_OPEN
+    pub open_timeout_seconds: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            enabled: true,
+            max_bytes: 100 * 1024 * 1024,
+            bytes_per_second: 0,
+            open_timeout_seconds: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalingConfig {
+    // largest SDP_OFFER/SDP_ANSWER/ICE_CAND payload, in bytes
+    pub max_payload: usize,
+}
+
+impl Default for SignalingConfig {
+    fn default() -> Self {
+        SignalingConfig {
+            max_payload: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtConfig {
+    // join the distributed nickname directory alongside the server
+    pub enabled: bool,
+    // udp address the dht node listens on
+    pub bind: String,
+    // known nodes to join through, as host:port
+    pub bootstrap: Vec<String>,
+    // bucket size and how many nodes each record is stored on
+    pub k: usize,
+    // lookups in flight at once during a lookup
+    pub alpha: usize,
+    // how long a published record lives, it is republished at half that
+    pub record_ttl_seconds: u64,
+    // how long to wait for another node to answer
+    pub request_timeout_ms: u64,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            enabled: false,
+            bind: "0.0.0.0:4002".to_string(),
+            bootstrap: Vec::new(),
+            k: 20,
+            alpha: 3,
+            record_ttl_seconds: 3600,
+            request_timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PexConfig {
+    // other servers to gossip adverts with, as host:port; they have to
+    // list this server too for their pushes to be accepted
+    pub servers: Vec<String>,
+    pub interval_seconds: u64,
+    // longest an advert may live, and what ADVERTISE uses without a ttl
+    pub max_ttl_seconds: u64,
+}
+
+impl Default for PexConfig {
+    fn default() -> Self {
+        PexConfig {
+            servers: Vec::new(),
+            interval_seconds: 60,
+            max_ttl_seconds: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoiseConfig {
+    // accept Noise XX handshakes next to plaintext connections
+    pub enabled: bool,
+    // base64 static private key from `p2p-rs noise-keygen`; without one a
+    // new key is made on every start
+    pub private_key: Option<String>,
+    // refuse plaintext connections
+    pub required: bool,
+    // base64 client static keys allowed to connect, empty for anyone
+    pub allowed_keys: Vec<String>,
+    // a connection that hasn't sent its first byte, or finished its
+    // handshake, this long after connecting is closed
+    pub handshake_timeout_seconds: u64,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        NoiseConfig {
+            enabled: false,
+            private_key: None,
+            required: false,
+            allowed_keys: Vec::new(),
+            handshake_timeout_seconds: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    // refuse REG without a signed identity key
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsConfig {
+    // announce the server on the LAN as _p2p._tcp.local
+    pub enabled: bool,
+    // instance name to announce, a random p2p-xxxx if unset
+    pub name: Option<String>,
+    // also log the other instances seen on the LAN
+    pub browse: bool,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            enabled: false,
+            name: None,
+            browse: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    // serve Prometheus metrics over http at /metrics
+    pub enabled: bool,
+    // address the metrics endpoint listens on
+    pub bind: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            bind: "127.0.0.1:4003".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // serve the admin http api
+    pub enabled: bool,
+    // address the admin api listens on
+    pub bind: String,
+    // bearer token every admin request has to carry
+    pub token: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            bind: "127.0.0.1:4004".to_string(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotdConfig {
+    // sent line by line right after a successful REG
+    pub text: Option<String>,
+    // read on every REG instead of `text`, which stays the fallback
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    // lines waiting to be written to one connection before it counts as a
+    // slow consumer: presence and room notices are dropped past half of
+    // it, anything else past all of it disconnects with ERR SLOW, 0 for
+    // no limit
+    pub max_queued: usize,
+    // a write to another connection taking longer than this disconnects
+    // it too, 0 to wait forever
+    pub write_timeout_seconds: u64,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig {
+            max_queued: 64,
+            write_timeout_seconds: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    // when false, CAP compress=<zstd|gzip> is refused with NO_COMPRESS
+    pub enabled: bool,
+    // writes smaller than this are sent as they are, compressing them
+    // costs more than it saves
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 512,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+    // shared by every linked server, LINK is refused without it
+    pub secret: Option<String>,
+    // servers to link to, as host:port, linked again whenever the link drops
+    pub links: Vec<String>,
+    // how this server introduces itself when linking, the address it
+    // listens on when unset
+    pub name: Option<String>,
+    pub retry_seconds: u64,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        FederationConfig {
+            secret: None,
+            links: Vec::new(),
+            name: None,
+            retry_seconds: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub enabled: bool,
+    pub redis_url: String,
+    // this node's name, unique in the cluster, made up at startup when
+    // unset
+    pub node: Option<String>,
+    // how long claims outlive a node that died without releasing them
+    pub claim_seconds: u64,
+    // in front of every key and channel, so clusters can share a redis
+    pub prefix: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            enabled: false,
+            redis_url: "redis://127.0.0.1/".to_string(),
+            node: None,
+            claim_seconds: 30,
+            prefix: "p2p".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    // where `p2p-rs --daemon` writes its pid, removed again on exit
+    pub pidfile: PathBuf,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            pidfile: PathBuf::from("p2p-rs.pid"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TypingConfig {
+    // a connection's TYPING notices passed on at most this often, the
+    // ones in between are dropped; 0 passes on every one
+    pub interval_seconds: u64,
+}
+
+impl Default for TypingConfig {
+    fn default() -> Self {
+        TypingConfig {
+            interval_seconds: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthConfig {
+    // relayed bytes a second a connection may send through the server,
+    // relay sessions and file chunks alike; 0 for no limit
+    pub connection_bytes_per_second: u64,
+    // the same, shared by every connection from one ip
+    pub ip_bytes_per_second: u64,
+    // bytes let through at once before the caps kick in
+    pub burst_bytes: u64,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        BandwidthConfig {
+            connection_bytes_per_second: 0,
+            ip_bytes_per_second: 0,
+            burst_bytes: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    // send small writes straight away instead of batching them (TCP_NODELAY)
+    pub nodelay: bool,
+    // idle time before the OS starts probing a silent peer, 0 for no probes
+    pub keepalive_seconds: u64,
+    // time between unanswered probes, 0 for the OS default
+    pub keepalive_interval_seconds: u64,
+    // unanswered probes before the peer is given up on, 0 for the OS default
+    pub keepalive_retries: u32,
+    // how long closing waits to flush what's unsent (SO_LINGER), unset to
+    // leave it to the OS in the background
+    pub linger_seconds: Option<u64>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            nodelay: true,
+            keepalive_seconds: 0,
+            keepalive_interval_seconds: 0,
+            keepalive_retries: 0,
+            linger_seconds: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    // every address connections are accepted on, all sharing one set of
+    // nicknames and rooms; the first is the one announced over mdns.
+    // Empty for just 127.0.0.1:4001
+    pub addrs: Vec<String>,
+}
+
+impl ListenConfig {
+    /// `addrs`, or the default address when there are none.
+    pub fn addrs_or_default(&self) -> Vec<String> {
+        if self.addrs.is_empty() {
+            vec![crate::server::BIND_ADDR.to_string()]
+        } else {
+            self.addrs.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RdnsConfig {
+    // look up host names for connecting addresses, for logs and WHOIS
+    pub enabled: bool,
+    // a lookup taking longer than this gives no name
+    pub timeout_seconds: u64,
+    // how long an answer, or the lack of one, is remembered
+    pub cache_seconds: u64,
+}
+
+impl Default for RdnsConfig {
+    fn default() -> Self {
+        RdnsConfig {
+            enabled: false,
+            timeout_seconds: 2,
+            cache_seconds: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NicknamesConfig {
+    // a nickname whose connection has sent nothing for this long goes to
+    // the next REG for it that passes [auth], which disconnects the silent
+    // one; 0 keeps it for as long as the connection stays up
+    pub idle_release_seconds: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // append registrations, disconnects, bans, admin actions and relay
+    // volume to this file as JSON lines, one file per day with the date
+    // appended to its name; no audit log if unset
+    pub path: Option<PathBuf>,
+    // days of files kept, older ones are deleted; 0 keeps them all
+    pub retention_days: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            path: None,
+            retention_days: 90,
+        }
+    }
+}
+
+impl Config {
+    /// The config file in use: `P2P_CONFIG` if set, else `p2p.toml` when it
+    /// exists.
+    pub fn path() -> Option<PathBuf> {
+        match std::env::var("P2P_CONFIG") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Some(PathBuf::from(DEFAULT_CONFIG_PATH))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Reads the config from `Config::path`, falling back to the default
+    /// config if there is no file.
+    pub fn load() -> io::Result<Config> {
+        match Config::path() {
+            Some(path) => Config::from_file(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Config> {
+        let contents = std::fs::read_to_string(path)?;
+
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
@@ -0,0 +1,161 @@
+//! Running in the background without a supervisor, for `p2p-rs --daemon`.
+//!
+//! [`detach`] has to happen before the tokio runtime starts: forking only
+//! keeps the calling thread. The process that ran `p2p-rs` waits until
+//! the detached one says it is listening, so a bad config or a taken port
+//! still fails the command, and exits with the outcome.
+//!
+//! The working directory stays as it was, relative paths in the config
+//! mean what they did in the foreground.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// The detached process. The pidfile is removed when this is dropped.
+pub struct Daemon {
+    pidfile: PathBuf,
+    // to the waiting parent, closed without a word if startup fails
+    ready: Option<File>,
+}
+
+impl Daemon {
+    /// Tells the waiting parent startup went fine, letting it exit.
+    pub fn ready(&mut self) {
+        if let Some(mut pipe) = self.ready.take() {
+            let _ = pipe.write_all(&[1]);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.pidfile);
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// the pid in `pidfile`, when that process is still around
+fn running(pidfile: &Path) -> Option<libc::pid_t> {
+    let pid: libc::pid_t = fs::read_to_string(pidfile).ok()?.trim().parse().ok()?;
+
+    // signal 0 checks without sending; EPERM means it exists as someone else
+    let alive = unsafe { libc::kill(pid, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+
+    (pid > 0 && alive).then_some(pid)
+}
+
+/// Writes this process's pid to `pidfile`, unless the pid already in it
+/// is still running. A stale one is overwritten.
+pub fn write_pidfile(pidfile: &Path) -> io::Result<()> {
+    if let Some(pid) = running(pidfile) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} says pid {} is running", pidfile.display(), pid),
+        ));
+    }
+
+    fs::write(pidfile, format!("{}\n", std::process::id()))
+}
+
+/// Forks into the background and writes `pidfile`. Only the detached
+/// process returns; the one that called this waits for
+/// [`Daemon::ready`] and exits, 0 once ready and 1 if the detached one
+/// gave up first.
+pub fn detach(pidfile: &Path) -> io::Result<Daemon> {
+    // refused here, while there's still a terminal to say so on
+    if let Some(pid) = running(pidfile) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("already running as pid {} ({})", pid, pidfile.display()),
+        ));
+    }
+
+    let mut fds: [RawFd; 2] = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (read_end, write_end) = (fds[0], fds[1]);
+
+    if check(unsafe { libc::fork() })? > 0 {
+        unsafe { libc::close(write_end) };
+        wait_for_ready(unsafe { File::from_raw_fd(read_end) }, pidfile);
+    }
+
+    unsafe { libc::close(read_end) };
+
+    // a session of its own, so the terminal closing doesn't take it along,
+    // then forked again so it can never get a terminal back
+    check(unsafe { libc::setsid() })?;
+
+    if check(unsafe { libc::fork() })? > 0 {
+        unsafe { libc::_exit(0) };
+    }
+
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        check(unsafe { libc::dup2(std::os::fd::AsRawFd::as_raw_fd(&null), fd) })?;
+    }
+
+    let ready = Some(unsafe { File::from_raw_fd(write_end) });
+    write_pidfile(pidfile)?;
+
+    Ok(Daemon {
+        pidfile: pidfile.to_path_buf(),
+        ready,
+    })
+}
+
+fn wait_for_ready(mut pipe: File, pidfile: &Path) -> ! {
+    let mut byte = [0];
+
+    match pipe.read(&mut byte) {
+        Ok(1) => {
+            let pid = fs::read_to_string(pidfile).unwrap_or_default();
+            println!("Running in the background as pid {}", pid.trim());
+            std::process::exit(0);
+        }
+        _ => {
+            eprintln!("Failed to start in the background, see the log");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_pidfiles_are_not_overwritten() {
+        let dir = std::env::temp_dir().join(format!("p2p-rs-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pidfile = dir.join("p2p-rs.pid");
+
+        // this process is certainly running
+        write_pidfile(&pidfile).unwrap();
+        let err = write_pidfile(&pidfile).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // pid_max on Linux is at most 2^22, so this one is gone
+        fs::write(&pidfile, "2147483646\n").unwrap();
+        write_pidfile(&pidfile).unwrap();
+        assert_eq!(
+            fs::read_to_string(&pidfile).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
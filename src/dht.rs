@@ -0,0 +1,583 @@
+use crate::config::DhtConfig;
+use crate::server::{get_connection_by_nickname, send_error_response, send_response, ServerState};
+use crate::transport::Writer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinSet;
+
+const ID_BYTES: usize = 32;
+// a full NODES reply with the default k fits well within this
+const MAX_DATAGRAM: usize = 16 * 1024;
+// how often expired records are thrown away
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Node ids and record keys share one 256 bit space. Record keys are the
+/// SHA-256 of the nickname, so the nodes closest to that hash hold it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeId([u8; ID_BYTES]);
+
+impl NodeId {
+    fn random() -> Self {
+        NodeId(rand::random())
+    }
+
+    pub fn for_nickname(nickname: &str) -> Self {
+        NodeId(Sha256::digest(nickname.as_bytes()).into())
+    }
+
+    // the xor metric, compared as a big-endian number
+    fn distance(&self, other: &NodeId) -> [u8; ID_BYTES] {
+        let mut distance = [0; ID_BYTES];
+
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+
+        distance
+    }
+
+    // index of the highest differing bit, None for the same id
+    fn bucket(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let byte = distance.iter().position(|b| *b != 0)?;
+        let bit = distance[byte].leading_zeros() as usize;
+
+        Some(ID_BYTES * 8 - 1 - (byte * 8 + bit))
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Contact {
+    id: NodeId,
+    addr: SocketAddr,
+}
+
+/// Where a nickname was last registered, and until when that holds.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub nickname: String,
+    pub endpoint: SocketAddr,
+    // unix seconds
+    pub expires: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Record {
+    fn is_expired(&self) -> bool {
+        self.expires <= unix_now()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Body {
+    Ping,
+    Pong,
+    FindNode { target: NodeId },
+    Nodes { nodes: Vec<Contact> },
+    Store { record: Record },
+    Stored,
+    FindValue { nickname: String },
+    Value { record: Record },
+}
+
+// one json datagram, replies carry the rpc id of their request
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    rpc: u64,
+    from: NodeId,
+    reply: bool,
+    body: Body,
+}
+
+// k-buckets, one per bit of distance from our own id
+struct RoutingTable {
+    own: NodeId,
+    k: usize,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId, k: usize) -> Self {
+        RoutingTable {
+            own,
+            k,
+            buckets: vec![Vec::new(); ID_BYTES * 8],
+        }
+    }
+
+    // most recently seen contacts go last; a full bucket keeps its old
+    // contacts, which have proven to stay up
+    fn insert(&mut self, contact: Contact) {
+        let Some(index) = self.own.bucket(&contact.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(i) = bucket.iter().position(|c| c.id == contact.id) {
+            bucket.remove(i);
+            bucket.push(contact);
+        } else if bucket.len() < self.k {
+            bucket.push(contact);
+        }
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.own.bucket(id) {
+            self.buckets[index].retain(|c| c.id != *id);
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flatten().copied().collect();
+
+        contacts.sort_by_key(|c| c.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+/// A Kademlia node. Nodes find each other through the bootstrap list and
+/// store nickname records on the k nodes closest to each nickname's hash,
+/// so a nickname can be found through any node without a central server.
+/// Records are taken at face value, the dht does not prove who published
+/// them.
+pub struct Dht {
+    id: NodeId,
+    config: DhtConfig,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    records: Mutex<HashMap<NodeId, Record>>,
+    next_rpc: AtomicU64,
+    // requests waiting for a reply, with the address it has to come from
+    pending: Mutex<HashMap<u64, (SocketAddr, oneshot::Sender<Body>)>>,
+}
+
+impl Dht {
+    /// Binds the node and joins the network through `bootstrap` in the
+    /// background.
+    pub async fn start(config: DhtConfig) -> io::Result<Arc<Dht>> {
+        let socket = UdpSocket::bind(&config.bind).await?;
+        let id = NodeId::random();
+
+        let dht = Arc::new(Dht {
+            id,
+            table: Mutex::new(RoutingTable::new(id, config.k)),
+            records: Mutex::new(HashMap::new()),
+            next_rpc: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+            socket,
+            config,
+        });
+
+        tokio::spawn(dht.clone().serve());
+        tokio::spawn(dht.clone().purge_expired());
+        tokio::spawn(dht.clone().bootstrap());
+
+        Ok(dht)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Publishes `nickname` at `endpoint` on the nodes closest to it.
+    pub async fn announce(self: &Arc<Self>, nickname: &str, endpoint: SocketAddr) {
+        let record = Record {
+            nickname: nickname.to_string(),
+            endpoint,
+            expires: unix_now() + self.config.record_ttl_seconds,
+        };
+        let key = NodeId::for_nickname(nickname);
+
+        // keep a copy too, so small networks always have one
+        self.keep(record.clone()).await;
+
+        let nodes = match self.iterate(key, None).await {
+            Ok(_) => self.table.lock().await.closest(&key, self.config.k),
+            Err(nodes) => nodes,
+        };
+
+        let mut stores = JoinSet::new();
+
+        for node in nodes {
+            let dht = self.clone();
+            let body = Body::Store {
+                record: record.clone(),
+            };
+
+            stores.spawn(async move { dht.request(node.addr, body).await });
+        }
+
+        while stores.join_next().await.is_some() {}
+    }
+
+    /// Finds the current record for `nickname`, if any node has one.
+    pub async fn lookup(self: &Arc<Self>, nickname: &str) -> Option<Record> {
+        let key = NodeId::for_nickname(nickname);
+
+        if let Some(record) = self.records.lock().await.get(&key) {
+            if !record.is_expired() {
+                return Some(record.clone());
+            }
+        }
+
+        self.iterate(key, Some(nickname)).await.ok()
+    }
+
+    // stores a record unless a fresher one for the nickname is already here
+    async fn keep(&self, mut record: Record) {
+        // nobody gets to keep a record here for longer than our own ttl
+        record.expires = record
+            .expires
+            .min(unix_now() + self.config.record_ttl_seconds);
+
+        if record.is_expired() {
+            return;
+        }
+
+        let mut records = self.records.lock().await;
+        let key = NodeId::for_nickname(&record.nickname);
+
+        match records.get(&key) {
+            Some(existing) if existing.expires >= record.expires => {}
+            _ => {
+                records.insert(key, record);
+            }
+        }
+    }
+
+    async fn send(&self, addr: SocketAddr, packet: Packet) {
+        if let Ok(data) = serde_json::to_vec(&packet) {
+            // udp is lossy anyway, a failed send looks like a timeout
+            let _ = self.socket.send_to(&data, addr).await;
+        }
+    }
+
+    // sends a request and waits for its reply, None on timeout
+    async fn request(&self, addr: SocketAddr, body: Body) -> Option<Body> {
+        let rpc = self.next_rpc.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+
+        self.pending.lock().await.insert(rpc, (addr, sender));
+
+        let packet = Packet {
+            rpc,
+            from: self.id,
+            reply: false,
+            body,
+        };
+        self.send(addr, packet).await;
+
+        let timeout = Duration::from_millis(self.config.request_timeout_ms);
+        let reply = tokio::time::timeout(timeout, receiver).await;
+
+        self.pending.lock().await.remove(&rpc);
+
+        reply.ok()?.ok()
+    }
+
+    // the iterative lookup: keep asking the closest nodes not asked yet,
+    // `alpha` at a time, until nobody closer turns up. With a nickname it
+    // stops at the first node holding its record.
+    async fn iterate(
+        self: &Arc<Self>,
+        target: NodeId,
+        nickname: Option<&str>,
+    ) -> Result<Record, Vec<Contact>> {
+        let k = self.config.k;
+        let mut shortlist = self.table.lock().await.closest(&target, k);
+        let mut queried: HashSet<NodeId> = HashSet::new();
+        let mut failed: HashSet<NodeId> = HashSet::new();
+
+        loop {
+            let batch: Vec<Contact> = shortlist
+                .iter()
+                .filter(|c| !queried.contains(&c.id))
+                .take(self.config.alpha.max(1))
+                .copied()
+                .collect();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut replies = JoinSet::new();
+
+            for contact in batch {
+                queried.insert(contact.id);
+
+                let dht = self.clone();
+                let body = match nickname {
+                    Some(nickname) => Body::FindValue {
+                        nickname: nickname.to_string(),
+                    },
+                    None => Body::FindNode { target },
+                };
+
+                replies.spawn(async move { (contact, dht.request(contact.addr, body).await) });
+            }
+
+            while let Some(Ok((contact, reply))) = replies.join_next().await {
+                match reply {
+                    Some(Body::Value { record })
+                        if Some(record.nickname.as_str()) == nickname && !record.is_expired() =>
+                    {
+                        return Ok(record);
+                    }
+                    Some(Body::Nodes { nodes }) => {
+                        for node in nodes {
+                            let known = shortlist.iter().any(|c| c.id == node.id);
+
+                            if node.id != self.id && !known && !failed.contains(&node.id) {
+                                shortlist.push(node);
+                            }
+                        }
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.table.lock().await.remove(&contact.id);
+                        failed.insert(contact.id);
+                        shortlist.retain(|c| c.id != contact.id);
+                    }
+                }
+            }
+
+            shortlist.sort_by_key(|c| c.id.distance(&target));
+            shortlist.truncate(k);
+        }
+
+        Err(shortlist)
+    }
+
+    async fn serve(self: Arc<Self>) {
+        let mut buffer = vec![0; MAX_DATAGRAM];
+
+        loop {
+            let Ok((n, addr)) = self.socket.recv_from(&mut buffer).await else {
+                continue;
+            };
+
+            let Ok(packet) = serde_json::from_slice::<Packet>(&buffer[..n]) else {
+                continue;
+            };
+
+            // anyone who talks to us is a candidate for the routing table
+            self.table.lock().await.insert(Contact {
+                id: packet.from,
+                addr,
+            });
+
+            if packet.reply {
+                let mut pending = self.pending.lock().await;
+
+                if pending
+                    .get(&packet.rpc)
+                    .is_some_and(|(from, _)| *from == addr)
+                {
+                    if let Some((_, sender)) = pending.remove(&packet.rpc) {
+                        let _ = sender.send(packet.body);
+                    }
+                }
+                continue;
+            }
+
+            if let Some(body) = self.answer(packet.body).await {
+                let reply = Packet {
+                    rpc: packet.rpc,
+                    from: self.id,
+                    reply: true,
+                    body,
+                };
+                self.send(addr, reply).await;
+            }
+        }
+    }
+
+    async fn answer(&self, body: Body) -> Option<Body> {
+        let k = self.config.k;
+
+        let body = match body {
+            Body::Ping => Body::Pong,
+            Body::FindNode { target } => Body::Nodes {
+                nodes: self.table.lock().await.closest(&target, k),
+            },
+            Body::Store { record } => {
+                self.keep(record).await;
+                Body::Stored
+            }
+            Body::FindValue { nickname } => {
+                let key = NodeId::for_nickname(&nickname);
+
+                match self.records.lock().await.get(&key) {
+                    Some(record) if !record.is_expired() => Body::Value {
+                        record: record.clone(),
+                    },
+                    _ => Body::Nodes {
+                        nodes: self.table.lock().await.closest(&key, k),
+                    },
+                }
+            }
+            // replies that came in without being asked for
+            _ => return None,
+        };
+
+        Some(body)
+    }
+
+    async fn bootstrap(self: Arc<Self>) {
+        let mut pings = JoinSet::new();
+
+        for host in self.config.bootstrap.clone() {
+            let dht = self.clone();
+
+            pings.spawn(async move {
+                let Ok(addrs) = tokio::net::lookup_host(&host).await else {
+                    tracing::warn!(component = "dht", %host, "Failed to resolve bootstrap node");
+                    return;
+                };
+
+                // a pong puts the node in the routing table
+                for addr in addrs {
+                    dht.request(addr, Body::Ping).await;
+                }
+            });
+        }
+
+        while pings.join_next().await.is_some() {}
+
+        // looking up our own id fills the buckets near us
+        let _ = self.iterate(self.id, None).await;
+
+        let nodes = self.table.lock().await.len();
+        tracing::info!(component = "dht", nodes, "Joined");
+    }
+
+    async fn purge_expired(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(PURGE_INTERVAL).await;
+
+            self.records.lock().await.retain(|_, r| !r.is_expired());
+        }
+    }
+}
+
+/// Keeps the records of everyone registered here alive by announcing them
+/// again at half their lifetime.
+pub fn republish_registered(state: Arc<ServerState>, dht: Arc<Dht>) {
+    let interval = Duration::from_secs((state.config.dht.record_ttl_seconds / 2).max(1));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let registered: Vec<(String, SocketAddr)> = state
+                .connections
+                .filter_map(|c| Some((c.nickname.clone(), c.addr)));
+
+            for (nickname, addr) in registered {
+                dht.announce(&nickname, addr).await;
+            }
+        }
+    });
+}
+
+/// `LOOKUP <nick>` finds where a nickname is registered, on this server or
+/// through the dht, as `LOOKUP <nick> <addr>`.
+pub async fn handle_lookup(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>, nickname: &str) {
+    if let Some(conn) = get_connection_by_nickname(nickname, state.clone()).await {
+        send_response(
+            socket,
+            format!("LOOKUP {} {}", conn.nickname, conn.addr).as_str(),
+            true,
+        )
+        .await;
+        return;
+    }
+
+    let record = match &state.dht {
+        Some(dht) => dht.lookup(nickname).await,
+        None => None,
+    };
+
+    match record {
+        Some(record) => {
+            send_response(
+                socket,
+                format!("LOOKUP {} {}", record.nickname, record.endpoint).as_str(),
+                true,
+            )
+            .await
+        }
+        None => send_error_response(socket, "NO_NICK").await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn node(bootstrap: Vec<String>) -> Arc<Dht> {
+        let config = DhtConfig {
+            enabled: true,
+            bind: "127.0.0.1:0".to_string(),
+            bootstrap,
+            request_timeout_ms: 200,
+            ..DhtConfig::default()
+        };
+
+        Dht::start(config).await.unwrap()
+    }
+
+    #[test]
+    fn buckets_follow_the_highest_differing_bit() {
+        let a = NodeId([0; ID_BYTES]);
+        let mut b = a;
+
+        assert_eq!(a.bucket(&b), None);
+
+        b.0[ID_BYTES - 1] = 1;
+        assert_eq!(a.bucket(&b), Some(0));
+
+        b.0[0] = 0x80;
+        assert_eq!(a.bucket(&b), Some(ID_BYTES * 8 - 1));
+    }
+
+    #[tokio::test]
+    async fn nickname_is_found_through_another_node() {
+        let first = node(Vec::new()).await;
+        let first_addr = first.local_addr().unwrap().to_string();
+
+        let second = node(vec![first_addr.clone()]).await;
+        let third = node(vec![first_addr]).await;
+
+        // give the bootstraps a moment to finish
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let endpoint: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        second.announce("alice", endpoint).await;
+
+        let record = third.lookup("alice").await.unwrap();
+        assert_eq!(record.endpoint, endpoint);
+
+        assert!(third.lookup("bob").await.is_none());
+    }
+}
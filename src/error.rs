@@ -0,0 +1,36 @@
+use thiserror::Error;
+
+/// Why a command line couldn't be handled. Each one is answered with
+/// `ERR <code>`, and the connection carries on.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("command line is not valid UTF-8")]
+    BadEncoding,
+    #[error("empty command line")]
+    NoCommand,
+    #[error("missing argument, {0}")]
+    MissingArgument(&'static str),
+    #[error("unexpected arguments")]
+    BadArgument,
+    #[error("more arguments than the command takes")]
+    TooManyArguments,
+    #[error("unclosed or misplaced quote")]
+    BadQuote,
+    #[error("unknown command")]
+    UnknownCommand,
+}
+
+impl ServerError {
+    /// The code sent back in the `ERR` line.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::BadEncoding => "BAD_UTF8",
+            ServerError::NoCommand => "NIL_CMD",
+            ServerError::MissingArgument(code) => code,
+            ServerError::BadArgument => "BAD_ARG",
+            ServerError::TooManyArguments => "TOO_MANY_ARGS",
+            ServerError::BadQuote => "BAD_QUOTE",
+            ServerError::UnknownCommand => "UNK_CMD",
+        }
+    }
+}
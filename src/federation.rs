@@ -0,0 +1,614 @@
+//! Links between servers, so peers registered on different servers can
+//! message each other and share rooms.
+//!
+//! A server links to another by connecting to it and sending `LINK <name>
+//! <secret>`, where the name is the address it is known by and the secret
+//! is `[federation] secret`, the same on both. The answer is `OK <name>`
+//! with the other server's name, and from then on the connection carries
+//! server lines instead of commands, each `<kind> <origin> <seq> ...`:
+//!
+//! - `NICK <origin> <seq> <nick> <server>`: `nick` is registered on `server`
+//! - `QUIT <origin> <seq> <nick> <server>`: and now it is gone
+//! - `MSG <origin> <seq> <from> <to> <payload>`: a message for `to`
+//! - `RMSG <origin> <seq> <room> <from> <payload>`: a message to a room
+//!
+//! `origin` and `seq` name the line wherever it goes: a server passes
+//! lines on to its other links unchanged and drops ones it has seen, so a
+//! line goes around a loop of links at most once. Messages for a nickname
+//! are passed along the link its `NICK` came in on, room messages go to
+//! every server, each of which hands them to its own members.
+//!
+//! A link that takes longer than `[outbound] write_timeout_seconds` to
+//! take a line is unlinked, the same as a client that stopped reading.
+
+use crate::auth;
+use crate::config::OutboundConfig;
+use crate::rooms;
+use crate::server::{get_connection_by_nickname, send_bytes, send_to, ServerState};
+use crate::transport::{self, Reader, Writer};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify};
+
+// server lines remembered to drop them the second time around
+const SEEN_LINES: usize = 4096;
+// longest server line, a full MSG with everything around it
+const MAX_LINK_LINE: usize = 2048;
+
+// a nickname registered on another server
+struct Remote {
+    // the server it is registered on
+    server: String,
+    // the link its NICK came in on, and messages for it go out on
+    via: String,
+}
+
+#[derive(Default)]
+struct Seen {
+    lines: HashSet<(String, u64)>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl Seen {
+    // true the first time a line comes by
+    fn insert(&mut self, origin: &str, seq: u64) -> bool {
+        let key = (origin.to_string(), seq);
+
+        if !self.lines.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+
+        if self.order.len() > SEEN_LINES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.lines.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+// a linked server's side of the connection
+#[derive(Clone)]
+struct Link {
+    socket: Arc<Mutex<Writer>>,
+    // ends the link's read loop, for a link too slow to write to
+    cut: Arc<Notify>,
+}
+
+/// The servers linked to this one and the nicknames registered on them.
+pub struct Federation {
+    name: String,
+    links: Mutex<HashMap<String, Link>>,
+    remote: Mutex<HashMap<String, Remote>>,
+    seen: Mutex<Seen>,
+    // started at random, so lines from before a restart aren't mistaken
+    // for new ones
+    seq: AtomicU64,
+    // zero to wait forever
+    write_timeout: Duration,
+}
+
+impl Link {
+    fn new(socket: Arc<Mutex<Writer>>) -> Self {
+        Link {
+            socket,
+            cut: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl Federation {
+    /// `name` is how this server introduces itself in `LINK`.
+    pub fn new(name: String, outbound: &OutboundConfig) -> Self {
+        Federation {
+            name,
+            links: Mutex::new(HashMap::new()),
+            remote: Mutex::new(HashMap::new()),
+            seen: Mutex::new(Seen::default()),
+            seq: AtomicU64::new(rand::random::<u32>() as u64),
+            write_timeout: Duration::from_secs(outbound.write_timeout_seconds),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether `nickname` is registered on a linked server.
+    pub async fn is_remote(&self, nickname: &str) -> bool {
+        self.remote.lock().await.contains_key(nickname)
+    }
+
+    /// The names of the linked servers, sorted.
+    pub async fn links(&self) -> Vec<String> {
+        let mut links: Vec<String> = self.links.lock().await.keys().cloned().collect();
+        links.sort();
+        links
+    }
+
+    // a line from here, with a fresh sequence number
+    fn originate(&self, kind: &str, rest: &str) -> String {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        format!("{} {} {} {}", kind, self.name, seq, rest)
+    }
+
+    // writes `data` to the link `name`, cutting it if that takes too long;
+    // a link that failed otherwise is noticed by its own read loop
+    async fn write(&self, name: &str, link: Link, data: &[u8]) -> bool {
+        let write = send_bytes(link.socket, data);
+
+        let written = if self.write_timeout.is_zero() {
+            Some(write.await)
+        } else {
+            tokio::time::timeout(self.write_timeout, write).await.ok()
+        };
+
+        match written {
+            Some(result) => result.is_ok(),
+            None => {
+                tracing::warn!(component = "federation", server = %name, "Unlinking, too slow");
+                link.cut.notify_one();
+                false
+            }
+        }
+    }
+
+    async fn link(&self, name: &str) -> Option<Link> {
+        self.links.lock().await.get(name).cloned()
+    }
+
+    // writes to every link but `except`
+    async fn flood(&self, line: &str, except: Option<&str>) {
+        let links: Vec<(String, Link)> = self
+            .links
+            .lock()
+            .await
+            .iter()
+            .filter(|(name, _)| Some(name.as_str()) != except)
+            .map(|(name, link)| (name.clone(), link.clone()))
+            .collect();
+
+        let data = format!("{}\n", line);
+
+        for (name, link) in links {
+            self.write(&name, link, data.as_bytes()).await;
+        }
+    }
+
+    async fn send_via(&self, link: &str, line: &str) -> bool {
+        let Some(socket) = self.link(link).await else {
+            return false;
+        };
+
+        self.write(link, socket, format!("{}\n", line).as_bytes())
+            .await
+    }
+}
+
+/// Tells the linked servers `nickname` registered here.
+pub async fn announce(state: &ServerState, nickname: &str) {
+    let federation = &state.federation;
+    let line = federation.originate("NICK", &format!("{} {}", nickname, federation.name));
+
+    federation.flood(&line, None).await;
+}
+
+/// Tells the linked servers `nickname` left.
+pub async fn retire(state: &ServerState, nickname: &str) {
+    let federation = &state.federation;
+    let line = federation.originate("QUIT", &format!("{} {}", nickname, federation.name));
+
+    federation.flood(&line, None).await;
+}
+
+/// Passes a message on towards the server `to` is registered on. False
+/// if nobody linked has the nickname.
+pub async fn route_message(state: &ServerState, from: &str, to: &str, payload: &str) -> bool {
+    let federation = &state.federation;
+
+    let Some(via) = federation
+        .remote
+        .lock()
+        .await
+        .get(to)
+        .map(|r| r.via.clone())
+    else {
+        return false;
+    };
+
+    let line = federation.originate("MSG", &format!("{} {} {}", from, to, payload));
+    federation.send_via(&via, &line).await
+}
+
+/// Passes a room message on to every linked server.
+pub async fn room_message(state: &ServerState, room: &str, from: &str, payload: &str) {
+    let federation = &state.federation;
+    let line = federation.originate("RMSG", &format!("{} {} {}", room, from, payload));
+
+    federation.flood(&line, None).await;
+}
+
+// one server line from the link `via`
+async fn handle_line(state: &Arc<ServerState>, via: &str, line: &str) {
+    let federation = &state.federation;
+
+    let mut words = line.splitn(4, ' ');
+    let (Some(kind), Some(origin), Some(seq), rest) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        return;
+    };
+    let Ok(seq) = seq.parse::<u64>() else {
+        return;
+    };
+    let rest = rest.unwrap_or_default();
+
+    // back from going around a loop, or already here another way
+    if origin == federation.name || !federation.seen.lock().await.insert(origin, seq) {
+        return;
+    }
+
+    match kind {
+        "NICK" => {
+            let Some((nickname, server)) = rest.split_once(' ') else {
+                return;
+            };
+
+            // whoever had the nickname first keeps it
+            if get_connection_by_nickname(nickname, state.clone())
+                .await
+                .is_some()
+            {
+                return;
+            }
+
+            {
+                let mut remote = federation.remote.lock().await;
+
+                if remote.contains_key(nickname) {
+                    return;
+                }
+
+                remote.insert(
+                    nickname.to_string(),
+                    Remote {
+                        server: server.to_string(),
+                        via: via.to_string(),
+                    },
+                );
+            }
+
+            federation.flood(line, Some(via)).await;
+        }
+
+        "QUIT" => {
+            let Some((nickname, server)) = rest.split_once(' ') else {
+                return;
+            };
+
+            {
+                let mut remote = federation.remote.lock().await;
+
+                if remote.get(nickname).is_some_and(|r| r.server == server) {
+                    remote.remove(nickname);
+                }
+            }
+
+            federation.flood(line, Some(via)).await;
+        }
+
+        "MSG" => {
+            let mut words = rest.splitn(3, ' ');
+            let (Some(from), Some(to), Some(payload)) = (words.next(), words.next(), words.next())
+            else {
+                return;
+            };
+
+            if let Some(target) = get_connection_by_nickname(to, state.clone()).await {
+                if send_to(state.clone(), &target, &format!("MSG {} {}", from, payload))
+                    .await
+                    .is_ok()
+                {
+                    state.metrics.direct_message();
+                }
+                return;
+            }
+
+            // on towards wherever it is registered, never back
+            let next = federation
+                .remote
+                .lock()
+                .await
+                .get(to)
+                .map(|r| r.via.clone());
+
+            if let Some(next) = next.filter(|next| next != via) {
+                federation.send_via(&next, line).await;
+            }
+        }
+
+        "RMSG" => {
+            let mut words = rest.splitn(3, ' ');
+            let (Some(room), Some(from), Some(payload)) =
+                (words.next(), words.next(), words.next())
+            else {
+                return;
+            };
+
+            rooms::deliver_linked(state.clone(), room, from, payload).await;
+            federation.flood(line, Some(via)).await;
+        }
+
+        _ => {}
+    }
+}
+
+/// Lines from a link, with whatever was read past the `LINK` already.
+pub struct Lines {
+    reader: Reader,
+    pending: Vec<u8>,
+}
+
+impl Lines {
+    // None once the link closes or sends something too long to be a line
+    async fn next(&mut self) -> Option<String> {
+        loop {
+            if let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+
+                return Some(line.trim_end_matches(['\r', '\n']).to_string());
+            }
+
+            if self.pending.len() > MAX_LINK_LINE {
+                return None;
+            }
+
+            let mut buffer = [0; 4096];
+            let n = self.reader.read(&mut buffer).await.ok()?;
+
+            if n == 0 {
+                return None;
+            }
+
+            self.pending.extend_from_slice(&buffer[..n]);
+        }
+    }
+}
+
+/// The name and secret of a `LINK <name> <secret>` line, which turns the
+/// connection it came in on into a server link.
+pub fn parse_link(line: &[u8]) -> Option<(String, String)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("LINK"), Some(name), Some(secret), None) => {
+            Some((name.to_string(), secret.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Takes a `LINK` from another server and serves the link until it
+/// closes. Refused with `ERR AUTH` for a wrong secret or when this server
+/// has none, and `ERR ALR_LINKED` when that server is linked already.
+pub async fn accept(
+    name: String,
+    secret: String,
+    reader: Reader,
+    socket: Arc<Mutex<Writer>>,
+    pending: Vec<u8>,
+    state: Arc<ServerState>,
+) {
+    let authorized = state
+        .config
+        .federation
+        .secret
+        .as_deref()
+        .is_some_and(|expected| auth::tokens_match(expected, &secret));
+
+    let error = if !authorized {
+        Some("AUTH")
+    } else if name == state.federation.name {
+        Some("LOOP")
+    } else {
+        None
+    };
+
+    if let Some(error) = error {
+        let _ = send_bytes(socket, format!("ERR {}\n", error).as_bytes()).await;
+        return;
+    }
+
+    {
+        let mut links = state.federation.links.lock().await;
+
+        if links.contains_key(&name) {
+            drop(links);
+            let _ = send_bytes(socket, b"ERR ALR_LINKED\n").await;
+            return;
+        }
+
+        links.insert(name.clone(), Link::new(socket.clone()));
+    }
+
+    let ok = format!("OK {}\n", state.federation.name);
+
+    if send_bytes(socket.clone(), ok.as_bytes()).await.is_ok() {
+        serve(&name, Lines { reader, pending }, &state).await;
+    }
+
+    unlink(&name, &state).await;
+}
+
+// what is registered here and on the other links, for a new link
+async fn burst(name: &str, state: &ServerState) {
+    let federation = &state.federation;
+
+    let mut nicknames: Vec<(String, String)> = state
+        .connections
+        .filter_map(|c| Some((c.nickname.clone(), federation.name.clone())));
+
+    nicknames.extend(
+        federation
+            .remote
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, r)| r.via != name)
+            .map(|(nickname, r)| (nickname.clone(), r.server.clone())),
+    );
+
+    let mut lines = String::new();
+
+    for (nickname, server) in nicknames {
+        lines.push_str(&federation.originate("NICK", &format!("{} {}", nickname, server)));
+        lines.push('\n');
+    }
+
+    if let Some(link) = federation.link(name).await {
+        federation.write(name, link, lines.as_bytes()).await;
+    }
+}
+
+async fn serve(name: &str, mut lines: Lines, state: &Arc<ServerState>) {
+    tracing::info!(component = "federation", server = %name, "Linked");
+
+    let Some(Link { cut, .. }) = state.federation.link(name).await else {
+        return;
+    };
+
+    burst(name, state).await;
+
+    loop {
+        tokio::select! {
+            line = lines.next() => match line {
+                Some(line) => handle_line(state, name, &line).await,
+                None => break,
+            },
+            _ = cut.notified() => break,
+        }
+    }
+}
+
+// forgets the link and everyone who was reached through it
+async fn unlink(name: &str, state: &ServerState) {
+    let federation = &state.federation;
+
+    if federation.links.lock().await.remove(name).is_none() {
+        return;
+    }
+
+    let gone: Vec<(String, String)> = {
+        let mut remote = federation.remote.lock().await;
+        let gone: Vec<String> = remote
+            .iter()
+            .filter(|(_, r)| r.via == name)
+            .map(|(nickname, _)| nickname.clone())
+            .collect();
+
+        gone.into_iter()
+            .filter_map(|nickname| remote.remove(&nickname).map(|r| (nickname, r.server)))
+            .collect()
+    };
+
+    for (nickname, server) in gone {
+        let line = federation.originate("QUIT", &format!("{} {}", nickname, server));
+        federation.flood(&line, None).await;
+    }
+
+    tracing::info!(component = "federation", server = %name, "Unlinked");
+}
+
+// links to `server` once, returning its name when the link closes
+async fn link_to(server: &str, state: &Arc<ServerState>) -> io::Result<String> {
+    let Some(secret) = state.config.federation.secret.as_deref() else {
+        return Err(io::Error::other("federation.secret is not set"));
+    };
+
+    let stream = TcpStream::connect(server).await?;
+    let (reader, mut writer) = transport::accept(stream, None).await?;
+
+    let mut hello = match &state.config.stealth.knock {
+        Some(knock) => format!("HELLO {}\n", knock),
+        None => "HELLO\n".to_string(),
+    };
+    hello.push_str(&format!("LINK {} {}\n", state.federation.name, secret));
+
+    writer.write_all(hello.as_bytes()).await?;
+    writer.flush().await?;
+
+    let mut lines = Lines {
+        reader,
+        pending: Vec::new(),
+    };
+
+    // the HELLO answer comes first
+    let name = loop {
+        let Some(line) = lines.next().await else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+
+        if let Some(name) = line.strip_prefix("OK ") {
+            break name.to_string();
+        }
+
+        if line.starts_with("ERR ") {
+            return Err(io::Error::other(line));
+        }
+    };
+
+    let socket = Arc::new(Mutex::new(writer));
+
+    {
+        let mut links = state.federation.links.lock().await;
+
+        // both ends dialed at once and the other link won
+        if links.contains_key(&name) {
+            return Ok(name);
+        }
+
+        links.insert(name.clone(), Link::new(socket.clone()));
+    }
+
+    serve(&name, lines, state).await;
+    unlink(&name, state).await;
+
+    let _ = socket.lock().await.shutdown().await;
+    Ok(name)
+}
+
+/// Keeps this server linked to `server`, one of `[federation] links`,
+/// linking again `retry_seconds` after the link drops or can't be made.
+pub async fn keep_linked(server: String, state: Arc<ServerState>) {
+    let retry = Duration::from_secs(state.config.federation.retry_seconds.max(1));
+    // known after the first link, to leave it be while the other end
+    // holds the link it dialed itself
+    let mut name = None;
+
+    loop {
+        let linked = match &name {
+            Some(name) => state.federation.links.lock().await.contains_key(name),
+            None => false,
+        };
+
+        if !linked {
+            match link_to(&server, &state).await {
+                Ok(linked_as) => name = Some(linked_as),
+                Err(e) => {
+                    tracing::warn!(component = "federation", %server, error = %e, "Failed to link")
+                }
+            }
+        }
+
+        tokio::time::sleep(retry).await;
+    }
+}
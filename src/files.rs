@@ -0,0 +1,549 @@
+use crate::auth;
+use crate::parser;
+use crate::room_files::{self, RoomTransfer};
+use crate::server::{
+    get_connection_by_addr, get_connection_by_nickname, send_error_response, send_response,
+    send_to, ServerState,
+};
+use crate::transport::Writer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Largest decoded chunk, small enough that a `FILE_CHUNK` line stays
+/// under the 1 KB line limit once base64 encoded.
+pub const CHUNK_SIZE: usize = 512;
+
+pub struct Transfer {
+    pub sender: std::net::SocketAddr,
+    pub recipient: std::net::SocketAddr,
+    // addresses change on reconnect, nicknames are what FILE_RESUME matches
+    pub sender_nick: String,
+    pub recipient_nick: String,
+    // handed to both ends on FILE_ACCEPT, so whoever registers one of the
+    // nicknames later can't pick the transfer up
+    pub resume_token: String,
+    pub name: String,
+    pub size: u64,
+    pub accepted: bool,
+    // bytes relayed to the recipient so far
+    pub relayed: u64,
+    // chunks relayed that the recipient has not acked yet
+    pub unacked: usize,
+    // set while one side is gone and the transfer waits for FILE_RESUME
+    pub interrupted_at: Option<Instant>,
+}
+
+impl Transfer {
+    fn is_done(&self) -> bool {
+        self.accepted && self.relayed == self.size && self.unacked == 0
+    }
+}
+
+/// File transfers relayed through the server, keyed by transfer id.
+#[derive(Default)]
+pub struct Transfers {
+    next_id: AtomicU64,
+    pub active: Mutex<HashMap<u64, Transfer>>,
+    // offered to a room, see room_files; ids are shared with `active`
+    pub rooms: Mutex<HashMap<u64, RoomTransfer>>,
+}
+
+fn new_token() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Transfers {
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+pub async fn send_to_addr(state: Arc<ServerState>, addr: std::net::SocketAddr, line: &str) {
+    if let Some(conn) = get_connection_by_addr(addr, state.clone()).await {
+        // a peer that is gone gets unregistered, which settles its transfers
+        let _ = send_to(state, &conn, line).await;
+    }
+}
+
+// tells both ends the transfer went through and forgets about it
+async fn finish(state: Arc<ServerState>, id: u64) {
+    let Some(transfer) = state.files.active.lock().await.remove(&id) else {
+        return;
+    };
+
+    let line = format!("FILE_DONE {}", id);
+
+    send_to_addr(state.clone(), transfer.sender, &line).await;
+    send_to_addr(state, transfer.recipient, &line).await;
+}
+
+/// `FILE_OFFER <nick> <name> <size>` offers a file to an online peer, who
+/// gets `FILE_OFFER <id> <from> <name> <size>`. The sender gets `OK <id>`.
+/// Offers to a `#room` go to all its members, see [`room_files`].
+pub async fn handle_offer(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    target: &str,
+    name: &str,
+    size: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    if target.starts_with('#') {
+        return room_files::handle_offer(socket, addr, state, target, name, size).await;
+    }
+
+    let Ok(size) = size.parse::<u64>() else {
+        send_error_response(socket, "BAD_SIZE").await;
+        return;
+    };
+
+    if size > state.config.files.max_size {
+        send_error_response(socket, "FILE_TOO_BIG").await;
+        return;
+    }
+
+    let Some(target) = get_connection_by_nickname(target, state.clone()).await else {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    };
+
+    let id = state.files.new_id();
+
+    state.files.active.lock().await.insert(
+        id,
+        Transfer {
+            sender: addr,
+            recipient: target.addr,
+            sender_nick: conn.nickname.clone(),
+            recipient_nick: target.nickname.clone(),
+            resume_token: new_token(),
+            name: name.to_string(),
+            size,
+            accepted: false,
+            relayed: 0,
+            unacked: 0,
+            interrupted_at: None,
+        },
+    );
+
+    send_response(socket, format!("OK {}", id).as_str(), true).await;
+
+    let _ = send_to(
+        state,
+        &target,
+        format!(
+            "FILE_OFFER {} {} {} {}",
+            id,
+            conn.nickname,
+            parser::quote(name),
+            size
+        )
+        .as_str(),
+    )
+    .await;
+}
+
+/// `FILE_ACCEPT <id>` or `FILE_REJECT <id>` from the recipient, passed on
+/// to the sender. An accepted transfer gets a token for
+/// [`FILE_RESUME`](handle_resume): the recipient is answered `OK <token>`
+/// and the sender told `FILE_ACCEPT <id> <token>`.
+pub async fn handle_answer(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+    accept: bool,
+) {
+    let id = id.parse::<u64>().unwrap_or(0);
+
+    if room_files::owns(&state, id).await {
+        return room_files::handle_answer(socket, addr, state, id, accept).await;
+    }
+
+    // answered once the lock is let go of, a sender that stops reading
+    // would hold up every transfer otherwise
+    let sender = {
+        let mut active = state.files.active.lock().await;
+
+        match active.get_mut(&id) {
+            Some(t) if t.recipient == addr && !t.accepted => {
+                let answered = (t.sender, t.resume_token.clone());
+
+                if accept {
+                    t.accepted = true;
+                } else {
+                    active.remove(&id);
+                }
+
+                Some(answered)
+            }
+            _ => None,
+        }
+    };
+
+    let Some((sender, token)) = sender else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    let line = if accept {
+        send_response(socket, format!("OK {}", token).as_str(), true).await;
+        format!("FILE_ACCEPT {} {}", id, token)
+    } else {
+        send_response(socket, "OK", true).await;
+        format!("FILE_REJECT {}", id)
+    };
+
+    send_to_addr(state.clone(), sender, &line).await;
+
+    // nothing to send for an empty file
+    let done = state
+        .files
+        .active
+        .lock()
+        .await
+        .get(&id)
+        .is_some_and(|t| t.is_done());
+
+    if done {
+        finish(state, id).await;
+    }
+}
+
+/// `FILE_CHUNK <id> <base64>` from the sender. At most `[files] window`
+/// chunks may be waiting for the recipient's `FILE_ACK` at once.
+pub async fn handle_chunk(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+    data: &str,
+) {
+    let id = id.parse::<u64>().unwrap_or(0);
+
+    let Ok(bytes) = BASE64.decode(data) else {
+        send_error_response(socket, "BAD_CHUNK").await;
+        return;
+    };
+
+    if bytes.is_empty() || bytes.len() > CHUNK_SIZE {
+        send_error_response(socket, "BAD_CHUNK").await;
+        return;
+    }
+
+    if room_files::owns(&state, id).await {
+        return room_files::handle_chunk(socket, addr, state, id, data, bytes.len()).await;
+    }
+
+    let recipient = {
+        let mut active = state.files.active.lock().await;
+
+        match active.get_mut(&id).filter(|t| t.sender == addr) {
+            None => Err("BAD_ID"),
+            Some(t) if !t.accepted => Err("NOT_ACCEPTED"),
+            Some(t) if t.interrupted_at.is_some() => Err("FILE_INTERRUPTED"),
+            Some(t) if t.relayed + bytes.len() as u64 > t.size => Err("FILE_OVERFLOW"),
+            Some(t) if t.unacked >= state.config.files.window => Err("FILE_WINDOW"),
+            Some(t) => {
+                t.relayed += bytes.len() as u64;
+                t.unacked += 1;
+
+                Ok(t.recipient)
+            }
+        }
+    };
+
+    let recipient = match recipient {
+        Ok(recipient) => recipient,
+        Err(error) => {
+            send_error_response(socket, error).await;
+            return;
+        }
+    };
+
+    let line = format!("FILE_CHUNK {} {}", id, data);
+    state
+        .bandwidth
+        .relay(&state, addr, vec![recipient], line, data.len())
+        .await;
+
+    send_response(socket, "OK", true).await;
+}
+
+/// `FILE_ACK <id>` from the recipient frees one window slot; the sender is
+/// told with `FILE_ACK <id>`.
+pub async fn handle_file_ack(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+) {
+    let id = id.parse::<u64>().unwrap_or(0);
+
+    if room_files::owns(&state, id).await {
+        return room_files::handle_file_ack(socket, addr, state, id).await;
+    }
+
+    let acked = {
+        let mut active = state.files.active.lock().await;
+
+        match active.get_mut(&id) {
+            Some(t) if t.recipient == addr && t.unacked > 0 => {
+                t.unacked -= 1;
+
+                Some((t.sender, t.is_done()))
+            }
+            _ => None,
+        }
+    };
+
+    let Some((sender, done)) = acked else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    send_response(socket, "OK", true).await;
+
+    send_to_addr(state.clone(), sender, format!("FILE_ACK {}", id).as_str()).await;
+
+    if done {
+        finish(state, id).await;
+    }
+}
+
+/// `FILE_CANCEL <id>` from either end; the other end gets `FILE_CANCEL <id>`.
+pub async fn handle_cancel(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+) {
+    let id = id.parse::<u64>().unwrap_or(0);
+
+    if room_files::owns(&state, id).await {
+        return room_files::handle_cancel(socket, addr, state, id).await;
+    }
+
+    let transfer = {
+        let mut active = state.files.active.lock().await;
+
+        match active.get(&id) {
+            Some(t) if t.sender == addr || t.recipient == addr => active.remove(&id),
+            _ => None,
+        }
+    };
+
+    let Some(transfer) = transfer else {
+        send_error_response(socket, "BAD_ID").await;
+        return;
+    };
+
+    send_response(socket, "OK", true).await;
+
+    let other = if transfer.sender == addr {
+        transfer.recipient
+    } else {
+        transfer.sender
+    };
+
+    send_to_addr(state, other, format!("FILE_CANCEL {}", id).as_str()).await;
+}
+
+/// `FILE_RESUME <id> <offset> <token>` from either end picks an accepted
+/// transfer back up at `offset`, which can't be past what was already
+/// relayed. The token is the one FILE_ACCEPT handed out. Both ends have to
+/// be registered again; the other end gets `FILE_RESUME <id> <offset>` and
+/// the sender continues from there.
+pub async fn handle_resume(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    id: &str,
+    offset: &str,
+    token: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let id = id.parse::<u64>().unwrap_or(0);
+
+    let Ok(offset) = offset.parse::<u64>() else {
+        send_error_response(socket, "BAD_OFFSET").await;
+        return;
+    };
+
+    let (sender_nick, recipient_nick) = {
+        let active = state.files.active.lock().await;
+
+        match active.get(&id) {
+            Some(t)
+                if t.accepted
+                    && (t.sender_nick == conn.nickname || t.recipient_nick == conn.nickname)
+                    && auth::tokens_match(&t.resume_token, token) =>
+            {
+                (t.sender_nick.clone(), t.recipient_nick.clone())
+            }
+            // a wrong token looks like no such transfer
+            _ => {
+                drop(active);
+                send_error_response(socket, "BAD_ID").await;
+                return;
+            }
+        }
+    };
+
+    let (Some(sender), Some(recipient)) = (
+        get_connection_by_nickname(&sender_nick, state.clone()).await,
+        get_connection_by_nickname(&recipient_nick, state.clone()).await,
+    ) else {
+        send_error_response(socket, "PEER_OFFLINE").await;
+        return;
+    };
+
+    let done = {
+        let mut active = state.files.active.lock().await;
+
+        match active.get_mut(&id) {
+            None => Err("BAD_ID"),
+            Some(t) if offset > t.relayed => Err("BAD_OFFSET"),
+            Some(t) => {
+                t.sender = sender.addr;
+                t.recipient = recipient.addr;
+                t.relayed = offset;
+                t.unacked = 0;
+                t.interrupted_at = None;
+
+                Ok(t.is_done())
+            }
+        }
+    };
+
+    let done = match done {
+        Ok(done) => done,
+        Err(error) => {
+            send_error_response(socket, error).await;
+            return;
+        }
+    };
+
+    send_response(socket, "OK", true).await;
+
+    let other = if sender.addr == addr {
+        recipient
+    } else {
+        sender
+    };
+
+    let _ = send_to(
+        state.clone(),
+        &other,
+        format!("FILE_RESUME {} {}", id, offset).as_str(),
+    )
+    .await;
+
+    if done {
+        finish(state, id).await;
+    }
+}
+
+/// Drops an interrupted transfer once nobody resumed it in time.
+fn expire_after_timeout(state: Arc<ServerState>, id: u64) {
+    let timeout = Duration::from_secs(state.config.files.resume_timeout_seconds);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+
+        let expired = {
+            let mut active = state.files.active.lock().await;
+
+            // it may have been resumed, or interrupted again since
+            let stale = active
+                .get(&id)
+                .and_then(|t| t.interrupted_at)
+                .is_some_and(|at| at.elapsed() >= timeout);
+
+            if stale {
+                active.remove(&id)
+            } else {
+                None
+            }
+        };
+
+        if let Some(transfer) = expired {
+            let line = format!("FILE_CANCEL {}", id);
+
+            send_to_addr(state.clone(), transfer.sender, &line).await;
+            send_to_addr(state, transfer.recipient, &line).await;
+        }
+    });
+}
+
+/// Called for a departing connection: pending offers are cancelled, accepted
+/// transfers are paused so they can be resumed after a reconnect.
+pub async fn drop_connection(addr: std::net::SocketAddr, state: Arc<ServerState>) {
+    room_files::drop_connection(addr, state.clone()).await;
+
+    let mut cancelled = Vec::new();
+    let mut interrupted = Vec::new();
+
+    {
+        let mut active = state.files.active.lock().await;
+
+        let ids: Vec<u64> = active
+            .iter()
+            .filter(|(_, t)| t.sender == addr || t.recipient == addr)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            let Some(transfer) = active.get_mut(&id) else {
+                continue;
+            };
+
+            let other = if transfer.sender == addr {
+                transfer.recipient
+            } else {
+                transfer.sender
+            };
+
+            if !transfer.accepted {
+                active.remove(&id);
+                cancelled.push((id, other));
+            } else if transfer.interrupted_at.is_none() {
+                transfer.interrupted_at = Some(Instant::now());
+                transfer.unacked = 0;
+                interrupted.push((id, other));
+            }
+        }
+    }
+
+    for (id, other) in cancelled {
+        send_to_addr(state.clone(), other, format!("FILE_CANCEL {}", id).as_str()).await;
+    }
+
+    for (id, other) in interrupted {
+        send_to_addr(
+            state.clone(),
+            other,
+            format!("FILE_INTERRUPTED {}", id).as_str(),
+        )
+        .await;
+
+        expire_after_timeout(state.clone(), id);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::config::HistoryConfig;
+use crate::server::{get_connection_by_addr, send_error_response, send_response, ServerState};
+use crate::transport::Writer;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+pub struct HistoryEntry {
+    pub at: SystemTime,
+    pub nickname: String,
+    pub payload: String,
+}
+
+impl HistoryEntry {
+    // "HISTORY <room> <unix time> <nick> <payload>"
+    fn to_line(&self, room: &str) -> String {
+        let at = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        format!("HISTORY {} {} {} {}", room, at, self.nickname, self.payload)
+    }
+}
+
+/// Ring buffer of the most recent messages in a room.
+#[derive(Default)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn push(&mut self, entry: HistoryEntry, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+
+        while self.entries.len() >= capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(entry);
+    }
+
+    /// The last `n` entries rendered as lines, oldest first.
+    pub fn lines(&self, room: &str, n: usize) -> Vec<String> {
+        let skip = self.entries.len().saturating_sub(n);
+
+        self.entries
+            .iter()
+            .skip(skip)
+            .map(|e| e.to_line(room))
+            .collect()
+    }
+}
+
+/// The history of every room, kept by name rather than with the room so
+/// it is still there for whoever joins once everyone has left. At most
+/// `[history] max_rooms` are kept, the one quiet the longest goes first.
+#[derive(Default)]
+pub struct Histories {
+    rooms: std::sync::Mutex<HashMap<String, History>>,
+}
+
+impl Histories {
+    pub fn push(&self, room: &str, entry: HistoryEntry, config: &HistoryConfig) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        if !rooms.contains_key(room) && rooms.len() >= config.max_rooms {
+            let quietest = rooms
+                .iter()
+                .min_by_key(|(_, h)| h.entries.back().map(|e| e.at))
+                .map(|(name, _)| name.clone());
+
+            match quietest {
+                Some(quietest) => rooms.remove(&quietest),
+                None => return,
+            };
+        }
+
+        rooms
+            .entry(room.to_string())
+            .or_default()
+            .push(entry, config.size);
+    }
+
+    /// The last `n` lines of `room`, see [`History::lines`].
+    pub fn lines(&self, room: &str, n: usize) -> Vec<String> {
+        match self.rooms.lock().unwrap().get(room) {
+            Some(history) => history.lines(room, n),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Only members can read a room's history.
+pub async fn handle_history(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    count: Option<&str>,
+) {
+    let count = match count.map(|c| c.parse::<usize>()) {
+        None => state.config.history.size,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            send_error_response(socket, "BAD_NUM").await;
+            return;
+        }
+    };
+
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    if !conn.rooms.contains(room) {
+        send_error_response(socket, "NOT_IN_ROOM").await;
+        return;
+    }
+
+    let lines = state.histories.lines(room, count);
+
+    for line in lines {
+        send_response(socket.clone(), &line, true).await;
+    }
+
+    send_response(socket, "OK", true).await;
+}
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+/// Whether a hook lets something through. A denial is answered with
+/// `ERR DENIED <reason>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny(String),
+}
+
+/// A direct message on its way to `to`. [`Hooks::on_message`] can change
+/// any of it, a different `to` sends it somewhere else.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub from: String,
+    pub to: String,
+    pub payload: String,
+}
+
+/// Callbacks for applications embedding the server, set with
+/// [`ServerBuilder::hooks`](crate::ServerBuilder::hooks). Every method
+/// does nothing by default, so only the interesting ones need writing.
+/// Hooks run inside the connection's task; anything slow should be
+/// spawned.
+#[async_trait]
+pub trait Hooks: Send + Sync + 'static {
+    /// A new connection, before anything is read from it. Ban and
+    /// connection limits have already been checked.
+    async fn on_accept(&self, _addr: SocketAddr) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A REG that passed every other check, just before the nickname is
+    /// taken.
+    async fn on_register(&self, _addr: SocketAddr, _nickname: &str) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A MSG from a registered connection, before it is delivered or
+    /// queued.
+    async fn on_message(&self, _message: &mut Message) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A registered connection left, after its rooms and transfers were
+    /// cleaned up.
+    async fn on_disconnect(&self, _addr: SocketAddr, _nickname: &str) {}
+}
+
+/// The hooks a server gets when none are set.
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
@@ -0,0 +1,88 @@
+use crate::server::{send_response, ServerState};
+use crate::transport::Writer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A public key and its signature over the REG challenge, as sent with
+/// `REG <nick> [token] <pubkey> <signature>`.
+pub struct Proof {
+    pub public_key: String,
+    pub signature: String,
+}
+
+/// One pending challenge per connection that asked for one. A challenge is
+/// used up by the next REG, whether or not it succeeds.
+#[derive(Default)]
+pub struct Challenges {
+    pending: Mutex<HashMap<std::net::SocketAddr, String>>,
+}
+
+impl Challenges {
+    async fn issue(&self, addr: std::net::SocketAddr) -> String {
+        let nonce: String = rand::random::<[u8; 32]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        self.pending.lock().await.insert(addr, nonce.clone());
+        nonce
+    }
+
+    pub async fn take(&self, addr: std::net::SocketAddr) -> Option<String> {
+        self.pending.lock().await.remove(&addr)
+    }
+}
+
+/// What a client signs to prove it holds the key for `nickname`.
+pub fn signed_message(nickname: &str, nonce: &str) -> String {
+    format!("p2p-rs REG {} {}", nickname, nonce)
+}
+
+/// `SHA256:` and the first 16 bytes of the key's hash in hex, short enough
+/// to compare by eye.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+    let hex: String = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("SHA256:{}", hex)
+}
+
+/// Checks an Ed25519 proof against the challenge and returns the key's
+/// fingerprint, or the error code to answer with.
+pub fn verify(nickname: &str, nonce: &str, proof: &Proof) -> Result<String, &'static str> {
+    let key: [u8; 32] = BASE64
+        .decode(&proof.public_key)
+        .ok()
+        .and_then(|k| k.try_into().ok())
+        .ok_or("BAD_KEY")?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| "BAD_KEY")?;
+
+    let signature = BASE64
+        .decode(&proof.signature)
+        .ok()
+        .and_then(|s| Signature::from_slice(&s).ok())
+        .ok_or("BAD_SIG")?;
+
+    key.verify_strict(signed_message(nickname, nonce).as_bytes(), &signature)
+        .map_err(|_| "BAD_SIG")?;
+
+    Ok(fingerprint(key.as_bytes()))
+}
+
+/// `CHALLENGE` answers with `CHALLENGE <nonce>`. To register with an
+/// identity key, sign `p2p-rs REG <nick> <nonce>` and send the key and
+/// signature with REG.
+pub async fn handle_challenge(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+) {
+    let nonce = state.challenges.issue(addr).await;
+
+    send_response(socket, format!("CHALLENGE {}", nonce).as_str(), true).await;
+}
@@ -0,0 +1,52 @@
+use crate::server::{get_connection_by_nickname, send_error_response, send_response, ServerState};
+use crate::transport::Writer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// `PUBKEY_SET <base64>` publishes a public key for the rest of the
+/// session. The server only checks that it is base64, what kind of key it
+/// is is up to the clients. Private keys never leave the client.
+pub async fn handle_set(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    key: &str,
+) {
+    if BASE64.decode(key).map_or(true, |k| k.is_empty()) {
+        send_error_response(socket, "BAD_KEY").await;
+        return;
+    }
+
+    let set = state
+        .connections
+        .update(addr, |conn| conn.public_key = Some(key.to_string()));
+
+    if set.is_none() {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+/// `PUBKEY_GET <nick>` answers with `PUBKEY <nick> <base64>`.
+pub async fn handle_get(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>, nickname: &str) {
+    let Some(conn) = get_connection_by_nickname(nickname, state).await else {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    };
+
+    match &conn.public_key {
+        Some(key) => {
+            send_response(
+                socket,
+                format!("PUBKEY {} {}", conn.nickname, key).as_str(),
+                true,
+            )
+            .await
+        }
+        None => send_error_response(socket, "NO_KEY").await,
+    }
+}
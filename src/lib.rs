@@ -0,0 +1,51 @@
+//! A small rendezvous and relay server for peer-to-peer chat. The `p2p-rs`
+//! binary runs it from a config file; [`Server`] runs it from code.
+
+pub mod acks;
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod bandwidth;
+pub mod caps;
+pub mod check;
+pub mod client;
+pub mod cluster;
+pub mod compression;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dht;
+pub mod error;
+pub mod federation;
+pub mod files;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod identity;
+pub mod keys;
+pub mod logging;
+pub mod mdns;
+pub mod messages;
+pub mod metrics;
+pub mod motd;
+pub mod offline;
+pub mod parser;
+pub mod pex;
+pub mod plugins;
+pub mod presence;
+pub mod proxy;
+pub mod punch;
+pub mod rdns;
+pub mod registry;
+pub mod relay;
+pub mod reload;
+pub mod room_files;
+pub mod rooms;
+pub mod server;
+pub mod sessions;
+pub mod signaling;
+pub mod tcp;
+pub mod transport;
+
+pub use error::ServerError;
+pub use server::{Server, ServerBuilder};
@@ -0,0 +1,191 @@
+use crate::config::{LogConfig, LogFormat, LogRotation};
+use colored::{Color, ColoredString, Colorize};
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+// overrides `[log] level`, same syntax as RUST_LOG
+const LOG_ENV: &str = "P2P_LOG";
+
+// for set_level, once init installed the subscriber
+static LEVEL: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// The filter to log with: `P2P_LOG` when set, `[log] level` otherwise.
+pub fn filter(config: &LogConfig) -> Result<EnvFilter, String> {
+    let directives = std::env::var(LOG_ENV).unwrap_or_else(|_| config.level.clone());
+
+    EnvFilter::try_new(&directives).map_err(|e| format!("bad log level {:?}: {}", directives, e))
+}
+
+// `[log] file` split into the directory and the name rotated files start with
+fn appender(file: &Path, config: &LogConfig) -> Result<RollingFileAppender, String> {
+    let directory = file.parent().unwrap_or(Path::new("."));
+    let name = file
+        .file_name()
+        .ok_or_else(|| format!("log.file {} has no file name", file.display()))?;
+
+    let rotation = match config.rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+
+    // pruning old files reads the directory before anything is written
+    std::fs::create_dir_all(directory)
+        .map_err(|e| format!("log.file {}: {}", file.display(), e))?;
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name.to_string_lossy());
+
+    if let Some(max) = config.max_files {
+        builder = builder.max_log_files(max);
+    }
+
+    builder
+        .build(directory)
+        .map_err(|e| format!("log.file {}: {}", file.display(), e))
+}
+
+/// Installs the subscriber for the `p2p-rs` binary. Embedders bring their
+/// own and get the same events.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let writer = match &config.file {
+        Some(file) => BoxMakeWriter::new(appender(file, config)?),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    // no color codes in files
+    let ansi = config.file.is_none();
+
+    let layer = match config.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .event_format(Console)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(writer)
+            .boxed(),
+    };
+
+    let (filter, level) = reload::Layer::new(filter(config)?);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    let _ = LEVEL.set(level);
+    Ok(())
+}
+
+/// Logs at `[log] level` from now on, `P2P_LOG` still taking precedence.
+/// Does nothing unless [`init`] installed the subscriber.
+pub fn set_level(config: &LogConfig) -> Result<(), String> {
+    let filter = filter(config)?;
+
+    match LEVEL.get() {
+        Some(level) => level.reload(filter).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
+
+/// `> <subject> <message>` lines in the colors the server has always
+/// printed them in. Spans are left out, they are for machine-read logs.
+pub struct Console;
+
+// the subject is whichever of these an event has, shown in bold
+#[derive(Default)]
+struct Fields {
+    message: String,
+    subject: Option<String>,
+    rest: Vec<String>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+impl Fields {
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "message" => self.message = value,
+            "nickname" | "ip" | "component" if self.subject.is_none() => self.subject = Some(value),
+            "relay" if self.subject.is_none() => self.subject = Some(format!("relay {}", value)),
+            name => self.rest.push(format!("{}={}", name, value)),
+        }
+    }
+}
+
+fn color(level: &Level, target: &str, message: &str) -> Color {
+    match (*level, message) {
+        (Level::ERROR, _) => Color::BrightRed,
+        (Level::WARN, _) | (_, "Banned") => Color::BrightYellow,
+        (_, "Joined" | "Resumed") if !target.ends_with("dht") => Color::BrightGreen,
+        (_, "Left") => Color::BrightRed,
+        _ if target.ends_with("relay") => Color::BrightBlue,
+        (Level::INFO, _) => Color::BrightCyan,
+        _ => Color::White,
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Console
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        let metadata = event.metadata();
+        let color = color(metadata.level(), metadata.target(), &fields.message);
+
+        let ansi = writer.has_ansi_escapes();
+        let paint = |text: &str, style: fn(ColoredString) -> ColoredString| {
+            if ansi {
+                style(text.color(color)).to_string()
+            } else {
+                text.to_string()
+            }
+        };
+
+        write!(writer, "{}", paint(">", |s| s))?;
+        if let Some(subject) = &fields.subject {
+            write!(writer, " {}", paint(subject, |s| s.bold()))?;
+        }
+        write!(writer, " {}", paint(&fields.message, |s| s))?;
+
+        for field in &fields.rest {
+            write!(writer, " {}", paint(field, |s| s.dimmed()))?;
+        }
+
+        writeln!(writer)
+    }
+}
@@ -1,8 +1,137 @@
-pub mod server;
+use p2p_rs::{check, config, logging, transport, Server};
+use std::io;
 
-#[tokio::main]
-async fn main() {
-    if let Err(e) = server::start_server().await {
-        eprintln!("Server error: {e}");
+fn main() {
+    let mut args = std::env::args().skip(1);
+
+    let daemon = match args.next().as_deref() {
+        // `p2p-rs check-config [path]` validates and exits
+        Some("check-config") => {
+            let ok = runtime().block_on(check::run(args.next()));
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        // `p2p-rs noise-keygen` prints a keypair for [noise]
+        Some("noise-keygen") => {
+            match transport::generate_keypair() {
+                Ok((private, public)) => {
+                    println!("private_key = \"{private}\"");
+                    println!("# public key: {public}");
+                }
+                Err(e) => eprintln!("Failed to generate a keypair: {e}"),
+            }
+            return;
+        }
+        // `p2p-rs --daemon` runs in the background, see [daemon]
+        Some("--daemon") => true,
+        _ => false,
+    };
+
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Config error: {e}");
+            return;
+        }
+    };
+
+    if daemon && config.log.file.is_none() {
+        eprintln!("Config error: --daemon needs [log] file, there is no stdout to log to");
+        return;
     }
+
+    // before forking, so a bad log file is still reported here
+    if let Err(e) = logging::init(&config.log) {
+        eprintln!("Config error: {e}");
+        return;
+    }
+
+    #[cfg(unix)]
+    let mut detached = if daemon {
+        match p2p_rs::daemon::detach(&config.daemon.pidfile) {
+            Ok(detached) => Some(detached),
+            Err(e) => {
+                eprintln!("Daemon error: {e}");
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    #[cfg(not(unix))]
+    if daemon {
+        eprintln!("--daemon is only supported on unix");
+        return;
+    }
+
+    runtime().block_on(async {
+        let server = match Server::builder().config(config).build().await {
+            Ok(server) => server,
+            Err(e) => {
+                // stderr goes nowhere once detached
+                tracing::error!(component = "server", error = %e, "Failed to start");
+                eprintln!("Server error: {e}");
+                return;
+            }
+        };
+
+        #[cfg(unix)]
+        if let Some(detached) = &mut detached {
+            detached.ready();
+        }
+
+        if let Err(e) = serve(&server).await {
+            tracing::error!(component = "server", error = %e, "Stopped");
+            eprintln!("Server error: {e}");
+        }
+    });
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Runtime::new().expect("failed to start the tokio runtime")
+}
+
+// runs until the server stops or is told to, by SIGTERM or Ctrl-C;
+// SIGHUP reloads the config
+#[cfg(unix)]
+async fn serve(server: &Server) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    let run = server.run();
+    tokio::pin!(run);
+
+    loop {
+        tokio::select! {
+            result = &mut run => return result,
+            _ = terminate.recv() => stop(server),
+            _ = interrupt.recv() => stop(server),
+            _ = hangup.recv() => {
+                // how it went is logged either way
+                let _ = server.reload();
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve(server: &Server) -> io::Result<()> {
+    let run = server.run();
+    tokio::pin!(run);
+
+    tokio::select! {
+        result = &mut run => result,
+        _ = tokio::signal::ctrl_c() => {
+            stop(server);
+            run.await
+        }
+    }
+}
+
+fn stop(server: &Server) {
+    tracing::info!(component = "server", "Shutting down");
+    server.shutdown();
 }
@@ -0,0 +1,96 @@
+use crate::config::MdnsConfig;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashSet;
+use std::io;
+
+/// What servers are announced as. Clients browse for it to find a server
+/// without being told its address.
+pub const SERVICE_TYPE: &str = "_p2p._tcp.local.";
+
+/// The running responder. Dropping it takes the announcement off the LAN.
+pub struct Mdns {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+fn to_io(e: mdns_sd::Error) -> io::Error {
+    io::Error::other(format!("mdns: {}", e))
+}
+
+// instance names can be anything, host names have to be a dns label
+fn host_label(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+impl Mdns {
+    /// Announces the server listening on `port` on every interface, and
+    /// with `[mdns] browse` logs the other instances that come and go.
+    pub fn start(config: &MdnsConfig, port: u16) -> io::Result<Mdns> {
+        let daemon = ServiceDaemon::new().map_err(to_io)?;
+
+        // two servers on one LAN shouldn't pick the same default name
+        let name = config
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("p2p-{:04x}", rand::random::<u16>()));
+        let host = format!("{}.local.", host_label(&name));
+        let properties = [("version", env!("CARGO_PKG_VERSION"))];
+
+        let info = ServiceInfo::new(SERVICE_TYPE, &name, &host, (), port, &properties[..])
+            .map_err(to_io)?
+            .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+
+        daemon.register(info).map_err(to_io)?;
+
+        tracing::info!(component = "mdns", %name, port, "Announcing");
+
+        if config.browse {
+            let events = daemon.browse(SERVICE_TYPE).map_err(to_io)?;
+            let own = fullname.clone();
+
+            tokio::spawn(async move {
+                // instances are resolved again on every answer they send
+                let mut seen = HashSet::new();
+
+                while let Ok(event) = events.recv_async().await {
+                    match event {
+                        ServiceEvent::ServiceResolved(service)
+                            if service.fullname != own && seen.insert(service.fullname.clone()) =>
+                        {
+                            let addresses: Vec<String> = service
+                                .addresses
+                                .iter()
+                                .map(|ip| ip.to_ip_addr().to_string())
+                                .collect();
+
+                            tracing::info!(
+                                component = "mdns",
+                                instance = %service.fullname,
+                                addresses = %addresses.join(","),
+                                port = service.port,
+                                "Found"
+                            );
+                        }
+                        ServiceEvent::ServiceRemoved(_, fullname) if seen.remove(&fullname) => {
+                            tracing::info!(component = "mdns", instance = %fullname, "Lost");
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+
+        Ok(Mdns { daemon, fullname })
+    }
+}
+
+impl Drop for Mdns {
+    fn drop(&mut self) {
+        // goodbye packets, so browsers forget us now rather than at ttl
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
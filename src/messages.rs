@@ -0,0 +1,190 @@
+use crate::acks;
+use crate::federation;
+use crate::hooks::{Message, Verdict};
+use crate::offline::QueueError;
+use crate::server::{
+    get_connection_by_addr, get_connection_by_nickname, send_bytes, send_denied,
+    send_error_response, send_response, send_to, ServerState,
+};
+use crate::transport::Writer;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// `MSG <nick> <payload>` delivers `MSG <from> <payload>` to the target, or
+/// queues it when the target is registered but offline.
+///
+/// `MSGID <id> <nick> <payload>` does the same but is tracked until the
+/// recipient acknowledges it: the recipient gets `MSGID <id> <from> <payload>`
+/// with a server-assigned id to `ACK`, and later `READ`. Queued messages are
+/// not tracked.
+///
+/// A target whose socket fails mid-write is treated as offline. One
+/// registered on a linked server or another node of the cluster gets the
+/// message through the link or the cluster store.
+pub async fn handle_message(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    target: &str,
+    payload: &str,
+    id: Option<&str>,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let mut message = Message {
+        from: conn.nickname.clone(),
+        to: target.to_string(),
+        payload: payload.to_string(),
+    };
+
+    if let Verdict::Deny(reason) = state.hooks.on_message(&mut message).await {
+        send_denied(socket, &reason).await;
+        return;
+    }
+
+    let (target, payload) = (message.to.as_str(), message.payload.as_str());
+
+    if let Some(target) = get_connection_by_nickname(target, state.clone()).await {
+        let delivery_id = match id {
+            Some(id) => Some(state.acks.track(addr, id, target.addr).await),
+            None => None,
+        };
+
+        let line = match delivery_id {
+            Some(delivery_id) => format!("MSGID {} {} {}", delivery_id, conn.nickname, payload),
+            None => format!("MSG {} {}", conn.nickname, payload),
+        };
+
+        if send_to(state.clone(), &target, &line).await.is_ok() {
+            state.metrics.direct_message();
+
+            if let Some(delivery_id) = delivery_id {
+                acks::expire_after_timeout(state.clone(), delivery_id);
+            }
+
+            send_response(socket, "OK", true).await;
+            return;
+        }
+
+        // the disconnect cleanup may already have reported it undelivered
+        if let Some(delivery_id) = delivery_id {
+            if state.acks.take(delivery_id).await.is_none() {
+                send_response(socket, "OK", true).await;
+                return;
+            }
+        }
+    }
+
+    // registered on a linked server, where it is delivered plain, without
+    // an ack
+    if federation::route_message(&state, &conn.nickname, target, payload).await {
+        state.metrics.direct_message();
+        send_response(socket, "OK", true).await;
+        return;
+    }
+
+    if let Some(cluster) = &state.cluster {
+        if cluster.route_message(&conn.nickname, target, payload).await {
+            state.metrics.direct_message();
+            send_response(socket, "OK", true).await;
+            return;
+        }
+    }
+
+    // a dropped connection about to resume gets it first
+    let held = state
+        .sessions
+        .hold(
+            target,
+            &conn.nickname,
+            payload,
+            state.config.resume.max_pending,
+        )
+        .await;
+
+    let queued = match held {
+        Err(QueueError::UnknownNickname) => {
+            state.offline.queue(target, &conn.nickname, payload).await
+        }
+        held => held,
+    };
+
+    match queued {
+        Ok(()) => send_response(socket, "OK QUEUED", true).await,
+        Err(QueueError::Full) => send_error_response(socket, "QUEUE_FULL").await,
+        Err(QueueError::Disabled | QueueError::UnknownNickname) => {
+            send_error_response(socket, "NO_NICK").await
+        }
+    }
+}
+
+/// Whether a `TYPING` from `addr` is due to be passed on, None if `addr`
+/// isn't registered. One that is counts as the last one passed on.
+pub fn typing_due(state: &ServerState, addr: std::net::SocketAddr) -> Option<bool> {
+    let interval = Duration::from_secs(state.config.typing.interval_seconds);
+    let now = Instant::now();
+
+    state.connections.update(addr, |conn| {
+        let due = conn
+            .last_typing
+            .is_none_or(|last| now.duration_since(last) >= interval);
+
+        if due {
+            conn.last_typing = Some(now);
+        }
+
+        due
+    })
+}
+
+/// `TYPING <nick>` tells the target `TYPING <from>`, for "is typing…"
+/// indicators. Only to a target registered here right now: nothing is
+/// queued, held for a resume or passed across links. Notices coming in
+/// faster than `[typing] interval_seconds` are answered `OK` and dropped,
+/// clients repeat them while typing anyway.
+pub async fn handle_typing(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    target: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Some(target) = get_connection_by_nickname(target, state.clone()).await else {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    };
+
+    send_response(socket, "OK", true).await;
+
+    if typing_due(&state, addr) == Some(true) {
+        let _ = send_to(state, &target, &format!("TYPING {}", conn.nickname)).await;
+    }
+}
+
+/// Hands a freshly registered nickname whatever was queued for it.
+pub async fn deliver_queued(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>, nickname: &str) {
+    let mut messages = state.offline.take(nickname).await.into_iter();
+
+    while let Some(message) = messages.next() {
+        let line = format!("MSG {} {}\n", message.from, message.payload);
+
+        if send_bytes(socket.clone(), line.as_bytes()).await.is_err() {
+            // gone again already, so the rest waits for the next time
+            for message in std::iter::once(message).chain(messages) {
+                let _ = state
+                    .offline
+                    .queue(nickname, &message.from, &message.payload)
+                    .await;
+            }
+            return;
+        }
+    }
+}
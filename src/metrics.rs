@@ -0,0 +1,399 @@
+use crate::http::{Request, Response};
+use crate::server::{send_response, ServerState};
+use crate::transport::Writer;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// upper bounds of the command latency buckets, in seconds
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+// joins and leaves kept for the dashboard
+const RECENT_SIZE: usize = 50;
+
+tokio::task_local! {
+    // the metrics of the server whose connection task this is, so error
+    // replies can be counted without handing state to every sender
+    static CURRENT: Arc<Metrics>;
+}
+
+#[derive(Default)]
+struct Histogram {
+    // per bucket, not cumulative; added up when rendered
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// A nickname coming or going.
+#[derive(Clone, Serialize)]
+pub struct Activity {
+    // seconds since the epoch
+    pub at: u64,
+    pub joined: bool,
+    pub nickname: String,
+}
+
+/// The counters as they are now, for the admin api's `/stats`.
+#[derive(Serialize)]
+pub struct Stats {
+    pub connections: u64,
+    pub registered: usize,
+    pub registrations: u64,
+    pub direct_messages: u64,
+    pub room_messages: u64,
+    pub relayed_bytes: u64,
+    pub uptime: u64,
+    // oldest first
+    pub recent: Vec<Activity>,
+}
+
+/// What a server has done since it started, rendered for Prometheus by
+/// [`Metrics::render`] and served on `/metrics` with `[metrics] enabled`.
+pub struct Metrics {
+    started: Instant,
+    connections: AtomicU64,
+    registrations: AtomicU64,
+    direct_messages: AtomicU64,
+    room_messages: AtomicU64,
+    relayed_bytes: AtomicU64,
+    // lines waiting to be written to another connection
+    outbound: AtomicI64,
+    dropped: AtomicU64,
+    slow_consumers: AtomicU64,
+    errors: Mutex<HashMap<String, u64>>,
+    commands: Mutex<HashMap<String, Histogram>>,
+    recent: Mutex<VecDeque<Activity>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started: Instant::now(),
+            connections: AtomicU64::default(),
+            registrations: AtomicU64::default(),
+            direct_messages: AtomicU64::default(),
+            room_messages: AtomicU64::default(),
+            relayed_bytes: AtomicU64::default(),
+            outbound: AtomicI64::default(),
+            dropped: AtomicU64::default(),
+            slow_consumers: AtomicU64::default(),
+            errors: Mutex::default(),
+            commands: Mutex::default(),
+            recent: Mutex::default(),
+        }
+    }
+}
+
+/// Counts an open connection until dropped.
+pub struct Open(Arc<Metrics>);
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn open(self: &Arc<Self>) -> Open {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        Open(self.clone())
+    }
+
+    pub fn registered(&self, nickname: &str) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+        self.saw(true, nickname);
+    }
+
+    pub fn left(&self, nickname: &str) {
+        self.saw(false, nickname);
+    }
+
+    fn saw(&self, joined: bool, nickname: &str) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+
+        if recent.len() >= RECENT_SIZE {
+            recent.pop_front();
+        }
+        recent.push_back(Activity {
+            at,
+            joined,
+            nickname: nickname.to_string(),
+        });
+    }
+
+    /// `registered` is sampled by the caller, as for [`render`](Metrics::render).
+    pub fn stats(&self, registered: usize) -> Stats {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+
+        Stats {
+            connections: self.connections.load(Ordering::Relaxed),
+            registered,
+            registrations: self.registrations.load(Ordering::Relaxed),
+            direct_messages: self.direct_messages.load(Ordering::Relaxed),
+            room_messages: self.room_messages.load(Ordering::Relaxed),
+            relayed_bytes: self.relayed_bytes.load(Ordering::Relaxed),
+            uptime: self.started.elapsed().as_secs(),
+            recent: recent.iter().cloned().collect(),
+        }
+    }
+
+    pub fn direct_message(&self) {
+        self.direct_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn room_message(&self) {
+        self.room_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn relayed(&self, bytes: usize) {
+        self.relayed_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn queue(&self, lines: i64) {
+        self.outbound.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_consumer(&self) {
+        self.slow_consumers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, code: &str) {
+        let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        *errors.entry(code.to_string()).or_default() += 1;
+    }
+
+    pub fn command(&self, command: &str, took: Duration) {
+        let mut commands = self.commands.lock().unwrap_or_else(|e| e.into_inner());
+
+        commands
+            .entry(command.to_string())
+            .or_default()
+            .observe(took.as_secs_f64());
+    }
+
+    /// Everything in the Prometheus text format. `registered` is sampled
+    /// by the caller, it lives with the connections.
+    pub fn render(&self, registered: usize) -> String {
+        let mut out = String::new();
+
+        // the HELP and TYPE lines come right before their samples
+        let mut section = |name: &str, kind: &str, help: &str, samples: Vec<String>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for sample in samples {
+                let _ = writeln!(out, "{}", sample);
+            }
+        };
+
+        section(
+            "p2p_connections",
+            "gauge",
+            "Open connections, registered or not.",
+            vec![format!(
+                "p2p_connections {}",
+                self.connections.load(Ordering::Relaxed)
+            )],
+        );
+        section(
+            "p2p_registered",
+            "gauge",
+            "Registered nicknames.",
+            vec![format!("p2p_registered {}", registered)],
+        );
+        section(
+            "p2p_registrations_total",
+            "counter",
+            "Successful REG and RESUME commands.",
+            vec![format!(
+                "p2p_registrations_total {}",
+                self.registrations.load(Ordering::Relaxed)
+            )],
+        );
+        section(
+            "p2p_messages_relayed_total",
+            "counter",
+            "Messages delivered to a peer or broadcast to a room.",
+            vec![
+                format!(
+                    "p2p_messages_relayed_total{{kind=\"direct\"}} {}",
+                    self.direct_messages.load(Ordering::Relaxed)
+                ),
+                format!(
+                    "p2p_messages_relayed_total{{kind=\"room\"}} {}",
+                    self.room_messages.load(Ordering::Relaxed)
+                ),
+            ],
+        );
+        section(
+            "p2p_relayed_bytes_total",
+            "counter",
+            "Bytes copied between RELAY data channels.",
+            vec![format!(
+                "p2p_relayed_bytes_total {}",
+                self.relayed_bytes.load(Ordering::Relaxed)
+            )],
+        );
+
+        section(
+            "p2p_outbound_queued",
+            "gauge",
+            "Lines waiting to be written to a connection.",
+            vec![format!(
+                "p2p_outbound_queued {}",
+                self.outbound.load(Ordering::Relaxed)
+            )],
+        );
+        section(
+            "p2p_outbound_dropped_total",
+            "counter",
+            "Presence and room notices dropped for connections that fell behind.",
+            vec![format!(
+                "p2p_outbound_dropped_total {}",
+                self.dropped.load(Ordering::Relaxed)
+            )],
+        );
+        section(
+            "p2p_slow_consumers_total",
+            "counter",
+            "Connections disconnected for not reading what they were sent.",
+            vec![format!(
+                "p2p_slow_consumers_total {}",
+                self.slow_consumers.load(Ordering::Relaxed)
+            )],
+        );
+
+        let errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        let mut codes: Vec<_> = errors.iter().collect();
+        codes.sort();
+
+        section(
+            "p2p_errors_total",
+            "counter",
+            "ERR replies sent, by code.",
+            codes
+                .into_iter()
+                .map(|(code, n)| format!("p2p_errors_total{{code=\"{}\"}} {}", escape(code), n))
+                .collect(),
+        );
+        drop(errors);
+
+        let commands = self.commands.lock().unwrap_or_else(|e| e.into_inner());
+        let mut names: Vec<_> = commands.keys().collect();
+        names.sort();
+
+        let mut samples = Vec::new();
+        for name in names {
+            let histogram = &commands[name];
+            let label = escape(name);
+            let mut cumulative = 0;
+
+            for (bound, count) in BUCKETS.iter().zip(histogram.counts) {
+                cumulative += count;
+                samples.push(format!(
+                    "p2p_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    label, bound, cumulative
+                ));
+            }
+            samples.push(format!(
+                "p2p_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                label, histogram.count
+            ));
+            samples.push(format!(
+                "p2p_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                label, histogram.sum
+            ));
+            samples.push(format!(
+                "p2p_command_duration_seconds_count{{command=\"{}\"}} {}",
+                label, histogram.count
+            ));
+        }
+
+        section(
+            "p2p_command_duration_seconds",
+            "histogram",
+            "Time taken to handle a command.",
+            samples,
+        );
+
+        out
+    }
+}
+
+// label values are quoted, so quotes, backslashes and newlines are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Runs `task` with `metrics` as the place its error replies are counted.
+pub fn scope<F: Future>(metrics: Arc<Metrics>, task: F) -> impl Future<Output = F::Output> {
+    CURRENT.scope(metrics, task)
+}
+
+/// Counts an `ERR <code>` reply against the server of the current
+/// connection. Outside a connection task there is nothing to count it on.
+pub fn count_error(code: &str) {
+    let _ = CURRENT.try_with(|metrics| metrics.error(code));
+}
+
+/// `STATS` answers with one `STATS <field> <value>` line per counter, then
+/// `OK`: `uptime` in seconds, `peers` registered, `messages` delivered
+/// directly or to a room, and the `version` that `HELLO` reports.
+pub async fn handle_stats(socket: Arc<tokio::sync::Mutex<Writer>>, state: Arc<ServerState>) {
+    let registered = state.connections.len();
+    let stats = state.metrics.stats(registered);
+
+    let fields = [
+        format!("uptime {}", stats.uptime),
+        format!("peers {}", stats.registered),
+        format!("messages {}", stats.direct_messages + stats.room_messages),
+        format!("version {}", env!("CARGO_PKG_VERSION")),
+    ];
+
+    for field in fields {
+        send_response(socket.clone(), &format!("STATS {}", field), true).await;
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+/// `GET /metrics`, for the endpoint `[metrics]` serves.
+pub async fn handle(request: Request, state: Arc<ServerState>) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let registered = state.connections.len();
+            let body = state.metrics.render(registered);
+
+            Response::text("200 OK", body).content_type("text/plain; version=0.0.4")
+        }
+        ("GET", _) => Response::text("404 Not Found", "Not found\n"),
+        _ => Response::text("405 Method Not Allowed", "Method not allowed\n"),
+    }
+}
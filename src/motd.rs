@@ -0,0 +1,42 @@
+use crate::config::MotdConfig;
+use crate::server::send_response;
+use crate::transport::Writer;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The message of the day as it is now: `motd.file` is read again for
+/// every registration so it can be edited while the server runs, falling
+/// back to `motd.text`.
+pub async fn current(config: &MotdConfig) -> Option<String> {
+    let Some(path) = &config.file else {
+        return config.text.clone();
+    };
+
+    match tokio::fs::read_to_string(path).await {
+        Ok(text) => Some(text),
+        Err(e) => {
+            tracing::warn!(component = "motd", path = %path.display(), error = %e, "Failed to read");
+            config.text.clone()
+        }
+    }
+}
+
+/// Sends the message of the day as one `MOTD <line>` per line, blank
+/// lines as a bare `MOTD`.
+pub async fn send(socket: Arc<Mutex<Writer>>, config: &MotdConfig) {
+    let Some(text) = current(config).await else {
+        return;
+    };
+
+    for line in text.trim_end().lines() {
+        let line = line.trim_end();
+
+        let line = if line.is_empty() {
+            "MOTD".to_string()
+        } else {
+            format!("MOTD {}", line)
+        };
+
+        send_response(socket.clone(), &line, true).await;
+    }
+}
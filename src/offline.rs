@@ -0,0 +1,350 @@
+use crate::config::OfflineConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub from: String,
+    pub payload: String,
+    // unix seconds, so the ttl survives restarts
+    pub queued_at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoreData {
+    // nicknames that registered here, and when they last did
+    #[serde(deserialize_with = "known_or_names")]
+    known: HashMap<String, u64>,
+    pending: HashMap<String, VecDeque<PendingMessage>>,
+}
+
+// stores written before nicknames were aged out only have the names, which
+// count as seen now
+fn known_or_names<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Known {
+        Seen(HashMap<String, u64>),
+        Names(HashSet<String>),
+    }
+
+    Ok(match Known::deserialize(deserializer)? {
+        Known::Seen(seen) => seen,
+        Known::Names(names) => names.into_iter().map(|n| (n, now())).collect(),
+    })
+}
+
+impl StoreData {
+    // forgets nicknames unseen for `max_age` seconds, then the longest
+    // unseen ones past `max` other than `keep`, along with anything queued
+    // for them
+    fn prune(&mut self, max_age: u64, max: usize, keep: &str) {
+        let now = now();
+
+        if max_age > 0 {
+            self.known
+                .retain(|_, seen| now.saturating_sub(*seen) <= max_age);
+        }
+
+        if max > 0 && self.known.len() > max {
+            let mut by_age: Vec<(u64, String)> = self
+                .known
+                .iter()
+                .filter(|(n, _)| *n != keep)
+                .map(|(n, seen)| (*seen, n.clone()))
+                .collect();
+            by_age.sort_unstable();
+
+            let over = self.known.len() - max;
+
+            for (_, nickname) in &by_age[..over] {
+                self.known.remove(nickname);
+            }
+        }
+
+        let known = &self.known;
+        self.pending
+            .retain(|nickname, _| known.contains_key(nickname));
+    }
+}
+
+pub enum QueueError {
+    Disabled,
+    UnknownNickname,
+    Full,
+}
+
+/// Messages waiting for registered nicknames that are currently offline.
+pub struct OfflineStore {
+    config: OfflineConfig,
+    data: Mutex<StoreData>,
+    // bumped on every change, so a save that lost the race to a newer one
+    // doesn't overwrite it
+    version: AtomicU64,
+    // the version on disk, held while writing
+    saved: Mutex<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl OfflineStore {
+    /// Loads the store from `[offline] path` if it is set and exists.
+    pub fn load(config: OfflineConfig) -> io::Result<Self> {
+        let data = match &config.path {
+            Some(path) if path.exists() => {
+                let contents = std::fs::read_to_string(path)?;
+                serde_json::from_str(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            _ => StoreData::default(),
+        };
+
+        Ok(OfflineStore {
+            config,
+            data: Mutex::new(data),
+            version: AtomicU64::new(0),
+            saved: Mutex::new(0),
+        })
+    }
+
+    // what `save` writes, taken while `data` is still locked
+    fn snapshot(&self, data: &StoreData) -> Option<(u64, Vec<u8>)> {
+        self.config.path.as_ref()?;
+
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+
+        match serde_json::to_vec(data) {
+            Ok(bytes) => Some((version, bytes)),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to encode offline messages");
+                None
+            }
+        }
+    }
+
+    // writes a snapshot once `data` is unlocked, so the store isn't held
+    // up by the disk
+    async fn save(&self, snapshot: Option<(u64, Vec<u8>)>) {
+        let (Some(path), Some((version, bytes))) = (&self.config.path, snapshot) else {
+            return;
+        };
+
+        let mut saved = self.saved.lock().await;
+
+        if *saved > version {
+            return;
+        }
+
+        match tokio::fs::write(path, bytes).await {
+            Ok(()) => *saved = version,
+            Err(e) => tracing::error!(error = %e, "Failed to save offline messages"),
+        }
+    }
+
+    fn is_expired(&self, message: &PendingMessage) -> bool {
+        now().saturating_sub(message.queued_at) > self.config.ttl_seconds
+    }
+
+    /// Marks a nickname as registered so messages can be queued for it
+    /// later, until it has been gone for `[offline] forget_after_days` or
+    /// is the longest gone of more than `[offline] max_known`.
+    pub async fn remember(&self, nickname: &str) {
+        let snapshot = {
+            let mut data = self.data.lock().await;
+
+            // seeing one again isn't worth a save on its own
+            if data.known.insert(nickname.to_string(), now()).is_some() {
+                return;
+            }
+
+            let max_age = self.config.forget_after_days * 24 * 60 * 60;
+            data.prune(max_age, self.config.max_known, nickname);
+
+            self.snapshot(&data)
+        };
+
+        self.save(snapshot).await;
+    }
+
+    pub async fn queue(&self, nickname: &str, from: &str, payload: &str) -> Result<(), QueueError> {
+        if !self.config.enabled {
+            return Err(QueueError::Disabled);
+        }
+
+        let snapshot = {
+            let mut data = self.data.lock().await;
+
+            if !data.known.contains_key(nickname) {
+                return Err(QueueError::UnknownNickname);
+            }
+
+            let queue = data.pending.entry(nickname.to_string()).or_default();
+
+            // make room by dropping whatever already expired
+            queue.retain(|m| !self.is_expired(m));
+
+            if queue.len() >= self.config.max_per_nickname {
+                return Err(QueueError::Full);
+            }
+
+            queue.push_back(PendingMessage {
+                from: from.to_string(),
+                payload: payload.to_string(),
+                queued_at: now(),
+            });
+
+            self.snapshot(&data)
+        };
+
+        self.save(snapshot).await;
+
+        Ok(())
+    }
+
+    /// Removes and returns the still-fresh messages queued for a nickname,
+    /// oldest first.
+    pub async fn take(&self, nickname: &str) -> Vec<PendingMessage> {
+        let (queue, snapshot) = {
+            let mut data = self.data.lock().await;
+
+            let Some(queue) = data.pending.remove(nickname) else {
+                return Vec::new();
+            };
+
+            (queue, self.snapshot(&data))
+        };
+
+        self.save(snapshot).await;
+
+        queue.into_iter().filter(|m| !self.is_expired(m)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OfflineConfig {
+        OfflineConfig {
+            max_known: 2,
+            ..OfflineConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn the_longest_gone_nicknames_are_forgotten() {
+        let store = OfflineStore::load(config()).unwrap();
+
+        store.remember("alice").await;
+        store.remember("bob").await;
+        store.data.lock().await.known.insert("alice".to_string(), 1);
+        assert!(store.queue("alice", "bob", "hi").await.is_ok());
+
+        // past max_known alice goes, along with what was queued for alice
+        store.remember("carol").await;
+        assert!(matches!(
+            store.queue("alice", "bob", "hi").await,
+            Err(QueueError::UnknownNickname)
+        ));
+        assert!(store.data.lock().await.pending.is_empty());
+        assert!(store.queue("carol", "bob", "hi").await.is_ok());
+
+        // and nobody seen within forget_after_days is kept
+        store.data.lock().await.known.insert("bob".to_string(), 1);
+        store.remember("dave").await;
+        assert!(!store.data.lock().await.known.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn messages_are_taken_once_oldest_first() {
+        let store = OfflineStore::load(config()).unwrap();
+
+        assert!(matches!(
+            store.queue("alice", "bob", "hi").await,
+            Err(QueueError::UnknownNickname)
+        ));
+
+        store.remember("alice").await;
+        assert!(store.queue("alice", "bob", "one").await.is_ok());
+        assert!(store.queue("alice", "carol", "two").await.is_ok());
+
+        let taken: Vec<_> = store
+            .take("alice")
+            .await
+            .into_iter()
+            .map(|m| (m.from, m.payload))
+            .collect();
+        assert_eq!(
+            taken,
+            [
+                ("bob".to_string(), "one".to_string()),
+                ("carol".to_string(), "two".to_string())
+            ]
+        );
+        assert!(store.take("alice").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_messages_make_room_and_are_never_delivered() {
+        let store = OfflineStore::load(OfflineConfig {
+            max_per_nickname: 1,
+            ..config()
+        })
+        .unwrap();
+
+        store.remember("alice").await;
+        assert!(store.queue("alice", "bob", "one").await.is_ok());
+        assert!(matches!(
+            store.queue("alice", "bob", "two").await,
+            Err(QueueError::Full)
+        ));
+
+        store.data.lock().await.pending.get_mut("alice").unwrap()[0].queued_at = 0;
+        assert!(store.queue("alice", "bob", "two").await.is_ok());
+
+        store.data.lock().await.pending.get_mut("alice").unwrap()[0].queued_at = 0;
+        assert!(store.take("alice").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_store_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("p2p-offline-{}.json", std::process::id()));
+        let config = OfflineConfig {
+            path: Some(path.clone()),
+            ..config()
+        };
+
+        let store = OfflineStore::load(config.clone()).unwrap();
+        store.remember("alice").await;
+        assert!(store.queue("alice", "bob", "hi").await.is_ok());
+        drop(store);
+
+        let store = OfflineStore::load(config).unwrap();
+        let taken = store.take("alice").await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].from, "bob");
+        assert_eq!(taken[0].payload, "hi");
+    }
+
+    #[test]
+    fn stores_with_only_names_still_load() {
+        let data: StoreData = serde_json::from_str(r#"{"known":["alice"],"pending":{}}"#).unwrap();
+        assert!(data.known["alice"] > 0);
+
+        let data: StoreData = serde_json::from_str(r#"{"known":{"bob":5},"pending":{}}"#).unwrap();
+        assert_eq!(data.known["bob"], 5);
+    }
+}
@@ -0,0 +1,318 @@
+//! Command lines, split into arguments and checked against what each
+//! command takes.
+//!
+//! A line is `COMMAND arg... [text]`. Arguments are separated by spaces;
+//! one that has to hold spaces can be quoted, `"like this"`, with `\"` and
+//! `\\` for a quote or backslash inside. Commands that carry a payload
+//! take the rest of the line as their last argument, verbatim and with its
+//! inner spacing kept, so payloads never need quoting.
+
+use crate::error::ServerError;
+use std::borrow::Cow;
+
+/// One argument of a command.
+#[derive(Debug, Clone, Copy)]
+enum Param {
+    /// A word that has to be there, `ERR <code>` otherwise.
+    Required(&'static str),
+    Optional,
+    /// The rest of the line, which has to be there, `ERR <code>` otherwise.
+    Text(&'static str),
+    /// The rest of the line, maybe empty.
+    OptionalText,
+    /// Any number of words.
+    Words,
+}
+
+use Param::*;
+
+// what every built-in command takes; commands added by the embedder are
+// split into words and not checked
+const GRAMMAR: &[(&str, &[Param])] = &[
+    ("HELLO", &[Optional]),
+    ("ADDR", &[]),
+    // `REG <nick> [token] [<pubkey> <signature>]`, told apart by count
+    ("REG", &[Required("NIL_NICK"), Optional, Optional, Optional]),
+    ("RESUME", &[Required("NIL_TOKEN")]),
+    ("GHOST", &[Required("NIL_NICK"), Required("NIL_TOKEN")]),
+    ("CHALLENGE", &[]),
+    ("JOIN", &[Required("NIL_ROOM")]),
+    ("PART", &[Required("NIL_ROOM")]),
+    ("MSG", &[Required("NIL_NICK"), Text("NIL_MSG")]),
+    (
+        "MSGID",
+        &[Required("NIL_ID"), Required("NIL_NICK"), Text("NIL_MSG")],
+    ),
+    ("ACK", &[Required("NIL_ID")]),
+    ("READ", &[Required("NIL_ID")]),
+    (
+        "FILE_OFFER",
+        &[
+            Required("NIL_ARG"),
+            Required("NIL_ARG"),
+            Required("NIL_ARG"),
+        ],
+    ),
+    ("FILE_ACCEPT", &[Required("NIL_ID")]),
+    ("FILE_REJECT", &[Required("NIL_ID")]),
+    ("FILE_CHUNK", &[Required("NIL_ARG"), Required("NIL_ARG")]),
+    ("FILE_ACK", &[Required("NIL_ID")]),
+    (
+        "FILE_RESUME",
+        &[
+            Required("NIL_ARG"),
+            Required("NIL_ARG"),
+            Required("NIL_TOKEN"),
+        ],
+    ),
+    ("FILE_CANCEL", &[Required("NIL_ID")]),
+    ("CONNECT", &[Required("NIL_NICK")]),
+    ("RELAY_OPEN", &[Required("NIL_NICK")]),
+    ("RMSG", &[Required("NIL_ROOM"), Text("NIL_MSG")]),
+    ("TYPING", &[Required("NIL_ARG")]),
+    ("TOPIC", &[Required("NIL_ROOM"), OptionalText]),
+    ("HISTORY", &[Required("NIL_ROOM"), Optional]),
+    ("KICK", &[Required("NIL_ROOM"), Required("NIL_NICK")]),
+    ("OP", &[Required("NIL_ROOM"), Required("NIL_NICK")]),
+    ("ROOMS", &[]),
+    ("STATUS", &[Required("NIL_STATUS"), OptionalText]),
+    ("WHOIS", &[Required("NIL_NICK")]),
+    ("LIST", &[]),
+    ("STATS", &[]),
+    ("CAP", &[Required("NIL_CAP"), Words]),
+    ("ADVERTISE", &[Required("NIL_ADDR"), Optional]),
+    ("PEERS", &[]),
+    ("PEERS_PUSH", &[Words]),
+    ("PUBKEY_SET", &[Required("NIL_KEY")]),
+    ("PUBKEY_GET", &[Required("NIL_NICK")]),
+    ("LOOKUP", &[Required("NIL_NICK")]),
+    ("WATCH", &[Required("NIL_NICK")]),
+    ("UNWATCH", &[Required("NIL_NICK")]),
+];
+
+/// A parsed command line.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Command<'a> {
+    pub name: &'a str,
+    /// The words after the command, unquoted.
+    pub args: Vec<Cow<'a, str>>,
+    /// The trailing text of commands that take one, empty if there is none.
+    pub text: &'a str,
+}
+
+impl Command<'_> {
+    pub fn arg(&self, i: usize) -> Option<&str> {
+        self.args.get(i).map(|arg| arg.as_ref())
+    }
+}
+
+/// `word` the way [`parse`] reads it back, quoted only if it has to be;
+/// for arguments passed on to other connections.
+pub fn quote(word: &str) -> Cow<'_, str> {
+    let plain = !word.is_empty() && !word.starts_with('"') && !word.contains(char::is_whitespace);
+
+    if plain {
+        return Cow::Borrowed(word);
+    }
+
+    let escaped = word.replace('\\', "\\\\").replace('"', "\\\"");
+    Cow::Owned(format!("\"{}\"", escaped))
+}
+
+// the next word of `line` and what follows it, quotes taken off
+fn word(line: &str) -> Result<Option<(Cow<'_, str>, &str)>, ServerError> {
+    let line = line.trim_start();
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let Some(quoted) = line.strip_prefix('"') else {
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        return Ok(Some((Cow::Borrowed(word), rest)));
+    };
+
+    let mut word = String::new();
+    let mut chars = quoted.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let rest = &quoted[i + 1..];
+
+                // `"a"b` is one word or two, neither is what was meant
+                if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+                    return Err(ServerError::BadQuote);
+                }
+
+                return Ok(Some((Cow::Owned(word), rest)));
+            }
+            '\\' => match chars.next() {
+                Some((_, c @ ('"' | '\\'))) => word.push(c),
+                _ => return Err(ServerError::BadQuote),
+            },
+            c => word.push(c),
+        }
+    }
+
+    // no closing quote
+    Err(ServerError::BadQuote)
+}
+
+fn words(mut line: &str) -> Result<Vec<Cow<'_, str>>, ServerError> {
+    let mut words = Vec::new();
+
+    while let Some((word, rest)) = word(line)? {
+        words.push(word);
+        line = rest;
+    }
+
+    Ok(words)
+}
+
+/// The first `count` words of `line`, unquoted, and whatever follows them
+/// with its spacing kept; for lines that aren't commands, like the ones
+/// the server sends.
+pub fn leading_words(
+    mut line: &str,
+    count: usize,
+) -> Result<(Vec<Cow<'_, str>>, &str), ServerError> {
+    let mut words = Vec::new();
+
+    while words.len() < count {
+        let Some((word, rest)) = word(line)? else {
+            break;
+        };
+        words.push(word);
+        line = rest;
+    }
+
+    Ok((words, line.trim_start()))
+}
+
+/// Splits `line` and checks it against the command's grammar. Unknown
+/// commands are left for the caller to turn away.
+pub fn parse(line: &str) -> Result<Command<'_>, ServerError> {
+    let line = line.trim();
+
+    let (name, mut rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+    if name.is_empty() {
+        return Err(ServerError::NoCommand);
+    }
+
+    let Some((_, params)) = GRAMMAR.iter().find(|(n, _)| *n == name) else {
+        return Ok(Command {
+            name,
+            args: words(rest)?,
+            text: "",
+        });
+    };
+
+    let mut command = Command {
+        name,
+        args: Vec::new(),
+        text: "",
+    };
+
+    for param in params.iter() {
+        match param {
+            Required(_) | Optional => match word(rest)? {
+                Some((word, tail)) => {
+                    command.args.push(word);
+                    rest = tail;
+                }
+                None => match param {
+                    Required(code) => return Err(ServerError::MissingArgument(code)),
+                    _ => break,
+                },
+            },
+            Text(_) | OptionalText => {
+                command.text = rest.trim_start();
+                rest = "";
+
+                if let Text(code) = param {
+                    if command.text.is_empty() {
+                        return Err(ServerError::MissingArgument(code));
+                    }
+                }
+            }
+            Words => {
+                command.args.extend(words(rest)?);
+                rest = "";
+            }
+        }
+    }
+
+    if !rest.trim().is_empty() {
+        return Err(ServerError::TooManyArguments);
+    }
+
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args<'a>(command: &'a Command) -> Vec<&'a str> {
+        command.args.iter().map(|a| a.as_ref()).collect()
+    }
+
+    #[test]
+    fn payloads_keep_their_spacing() {
+        let command = parse("MSG bob  hello   there ").unwrap();
+
+        assert_eq!(command.name, "MSG");
+        assert_eq!(args(&command), ["bob"]);
+        assert_eq!(command.text, "hello   there");
+
+        // even with quotes in them
+        let command = parse(r#"MSG bob "hi""#).unwrap();
+        assert_eq!(command.text, r#""hi""#);
+    }
+
+    #[test]
+    fn quoted_words_can_hold_spaces() {
+        let command = parse(r#"FILE_OFFER bob "my notes.txt" 12"#).unwrap();
+        assert_eq!(args(&command), ["bob", "my notes.txt", "12"]);
+
+        let command = parse(r#"WHOIS "a \"b\" \\c""#).unwrap();
+        assert_eq!(args(&command), [r#"a "b" \c"#]);
+
+        for word in ["plain", "my notes.txt", r#""quoted""#, r#"back\slash "#, ""] {
+            let line = format!("WHOIS {}", quote(word));
+            assert_eq!(args(&parse(&line).unwrap()), [word]);
+        }
+
+        assert!(matches!(
+            parse(r#"WHOIS "alice"#),
+            Err(ServerError::BadQuote)
+        ));
+        assert!(matches!(
+            parse(r#"WHOIS "ali"ce"#),
+            Err(ServerError::BadQuote)
+        ));
+    }
+
+    #[test]
+    fn arguments_are_counted() {
+        assert!(matches!(
+            parse("MSGID 1 bob"),
+            Err(ServerError::MissingArgument("NIL_MSG"))
+        ));
+        assert!(matches!(
+            parse("KICK #room"),
+            Err(ServerError::MissingArgument("NIL_NICK"))
+        ));
+        assert!(matches!(
+            parse("JOIN #a #b"),
+            Err(ServerError::TooManyArguments)
+        ));
+        assert!(matches!(parse("  "), Err(ServerError::NoCommand)));
+
+        assert_eq!(args(&parse("HISTORY #room").unwrap()), ["#room"]);
+        assert_eq!(parse("PEERS_PUSH a b c").unwrap().args.len(), 3);
+        assert_eq!(parse("CUSTOM x \"y z\"").unwrap().args.len(), 2);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::server::{get_connection_by_addr, send_error_response, send_response, ServerState};
+use crate::transport::Writer;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+// an endpoint a nickname says it can be reached at, until `expires`
+struct Advert {
+    endpoint: SocketAddr,
+    expires: Instant,
+}
+
+/// Advertised endpoints, both from clients here and gossiped in from the
+/// servers in `[pex] servers`.
+#[derive(Default)]
+pub struct PeerExchange {
+    adverts: Mutex<HashMap<String, Advert>>,
+    // addresses of the configured servers, the only ones allowed to push
+    trusted: Mutex<HashSet<IpAddr>>,
+}
+
+impl PeerExchange {
+    // a newer advert replaces an older one, never the other way around
+    async fn learn(&self, nickname: &str, endpoint: SocketAddr, ttl: Duration) {
+        let expires = Instant::now() + ttl;
+        let mut adverts = self.adverts.lock().await;
+
+        match adverts.get(nickname) {
+            Some(advert) if advert.expires >= expires => {}
+            _ => {
+                adverts.insert(nickname.to_string(), Advert { endpoint, expires });
+            }
+        }
+    }
+
+    // live adverts with the seconds they have left, expired ones are dropped
+    async fn live(&self) -> Vec<(String, SocketAddr, u64)> {
+        let now = Instant::now();
+        let mut adverts = self.adverts.lock().await;
+
+        adverts.retain(|_, a| a.expires > now);
+
+        let mut live: Vec<(String, SocketAddr, u64)> = adverts
+            .iter()
+            .map(|(nick, a)| (nick.clone(), a.endpoint, (a.expires - now).as_secs()))
+            .collect();
+        live.sort();
+        live
+    }
+}
+
+// ttls are capped so nobody can advertise forever
+fn parse_ttl(state: &ServerState, ttl: Option<&str>) -> Option<Duration> {
+    let max = state.config.pex.max_ttl_seconds;
+
+    let seconds = match ttl {
+        None => max,
+        Some(ttl) => ttl.parse::<u64>().ok()?.min(max),
+    };
+
+    Some(Duration::from_secs(seconds))
+}
+
+/// `ADVERTISE <addr> [ttl]` publishes an endpoint, such as a listening port,
+/// for other peers to find through `PEERS`, here and on gossiping servers.
+pub async fn handle_advertise(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    endpoint: &str,
+    ttl: Option<&str>,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Ok(endpoint) = endpoint.parse::<SocketAddr>() else {
+        send_error_response(socket, "BAD_ADDR").await;
+        return;
+    };
+
+    let Some(ttl) = parse_ttl(&state, ttl) else {
+        send_error_response(socket, "BAD_NUM").await;
+        return;
+    };
+
+    state.pex.learn(&conn.nickname, endpoint, ttl).await;
+
+    send_response(socket, "OK", true).await;
+}
+
+/// Answers with one `PEERS <nick> <addr> <ttl>` line per advertised
+/// endpoint, then `OK`.
+pub async fn handle_peers(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>) {
+    for (nickname, endpoint, ttl) in state.pex.live().await {
+        send_response(
+            socket.clone(),
+            format!("PEERS {} {} {}", nickname, endpoint, ttl).as_str(),
+            true,
+        )
+        .await;
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+/// `PEERS_PUSH <nick> <addr> <ttl>` is how servers gossip adverts to each
+/// other. It is only taken from the servers listed in `[pex] servers`.
+pub async fn handle_push(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    args: &[&str],
+) {
+    if !state.pex.trusted.lock().await.contains(&addr.ip()) {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    }
+
+    let [nickname, endpoint, ttl] = args else {
+        send_error_response(socket, "NIL_ARG").await;
+        return;
+    };
+
+    let (Ok(endpoint), Some(ttl)) = (endpoint.parse::<SocketAddr>(), parse_ttl(&state, Some(ttl)))
+    else {
+        send_error_response(socket, "BAD_ARG").await;
+        return;
+    };
+
+    state.pex.learn(nickname, endpoint, ttl).await;
+
+    send_response(socket, "OK", true).await;
+}
+
+// sends every live advert to one server over a short-lived connection
+async fn push_to(server: &str, state: &ServerState) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(server).await?;
+
+    let mut lines = match &state.config.stealth.knock {
+        Some(knock) => format!("HELLO {}\n", knock),
+        None => "HELLO\n".to_string(),
+    };
+
+    for (nickname, endpoint, ttl) in state.pex.live().await {
+        lines.push_str(&format!("PEERS_PUSH {} {} {}\n", nickname, endpoint, ttl));
+    }
+
+    stream.write_all(lines.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    // the replies don't matter, wait for the other side to hang up
+    let mut replies = Vec::new();
+    stream.read_to_end(&mut replies).await?;
+
+    Ok(())
+}
+
+/// Every `[pex] interval_seconds`, pushes all live adverts to the configured
+/// servers. Adverts learned from one server are passed on to the others with
+/// whatever ttl they have left, so they spread across the mesh and die out.
+pub fn gossip(state: Arc<ServerState>) {
+    let interval = Duration::from_secs(state.config.pex.interval_seconds.max(1));
+
+    tokio::spawn(async move {
+        loop {
+            // resolved every round, server addresses can change
+            let mut trusted = HashSet::new();
+
+            for server in &state.config.pex.servers {
+                if let Ok(addrs) = tokio::net::lookup_host(server).await {
+                    trusted.extend(addrs.map(|a| a.ip()));
+                }
+            }
+            *state.pex.trusted.lock().await = trusted;
+
+            for server in &state.config.pex.servers {
+                if let Err(e) = push_to(server, &state).await {
+                    tracing::warn!(component = "pex", %server, error = %e, "Failed to gossip peers");
+                }
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
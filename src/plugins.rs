@@ -0,0 +1,63 @@
+use crate::server::{send_response, Connection, ServerState};
+use crate::transport::Writer;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// What a custom command's handler is called with.
+pub struct Context {
+    pub socket: Arc<Mutex<Writer>>,
+    pub addr: std::net::SocketAddr,
+    /// The sender's registration. Custom commands are only taken from
+    /// registered connections, so it is always there.
+    pub connection: Arc<Connection>,
+    /// Every connection, room and store, `state.connections` included.
+    pub state: Arc<ServerState>,
+    /// The words after the command name.
+    pub args: Vec<String>,
+}
+
+impl Context {
+    /// Answers the sender with one line.
+    pub async fn reply(&self, line: &str) {
+        send_response(self.socket.clone(), line, true).await;
+    }
+}
+
+type Handler = Arc<dyn Fn(Context) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Commands added by the embedding application, tried after every
+/// built-in one and before answering `UNK_CMD`.
+#[derive(Default, Clone)]
+pub struct Commands {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Commands {
+    /// Adds `name`, replacing any earlier handler for it. Built-in commands
+    /// can't be replaced.
+    pub fn register<F, Fut>(&mut self, name: impl Into<String>, handler: F)
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.handlers
+            .insert(name.into(), Arc::new(move |ctx| Box::pin(handler(ctx))));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+
+    // false when nothing handles `name`
+    pub(crate) async fn dispatch(&self, name: &str, ctx: Context) -> bool {
+        let Some(handler) = self.handlers.get(name) else {
+            return false;
+        };
+
+        handler(ctx).await;
+        true
+    }
+}
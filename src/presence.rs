@@ -0,0 +1,226 @@
+use crate::server::{
+    get_connection_by_nickname, send_error_response, send_response, send_to, Connection,
+    ServerState,
+};
+use crate::transport::Writer;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+}
+
+impl Status {
+    pub fn parse(s: &str) -> Option<Status> {
+        match s.to_ascii_lowercase().as_str() {
+            "online" => Some(Status::Online),
+            "away" => Some(Status::Away),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Online => "online",
+            Status::Away => "away",
+        }
+    }
+}
+
+// `STATUS away "be right back"` -> be right back
+fn unquote(text: &str) -> &str {
+    text.strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(text)
+}
+
+// "<nick> <status> [text]", shared by LIST and PRESENCE lines
+fn describe(nickname: &str, status: &str, text: Option<&str>) -> String {
+    match text {
+        Some(text) => format!("{} {} {}", nickname, status, text),
+        None => format!("{} {}", nickname, status),
+    }
+}
+
+/// Pushes a presence line about `nickname` to everyone watching it.
+pub async fn notify_watchers(
+    state: Arc<ServerState>,
+    nickname: &str,
+    status: &str,
+    text: Option<&str>,
+) {
+    let watchers: Vec<Connection> = state
+        .connections
+        .filter_map(|c| c.watching.contains(nickname).then(|| c.clone()));
+
+    let line = format!("PRESENCE {}", describe(nickname, status, text));
+
+    for watcher in watchers {
+        let _ = send_to(state.clone(), &watcher, &line).await;
+    }
+}
+
+pub async fn handle_status(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    status: &str,
+    text: &str,
+) {
+    let Some(status) = Status::parse(status) else {
+        send_error_response(socket, "BAD_STATUS").await;
+        return;
+    };
+
+    let text = unquote(text);
+    let text = (!text.is_empty()).then(|| text.to_string());
+
+    let nickname = state.connections.update(addr, |conn| {
+        conn.status = status;
+        conn.status_text = text.clone();
+
+        conn.nickname.clone()
+    });
+
+    let Some(nickname) = nickname else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    send_response(socket, "OK", true).await;
+
+    if let Some(cluster) = &state.cluster {
+        cluster.set_presence(&nickname, status.as_str()).await;
+    }
+
+    notify_watchers(state, &nickname, status.as_str(), text.as_deref()).await;
+}
+
+/// One `LIST <nick> <fingerprint> <status> [text]` line per registered peer,
+/// then `OK`. The fingerprint is `-` for peers without an identity key.
+pub async fn handle_list(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>) {
+    let mut lines: Vec<String> = state.connections.filter_map(|c| {
+        let fingerprint = c.fingerprint.as_deref().unwrap_or("-");
+
+        Some(match &c.status_text {
+            Some(text) => format!(
+                "LIST {} {} {} {}",
+                c.nickname,
+                fingerprint,
+                c.status.as_str(),
+                text
+            ),
+            None => format!("LIST {} {} {}", c.nickname, fingerprint, c.status.as_str()),
+        })
+    });
+    lines.sort();
+
+    for line in lines {
+        send_response(socket.clone(), &line, true).await;
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+/// Answers with one `WHOIS <nick> <field> <value>` line per detail, then `OK`.
+/// For a nickname on another node of the cluster that is only its `node`
+/// and `status`.
+pub async fn handle_whois(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>, nickname: &str) {
+    let Some(conn) = get_connection_by_nickname(nickname, state.clone()).await else {
+        let located = match &state.cluster {
+            Some(cluster) => cluster.locate(nickname).await,
+            None => None,
+        };
+
+        let Some((node, status)) = located else {
+            send_error_response(socket, "NO_NICK").await;
+            return;
+        };
+
+        let status = status.unwrap_or_else(|| Status::Online.as_str().to_string());
+
+        for field in [format!("node {}", node), format!("status {}", status)] {
+            send_response(
+                socket.clone(),
+                format!("WHOIS {} {}", nickname, field).as_str(),
+                true,
+            )
+            .await;
+        }
+
+        send_response(socket, "OK", true).await;
+        return;
+    };
+
+    let registered = conn
+        .registered_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut rooms: Vec<&str> = conn.rooms.iter().map(|r| r.as_str()).collect();
+    rooms.sort();
+
+    let mut fields = vec![format!("registered {}", registered)];
+
+    if !state.config.whois.hide_address {
+        fields.push(format!("addr {}", conn.addr));
+
+        // looked up when it connected, never waited for here
+        let host = state.rdns.as_ref().and_then(|r| r.cached(conn.addr.ip()));
+
+        if let Some(host) = host {
+            fields.push(format!("host {}", host));
+        }
+    }
+
+    if let Some(fingerprint) = &conn.fingerprint {
+        fields.push(format!("fingerprint {}", fingerprint));
+    }
+
+    fields.push(format!("idle {}", conn.last_activity.elapsed().as_secs()));
+    fields.push(format!("rooms {}", rooms.join(" ")).trim_end().to_string());
+    fields.push(match &conn.status_text {
+        Some(text) => format!("status {} {}", conn.status.as_str(), text),
+        None => format!("status {}", conn.status.as_str()),
+    });
+
+    for field in fields {
+        send_response(
+            socket.clone(),
+            format!("WHOIS {} {}", conn.nickname, field).as_str(),
+            true,
+        )
+        .await;
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+/// `WATCH <nick>` opts into presence updates for a nickname, `UNWATCH` opts out.
+pub async fn handle_watch(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    nickname: &str,
+    watch: bool,
+) {
+    let updated = state.connections.update(addr, |conn| {
+        if watch {
+            conn.watching.insert(nickname.to_string());
+        } else {
+            conn.watching.remove(nickname);
+        }
+    });
+
+    if updated.is_none() {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    }
+
+    send_response(socket, "OK", true).await;
+}
@@ -0,0 +1,65 @@
+use crate::server::{
+    get_connection_by_addr, get_connection_by_nickname, send_error_response, send_response,
+    send_to, ServerState,
+};
+use crate::transport::Writer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// `CONNECT <nick>` brokers a direct connection: both peers get
+/// `CONNECT <other nick> <other addr>` with the address the server sees for
+/// the other side, then, after `[punch] delay_ms`, both get `PUNCH <other
+/// nick>` at the same moment as the cue to start hole punching.
+pub async fn handle_connect(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    target: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Some(target) = get_connection_by_nickname(target, state.clone()).await else {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    };
+
+    if target.addr == addr {
+        send_error_response(socket, "SELF").await;
+        return;
+    }
+
+    let to_target = format!("CONNECT {} {}", conn.nickname, conn.addr);
+
+    if send_to(state.clone(), &target, &to_target).await.is_err() {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    }
+
+    send_response(socket.clone(), "OK", true).await;
+
+    send_response(
+        socket.clone(),
+        format!("CONNECT {} {}", target.nickname, target.addr).as_str(),
+        true,
+    )
+    .await;
+
+    let delay = Duration::from_millis(state.config.punch.delay_ms);
+
+    // give both sides a moment to get ready, then cue them together
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        let to_target = format!("PUNCH {}", conn.nickname);
+        let to_sender = format!("PUNCH {}", target.nickname);
+
+        let (_, ()) = tokio::join!(
+            send_to(state.clone(), &target, &to_target),
+            send_response(socket, &to_sender, true),
+        );
+    });
+}
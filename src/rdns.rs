@@ -0,0 +1,142 @@
+//! Host names for connecting addresses, when `[rdns]` is enabled.
+//!
+//! Lookups start as a connection is accepted and run on their own, so
+//! nothing waits for them. A name is only kept if it resolves back to the
+//! address it came from, anyone can put any name in their reverse zone.
+//! Answers, including the lack of one, are cached per ip.
+
+use crate::config::RdnsConfig;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// ips remembered at once, past this new answers aren't cached
+const MAX_CACHED: usize = 4096;
+
+pub struct Resolver {
+    timeout: Duration,
+    ttl: Duration,
+    cache: Mutex<HashMap<IpAddr, (Option<String>, Instant)>>,
+}
+
+impl Resolver {
+    pub fn new(config: &RdnsConfig) -> Self {
+        Resolver {
+            timeout: Duration::from_secs(config.timeout_seconds),
+            ttl: Duration::from_secs(config.cache_seconds),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The name `ip` was last resolved to, without looking it up.
+    pub fn cached(&self, ip: IpAddr) -> Option<String> {
+        let cache = self.cache.lock().unwrap();
+
+        match cache.get(&ip) {
+            Some((name, at)) if at.elapsed() < self.ttl => name.clone(),
+            _ => None,
+        }
+    }
+
+    /// The name of `ip`, from the cache or looked up, `None` if it has none
+    /// or the lookup took longer than `[rdns] timeout_seconds`.
+    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
+        if let Some((name, at)) = self.cache.lock().unwrap().get(&ip) {
+            if at.elapsed() < self.ttl {
+                return name.clone();
+            }
+        }
+
+        let name = tokio::time::timeout(self.timeout, confirmed(ip))
+            .await
+            .ok()
+            .flatten();
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, at)| at.elapsed() < self.ttl);
+
+        if cache.len() < MAX_CACHED {
+            cache.insert(ip, (name.clone(), Instant::now()));
+        }
+
+        name
+    }
+}
+
+// the reverse name of `ip`, if it also resolves forward to `ip`
+async fn confirmed(ip: IpAddr) -> Option<String> {
+    let name = tokio::task::spawn_blocking(move || reverse(ip))
+        .await
+        .ok()
+        .flatten()?;
+
+    let matches = tokio::net::lookup_host((name.as_str(), 0))
+        .await
+        .ok()?
+        .any(|addr| addr.ip() == ip);
+
+    matches.then_some(name)
+}
+
+#[cfg(unix)]
+fn reverse(ip: IpAddr) -> Option<String> {
+    use std::ffi::CStr;
+
+    // NI_MAXHOST, which libc doesn't agree on the type of
+    let mut host = [0 as libc::c_char; 1025];
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+
+    // addr is a sockaddr of addr.len() bytes, host is written to at most
+    // its full length and nul-terminated
+    let found = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr().cast(),
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+
+    if found != 0 {
+        return None;
+    }
+
+    // nul-terminated on success
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+
+    name.to_str().ok().map(str::to_string)
+}
+
+#[cfg(not(unix))]
+fn reverse(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_are_cached() {
+        let resolver = Resolver::new(&RdnsConfig {
+            enabled: true,
+            timeout_seconds: 2,
+            cache_seconds: 60,
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .insert(ip, (Some("peer.example".to_string()), Instant::now()));
+
+        assert_eq!(resolver.lookup(ip).await.as_deref(), Some("peer.example"));
+        assert_eq!(resolver.cached(ip).as_deref(), Some("peer.example"));
+        assert_eq!(resolver.cached("192.0.2.2".parse().unwrap()), None);
+    }
+}
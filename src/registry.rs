@@ -0,0 +1,159 @@
+//! The registered connections, by address and by nickname.
+//!
+//! Both maps are split into shards behind their own locks, so lookups and
+//! updates for different connections don't wait on each other. Every
+//! method takes and releases its locks before returning; nothing here is
+//! ever held across an `.await`.
+//!
+//! That last part is what a single manager task owning the registry would
+//! buy, without every lookup becoming a round trip through a channel to
+//! one task. Writes to a connection stay with its shared writer for the
+//! same reason: `send_to` bounds and times them per connection, and `CAP`
+//! switches compression between two writes, neither of which a queue in
+//! front of the socket makes simpler.
+
+use crate::server::Connection;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::net::SocketAddr;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const SHARDS: usize = 16;
+
+// a panic while a shard was held leaves it as it was, which is still
+// better than every later lookup panicking too
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+struct Shards<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> Shards<K, V> {
+    fn new() -> Self {
+        Shards {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &RwLock<HashMap<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % SHARDS;
+        &self.shards[index]
+    }
+}
+
+/// Every registered connection. A nickname belongs to at most one of
+/// them, see [`insert`](Registry::insert).
+pub struct Registry {
+    connections: Shards<SocketAddr, Connection>,
+    nicknames: Shards<String, SocketAddr>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            connections: Shards::new(),
+            nicknames: Shards::new(),
+        }
+    }
+}
+
+impl Registry {
+    /// Registers `conn`, unless its nickname or address is registered
+    /// already. Checked and taken in one step, so of two connections
+    /// inserting the same nickname only one gets it.
+    pub fn insert(&self, conn: Connection) -> bool {
+        // nickname shard before address shard, everywhere both are taken
+        let mut nicknames = write(self.nicknames.shard(conn.nickname.as_str()));
+
+        if nicknames.contains_key(&conn.nickname) {
+            return false;
+        }
+
+        let mut connections = write(self.connections.shard(&conn.addr));
+
+        if connections.contains_key(&conn.addr) {
+            return false;
+        }
+
+        nicknames.insert(conn.nickname.clone(), conn.addr);
+        connections.insert(conn.addr, conn);
+        true
+    }
+
+    pub fn remove(&self, addr: SocketAddr) -> Option<Connection> {
+        let conn = write(self.connections.shard(&addr)).remove(&addr)?;
+
+        let mut nicknames = write(self.nicknames.shard(conn.nickname.as_str()));
+
+        if nicknames.get(&conn.nickname) == Some(&addr) {
+            nicknames.remove(&conn.nickname);
+        }
+
+        Some(conn)
+    }
+
+    pub fn get(&self, addr: SocketAddr) -> Option<Connection> {
+        read(self.connections.shard(&addr)).get(&addr).cloned()
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        read(self.connections.shard(&addr)).contains_key(&addr)
+    }
+
+    /// Where `nickname` is registered from.
+    pub fn addr_of(&self, nickname: &str) -> Option<SocketAddr> {
+        read(self.nicknames.shard(nickname)).get(nickname).copied()
+    }
+
+    pub fn by_nickname(&self, nickname: &str) -> Option<Connection> {
+        self.get(self.addr_of(nickname)?)
+    }
+
+    pub fn has_nickname(&self, nickname: &str) -> bool {
+        self.addr_of(nickname).is_some()
+    }
+
+    /// Changes the connection at `addr` in place, None if it isn't
+    /// registered.
+    pub fn update<R>(&self, addr: SocketAddr, f: impl FnOnce(&mut Connection) -> R) -> Option<R> {
+        write(self.connections.shard(&addr)).get_mut(&addr).map(f)
+    }
+
+    /// What `f` makes of each connection, skipping the ones it gives None
+    /// for. Shards are looked at one after another, so this is not a
+    /// snapshot of one moment.
+    pub fn filter_map<T>(&self, mut f: impl FnMut(&Connection) -> Option<T>) -> Vec<T> {
+        self.connections
+            .shards
+            .iter()
+            .flat_map(|shard| read(shard).values().filter_map(&mut f).collect::<Vec<T>>())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.shards.iter().map(|s| read(s).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.nicknames.shards {
+            write(shard).clear();
+        }
+
+        for shard in &self.connections.shards {
+            write(shard).clear();
+        }
+    }
+}
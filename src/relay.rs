@@ -0,0 +1,294 @@
+use crate::audit::Event;
+use crate::config::RelayConfig;
+use crate::server::{
+    get_connection_by_addr, get_connection_by_nickname, send_error_response, send_response,
+    send_to, ServerState,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::transport::{Reader, Writer};
+use tokio::sync::Mutex;
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+// the first side of a session to show up, parked until its peer arrives
+struct Waiting {
+    token: String,
+    // who the token was handed to
+    nickname: String,
+    addr: SocketAddr,
+    reader: Reader,
+    writer: Arc<Mutex<Writer>>,
+    // bytes that arrived right behind the RELAY line
+    leftover: Vec<u8>,
+}
+
+pub struct RelaySession {
+    // one token per side, handed out by RELAY_OPEN
+    tokens: [String; 2],
+    // the nicknames they went to, in the same order
+    nicknames: [String; 2],
+    waiting: Option<Waiting>,
+}
+
+#[derive(Default)]
+pub struct Relays {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, RelaySession>>,
+}
+
+/// `[relay]` quotas as they are now, read for every chunk so they can be
+/// changed while the server runs. 0 is unlimited.
+pub struct RelayLimits {
+    pub max_bytes: AtomicU64,
+    pub bytes_per_second: AtomicU64,
+}
+
+impl RelayLimits {
+    pub fn new(config: &RelayConfig) -> Self {
+        RelayLimits {
+            max_bytes: AtomicU64::new(config.max_bytes),
+            bytes_per_second: AtomicU64::new(config.bytes_per_second),
+        }
+    }
+}
+
+fn new_token() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Returns the token if the line is `RELAY <token>`, which turns the
+/// connection into a relay data channel.
+pub fn parse_attach(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (Some("RELAY"), Some(token)) => Some(token.to_string()),
+        _ => None,
+    }
+}
+
+/// `RELAY_OPEN <nick>` sets up a relay session with a peer. The requester
+/// gets `OK <id> <token>`, the peer gets `RELAY_OPEN <id> <from> <token>`.
+/// Each side then opens a fresh connection and sends `RELAY <token>`; once
+/// both are there, the server answers `OK` on both and copies bytes between
+/// them until either side closes or the session runs out of quota.
+pub async fn handle_open(
+    socket: Arc<Mutex<Writer>>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    target: &str,
+) {
+    if !state.config.relay.enabled {
+        send_error_response(socket, "RELAY_OFF").await;
+        return;
+    }
+
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Some(target) = get_connection_by_nickname(target, state.clone()).await else {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    };
+
+    let id = state.relays.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let tokens = [new_token(), new_token()];
+
+    let line = format!("RELAY_OPEN {} {} {}", id, conn.nickname, tokens[1]);
+
+    if send_to(state.clone(), &target, &line).await.is_err() {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    }
+
+    send_response(socket, format!("OK {} {}", id, tokens[0]).as_str(), true).await;
+
+    state.relays.sessions.lock().await.insert(
+        id,
+        RelaySession {
+            tokens,
+            nicknames: [conn.nickname.clone(), target.nickname.clone()],
+            waiting: None,
+        },
+    );
+
+    expire_if_unused(state, id);
+}
+
+// sessions that never got both sides attached are thrown away
+fn expire_if_unused(state: Arc<ServerState>, id: u64) {
+    let timeout = Duration::from_secs(state.config.relay.open_timeout_seconds);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+
+        // a bridged session is no longer in the map
+        state.relays.sessions.lock().await.remove(&id);
+    });
+}
+
+/// Takes over a connection that sent `RELAY <token>`.
+pub async fn attach(
+    token: String,
+    addr: SocketAddr,
+    reader: Reader,
+    writer: Arc<Mutex<Writer>>,
+    leftover: Vec<u8>,
+    state: Arc<ServerState>,
+) {
+    let mut sessions = state.relays.sessions.lock().await;
+
+    let Some((id, session)) = sessions.iter_mut().find(|(_, s)| s.tokens.contains(&token)) else {
+        drop(sessions);
+        send_error_response(writer, "BAD_TOKEN").await;
+        return;
+    };
+    let id = *id;
+
+    let side = session.tokens.iter().position(|t| *t == token).unwrap();
+    let nickname = session.nicknames[side].clone();
+
+    let other = match session.waiting.take() {
+        // the same side showing up twice
+        Some(waiting) if waiting.token == token => {
+            session.waiting = Some(waiting);
+            drop(sessions);
+            send_error_response(writer, "BAD_TOKEN").await;
+            return;
+        }
+        Some(waiting) => waiting,
+        None => {
+            session.waiting = Some(Waiting {
+                token,
+                nickname,
+                addr,
+                reader,
+                writer,
+                leftover,
+            });
+            return;
+        }
+    };
+
+    // both sides are here, the session leaves the registry
+    sessions.remove(&id);
+    drop(sessions);
+
+    let this = Waiting {
+        token,
+        nickname,
+        addr,
+        reader,
+        writer,
+        leftover,
+    };
+
+    tokio::spawn(bridge(id, other, this, state));
+}
+
+// copies one direction of a session, counting against the shared quota
+// and the bandwidth caps of `from`, and into `sent`
+async fn pump(
+    from: SocketAddr,
+    mut reader: Reader,
+    writer: Arc<Mutex<Writer>>,
+    leftover: Vec<u8>,
+    relayed: Arc<AtomicU64>,
+    sent: &AtomicU64,
+    state: Arc<ServerState>,
+) {
+    let limits = &state.relay_limits;
+    let started = Instant::now();
+    let mut buffer = vec![0; RELAY_BUFFER_SIZE];
+    let mut writer = writer.lock().await;
+
+    let mut chunk = leftover;
+
+    loop {
+        if !chunk.is_empty() {
+            let total =
+                relayed.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+
+            let max_bytes = limits.max_bytes.load(Ordering::Relaxed);
+
+            if max_bytes > 0 && total > max_bytes {
+                break;
+            }
+
+            state.bandwidth.wait(from, chunk.len()).await;
+
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+            state.metrics.relayed(chunk.len());
+            sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+
+            // hold the session to its byte rate by sleeping off any surplus
+            let bytes_per_second = limits.bytes_per_second.load(Ordering::Relaxed);
+
+            if bytes_per_second > 0 {
+                let due = Duration::from_secs_f64(total as f64 / bytes_per_second as f64);
+
+                if let Some(wait) = due.checked_sub(started.elapsed()) {
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+
+        match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => chunk = buffer[..n].to_vec(),
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+async fn bridge(id: u64, a: Waiting, b: Waiting, state: Arc<ServerState>) {
+    let relayed = Arc::new(AtomicU64::new(0));
+    let sent = [AtomicU64::new(0), AtomicU64::new(0)];
+
+    send_response(a.writer.clone(), "OK", true).await;
+    send_response(b.writer.clone(), "OK", true).await;
+
+    tracing::info!(relay = id, "Opened");
+
+    // when one direction ends the other one is dropped with it
+    tokio::select! {
+        _ = pump(a.addr, a.reader, b.writer.clone(), a.leftover, relayed.clone(), &sent[0], state.clone()) => {}
+        _ = pump(b.addr, b.reader, a.writer.clone(), b.leftover, relayed.clone(), &sent[1], state.clone()) => {}
+    }
+
+    // data channels never register, so nothing else lets go of them
+    state.bandwidth.forget(a.addr);
+    state.bandwidth.forget(b.addr);
+
+    tracing::info!(
+        relay = id,
+        bytes = relayed.load(Ordering::Relaxed),
+        "Closed"
+    );
+
+    for (nickname, addr, sent) in [
+        (&a.nickname, a.addr, &sent[0]),
+        (&b.nickname, b.addr, &sent[1]),
+    ] {
+        state.audit.record(Event::Relay {
+            id,
+            nickname,
+            addr,
+            bytes: sent.load(Ordering::Relaxed),
+        });
+    }
+}
@@ -0,0 +1,171 @@
+//! Rereading the config file while the server runs, on `SIGHUP` or
+//! `POST /reload`. Connections stay up.
+//!
+//! Only some settings can change this way: `[auth]`, `[motd]`, the relay
+//! quotas, `noise.allowed_keys` and `log.level`. Changing anything else
+//! needs a restart, and a file that does is refused as a whole rather than
+//! half applied.
+
+use crate::check;
+use crate::config::Config;
+use crate::server::ServerState;
+use std::io;
+use thiserror::Error;
+
+/// Why a reload didn't happen. The server carries on as it was.
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error("no config file to reload, set P2P_CONFIG or create p2p.toml")]
+    NoFile,
+    #[error("failed to read the config: {0}")]
+    Read(io::Error),
+    #[error("{}", .0.join("; "))]
+    Invalid(Vec<String>),
+    #[error("changing [{}] needs a restart, nothing was reloaded", .0.join("], ["))]
+    NeedsRestart(Vec<&'static str>),
+}
+
+/// The sections of `new` that differ from `running` in ways a reload
+/// can't apply.
+pub fn needs_restart(running: &Config, new: &Config) -> Vec<&'static str> {
+    let mut new = new.clone();
+
+    // what a reload applies isn't in the way
+    new.auth = running.auth.clone();
+    new.motd = running.motd.clone();
+    new.relay.max_bytes = running.relay.max_bytes;
+    new.relay.bytes_per_second = running.relay.bytes_per_second;
+    new.noise.allowed_keys = running.noise.allowed_keys.clone();
+    new.log.level = running.log.level.clone();
+
+    let sections = [
+        ("auth", running.auth == new.auth),
+        ("whois", running.whois == new.whois),
+        ("history", running.history == new.history),
+        ("offline", running.offline == new.offline),
+        ("acks", running.acks == new.acks),
+        ("files", running.files == new.files),
+        ("stealth", running.stealth == new.stealth),
+        ("punch", running.punch == new.punch),
+        ("relay", running.relay == new.relay),
+        ("signaling", running.signaling == new.signaling),
+        ("dht", running.dht == new.dht),
+        ("pex", running.pex == new.pex),
+        ("noise", running.noise == new.noise),
+        ("identity", running.identity == new.identity),
+        ("resume", running.resume == new.resume),
+        ("log", running.log == new.log),
+        ("mdns", running.mdns == new.mdns),
+        ("metrics", running.metrics == new.metrics),
+        ("admin", running.admin == new.admin),
+        ("motd", running.motd == new.motd),
+        ("outbound", running.outbound == new.outbound),
+        ("compression", running.compression == new.compression),
+        ("federation", running.federation == new.federation),
+        ("cluster", running.cluster == new.cluster),
+        ("daemon", running.daemon == new.daemon),
+        ("typing", running.typing == new.typing),
+        ("bandwidth", running.bandwidth == new.bandwidth),
+        ("tcp", running.tcp == new.tcp),
+        ("listen", running.listen == new.listen),
+        ("rdns", running.rdns == new.rdns),
+        ("nicknames", running.nicknames == new.nicknames),
+        ("audit", running.audit == new.audit),
+    ];
+
+    sections
+        .into_iter()
+        .filter(|(_, same)| !same)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The settings a reload from `running` to `new` would change, by the
+/// names they have in the file.
+pub fn changes(running: &Config, new: &Config) -> Vec<&'static str> {
+    let changes = [
+        ("auth", running.auth != new.auth),
+        ("motd", running.motd != new.motd),
+        (
+            "relay.max_bytes",
+            running.relay.max_bytes != new.relay.max_bytes,
+        ),
+        (
+            "relay.bytes_per_second",
+            running.relay.bytes_per_second != new.relay.bytes_per_second,
+        ),
+        (
+            "noise.allowed_keys",
+            running.noise.allowed_keys != new.noise.allowed_keys,
+        ),
+        ("log.level", running.log.level != new.log.level),
+    ];
+
+    changes
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Refuses `new` outright if `check-config` would.
+pub fn validate(new: &Config) -> Result<(), ReloadError> {
+    let errors = check::errors(new);
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ReloadError::Invalid(errors))
+    }
+}
+
+/// The config file the server was started from, read again.
+pub fn read() -> Result<Config, ReloadError> {
+    let path = Config::path().ok_or(ReloadError::NoFile)?;
+
+    Config::from_file(path).map_err(ReloadError::Read)
+}
+
+/// Reloads from the config file the server was started from, logging how
+/// it went.
+pub fn from_file(state: &ServerState) -> Result<Vec<&'static str>, ReloadError> {
+    let reloaded = read().and_then(|config| state.reload(config));
+
+    match &reloaded {
+        Ok(changes) => {
+            tracing::info!(component = "config", changes = ?changes, "Reloaded");
+        }
+        Err(e) => tracing::warn!(component = "config", error = %e, "Reload refused"),
+    }
+
+    reloaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_some_settings_reload() {
+        let running = Config::default();
+
+        let mut new = running.clone();
+        new.motd.text = Some("hello".to_string());
+        new.relay.bytes_per_second = 1024;
+        new.log.level = "debug".to_string();
+
+        assert!(needs_restart(&running, &new).is_empty());
+        assert_eq!(
+            changes(&running, &new),
+            ["motd", "relay.bytes_per_second", "log.level"]
+        );
+
+        new.relay.enabled = !running.relay.enabled;
+        new.dht.enabled = !running.dht.enabled;
+        assert_eq!(needs_restart(&running, &new), ["relay", "dht"]);
+        assert_eq!(
+            ReloadError::NeedsRestart(needs_restart(&running, &new)).to_string(),
+            "changing [relay], [dht] needs a restart, nothing was reloaded"
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! Offering a file to a whole room with `FILE_OFFER #room <name> <size>`.
+//!
+//! The sender uploads every chunk once and the server copies it to each
+//! member who accepted. The other commands are the ones of a transfer to
+//! one peer, with the same ids, and differ only where many recipients
+//! make them:
+//!
+//! - members get `FILE_OFFER <id> <from> <name> <size> <room>`, and the
+//!   sender hears each answer as `FILE_ACCEPT <id> <nick>` or
+//!   `FILE_REJECT <id> <nick>`
+//! - the first chunk closes the offer, members who haven't accepted by
+//!   then get `FILE_CANCEL <id>`
+//! - the `[files] window` is kept by the slowest acceptor, and the sender
+//!   gets `FILE_ACK <id>` each time that one catches up by a chunk
+//! - an acceptor who cancels or leaves drops out, the sender hears
+//!   `FILE_CANCEL <id> <nick>`; once nobody is left the whole transfer is
+//!   cancelled
+//!
+//! Room transfers can't be resumed, a sender leaving cancels them.
+
+use crate::files::send_to_addr;
+use crate::parser;
+use crate::server::{
+    get_connection_by_addr, send_error_response, send_response, send_to, ServerState,
+};
+use crate::transport::Writer;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub struct Member {
+    pub nickname: String,
+    pub accepted: bool,
+    // chunks this member has acked
+    pub acked: u64,
+}
+
+pub struct RoomTransfer {
+    pub sender: SocketAddr,
+    pub sender_nick: String,
+    pub room: String,
+    pub name: String,
+    pub size: u64,
+    // bytes and chunks relayed so far, the same for every acceptor
+    pub relayed: u64,
+    pub chunks: u64,
+    // members offered the file, until they reject, cancel or leave
+    pub members: HashMap<SocketAddr, Member>,
+}
+
+impl RoomTransfer {
+    fn acceptors(&self) -> impl Iterator<Item = (&SocketAddr, &Member)> {
+        self.members.iter().filter(|(_, m)| m.accepted)
+    }
+
+    // chunks every acceptor has acked
+    fn slowest(&self) -> u64 {
+        self.acceptors()
+            .map(|(_, m)| m.acked)
+            .min()
+            .unwrap_or(self.chunks)
+    }
+
+    fn is_done(&self) -> bool {
+        self.relayed == self.size && self.chunks > 0 && self.slowest() == self.chunks
+    }
+
+    // the methods below are what the handlers do under the `rooms` lock,
+    // which is let go of before anything is written to a connection
+
+    // `addr` answering the offer, the nickname it was made to
+    fn answer(&mut self, addr: SocketAddr, accept: bool) -> Result<String, &'static str> {
+        let started = self.chunks > 0;
+
+        let Some(member) = self.members.get_mut(&addr).filter(|m| !m.accepted) else {
+            return Err("BAD_ID");
+        };
+
+        if started {
+            return Err("FILE_STARTED");
+        }
+
+        let nickname = member.nickname.clone();
+
+        if accept {
+            member.accepted = true;
+        } else {
+            self.members.remove(&addr);
+        }
+
+        Ok(nickname)
+    }
+
+    // one chunk of `len` bytes, and the members whose offer it closed
+    fn chunk(&mut self, len: usize, window: usize) -> Result<Vec<SocketAddr>, &'static str> {
+        if self.acceptors().next().is_none() {
+            return Err("NOT_ACCEPTED");
+        }
+
+        if self.relayed + len as u64 > self.size {
+            return Err("FILE_OVERFLOW");
+        }
+
+        if self.chunks - self.slowest() >= window as u64 {
+            return Err("FILE_WINDOW");
+        }
+
+        // the offer closes with the first chunk
+        let mut closed = Vec::new();
+
+        if self.chunks == 0 {
+            self.members.retain(|addr, m| {
+                if !m.accepted {
+                    closed.push(*addr);
+                }
+                m.accepted
+            });
+        }
+
+        self.relayed += len as u64;
+        self.chunks += 1;
+
+        Ok(closed)
+    }
+
+    // an ack from `addr`, whether the slowest acceptor caught up with it
+    fn ack(&mut self, addr: SocketAddr) -> Result<bool, &'static str> {
+        let (chunks, before) = (self.chunks, self.slowest());
+
+        match self.members.get_mut(&addr) {
+            Some(m) if m.accepted && m.acked < chunks => m.acked += 1,
+            _ => return Err("BAD_ID"),
+        }
+
+        Ok(self.slowest() > before)
+    }
+}
+
+// tells the sender and every acceptor the transfer went through
+async fn finish(state: Arc<ServerState>, id: u64) {
+    let Some(transfer) = state.files.rooms.lock().await.remove(&id) else {
+        return;
+    };
+
+    let line = format!("FILE_DONE {}", id);
+    send_to_addr(state.clone(), transfer.sender, &line).await;
+
+    for (addr, _) in transfer.acceptors() {
+        send_to_addr(state.clone(), *addr, &line).await;
+    }
+}
+
+/// Whether `id` is a room transfer, which the `FILE_*` handlers then leave
+/// to this module.
+pub async fn owns(state: &ServerState, id: u64) -> bool {
+    state.files.rooms.lock().await.contains_key(&id)
+}
+
+pub async fn handle_offer(
+    socket: Arc<Mutex<Writer>>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    name: &str,
+    size: &str,
+) {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    // an empty file would be done before anyone could accept it
+    let Some(size) = size.parse::<u64>().ok().filter(|s| *s > 0) else {
+        send_error_response(socket, "BAD_SIZE").await;
+        return;
+    };
+
+    if size > state.config.files.max_size {
+        send_error_response(socket, "FILE_TOO_BIG").await;
+        return;
+    }
+
+    if !conn.rooms.contains(room) {
+        send_error_response(socket, "NOT_IN_ROOM").await;
+        return;
+    }
+
+    let members: Vec<SocketAddr> = match state.rooms.lock().await.get(room) {
+        Some(r) => r.members.iter().copied().filter(|m| *m != addr).collect(),
+        None => Vec::new(),
+    };
+    let members: Vec<_> = members
+        .into_iter()
+        .filter_map(|m| state.connections.get(m))
+        .collect();
+
+    if members.is_empty() {
+        send_error_response(socket, "NO_MEMBERS").await;
+        return;
+    }
+
+    let id = state.files.new_id();
+
+    state.files.rooms.lock().await.insert(
+        id,
+        RoomTransfer {
+            sender: addr,
+            sender_nick: conn.nickname.clone(),
+            room: room.to_string(),
+            name: name.to_string(),
+            size,
+            relayed: 0,
+            chunks: 0,
+            members: members
+                .iter()
+                .map(|m| {
+                    let member = Member {
+                        nickname: m.nickname.clone(),
+                        accepted: false,
+                        acked: 0,
+                    };
+                    (m.addr, member)
+                })
+                .collect(),
+        },
+    );
+
+    send_response(socket, format!("OK {}", id).as_str(), true).await;
+
+    let line = format!(
+        "FILE_OFFER {} {} {} {} {}",
+        id,
+        conn.nickname,
+        parser::quote(name),
+        size,
+        room
+    );
+
+    for member in members {
+        let _ = send_to(state.clone(), &member, &line).await;
+    }
+}
+
+pub async fn handle_answer(
+    socket: Arc<Mutex<Writer>>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    id: u64,
+    accept: bool,
+) {
+    let answered = {
+        let mut rooms = state.files.rooms.lock().await;
+
+        let answered = match rooms.get_mut(&id) {
+            Some(transfer) => transfer
+                .answer(addr, accept)
+                .map(|nickname| (transfer.sender, nickname, transfer.members.is_empty())),
+            None => Err("BAD_ID"),
+        };
+
+        if let Ok((_, _, true)) = answered {
+            rooms.remove(&id);
+        }
+
+        answered
+    };
+
+    let (sender, nickname, abandoned) = match answered {
+        Ok(answered) => answered,
+        Err(error) => {
+            send_error_response(socket, error).await;
+            return;
+        }
+    };
+
+    send_response(socket, "OK", true).await;
+
+    let line = if accept {
+        format!("FILE_ACCEPT {} {}", id, nickname)
+    } else {
+        format!("FILE_REJECT {} {}", id, nickname)
+    };
+    send_to_addr(state.clone(), sender, &line).await;
+
+    if abandoned {
+        send_to_addr(state, sender, &format!("FILE_CANCEL {}", id)).await;
+    }
+}
+
+pub async fn handle_chunk(
+    socket: Arc<Mutex<Writer>>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    id: u64,
+    data: &str,
+    len: usize,
+) {
+    let relayed = match state
+        .files
+        .rooms
+        .lock()
+        .await
+        .get_mut(&id)
+        .filter(|t| t.sender == addr)
+    {
+        Some(transfer) => transfer
+            .chunk(len, state.config.files.window)
+            .map(|closed| {
+                let acceptors: Vec<SocketAddr> = transfer.acceptors().map(|(a, _)| *a).collect();
+                (acceptors, closed)
+            }),
+        None => Err("BAD_ID"),
+    };
+
+    let (acceptors, closed) = match relayed {
+        Ok(relayed) => relayed,
+        Err(error) => {
+            send_error_response(socket, error).await;
+            return;
+        }
+    };
+
+    for member in closed {
+        send_to_addr(state.clone(), member, &format!("FILE_CANCEL {}", id)).await;
+    }
+
+    // every copy goes out over the uplink
+    let bytes = data.len() * acceptors.len();
+    let line = format!("FILE_CHUNK {} {}", id, data);
+    state
+        .bandwidth
+        .relay(&state, addr, acceptors, line, bytes)
+        .await;
+
+    send_response(socket, "OK", true).await;
+}
+
+pub async fn handle_file_ack(
+    socket: Arc<Mutex<Writer>>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    id: u64,
+) {
+    let acked = match state.files.rooms.lock().await.get_mut(&id) {
+        Some(transfer) => transfer
+            .ack(addr)
+            .map(|caught_up| (transfer.sender, caught_up, transfer.is_done())),
+        None => Err("BAD_ID"),
+    };
+
+    let (sender, caught_up, done) = match acked {
+        Ok(acked) => acked,
+        Err(error) => {
+            send_error_response(socket, error).await;
+            return;
+        }
+    };
+
+    send_response(socket, "OK", true).await;
+
+    if caught_up {
+        send_to_addr(state.clone(), sender, &format!("FILE_ACK {}", id)).await;
+    }
+
+    if done {
+        finish(state, id).await;
+    }
+}
+
+// `addr` drops out of transfer `id`, as the sender that ends it for
+// everyone, as a member just for itself. False if it had no part in it.
+async fn leave(state: Arc<ServerState>, id: u64, addr: SocketAddr) -> bool {
+    let mut rooms = state.files.rooms.lock().await;
+
+    let Some(transfer) = rooms.get_mut(&id) else {
+        return false;
+    };
+
+    if transfer.sender == addr {
+        let transfer = rooms.remove(&id).unwrap();
+        drop(rooms);
+
+        for member in transfer.members.keys() {
+            send_to_addr(state.clone(), *member, &format!("FILE_CANCEL {}", id)).await;
+        }
+
+        return true;
+    }
+
+    let before = transfer.slowest();
+
+    let Some(member) = transfer.members.remove(&addr) else {
+        return false;
+    };
+
+    let sender = transfer.sender;
+    let abandoned = transfer.members.is_empty();
+    let caught_up = transfer.slowest() > before;
+    let done = transfer.is_done();
+
+    if abandoned {
+        rooms.remove(&id);
+    }
+    drop(rooms);
+
+    let line = format!("FILE_CANCEL {} {}", id, member.nickname);
+    send_to_addr(state.clone(), sender, &line).await;
+
+    if abandoned {
+        send_to_addr(state, sender, &format!("FILE_CANCEL {}", id)).await;
+    } else if done {
+        finish(state, id).await;
+    } else if caught_up {
+        send_to_addr(state, sender, &format!("FILE_ACK {}", id)).await;
+    }
+
+    true
+}
+
+pub async fn handle_cancel(
+    socket: Arc<Mutex<Writer>>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    id: u64,
+) {
+    if leave(state, id, addr).await {
+        send_response(socket, "OK", true).await;
+    } else {
+        send_error_response(socket, "BAD_ID").await;
+    }
+}
+
+/// A departing connection leaves every room transfer it was part of.
+pub async fn drop_connection(addr: SocketAddr, state: Arc<ServerState>) {
+    let ids: Vec<u64> = state
+        .files
+        .rooms
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, t)| t.sender == addr || t.members.contains_key(&addr))
+        .map(|(id, _)| *id)
+        .collect();
+
+    for id in ids {
+        leave(state.clone(), id, addr).await;
+    }
+}
@@ -0,0 +1,488 @@
+use crate::federation;
+use crate::history::HistoryEntry;
+use crate::messages;
+use crate::server::{
+    get_connection_by_addr, get_connection_by_nickname, send_error_response, send_response,
+    send_to, Connection, ServerState,
+};
+use crate::transport::Writer;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+#[derive(Default)]
+pub struct Room {
+    pub members: HashSet<std::net::SocketAddr>,
+    // members allowed to kick, op and set the topic
+    pub operators: HashSet<std::net::SocketAddr>,
+    pub topic: Option<String>,
+}
+
+// rooms look like "#lobby"
+fn is_valid_room_name(name: &str) -> bool {
+    name.len() > 1 && name.starts_with('#') && !name.contains(char::is_whitespace)
+}
+
+// send a line to every member of the room, optionally skipping one of them
+async fn broadcast_to_room(
+    state: Arc<ServerState>,
+    room: &str,
+    except: Option<std::net::SocketAddr>,
+    line: &str,
+) {
+    let members: Vec<std::net::SocketAddr> = match state.rooms.lock().await.get(room) {
+        Some(room) => room.members.iter().copied().collect(),
+        None => return,
+    };
+
+    // collect the members first so no lock is held while writing
+    let connections: Vec<Connection> = members
+        .iter()
+        .filter(|addr| Some(**addr) != except)
+        .filter_map(|addr| state.connections.get(*addr))
+        .collect();
+
+    // a member that turns out to be gone is unregistered by send_to,
+    // everyone else still gets the line
+    for conn in connections {
+        let _ = send_to(state.clone(), &conn, line).await;
+    }
+}
+
+pub async fn handle_join(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+) {
+    if !is_valid_room_name(room) {
+        send_error_response(socket, "BAD_ROOM").await;
+        return;
+    }
+
+    let joined = state.connections.update(addr, |conn| {
+        (conn.rooms.insert(room.to_string()), conn.nickname.clone())
+    });
+
+    let nickname = match joined {
+        None => {
+            send_error_response(socket, "NOT_REG").await;
+            return;
+        }
+        Some((false, _)) => {
+            send_error_response(socket, "ALR_JOINED").await;
+            return;
+        }
+        Some((true, nickname)) => nickname,
+    };
+
+    // rooms are created on first join, and whoever creates one runs it
+    let (topic, replay) = {
+        let mut rooms = state.rooms.lock().await;
+        let r = rooms.entry(room.to_string()).or_default();
+
+        if r.members.is_empty() {
+            r.operators.insert(addr);
+        }
+        r.members.insert(addr);
+
+        (
+            r.topic.clone(),
+            state
+                .histories
+                .lines(room, state.config.history.replay_on_join),
+        )
+    };
+
+    send_response(socket.clone(), "OK", true).await;
+
+    if let Some(topic) = topic {
+        send_response(
+            socket.clone(),
+            format!("TOPIC {} {}", room, topic).as_str(),
+            true,
+        )
+        .await;
+    }
+
+    // catch the newcomer up on what was said before
+    for line in replay {
+        send_response(socket.clone(), &line, true).await;
+    }
+
+    broadcast_to_room(
+        state,
+        room,
+        Some(addr),
+        format!("JOIN {} {}", room, nickname).as_str(),
+    )
+    .await;
+}
+
+pub async fn handle_part(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+) {
+    let parted = state.connections.update(addr, |conn| {
+        (conn.rooms.remove(room), conn.nickname.clone())
+    });
+
+    let nickname = match parted {
+        None => {
+            send_error_response(socket, "NOT_REG").await;
+            return;
+        }
+        Some((false, _)) => {
+            send_error_response(socket, "NOT_IN_ROOM").await;
+            return;
+        }
+        Some((true, nickname)) => nickname,
+    };
+
+    leave_room(addr, &nickname, room, state).await;
+
+    send_response(socket, "OK", true).await;
+}
+
+pub async fn handle_room_message(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    payload: &str,
+) {
+    let Some(conn) = check_member(socket.clone(), addr, state.clone(), room, false).await else {
+        return;
+    };
+
+    state.histories.push(
+        room,
+        HistoryEntry {
+            at: SystemTime::now(),
+            nickname: conn.nickname.clone(),
+            payload: payload.to_string(),
+        },
+        &state.config.history,
+    );
+
+    send_response(socket, "OK", true).await;
+    state.metrics.room_message();
+
+    broadcast_to_room(
+        state.clone(),
+        room,
+        Some(addr),
+        format!("RMSG {} {} {}", room, conn.nickname, payload).as_str(),
+    )
+    .await;
+
+    federation::room_message(&state, room, &conn.nickname, payload).await;
+
+    if let Some(cluster) = &state.cluster {
+        cluster.room_message(room, &conn.nickname, payload).await;
+    }
+}
+
+/// A room message said on a linked server, for the members here. Nothing
+/// happens if nobody here is in the room.
+pub async fn deliver_linked(state: Arc<ServerState>, room: &str, nickname: &str, payload: &str) {
+    if !state.rooms.lock().await.contains_key(room) {
+        return;
+    }
+
+    state.histories.push(
+        room,
+        HistoryEntry {
+            at: SystemTime::now(),
+            nickname: nickname.to_string(),
+            payload: payload.to_string(),
+        },
+        &state.config.history,
+    );
+
+    broadcast_to_room(
+        state,
+        room,
+        None,
+        format!("RMSG {} {} {}", room, nickname, payload).as_str(),
+    )
+    .await;
+}
+
+/// `TYPING <room>` tells the other members `TYPING <room> <from>`, the
+/// way [`messages::handle_typing`] does for one peer: throttled the same,
+/// and never kept in the history or passed across links.
+pub async fn handle_typing(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+) {
+    let Some(conn) = check_member(socket.clone(), addr, state.clone(), room, false).await else {
+        return;
+    };
+
+    send_response(socket, "OK", true).await;
+
+    if messages::typing_due(&state, addr) == Some(true) {
+        let line = format!("TYPING {} {}", room, conn.nickname);
+        broadcast_to_room(state, room, Some(addr), &line).await;
+    }
+}
+
+pub async fn handle_list_rooms(socket: Arc<Mutex<Writer>>, state: Arc<ServerState>) {
+    let mut names: Vec<String> = state.rooms.lock().await.keys().cloned().collect();
+    names.sort();
+
+    let response = if names.is_empty() {
+        "ROOMS".to_string()
+    } else {
+        format!("ROOMS {}", names.join(" "))
+    };
+
+    send_response(socket, &response, true).await;
+}
+
+// checks that the sender is registered, in the room and (optionally) an
+// operator there, answering with the matching error when it is not
+async fn check_member(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    needs_op: bool,
+) -> Option<Arc<Connection>> {
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return None;
+    };
+
+    if !conn.rooms.contains(room) {
+        send_error_response(socket, "NOT_IN_ROOM").await;
+        return None;
+    }
+
+    if needs_op {
+        let is_op = state
+            .rooms
+            .lock()
+            .await
+            .get(room)
+            .is_some_and(|r| r.operators.contains(&addr));
+
+        if !is_op {
+            send_error_response(socket, "NOT_OP").await;
+            return None;
+        }
+    }
+
+    Some(conn)
+}
+
+// finds a target nickname that is a member of the room
+async fn find_member(
+    socket: Arc<Mutex<Writer>>,
+    state: Arc<ServerState>,
+    room: &str,
+    nickname: &str,
+) -> Option<Arc<Connection>> {
+    match get_connection_by_nickname(nickname, state).await {
+        Some(target) if target.rooms.contains(room) => Some(target),
+        _ => {
+            send_error_response(socket, "NO_NICK").await;
+            None
+        }
+    }
+}
+
+/// `TOPIC <room>` shows the topic, `TOPIC <room> <text>` sets it (operators only).
+pub async fn handle_topic(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    text: &str,
+) {
+    let setting = !text.is_empty();
+
+    if check_member(socket.clone(), addr, state.clone(), room, setting)
+        .await
+        .is_none()
+    {
+        return;
+    }
+
+    if !setting {
+        let topic = state
+            .rooms
+            .lock()
+            .await
+            .get(room)
+            .and_then(|r| r.topic.clone())
+            .unwrap_or_default();
+
+        send_response(socket, format!("TOPIC {} {}", room, topic).trim_end(), true).await;
+        return;
+    }
+
+    if let Some(r) = state.rooms.lock().await.get_mut(room) {
+        r.topic = Some(text.to_string());
+    }
+
+    send_response(socket, "OK", true).await;
+
+    broadcast_to_room(
+        state,
+        room,
+        Some(addr),
+        format!("TOPIC {} {}", room, text).as_str(),
+    )
+    .await;
+}
+
+pub async fn handle_kick(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    nickname: &str,
+) {
+    let Some(conn) = check_member(socket.clone(), addr, state.clone(), room, true).await else {
+        return;
+    };
+
+    let Some(target) = find_member(socket.clone(), state.clone(), room, nickname).await else {
+        return;
+    };
+
+    // tell everyone, the target included, before it is gone from the room
+    broadcast_to_room(
+        state.clone(),
+        room,
+        None,
+        format!("KICK {} {} {}", room, target.nickname, conn.nickname).as_str(),
+    )
+    .await;
+
+    state
+        .connections
+        .update(target.addr, |c| c.rooms.remove(room));
+
+    {
+        let mut rooms = state.rooms.lock().await;
+
+        if let Some(r) = rooms.get_mut(room) {
+            r.members.remove(&target.addr);
+            r.operators.remove(&target.addr);
+
+            if r.members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+
+    send_response(socket, "OK", true).await;
+}
+
+pub async fn handle_op(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    room: &str,
+    nickname: &str,
+) {
+    if check_member(socket.clone(), addr, state.clone(), room, true)
+        .await
+        .is_none()
+    {
+        return;
+    }
+
+    let Some(target) = find_member(socket.clone(), state.clone(), room, nickname).await else {
+        return;
+    };
+
+    if let Some(r) = state.rooms.lock().await.get_mut(room) {
+        r.operators.insert(target.addr);
+    }
+
+    send_response(socket, "OK", true).await;
+
+    broadcast_to_room(
+        state,
+        room,
+        Some(addr),
+        format!("OP {} {}", room, target.nickname).as_str(),
+    )
+    .await;
+}
+
+// drop the member from the room registry and let the others know
+async fn leave_room(
+    addr: std::net::SocketAddr,
+    nickname: &str,
+    room: &str,
+    state: Arc<ServerState>,
+) {
+    {
+        let mut rooms = state.rooms.lock().await;
+
+        if let Some(r) = rooms.get_mut(room) {
+            r.members.remove(&addr);
+            r.operators.remove(&addr);
+
+            // nobody left, so the room goes away
+            if r.members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+
+    broadcast_to_room(
+        state,
+        room,
+        None,
+        format!("PART {} {}", room, nickname).as_str(),
+    )
+    .await;
+}
+
+/// Parts every room of a connection that is going away.
+pub async fn part_all(conn: &Connection, state: Arc<ServerState>) {
+    for room in &conn.rooms {
+        leave_room(conn.addr, &conn.nickname, room, state.clone()).await;
+    }
+}
+
+/// Puts a resumed connection back in the rooms it was in, announced like a
+/// JOIN. Operator status is only kept if the room was left empty meanwhile.
+pub async fn rejoin_all(conn: &Connection, state: Arc<ServerState>) {
+    for room in &conn.rooms {
+        let topic = {
+            let mut rooms = state.rooms.lock().await;
+            let r = rooms.entry(room.to_string()).or_default();
+
+            if r.members.is_empty() {
+                r.operators.insert(conn.addr);
+            }
+            r.members.insert(conn.addr);
+
+            r.topic.clone()
+        };
+
+        if let Some(topic) = topic {
+            let _ = send_to(state.clone(), conn, &format!("TOPIC {} {}", room, topic)).await;
+        }
+
+        broadcast_to_room(
+            state.clone(),
+            room,
+            Some(conn.addr),
+            format!("JOIN {} {}", room, conn.nickname).as_str(),
+        )
+        .await;
+    }
+}
@@ -1,211 +1,2169 @@
-use colored::Colorize;
-use std::collections::HashMap;
+use crate::acks::{self, Acks};
+use crate::admin;
+use crate::audit::{Audit, Event};
+use crate::auth::{self, Auth};
+use crate::bandwidth::Bandwidth;
+use crate::caps;
+use crate::cluster::{self, Cluster, RedisStore, Store};
+use crate::config::{Config, MotdConfig};
+use crate::dht::{self, Dht};
+use crate::error::ServerError;
+use crate::federation::{self, Federation};
+use crate::files::{self, Transfers};
+use crate::history::{self, Histories};
+use crate::hooks::{Hooks, NoHooks, Verdict};
+use crate::http;
+use crate::identity::{self, Challenges, Proof};
+use crate::keys;
+use crate::logging;
+use crate::mdns::Mdns;
+use crate::messages;
+use crate::metrics::{self, Metrics};
+use crate::motd;
+use crate::offline::OfflineStore;
+use crate::parser;
+use crate::pex::{self, PeerExchange};
+use crate::plugins::{Commands, Context};
+use crate::presence::{self, Status};
+use crate::punch;
+use crate::rdns::Resolver;
+use crate::registry::Registry;
+use crate::relay::{self, RelayLimits, Relays};
+use crate::reload::{self, ReloadError};
+use crate::rooms::{self, Room};
+use crate::sessions::{self, Sessions};
+use crate::signaling;
+use crate::transport::{self, Noise, PeerStream, Transport, Writer};
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::JoinSet;
+use tracing::Instrument;
 
 #[derive(Clone)]
 pub struct Connection {
-    socket: Arc<Mutex<TcpStream>>,
-    addr: std::net::SocketAddr,
-    nickname: String,
+    pub socket: Arc<Mutex<Writer>>,
+    pub addr: std::net::SocketAddr,
+    pub nickname: String,
+    // rooms this connection has joined
+    pub rooms: HashSet<String>,
+    pub status: Status,
+    pub status_text: Option<String>,
+    // nicknames this connection wants presence updates for
+    pub watching: HashSet<String>,
+    // base64 public key published with PUBKEY_SET
+    pub public_key: Option<String>,
+    // fingerprint of the identity key proven at REG
+    pub fingerprint: Option<String>,
+    pub registered_at: SystemTime,
+    // last time a command came in, for idle times
+    pub last_activity: Instant,
+    // lines other connections are waiting to write here, see send_to
+    pub queued: Arc<AtomicUsize>,
+    // when a TYPING from here was last passed on, to throttle them
+    pub last_typing: Option<Instant>,
+}
+
+// everything the connection tasks share
+pub struct ServerState {
+    // as the server started; what a reload changes is read from `current`
+    pub config: Config,
+    pub connections: Registry,
+    pub rooms: Mutex<HashMap<String, Room>>,
+    pub histories: Histories,
+    pub offline: OfflineStore,
+    pub acks: Acks,
+    pub files: Transfers,
+    pub relays: Relays,
+    pub relay_limits: RelayLimits,
+    pub bandwidth: Bandwidth,
+    pub pex: PeerExchange,
+    pub challenges: Challenges,
+    pub sessions: Sessions,
+    // only there when [dht] is enabled
+    pub dht: Option<Arc<Dht>>,
+    // only there when [noise] is enabled
+    pub noise: Option<Noise>,
+    // only there when [cluster] is enabled
+    pub cluster: Option<Arc<Cluster>>,
+    // only there when [rdns] is enabled
+    pub rdns: Option<Arc<Resolver>>,
+    pub hooks: Arc<dyn Hooks>,
+    pub commands: Commands,
+    pub metrics: Arc<Metrics>,
+    pub federation: Federation,
+    pub audit: Audit,
+    // wakes a connection's read loop to close it, see `kick`
+    kicks: Mutex<HashMap<std::net::SocketAddr, Arc<Notify>>>,
+    auth: Auth,
+    // the config as last loaded, see `reload`
+    current: std::sync::Mutex<Config>,
+}
+
+impl ServerState {
+    pub fn new(config: Config) -> io::Result<Self> {
+        Ok(ServerState {
+            connections: Registry::default(),
+            rooms: Mutex::new(HashMap::new()),
+            histories: Histories::default(),
+            offline: OfflineStore::load(config.offline.clone())?,
+            acks: Acks::default(),
+            files: Transfers::default(),
+            relays: Relays::default(),
+            relay_limits: RelayLimits::new(&config.relay),
+            bandwidth: Bandwidth::new(&config.bandwidth),
+            pex: PeerExchange::default(),
+            challenges: Challenges::default(),
+            sessions: Sessions::default(),
+            dht: None,
+            noise: Noise::load(&config.noise)?,
+            cluster: None,
+            rdns: config
+                .rdns
+                .enabled
+                .then(|| Arc::new(Resolver::new(&config.rdns))),
+            hooks: Arc::new(NoHooks),
+            commands: Commands::default(),
+            metrics: Arc::new(Metrics::default()),
+            federation: Federation::new(
+                config
+                    .federation
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| BIND_ADDR.to_string()),
+                &config.outbound,
+            ),
+            audit: Audit::open(&config.audit)?,
+            kicks: Mutex::new(HashMap::new()),
+            auth: Auth::new(config.auth.clone()),
+            current: std::sync::Mutex::new(config.clone()),
+            config,
+        })
+    }
+
+    fn current(&self) -> std::sync::MutexGuard<'_, Config> {
+        self.current.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// `[motd]` as last loaded.
+    pub fn motd(&self) -> MotdConfig {
+        self.current().motd.clone()
+    }
+
+    /// Applies the settings of `config` that can change while running and
+    /// returns the ones that did, see [`reload`](crate::reload). Refused
+    /// without changing anything if `config` differs in any other way.
+    pub fn reload(&self, config: Config) -> Result<Vec<&'static str>, ReloadError> {
+        reload::validate(&config)?;
+
+        let mut current = self.current();
+        let restart = reload::needs_restart(&current, &config);
+
+        if !restart.is_empty() {
+            return Err(ReloadError::NeedsRestart(restart));
+        }
+
+        let changes = reload::changes(&current, &config);
+
+        // the two that can fail first, both were validated above
+        if let Some(noise) = &self.noise {
+            noise
+                .set_allowed_keys(&config.noise.allowed_keys)
+                .map_err(|e| ReloadError::Invalid(vec![format!("noise: {}", e)]))?;
+        }
+
+        if current.log.level != config.log.level {
+            logging::set_level(&config.log).map_err(|e| ReloadError::Invalid(vec![e]))?;
+        }
+
+        if current.auth != config.auth {
+            self.auth.set_config(config.auth.clone());
+        }
+
+        // left alone unless the file changed them, PUT /limits may have
+        if current.relay.max_bytes != config.relay.max_bytes {
+            let max_bytes = &self.relay_limits.max_bytes;
+            max_bytes.store(config.relay.max_bytes, Ordering::Relaxed);
+        }
+
+        if current.relay.bytes_per_second != config.relay.bytes_per_second {
+            let bytes_per_second = &self.relay_limits.bytes_per_second;
+            bytes_per_second.store(config.relay.bytes_per_second, Ordering::Relaxed);
+        }
+
+        *current = config;
+        Ok(changes)
+    }
+
+    pub async fn is_banned(&self, ip: std::net::IpAddr) -> bool {
+        self.auth.is_banned(ip).await
+    }
+
+    /// Whether `nickname` has its own `[auth.users]` credential.
+    pub fn has_own_credential(&self, nickname: &str) -> bool {
+        self.auth.has_user(nickname)
+    }
+
+    /// Checks `token` as the `[auth]` credential for `nickname`, counting a
+    /// wrong one towards banning `ip`. The error code to answer otherwise.
+    pub async fn authenticate(
+        &self,
+        ip: std::net::IpAddr,
+        nickname: &str,
+        token: Option<&str>,
+    ) -> Result<(), &'static str> {
+        if self.auth.is_banned(ip).await {
+            return Err("BANNED");
+        }
+
+        if !self.auth.verify(nickname, token) {
+            if self.auth.record_failure(ip).await {
+                tracing::info!(%ip, "Banned");
+                self.audit.record(Event::Ban { ip });
+            }
+            return Err("AUTH");
+        }
+
+        self.auth.clear_failures(ip).await;
+        Ok(())
+    }
+
+    /// Closes the connection registered as `nickname`, which then leaves
+    /// like any dropped connection but can't be resumed. False if nobody
+    /// has the nickname.
+    pub async fn kick(&self, nickname: &str) -> bool {
+        let Some(addr) = self.connections.addr_of(nickname) else {
+            return false;
+        };
+
+        self.sessions.forget(addr).await;
+
+        match self.kicks.lock().await.get(&addr) {
+            Some(kick) => {
+                kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like [`kick`](ServerState::kick), but `nickname` is free by the time
+    /// this returns. The connection is told `ERR <reason>` if it still
+    /// reads.
+    pub async fn evict(self: &Arc<Self>, nickname: &str, reason: &str) -> bool {
+        let Some(conn) = self
+            .connections
+            .addr_of(nickname)
+            .and_then(|addr| self.connections.get(addr))
+        else {
+            return false;
+        };
+
+        self.sessions.forget(conn.addr).await;
+
+        if let Some(kick) = self.kicks.lock().await.remove(&conn.addr) {
+            kick.notify_one();
+        }
+        handle_disconnect(conn.addr, self.clone()).await;
+
+        // a stale connection is likely not reading, this is only a courtesy
+        let line = format!("ERR {}\n", reason);
+        let write = send_bytes(conn.socket, line.as_bytes());
+        let _ = tokio::time::timeout(Duration::from_secs(1), write).await;
+
+        true
+    }
 }
 
+pub const BIND_ADDR: &str = "127.0.0.1:4001";
+
+// how long shutdown waits for registered connections to be let go of
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 const CONNECTION_BUFFER_SIZE: usize = 1024;
+// longest command line accepted, newline included
+const MAX_LINE_LENGTH: usize = CONNECTION_BUFFER_SIZE;
 
-async fn get_connection_by_addr(
+// until a socket registers it gets a small buffer and short lines,
+// enough for HELLO and REG but not much else
+const UNREGISTERED_BUFFER_SIZE: usize = 128;
+const UNREGISTERED_LINE_LENGTH: usize = 256;
+
+pub async fn get_connection_by_addr(
     addr: std::net::SocketAddr,
-    connections: Arc<Mutex<HashMap<std::net::SocketAddr, Connection>>>,
+    state: Arc<ServerState>,
 ) -> Option<Arc<Connection>> {
-    // get lock
-    connections
-        .lock()
-        .await
-        // from key
-        .get(&addr)
-        // clone connection into new Arc
-        .map(|c| Arc::new(c.clone()))
+    state.connections.get(addr).map(Arc::new)
+}
+
+pub async fn get_connection_by_nickname(
+    nickname: &str,
+    state: Arc<ServerState>,
+) -> Option<Arc<Connection>> {
+    state.connections.by_nickname(nickname).map(Arc::new)
 }
 
-async fn send_error_response(socket: Arc<Mutex<TcpStream>>, error: &str) {
+/// A write failed because the peer on the other end is gone.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PeerGone;
+
+pub async fn send_error_response(socket: Arc<Mutex<Writer>>, error: &str) {
+    metrics::count_error(error);
     send_response(socket, format!("ERR {}", error).as_str(), true).await;
 }
 
-async fn send_response(socket: Arc<Mutex<TcpStream>>, response: &str, add_new_line: bool) {
-    let mut locked_socket = socket.lock().await;
+/// Answers a [`Verdict::Deny`] with `ERR DENIED <reason>`. The reason comes
+/// from a hook, so it is counted as `DENIED` rather than as a code of its
+/// own.
+pub async fn send_denied(socket: Arc<Mutex<Writer>>, reason: &str) {
+    metrics::count_error("DENIED");
+    send_response(socket, format!("ERR DENIED {}", reason).as_str(), true).await;
+}
 
+pub async fn send_response(socket: Arc<Mutex<Writer>>, response: &str, add_new_line: bool) {
     let response = if add_new_line {
         format!("{}\n", response)
     } else {
         response.to_string()
     };
 
-    locked_socket.write_all(response.as_bytes()).await.unwrap();
+    // a reply that can't be written means the asking connection is
+    // closing, which its own read loop is about to notice
+    let _ = send_bytes(socket, response.as_bytes()).await;
+}
+
+pub async fn send_bytes(socket: Arc<Mutex<Writer>>, data: &[u8]) -> Result<(), PeerGone> {
+    let mut locked_socket = socket.lock().await;
+
+    locked_socket
+        .write_lines(data)
+        .await
+        .map_err(|_| PeerGone)?;
+
+    locked_socket.flush().await.map_err(|_| PeerGone)
+}
+
+// notices that are fine to lose when the connection can't keep up
+fn is_droppable(line: &str) -> bool {
+    let command = line.split(' ').next().unwrap_or_default();
+
+    matches!(command, "PRESENCE" | "JOIN" | "PART" | "TYPING")
+}
+
+// cuts off a connection that stopped reading, telling it why if it ever
+// reads again
+fn disconnect_slow(conn: &Connection, state: Arc<ServerState>) {
+    let (socket, addr, nickname) = (conn.socket.clone(), conn.addr, conn.nickname.clone());
+
+    tokio::spawn(async move {
+        // every sender that gives up ends up here, only the first one counts
+        let Some(kick) = state.kicks.lock().await.remove(&addr) else {
+            return;
+        };
+
+        state.metrics.slow_consumer();
+        tracing::info!(%nickname, "Disconnected, too slow");
+
+        kick.notify_one();
+        drop_peer(addr, state.clone());
+
+        // the writer is likely stuck, this is only a courtesy
+        let write = send_bytes(socket, b"ERR SLOW\n");
+        let _ = tokio::time::timeout(Duration::from_secs(1), write).await;
+    });
+}
+
+/// Writes a line to another registered connection. If the write fails the
+/// peer is unregistered, and the caller gets `PeerGone` to reroute or drop
+/// what it was sending.
+///
+/// Writes to one connection wait their turn, at most `[outbound]
+/// max_queued` of them: past that a connection that stopped reading is
+/// disconnected rather than holding up everyone who writes to it.
+pub async fn send_to(
+    state: Arc<ServerState>,
+    conn: &Connection,
+    line: &str,
+) -> Result<(), PeerGone> {
+    let data = format!("{}\n", line);
+    let write = send_bytes(conn.socket.clone(), data.as_bytes());
+
+    send_queued(state, conn, is_droppable(line), write).await
+}
+
+/// Like [`send_to`], for a header line followed by a payload written as it
+/// is, such as a signaling frame.
+pub async fn send_framed_to(
+    state: Arc<ServerState>,
+    conn: &Connection,
+    header: &str,
+    payload: &[u8],
+) -> Result<(), PeerGone> {
+    let write = async {
+        let mut writer = conn.socket.lock().await;
+
+        // the header as a line, json or not, and the payload as it came
+        writer
+            .write_lines(format!("{}\n", header).as_bytes())
+            .await
+            .map_err(|_| PeerGone)?;
+        writer.write_all(payload).await.map_err(|_| PeerGone)?;
+        writer.flush().await.map_err(|_| PeerGone)
+    };
+
+    send_queued(state, conn, false, write).await
+}
+
+// runs `write` as one of the writes queued for `conn`, see `send_to`
+async fn send_queued(
+    state: Arc<ServerState>,
+    conn: &Connection,
+    droppable: bool,
+    write: impl std::future::Future<Output = Result<(), PeerGone>>,
+) -> Result<(), PeerGone> {
+    let limits = &state.config.outbound;
+    let queued = conn.queued.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if limits.max_queued > 0 && queued > limits.max_queued / 2 && droppable {
+        conn.queued.fetch_sub(1, Ordering::Relaxed);
+        state.metrics.dropped();
+        return Ok(());
+    }
+
+    if limits.max_queued > 0 && queued > limits.max_queued {
+        conn.queued.fetch_sub(1, Ordering::Relaxed);
+        disconnect_slow(conn, state);
+        return Err(PeerGone);
+    }
 
-    locked_socket.flush().await.unwrap();
+    state.metrics.queue(1);
+
+    let result = match limits.write_timeout_seconds {
+        0 => Some(write.await),
+        seconds => tokio::time::timeout(Duration::from_secs(seconds), write)
+            .await
+            .ok(),
+    };
+
+    conn.queued.fetch_sub(1, Ordering::Relaxed);
+    state.metrics.queue(-1);
+
+    match result {
+        Some(Ok(())) => Ok(()),
+        Some(Err(PeerGone)) => {
+            drop_peer(conn.addr, state);
+            Err(PeerGone)
+        }
+        None => {
+            disconnect_slow(conn, state);
+            Err(PeerGone)
+        }
+    }
+}
+
+/// Unregisters a connection whose socket has failed. Safe to call any number
+/// of times, only the first call finds it registered.
+pub fn drop_peer(addr: std::net::SocketAddr, state: Arc<ServerState>) {
+    // the cleanup writes to other peers, which can fail in turn, so it
+    // runs as its own task instead of inside whatever write noticed it
+    tokio::spawn(handle_disconnect(addr, state));
 }
 
 async fn handle_socket_registration(
-    socket: Arc<Mutex<TcpStream>>,
+    socket: Arc<Mutex<Writer>>,
     addr: std::net::SocketAddr,
-    connections: Arc<Mutex<HashMap<std::net::SocketAddr, Connection>>>,
+    state: Arc<ServerState>,
     nickname: String,
+    token: Option<String>,
+    proof: Option<Proof>,
 ) {
+    // nicknames go into other lines unquoted, so a quoted one with
+    // spaces can't be had
+    if nickname.is_empty() || nickname.contains(char::is_whitespace) {
+        send_error_response(socket.clone(), "BAD_NICK").await;
+        return;
+    }
+
     // check if socket has already registered
-    {
-        if connections.lock().await.contains_key(&addr) {
-            send_error_response(socket.clone(), "ALR_REG").await;
+    if state.connections.contains(addr) {
+        send_error_response(socket.clone(), "ALR_REG").await;
+        return;
+    }
+
+    // the challenge is spent whatever happens next
+    let challenge = state.challenges.take(addr).await;
+
+    // check credentials before revealing anything about taken nicknames
+    if state.auth.enabled() {
+        let authenticated = state
+            .authenticate(addr.ip(), &nickname, token.as_deref())
+            .await;
+
+        if let Err(error) = authenticated {
+            send_error_response(socket.clone(), error).await;
             return;
         }
     }
 
+    let fingerprint = match (proof, challenge) {
+        (None, _) if state.config.identity.required => {
+            send_error_response(socket.clone(), "KEY_REQUIRED").await;
+            return;
+        }
+        (None, _) => None,
+        (Some(_), None) => {
+            send_error_response(socket.clone(), "NO_CHALLENGE").await;
+            return;
+        }
+        (Some(proof), Some(nonce)) => match identity::verify(&nickname, &nonce, &proof) {
+            Ok(fingerprint) => Some(fingerprint),
+            Err(error) => {
+                send_error_response(socket.clone(), error).await;
+                return;
+            }
+        },
+    };
+
+    // a holder that went silent long enough gives way, see [nicknames]; it
+    // is let go only once nothing below can refuse the REG
+    let idle_release = Duration::from_secs(state.config.nicknames.idle_release_seconds);
+    let idle = !idle_release.is_zero()
+        && state
+            .connections
+            .addr_of(&nickname)
+            .and_then(|addr| state.connections.get(addr))
+            .is_some_and(|c| c.last_activity.elapsed() >= idle_release);
+
     // check if nickname is already taken
+    if (state.connections.has_nickname(&nickname) && !idle)
+        || state.sessions.is_reserved(&nickname).await
+        || state.federation.is_remote(&nickname).await
     {
-        if connections
-            .lock()
-            .await
-            .values()
-            .any(|c| c.nickname == nickname)
-        {
-            send_error_response(socket.clone(), "TKN").await;
-            return;
+        send_error_response(socket.clone(), "TKN").await;
+        return;
+    }
+
+    if let Verdict::Deny(reason) = state.hooks.on_register(addr, &nickname).await {
+        send_denied(socket.clone(), &reason).await;
+        return;
+    }
+
+    // the node that claims it first has it; a REG here racing this one
+    // claims it too, and is left to the check below
+    if let Some(cluster) = &state.cluster {
+        match cluster.claim(&nickname).await {
+            Ok(true) => {}
+            Ok(false) => {
+                send_error_response(socket.clone(), "TKN").await;
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(component = "cluster", error = %e, "Failed to claim nickname");
+                send_error_response(socket.clone(), "UNAVAILABLE").await;
+                return;
+            }
         }
     }
 
-    // create new kv pair
-    connections.lock().await.insert(
-        addr,
-        Connection {
-            socket: socket.clone(),
+    if idle && state.evict(&nickname, "GHOSTED").await {
+        tracing::info!(%nickname, "Released, idle");
+        state.audit.record(Event::Ghost {
+            nickname: &nickname,
             addr,
-            nickname: nickname.clone(),
-        },
-    );
+        });
+    }
 
-    send_response(socket.clone(), "OK", true).await;
+    // checked again as it is taken, a REG for the same nickname may have
+    // finished while the hooks ran
+    let inserted = state.connections.insert(Connection {
+        socket: socket.clone(),
+        addr,
+        nickname: nickname.clone(),
+        rooms: HashSet::new(),
+        status: Status::Online,
+        status_text: None,
+        watching: HashSet::new(),
+        public_key: None,
+        fingerprint,
+        registered_at: SystemTime::now(),
+        last_activity: Instant::now(),
+        queued: Arc::default(),
+        last_typing: None,
+    });
 
-    println!(
-        "{} {} {}",
-        ">".bright_green(),
-        nickname.bright_green().bold(),
-        "Joined".bright_green()
-    );
+    if !inserted {
+        send_error_response(socket.clone(), "TKN").await;
+        return;
+    }
+
+    // the token is what RESUME takes after a dropped connection
+    if state.config.resume.enabled {
+        let token = state.sessions.issue(addr).await;
+        send_response(socket.clone(), format!("OK {}", token).as_str(), true).await;
+    } else {
+        send_response(socket.clone(), "OK", true).await;
+    }
+    state.metrics.registered(&nickname);
+    motd::send(socket.clone(), &state.motd()).await;
+
+    state.offline.remember(&nickname).await;
+    messages::deliver_queued(socket.clone(), state.clone(), &nickname).await;
+
+    presence::notify_watchers(state.clone(), &nickname, Status::Online.as_str(), None).await;
+    federation::announce(&state, &nickname).await;
+
+    if let Some(cluster) = &state.cluster {
+        cluster
+            .set_presence(&nickname, Status::Online.as_str())
+            .await;
+    }
+
+    if let Some(dht) = state.dht.clone() {
+        let nickname = nickname.clone();
+
+        tokio::spawn(async move { dht.announce(&nickname, addr).await });
+    }
+
+    tracing::Span::current().record("nickname", nickname.as_str());
+    tracing::info!(nickname = %nickname, "Joined");
+    state.audit.record(Event::Register {
+        nickname: &nickname,
+        addr,
+    });
+}
+
+// `HELLO [knock]`, where the knock token is only checked when configured
+fn is_valid_hello(line: &[u8], knock: Option<&str>) -> bool {
+    let Ok(line) = std::str::from_utf8(line) else {
+        return false;
+    };
+
+    let mut words = line.split_whitespace();
+
+    if words.next() != Some("HELLO") {
+        return false;
+    }
+
+    match knock {
+        Some(knock) => words.next().is_some_and(|k| auth::tokens_match(knock, k)),
+        None => true,
+    }
+}
+
+// the only commands answered before REG, besides RELAY data channels;
+// PEERS_PUSH is for other servers and STATS for health checks, neither
+// of which register, CAP is best agreed on before anything else and GHOST
+// frees the nickname about to be registered
+fn allowed_before_registration(line: &[u8]) -> bool {
+    let command = line.split(|b| b.is_ascii_whitespace()).next();
+
+    matches!(
+        command,
+        Some(b"HELLO")
+            | Some(b"CHALLENGE")
+            | Some(b"REG")
+            | Some(b"RESUME")
+            | Some(b"GHOST")
+            | Some(b"PEERS_PUSH")
+            | Some(b"STATS")
+            | Some(b"CAP")
+    )
 }
 
 async fn handle_incoming_buffer(
-    socket: Arc<Mutex<TcpStream>>,
+    socket: Arc<Mutex<Writer>>,
     addr: std::net::SocketAddr,
-    connections: Arc<Mutex<HashMap<std::net::SocketAddr, Connection>>>,
-    data: &Vec<u8>,
+    state: Arc<ServerState>,
+    data: &[u8],
 ) {
-    // convert vector into string
-    let data = String::from_utf8(data.to_vec()).unwrap();
+    let started = Instant::now();
 
-    // split the string into words
-    let mut data_splitted = data.split_whitespace();
+    // nothing a client sends may take the connection down, a bad line
+    // just gets its error code back
+    let result = dispatch(socket.clone(), addr, state.clone(), data).await;
 
-    if data_splitted.clone().count() < 1 {
-        send_error_response(socket.clone(), "NIL_CMD").await;
-        return;
+    // made-up command names would make for endless histograms
+    let known = !matches!(
+        result,
+        Err(ServerError::BadEncoding | ServerError::NoCommand | ServerError::UnknownCommand)
+    );
+
+    if known {
+        let command = data.split(|b| b.is_ascii_whitespace()).next();
+
+        if let Some(command) = command.and_then(|c| std::str::from_utf8(c).ok()) {
+            state.metrics.command(command, started.elapsed());
+        }
+    }
+
+    if let Err(error) = result {
+        send_error_response(socket, error.code()).await;
     }
+}
+
+async fn dispatch(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    data: &[u8],
+) -> Result<(), ServerError> {
+    let data = std::str::from_utf8(data).map_err(|_| ServerError::BadEncoding)?;
+
+    // checked against what the command takes, so every argument the
+    // grammar requires is there
+    let line = parser::parse(data)?;
+    let command = line.name;
+    let arg = |i| line.arg(i).unwrap_or_default();
 
-    // get the first word
-    let command = data_splitted.next().unwrap();
+    tracing::debug!(command, "Received");
 
     match command {
+        "HELLO" => {
+            send_response(
+                socket.clone(),
+                format!("HELLO p2p-rs {}", env!("CARGO_PKG_VERSION")).as_str(),
+                true,
+            )
+            .await;
+        }
+
+        // the address as seen from here, so clients behind NAT learn
+        // their public ip and port
+        "ADDR" => {
+            send_response(socket.clone(), format!("ADDR {}", addr).as_str(), true).await;
+        }
+
         "REG" => {
-            let nickname = data_splitted.clone().next().expect("NIL_NICK");
+            let nickname = arg(0);
+
+            // `REG <nick> [token] [<pubkey> <signature>]`, told apart by count
+            let (token, proof) = match &line.args[1..] {
+                [] => (None, None),
+                [token] => (Some(token), None),
+                [public_key, signature] => (None, Some((public_key, signature))),
+                [token, public_key, signature] => (Some(token), Some((public_key, signature))),
+                _ => return Err(ServerError::BadArgument),
+            };
 
             handle_socket_registration(
                 socket.clone(),
                 addr,
-                connections.clone(),
+                state.clone(),
                 nickname.to_string(),
+                token.map(|t| t.to_string()),
+                proof.map(|(public_key, signature)| Proof {
+                    public_key: public_key.to_string(),
+                    signature: signature.to_string(),
+                }),
             )
             .await;
         }
 
-        // all other commands
-        _ => {
-            send_error_response(socket.clone(), "UNK_CMD").await;
+        "RESUME" => sessions::handle_resume(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "GHOST" => {
+            sessions::handle_ghost(socket.clone(), addr, state.clone(), arg(0), arg(1)).await;
         }
-    }
-}
 
-async fn process_socket(
-    socket: Arc<Mutex<TcpStream>>,
-    addr: std::net::SocketAddr,
-    connections: Arc<Mutex<HashMap<std::net::SocketAddr, Connection>>>,
-) {
-    // create data buffer
-    let mut buffer = vec![0; CONNECTION_BUFFER_SIZE];
-
-    loop {
-        // try to read from socket
-        let data_size = {
-            // lock the socket
-            let mut locked_socket = socket.lock().await;
-            // read into buffer
-            locked_socket.read(&mut buffer).await
-        };
+        "CHALLENGE" => {
+            identity::handle_challenge(socket.clone(), addr, state.clone()).await;
+        }
 
-        match data_size {
-            // close connection
-            Ok(0) => {
-                // try to get connection
-                let conn = get_connection_by_addr(addr, connections.clone()).await;
-                match conn {
-                    None => {
-                        // client did no register
-                        // so no need to log anything
-                    }
-                    Some(conn) => {
-                        // client had registered
-                        println!(
-                            "{} {} {}",
-                            ">".bright_red(),
-                            conn.nickname.bright_red().bold(),
-                            "Left".bright_red()
-                        );
-                    }
-                }
-                break;
-            }
-            Ok(n) => {
-                // extract out the n bytes from data buffer
-                // and convert it into vector
-                let data_buffer = buffer[..n].to_vec();
+        "JOIN" => rooms::handle_join(socket.clone(), addr, state.clone(), arg(0)).await,
 
-                handle_incoming_buffer(socket.clone(), addr, connections.clone(), &data_buffer)
-                    .await
-            }
-            // failed to read
-            Err(_e) => {
-                break;
-            }
+        "PART" => rooms::handle_part(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "MSG" => {
+            messages::handle_message(socket.clone(), addr, state.clone(), arg(0), line.text, None)
+                .await
         }
-    }
-}
 
-pub async fn start_server() -> io::Result<()> {
-    let addr = "127.0.0.1:4001";
-    let listener = TcpListener::bind(addr).await?;
+        "MSGID" => {
+            messages::handle_message(
+                socket.clone(),
+                addr,
+                state.clone(),
+                arg(1),
+                line.text,
+                Some(arg(0)),
+            )
+            .await
+        }
 
-    let connections = Arc::new(Mutex::new(HashMap::new()));
+        "ACK" => acks::handle_ack(socket.clone(), addr, state.clone(), arg(0)).await,
 
-    // for every incoming connection
-    loop {
-        // accept the connection
-        let (socket, addr) = listener.accept().await?;
+        "READ" => acks::handle_read(socket.clone(), addr, state.clone(), arg(0)).await,
 
-        let socket_arc = Arc::new(Mutex::new(socket));
-        let connections_clone = connections.clone();
+        "FILE_OFFER" => {
+            files::handle_offer(socket.clone(), addr, state.clone(), arg(0), arg(1), arg(2)).await
+        }
 
-        // spawn new thread
-        tokio::spawn(async move {
-            process_socket(socket_arc, addr, connections_clone).await;
-        });
+        "FILE_ACCEPT" | "FILE_REJECT" => {
+            files::handle_answer(
+                socket.clone(),
+                addr,
+                state.clone(),
+                arg(0),
+                command == "FILE_ACCEPT",
+            )
+            .await
+        }
+
+        "FILE_CHUNK" => {
+            files::handle_chunk(socket.clone(), addr, state.clone(), arg(0), arg(1)).await
+        }
+
+        "FILE_ACK" => files::handle_file_ack(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "FILE_RESUME" => {
+            files::handle_resume(socket.clone(), addr, state.clone(), arg(0), arg(1), arg(2)).await
+        }
+
+        "FILE_CANCEL" => files::handle_cancel(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "CONNECT" => punch::handle_connect(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "RELAY_OPEN" => relay::handle_open(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "RMSG" => {
+            rooms::handle_room_message(socket.clone(), addr, state.clone(), arg(0), line.text).await
+        }
+
+        "TOPIC" => {
+            rooms::handle_topic(socket.clone(), addr, state.clone(), arg(0), line.text).await
+        }
+
+        "HISTORY" => {
+            history::handle_history(socket.clone(), addr, state.clone(), arg(0), line.arg(1)).await
+        }
+
+        "KICK" => rooms::handle_kick(socket.clone(), addr, state.clone(), arg(0), arg(1)).await,
+
+        "OP" => rooms::handle_op(socket.clone(), addr, state.clone(), arg(0), arg(1)).await,
+
+        "ROOMS" => {
+            rooms::handle_list_rooms(socket.clone(), state.clone()).await;
+        }
+
+        "TYPING" if arg(0).starts_with('#') => {
+            rooms::handle_typing(socket.clone(), addr, state.clone(), arg(0)).await
+        }
+
+        "TYPING" => messages::handle_typing(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "STATUS" => {
+            presence::handle_status(socket.clone(), addr, state.clone(), arg(0), line.text).await
+        }
+
+        "WHOIS" => presence::handle_whois(socket.clone(), state.clone(), arg(0)).await,
+
+        "LIST" => {
+            presence::handle_list(socket.clone(), state.clone()).await;
+        }
+
+        "STATS" => {
+            metrics::handle_stats(socket.clone(), state.clone()).await;
+        }
+
+        "CAP" => {
+            let args: Vec<&str> = line.args.iter().map(|a| a.as_ref()).collect();
+            caps::handle_cap(socket.clone(), state.clone(), &args).await;
+        }
+
+        "ADVERTISE" => {
+            pex::handle_advertise(socket.clone(), addr, state.clone(), arg(0), line.arg(1)).await
+        }
+
+        "PEERS" => {
+            pex::handle_peers(socket.clone(), state.clone()).await;
+        }
+
+        "PEERS_PUSH" => {
+            let args: Vec<&str> = line.args.iter().map(|a| a.as_ref()).collect();
+            pex::handle_push(socket.clone(), addr, state.clone(), &args).await;
+        }
+
+        "PUBKEY_SET" => keys::handle_set(socket.clone(), addr, state.clone(), arg(0)).await,
+
+        "PUBKEY_GET" => keys::handle_get(socket.clone(), state.clone(), arg(0)).await,
+
+        "LOOKUP" => dht::handle_lookup(socket.clone(), state.clone(), arg(0)).await,
+
+        "WATCH" | "UNWATCH" => {
+            presence::handle_watch(
+                socket.clone(),
+                addr,
+                state.clone(),
+                arg(0),
+                command == "WATCH",
+            )
+            .await
+        }
+
+        // all other commands, unless the embedder added them
+        _ => {
+            let connection = if state.commands.contains(command) {
+                get_connection_by_addr(addr, state.clone()).await
+            } else {
+                None
+            };
+
+            let Some(connection) = connection else {
+                return Err(ServerError::UnknownCommand);
+            };
+
+            let ctx = Context {
+                socket: socket.clone(),
+                addr,
+                connection,
+                state: state.clone(),
+                args: line.args.iter().map(|a| a.to_string()).collect(),
+            };
+
+            state.commands.dispatch(command, ctx).await;
+        }
+    }
+
+    Ok(())
+}
+
+// forget about the connection and tell its rooms it is gone
+async fn handle_disconnect(addr: std::net::SocketAddr, state: Arc<ServerState>) {
+    // an unused challenge goes with the connection, and so does its share
+    // of the bandwidth caps
+    state.challenges.take(addr).await;
+    state.bandwidth.forget(addr);
+
+    // try to remove connection
+    let conn = state.connections.remove(addr);
+
+    match conn {
+        None => {
+            // client did no register
+            // so no need to log anything
+        }
+        Some(conn) => {
+            // kept for RESUME, if the connection was given a token
+            let grace = Duration::from_secs(state.config.resume.grace_seconds);
+            state.sessions.park(&conn, grace).await;
+
+            rooms::part_all(&conn, state.clone()).await;
+            acks::drop_connection(addr, state.clone()).await;
+            files::drop_connection(addr, state.clone()).await;
+            presence::notify_watchers(state.clone(), &conn.nickname, "offline", None).await;
+            federation::retire(&state, &conn.nickname).await;
+
+            // a parked session keeps its claim until the claim lapses
+            if let Some(cluster) = &state.cluster {
+                if !state.sessions.is_reserved(&conn.nickname).await {
+                    cluster.release(&conn.nickname).await;
+                }
+            }
+            state.hooks.on_disconnect(addr, &conn.nickname).await;
+            state.metrics.left(&conn.nickname);
+
+            // client had registered
+            tracing::info!(nickname = %conn.nickname, "Left");
+            state.audit.record(Event::Disconnect {
+                nickname: &conn.nickname,
+                addr,
+            });
+        }
+    }
+}
+
+fn line_limit(registered: bool) -> usize {
+    if registered {
+        MAX_LINE_LENGTH
+    } else {
+        UNREGISTERED_LINE_LENGTH
+    }
+}
+
+async fn process_socket<S: PeerStream>(
+    mut stream: S,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+) {
+    let _open = state.metrics.open();
+
+    if let Verdict::Deny(reason) = state.hooks.on_accept(addr).await {
+        if !state.config.stealth.enabled {
+            state.metrics.error("DENIED");
+            let _ = stream
+                .write_all(format!("ERR DENIED {}\n", reason).as_bytes())
+                .await;
+        }
+        return;
+    }
+
+    // a failed or refused handshake just drops the connection
+    let Ok((mut reader, writer)) = transport::accept(stream, state.noise.as_ref()).await else {
+        return;
+    };
+
+    // reads stay with the connection task, writes are shared
+    // so other connections can deliver messages to this one
+    let caps = writer.caps().clone();
+    let socket = Arc::new(Mutex::new(writer));
+
+    let kicked = Arc::new(Notify::new());
+    state.kicks.lock().await.insert(addr, kicked.clone());
+
+    // create data buffer, grown to full size once registered
+    let mut buffer = vec![0; UNREGISTERED_BUFFER_SIZE];
+    let mut registered = false;
+    // bytes of a line whose newline has not arrived yet
+    let mut pending: Vec<u8> = Vec::new();
+    // in stealth mode, nothing is answered until a valid HELLO
+    let mut greeted = !state.config.stealth.enabled;
+    // set when the connection turns into a relay data channel
+    let mut relay_token = None;
+    // or into a link with another server
+    let mut link = None;
+    // a signaling header waiting for the rest of its payload
+    let mut awaiting: Option<signaling::Frame> = None;
+    // set while skipping the rest of a line that grew too long
+    let mut discarding = false;
+
+    'read: loop {
+        // try to read from socket, unless someone wants it closed
+        let data_size = tokio::select! {
+            read = reader.read(&mut buffer) => read,
+            _ = kicked.notified() => break,
+        };
+
+        match data_size {
+            // close connection
+            Ok(0) => {
+                break;
+            }
+            Ok(n) => {
+                state
+                    .connections
+                    .update(addr, |conn| conn.last_activity = Instant::now());
+
+                pending.extend_from_slice(&buffer[..n]);
+
+                // every newline ends one command, except while a
+                // signaling payload is due, which is taken by length
+                loop {
+                    if let Some(frame) = awaiting.as_mut() {
+                        // oversized payloads are thrown away as they come in
+                        if frame.discard {
+                            let skipped = frame.len.min(pending.len());
+                            pending.drain(..skipped);
+                            frame.len -= skipped;
+                        }
+
+                        if pending.len() < frame.len {
+                            break;
+                        }
+
+                        let payload: Vec<u8> = pending.drain(..frame.len).collect();
+
+                        if let Some(frame) = awaiting.take() {
+                            signaling::deliver(socket.clone(), addr, state.clone(), frame, payload)
+                                .await;
+                        }
+                        continue;
+                    }
+
+                    let Some(end) = pending.iter().position(|b| *b == b'\n') else {
+                        break;
+                    };
+                    let line: Vec<u8> = pending.drain(..=end).collect();
+
+                    if discarding {
+                        discarding = false;
+                        continue;
+                    }
+
+                    if !greeted {
+                        // anything but the expected HELLO gets the
+                        // connection dropped without a word
+                        if !is_valid_hello(&line, state.config.stealth.knock.as_deref()) {
+                            break 'read;
+                        }
+
+                        greeted = true;
+                    }
+
+                    // everything from here on takes it as a plain line
+                    let line = if caps.json() {
+                        match caps::from_json(&line) {
+                            Ok(line) => line,
+                            Err(error) => {
+                                send_error_response(socket.clone(), error).await;
+                                continue;
+                            }
+                        }
+                    } else {
+                        line
+                    };
+
+                    if line.len() > line_limit(registered) {
+                        send_error_response(socket.clone(), "TOO_LONG").await;
+                        continue;
+                    }
+
+                    if let Some(token) = relay::parse_attach(&line) {
+                        // data channels are fresh connections, never the
+                        // registered command connection
+                        if state.connections.contains(addr) {
+                            send_error_response(socket.clone(), "ALR_REG").await;
+                            continue;
+                        }
+
+                        relay_token = Some(token);
+                        break 'read;
+                    }
+
+                    if let Some(request) = federation::parse_link(&line) {
+                        if state.connections.contains(addr) {
+                            send_error_response(socket.clone(), "ALR_REG").await;
+                            continue;
+                        }
+
+                        link = Some(request);
+                        break 'read;
+                    }
+
+                    if !registered && !allowed_before_registration(&line) {
+                        send_error_response(socket.clone(), "NOT_REG").await;
+                        continue;
+                    }
+
+                    match signaling::parse_header(&line, state.config.signaling.max_payload) {
+                        Some(Ok(frame)) => {
+                            awaiting = Some(frame);
+                            continue;
+                        }
+                        Some(Err(error)) => {
+                            send_error_response(socket.clone(), error).await;
+                            continue;
+                        }
+                        None => {}
+                    }
+
+                    handle_incoming_buffer(socket.clone(), addr, state.clone(), &line).await;
+
+                    if !registered && state.connections.contains(addr) {
+                        registered = true;
+                        buffer.resize(CONNECTION_BUFFER_SIZE, 0);
+                    }
+                }
+
+                if awaiting.is_none() && pending.len() > line_limit(registered) {
+                    if !greeted {
+                        break;
+                    }
+
+                    pending.clear();
+
+                    if !discarding {
+                        send_error_response(socket.clone(), "TOO_LONG").await;
+                        discarding = true;
+                    }
+                }
+            }
+            // failed to read
+            Err(_e) => {
+                break;
+            }
+        }
+    }
+
+    state.kicks.lock().await.remove(&addr);
+
+    if let Some(token) = relay_token {
+        // whatever followed the RELAY line already belongs to the peer
+        relay::attach(token, addr, reader, socket, pending, state).await;
+        return;
+    }
+
+    if let Some((name, secret)) = link {
+        federation::accept(name, secret, reader, socket, pending, state).await;
+        return;
+    }
+
+    handle_disconnect(addr, state).await;
+}
+
+// the next connection on any of `listeners`, never if there are none
+async fn accept_any(listeners: &[TcpListener]) -> io::Result<TcpStream> {
+    if listeners.is_empty() {
+        return std::future::pending().await;
+    }
+
+    let accepts = listeners.iter().map(|l| Box::pin(l.accept()));
+    let (accepted, _, _) = futures_util::future::select_all(accepts).await;
+
+    accepted.map(|(stream, _)| stream)
+}
+
+/// Builds a [`Server`]. Everything not set falls back to the defaults the
+/// `p2p-rs` binary uses.
+pub struct ServerBuilder {
+    config: Config,
+    // in place of the [listen] addresses
+    bind: Option<String>,
+    max_connections: Option<usize>,
+    hooks: Arc<dyn Hooks>,
+    commands: Commands,
+    store: Option<Arc<dyn Store>>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Where to listen, instead of the `[listen]` addresses or
+    /// [`BIND_ADDR`]. Port 0 picks a free port, see [`Server::local_addr`].
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind = Some(addr.into());
+        self
+    }
+
+    /// Connections open at once, registered or not, before new ones are
+    /// turned away with `ERR FULL`.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Callbacks to watch and veto what connections do, see [`Hooks`].
+    pub fn hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Adds a custom command, answered by `handler` for registered
+    /// connections. See [`Commands::register`].
+    pub fn command<F, Fut>(mut self, name: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.commands.register(name, handler);
+        self
+    }
+
+    /// Where `[cluster]` keeps its shared state, instead of the Redis at
+    /// `redis_url`. See [`Store`].
+    pub fn cluster_store(mut self, store: impl Store) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Binds the listeners and sets up shared state. Nothing is accepted
+    /// until [`Server::run`].
+    pub async fn build(self) -> io::Result<Server> {
+        let addrs = match &self.bind {
+            Some(addr) => vec![addr.clone()],
+            None => self.config.listen.addrs_or_default(),
+        };
+
+        let mut listeners = Vec::new();
+
+        for addr in addrs {
+            let listener = TcpListener::bind(&addr)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+            listeners.push(listener);
+        }
+
+        let listener = listeners.remove(0);
+        let mut server = self.build_with(listener).await?;
+        server.others = listeners;
+
+        Ok(server)
+    }
+
+    /// Like [`build`](ServerBuilder::build), but accepts connections from
+    /// `listener` instead of binding a TCP port, and ignores
+    /// [`bind`](ServerBuilder::bind).
+    pub async fn build_with<T: Transport>(self, listener: T) -> io::Result<Server<T>> {
+        let mut state = ServerState::new(self.config)?;
+        state.hooks = self.hooks;
+
+        if state.config.cluster.enabled {
+            let config = &state.config.cluster;
+
+            let store = match self.store {
+                Some(store) => store,
+                None => Arc::new(RedisStore::connect(&config.redis_url, &config.prefix).await?),
+            };
+
+            let node = config
+                .node
+                .clone()
+                .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+            let claim_for = Duration::from_secs(config.claim_seconds.max(1));
+
+            state.cluster = Some(Arc::new(Cluster::new(node, store, claim_for)));
+        }
+
+        if state.config.federation.name.is_none() {
+            state.federation =
+                Federation::new(listener.local_addr()?.to_string(), &state.config.outbound);
+        }
+
+        state.commands = self.commands;
+
+        if state.config.dht.enabled {
+            state.dht = Some(Dht::start(state.config.dht.clone()).await?);
+        }
+
+        let metrics = if state.config.metrics.enabled {
+            Some(Arc::new(
+                TcpListener::bind(&state.config.metrics.bind).await?,
+            ))
+        } else {
+            None
+        };
+
+        let admin = if state.config.admin.enabled {
+            Some(Arc::new(TcpListener::bind(&state.config.admin.bind).await?))
+        } else {
+            None
+        };
+
+        let (shutdown, _) = watch::channel(false);
+
+        Ok(Server {
+            listener,
+            others: Vec::new(),
+            state: Arc::new(state),
+            max_connections: self.max_connections,
+            metrics,
+            admin,
+            shutdown,
+        })
+    }
+}
+
+/// A bound server, ready to [`run`](Server::run). TCP unless built with
+/// [`ServerBuilder::build_with`].
+pub struct Server<T: Transport = TcpListener> {
+    listener: T,
+    // the rest of the [listen] addresses, accepted from alongside `listener`
+    others: Vec<TcpListener>,
+    state: Arc<ServerState>,
+    max_connections: Option<usize>,
+    // the /metrics listener, when [metrics] is enabled
+    metrics: Option<Arc<TcpListener>>,
+    // the admin api listener, when [admin] is enabled
+    admin: Option<Arc<TcpListener>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+            bind: None,
+            max_connections: None,
+            hooks: Arc::new(NoHooks),
+            commands: Commands::default(),
+            store: None,
+        }
+    }
+}
+
+impl<T: Transport> Server<T> {
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Every address connections are accepted on, [`local_addr`] first.
+    ///
+    /// [`local_addr`]: Server::local_addr
+    pub fn local_addrs(&self) -> io::Result<Vec<std::net::SocketAddr>> {
+        let mut addrs = vec![self.listener.local_addr()?];
+
+        for listener in &self.others {
+            addrs.push(listener.local_addr()?);
+        }
+
+        Ok(addrs)
+    }
+
+    /// Where `/metrics` is served, if `[metrics]` is enabled.
+    pub fn metrics_addr(&self) -> Option<std::net::SocketAddr> {
+        self.metrics.as_ref()?.local_addr().ok()
+    }
+
+    /// Where the admin api is served, if `[admin]` is enabled.
+    pub fn admin_addr(&self) -> Option<std::net::SocketAddr> {
+        self.admin.as_ref()?.local_addr().ok()
+    }
+
+    /// Reads the config file again and applies what can change without a
+    /// restart, see [`reload`](crate::reload).
+    pub fn reload(&self) -> Result<Vec<&'static str>, ReloadError> {
+        reload::from_file(&self.state)
+    }
+
+    /// Stops [`run`](Server::run): no more connections are accepted, every
+    /// registered one is told `ERR SHUTDOWN` and leaves as if it had
+    /// disconnected, and whatever is still open after a few seconds is
+    /// closed.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Accepts connections until [`shutdown`](Server::shutdown) is called.
+    pub async fn run(&self) -> io::Result<()> {
+        let state = &self.state;
+
+        if let Some(dht) = state.dht.clone() {
+            dht::republish_registered(state.clone(), dht);
+        }
+
+        if !state.config.pex.servers.is_empty() {
+            pex::gossip(state.clone());
+        }
+
+        if let Some(public_key) = state
+            .noise
+            .as_ref()
+            .and_then(|n| n.generated_public_key.as_ref())
+        {
+            tracing::info!(component = "noise", %public_key, "Generated static key");
+        }
+
+        // announced until run returns
+        let _mdns = if state.config.mdns.enabled {
+            Some(Mdns::start(
+                &state.config.mdns,
+                self.listener.local_addr()?.port(),
+            )?)
+        } else {
+            None
+        };
+
+        // stopped when run returns, however it returns
+        let mut background = JoinSet::new();
+
+        if let Some(listener) = self.metrics.clone() {
+            let state = state.clone();
+            background.spawn(http::serve(listener, move |request| {
+                metrics::handle(request, state.clone())
+            }));
+        }
+
+        if let Some(cluster) = state.cluster.clone() {
+            background.spawn(cluster::listen(state.clone(), cluster.clone()));
+            background.spawn(cluster::renew(state.clone(), cluster));
+        }
+
+        for server in &state.config.federation.links {
+            background.spawn(federation::keep_linked(server.clone(), state.clone()));
+        }
+
+        if let Some(listener) = self.admin.clone() {
+            let (state, shutdown) = (state.clone(), self.shutdown.clone());
+            background.spawn(http::serve(listener, move |request| {
+                admin::handle(request, state.clone(), shutdown.clone())
+            }));
+        }
+
+        let mut shutdown = self.shutdown.subscribe();
+        // one task per open connection, dropped together at shutdown
+        let mut connections = JoinSet::new();
+
+        // for every incoming connection
+        loop {
+            // accept the connection, keeping up with finished tasks
+            let mut socket: Box<dyn PeerStream> = tokio::select! {
+                accepted = self.listener.accept() => Box::new(accepted?),
+                accepted = accept_any(&self.others) => Box::new(accepted?),
+                Some(_) = connections.join_next() => continue,
+                _ = shutdown.wait_for(|stop| *stop) => break,
+            };
+
+            // the peer may already be gone
+            let Ok(addr) = socket.peer_addr() else {
+                continue;
+            };
+
+            if let Err(e) = socket.configure(&state.config.tcp) {
+                tracing::warn!(component = "tcp", %addr, error = %e, "Socket options not set");
+            }
+
+            // turn banned ips away before spawning anything for them
+            if state.auth.is_banned(addr.ip()).await {
+                if !state.config.stealth.enabled {
+                    let _ = socket.write_all(b"ERR BANNED\n").await;
+                }
+                continue;
+            }
+
+            if self
+                .max_connections
+                .is_some_and(|max| connections.len() >= max)
+            {
+                if !state.config.stealth.enabled {
+                    let _ = socket.write_all(b"ERR FULL\n").await;
+                }
+                continue;
+            }
+
+            // whatever a connection logs carries its address, and its
+            // host and nickname once known
+            let span = tracing::info_span!(
+                "connection",
+                %addr,
+                host = tracing::field::Empty,
+                nickname = tracing::field::Empty
+            );
+
+            if let Some(rdns) = state.rdns.clone() {
+                let span = span.clone();
+
+                tokio::spawn(async move {
+                    if let Some(host) = rdns.lookup(addr.ip()).await {
+                        span.record("host", host.as_str());
+                    }
+                });
+            }
+
+            let task = process_socket(socket, addr, state.clone()).instrument(span);
+            connections.spawn(metrics::scope(state.metrics.clone(), task));
+        }
+
+        // everyone registered leaves the way an evicted peer does, so links,
+        // the cluster, watchers and hooks hear about it
+        let nicknames = state.connections.filter_map(|c| Some(c.nickname.clone()));
+        let leaving = nicknames.iter().map(|n| state.evict(n, "SHUTDOWN"));
+
+        if tokio::time::timeout(SHUTDOWN_GRACE, futures_util::future::join_all(leaving))
+            .await
+            .is_err()
+        {
+            tracing::warn!("Closing connections still leaving");
+        }
+
+        // nothing parked for RESUME outlives the process, nor its claims
+        for nickname in state.sessions.drain().await {
+            if let Some(cluster) = &state.cluster {
+                cluster.release(&nickname).await;
+            }
+        }
+
+        // what is left never registered, or is stuck
+        connections.shutdown().await;
+        state.connections.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    async fn test_state() -> Arc<ServerState> {
+        Arc::new(ServerState::new(Config::default()).unwrap())
+    }
+
+    // registers a nickname over a real socket pair, returning the client end
+    async fn register(state: &Arc<ServerState>, nickname: &str) -> (TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, addr) = listener.accept().await.unwrap();
+        let (_, writer) = transport::accept(server, None).await.unwrap();
+
+        let conn = Connection {
+            socket: Arc::new(Mutex::new(writer)),
+            addr,
+            nickname: nickname.to_string(),
+            rooms: HashSet::new(),
+            status: Status::Online,
+            status_text: None,
+            watching: HashSet::new(),
+            public_key: None,
+            fingerprint: None,
+            registered_at: SystemTime::now(),
+            last_activity: Instant::now(),
+            queued: Arc::default(),
+            last_typing: None,
+        };
+
+        state.connections.insert(conn.clone());
+        state.offline.remember(nickname).await;
+
+        (client, conn)
+    }
+
+    // makes every further write to the connection fail
+    async fn kill(conn: &Connection) {
+        conn.socket.lock().await.shutdown().await.unwrap();
+    }
+
+    // everything the client has been sent so far
+    async fn received(client: &mut TcpStream) -> String {
+        let mut data = Vec::new();
+        let mut buffer = [0; 1024];
+
+        while let Ok(Ok(n)) =
+            tokio::time::timeout(Duration::from_millis(100), client.read(&mut buffer)).await
+        {
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buffer[..n]);
+        }
+
+        String::from_utf8(data).unwrap()
+    }
+
+    async fn wait_until_unregistered(state: &Arc<ServerState>, addr: std::net::SocketAddr) {
+        for _ in 0..50 {
+            if !state.connections.contains(addr) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("{} is still registered", addr);
+    }
+
+    #[tokio::test]
+    async fn failed_write_is_peer_gone() {
+        let state = test_state().await;
+        let (_client, bob) = register(&state, "bob").await;
+
+        kill(&bob).await;
+
+        assert_eq!(
+            send_bytes(bob.socket.clone(), b"MSG x\n").await,
+            Err(PeerGone)
+        );
+        assert_eq!(send_to(state.clone(), &bob, "MSG x").await, Err(PeerGone));
+
+        wait_until_unregistered(&state, bob.addr).await;
+    }
+
+    #[tokio::test]
+    async fn broadcast_survives_a_member_disconnecting() {
+        let state = test_state().await;
+        let (mut alice_client, alice) = register(&state, "alice").await;
+        let (_bob_client, bob) = register(&state, "bob").await;
+        let (mut carol_client, carol) = register(&state, "carol").await;
+
+        for conn in [&alice, &bob, &carol] {
+            rooms::handle_join(conn.socket.clone(), conn.addr, state.clone(), "#r").await;
+        }
+        received(&mut alice_client).await;
+        received(&mut carol_client).await;
+
+        kill(&bob).await;
+
+        rooms::handle_room_message(alice.socket.clone(), alice.addr, state.clone(), "#r", "hi")
+            .await;
+
+        assert!(received(&mut carol_client)
+            .await
+            .contains("RMSG #r alice hi\n"));
+        wait_until_unregistered(&state, bob.addr).await;
+        assert!(!state.rooms.lock().await["#r"].members.contains(&bob.addr));
+
+        // the rest of the room hears that bob left
+        assert!(received(&mut alice_client).await.contains("PART #r bob\n"));
+    }
+
+    #[tokio::test]
+    async fn message_to_a_dead_peer_is_queued() {
+        let state = test_state().await;
+        let (mut alice_client, alice) = register(&state, "alice").await;
+        let (_bob_client, bob) = register(&state, "bob").await;
+
+        kill(&bob).await;
+
+        messages::handle_message(
+            alice.socket.clone(),
+            alice.addr,
+            state.clone(),
+            "bob",
+            "hello",
+            None,
+        )
+        .await;
+
+        assert_eq!(received(&mut alice_client).await, "OK QUEUED\n");
+
+        let queued = state.offline.take("bob").await;
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].payload, "hello");
+    }
+
+    #[tokio::test]
+    async fn dead_peer_is_unregistered_once() {
+        let state = test_state().await;
+        let (mut alice_client, alice) = register(&state, "alice").await;
+        let (_bob_client, bob) = register(&state, "bob").await;
+
+        state
+            .connections
+            .update(alice.addr, |c| c.watching.insert("bob".to_string()));
+
+        kill(&bob).await;
+
+        for _ in 0..3 {
+            assert_eq!(send_to(state.clone(), &bob, "MSG x").await, Err(PeerGone));
+        }
+        drop_peer(bob.addr, state.clone());
+
+        wait_until_unregistered(&state, bob.addr).await;
+
+        let lines = received(&mut alice_client).await;
+        assert_eq!(lines.matches("PRESENCE bob offline").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn server_runs_until_shut_down() {
+        let server = Arc::new(
+            Server::builder()
+                .bind("127.0.0.1:0")
+                .max_connections(1)
+                .build()
+                .await
+                .unwrap(),
+        );
+        let addr = server.local_addr().unwrap();
+
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"HELLO\n").await.unwrap();
+        assert!(received(&mut first).await.starts_with("HELLO p2p-rs"));
+
+        // the one allowed connection is taken
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(received(&mut second).await, "ERR FULL\n");
+
+        server.shutdown();
+        running.await.unwrap().unwrap();
+
+        // the open connection is closed along with the server
+        let mut buffer = [0; 16];
+        assert_eq!(first.read(&mut buffer).await.unwrap_or(0), 0);
+    }
+
+    // in-memory connections, handed over one at a time, each from its own port
+    struct Pipes(
+        Mutex<tokio::sync::mpsc::Receiver<tokio::io::DuplexStream>>,
+        std::sync::atomic::AtomicU16,
+    );
+
+    impl Transport for Pipes {
+        type Stream = transport::WithAddr<tokio::io::DuplexStream>;
+
+        async fn accept(&self) -> io::Result<Self::Stream> {
+            let stream = self.0.lock().await.recv().await;
+            let stream = stream.ok_or(io::ErrorKind::ConnectionAborted)?;
+            let port = self.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+
+            Ok(transport::WithAddr::new(
+                stream,
+                ([10, 0, 0, 1], port).into(),
+            ))
+        }
+
+        fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+            Ok(([10, 0, 0, 1], 0).into())
+        }
+    }
+
+    type Connect = tokio::sync::mpsc::Sender<tokio::io::DuplexStream>;
+
+    // runs a server over `Pipes`, returning it and what opens connections to it
+    async fn piped(builder: ServerBuilder) -> (Arc<Server<Pipes>>, Connect) {
+        let (connect, pipes) = tokio::sync::mpsc::channel(1);
+        let server = Arc::new(
+            builder
+                .build_with(Pipes(Mutex::new(pipes), Default::default()))
+                .await
+                .unwrap(),
+        );
+
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+
+        (server, connect)
+    }
+
+    // sends a line on a new piped connection and returns the reply
+    async fn pipe(connect: &Connect, line: &str) -> (tokio::io::DuplexStream, String) {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        connect.send(stream).await.unwrap();
+
+        client.write_all(line.as_bytes()).await.unwrap();
+        let mut buffer = [0; 256];
+        let n = client.read(&mut buffer).await.unwrap();
+
+        (client, String::from_utf8_lossy(&buffer[..n]).to_string())
+    }
+
+    #[tokio::test]
+    async fn any_transport_gets_the_same_commands() {
+        let (server, connect) = piped(Server::builder()).await;
+
+        let (_client, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "OK\n");
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn registration_gets_the_motd() {
+        let mut config = Config::default();
+        config.motd.text = Some("Welcome\n\nbe nice\n".to_string());
+
+        let (server, connect) = piped(Server::builder().config(config)).await;
+        let (mut client, mut reply) = pipe(&connect, "REG alice\n").await;
+
+        // the lines can come in separate writes
+        let mut buffer = [0; 256];
+        while !reply.ends_with("MOTD be nice\n") {
+            let n = client.read(&mut buffer).await.unwrap();
+            reply.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+        assert_eq!(reply, "OK\nMOTD Welcome\nMOTD\nMOTD be nice\n");
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn reloads_apply_what_they_can() {
+        let (server, connect) = piped(Server::builder()).await;
+
+        let mut config = Config::default();
+        config.motd.text = Some("Reloaded".to_string());
+        assert_eq!(server.state.reload(config.clone()).unwrap(), ["motd"]);
+
+        let (mut client, mut reply) = pipe(&connect, "REG alice\n").await;
+        let mut buffer = [0; 256];
+        while !reply.ends_with("MOTD Reloaded\n") {
+            let n = client.read(&mut buffer).await.unwrap();
+            reply.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+
+        // refused as a whole, the motd stays
+        config.motd.text = Some("Never seen".to_string());
+        config.history.size += 1;
+        assert!(matches!(
+            server.state.reload(config),
+            Err(ReloadError::NeedsRestart(sections)) if sections == ["history"]
+        ));
+        assert_eq!(server.state.motd().text.as_deref(), Some("Reloaded"));
+
+        server.shutdown();
+    }
+
+    struct Moderator;
+
+    #[async_trait::async_trait]
+    impl Hooks for Moderator {
+        async fn on_register(&self, _addr: std::net::SocketAddr, nickname: &str) -> Verdict {
+            match nickname {
+                "mallory" => Verdict::Deny("NOPE".to_string()),
+                _ => Verdict::Allow,
+            }
+        }
+
+        async fn on_message(&self, message: &mut crate::hooks::Message) -> Verdict {
+            // everything for the old name goes to the new one
+            if message.to == "bob" {
+                message.to = "robert".to_string();
+            }
+            message.payload = message.payload.to_uppercase();
+            Verdict::Allow
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_can_refuse_and_reroute() {
+        let (server, connect) = piped(Server::builder().hooks(Moderator)).await;
+
+        let (_mallory, reply) = pipe(&connect, "REG mallory\n").await;
+        assert_eq!(reply, "ERR DENIED NOPE\n");
+
+        let (mut robert, reply) = pipe(&connect, "REG robert\n").await;
+        assert_eq!(reply, "OK\n");
+
+        let (mut alice, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "OK\n");
+
+        alice.write_all(b"MSG bob hi\n").await.unwrap();
+
+        let mut buffer = [0; 64];
+        let n = robert.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"MSG alice HI\n");
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn custom_commands_run_before_unknown() {
+        let builder = Server::builder().command("ROLL", |ctx| async move {
+            let line = format!("ROLLED {} {}", ctx.connection.nickname, ctx.args.join(" "));
+            ctx.reply(&line).await;
+        });
+        let (server, connect) = piped(builder).await;
+
+        let (mut alice, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "OK\n");
+
+        alice.write_all(b"ROLL 2 d6\nFLIP\n").await.unwrap();
+
+        let mut replies = String::new();
+        while replies.lines().count() < 2 {
+            let mut buffer = [0; 64];
+            let n = alice.read(&mut buffer).await.unwrap();
+            replies.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+        assert_eq!(replies, "ROLLED alice 2 d6\nERR UNK_CMD\n");
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn bad_lines_get_an_error_not_a_panic() {
+        let (server, connect) = piped(Server::builder()).await;
+
+        let (mut client, reply) = pipe(&connect, "REG\n").await;
+        assert_eq!(reply, "ERR NIL_NICK\n");
+
+        client.write_all(b"REG alice\n").await.unwrap();
+        client.write_all(b"MSG \xff\xfe\n").await.unwrap();
+
+        let mut replies = String::new();
+        while replies.lines().count() < 2 {
+            let mut buffer = [0; 64];
+            let n = client.read(&mut buffer).await.unwrap();
+            replies.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+        assert_eq!(replies, "OK\nERR BAD_UTF8\n");
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn slow_consumers_are_cut_off() {
+        let mut config = Config::default();
+        config.outbound.max_queued = 2;
+        config.outbound.write_timeout_seconds = 0;
+
+        let (server, connect) = piped(Server::builder().config(config)).await;
+
+        // bob never reads, so his pipe fills up after one message
+        let (_bob, reply) = pipe(&connect, "REG bob\n").await;
+        assert_eq!(reply, "OK\n");
+
+        let line = format!("MSG bob {}\n", "x".repeat(900));
+
+        // counted as the sends are handled, which is out of step with the test
+        let seen = |sample: &'static str| {
+            let server = server.clone();
+            async move {
+                for _ in 0..100 {
+                    if server.state.metrics.render(0).contains(sample) {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("no {:?}", sample);
+            }
+        };
+
+        // the first message fills bob's pipe, the next two are stuck
+        // waiting on him
+        let mut senders = Vec::new();
+        let queued = [
+            ("alice", "p2p_outbound_queued 0\n"),
+            ("carol", "p2p_outbound_queued 1\n"),
+            ("dave", "p2p_outbound_queued 2\n"),
+        ];
+
+        for (nickname, sample) in queued {
+            let (mut sender, reply) = pipe(&connect, &format!("REG {}\n", nickname)).await;
+            assert_eq!(reply, "OK\n");
+
+            sender.write_all(line.as_bytes()).await.unwrap();
+            seen(sample).await;
+            senders.push(sender);
+        }
+
+        // one more is too many, bob is dropped and it waits offline instead
+        let (mut erin, _) = pipe(&connect, "REG erin\n").await;
+        erin.write_all(line.as_bytes()).await.unwrap();
+
+        let mut buffer = [0; 64];
+        let n = erin.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"OK QUEUED\n");
+        seen("p2p_slow_consumers_total 1\n").await;
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn metrics_follow_what_connections_do() {
+        let mut config = Config::default();
+        config.metrics.enabled = true;
+        config.metrics.bind = "127.0.0.1:0".to_string();
+
+        let (server, connect) = piped(Server::builder().config(config)).await;
+
+        let (_alice, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "OK\n");
+        let (_other, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "ERR TKN\n");
+
+        let mut scrape = TcpStream::connect(server.metrics_addr().unwrap())
+            .await
+            .unwrap();
+        scrape
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        scrape.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "p2p_connections 2",
+            "p2p_registered 1",
+            "p2p_registrations_total 1",
+            "p2p_errors_total{code=\"TKN\"} 1",
+            "p2p_command_duration_seconds_count{command=\"REG\"} 2",
+        ] {
+            assert!(response.lines().any(|l| l == line), "no {:?}", line);
+        }
+
+        server.shutdown();
+    }
+
+    // one request to a side endpoint, returning the status line and body
+    async fn http(addr: std::net::SocketAddr, request: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn admins_can_list_kick_and_change_limits() {
+        let mut config = Config::default();
+        config.admin.enabled = true;
+        config.admin.bind = "127.0.0.1:0".to_string();
+        config.admin.token = Some("secret".to_string());
+
+        let (server, connect) = piped(Server::builder().config(config)).await;
+        let admin = server.admin_addr().unwrap();
+
+        let (mut alice, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "OK\n");
+
+        let (status, _) = http(admin, "GET /connections HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        let auth = "Authorization: Bearer secret\r\n";
+
+        let (status, body) = http(admin, &format!("GET /connections HTTP/1.1\r\n{auth}\r\n")).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(list[0]["nickname"], "alice");
+
+        let body = r#"{"relay_bytes_per_second":2048}"#;
+        let (status, body) = http(
+            admin,
+            &format!(
+                "PUT /limits HTTP/1.1\r\n{auth}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains(r#""relay_bytes_per_second":2048"#));
+
+        let kick = format!("DELETE /connections/alice HTTP/1.1\r\n{auth}\r\n");
+        let (status, _) = http(admin, &kick).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        // kicked connections are closed and let go of the nickname
+        let mut buffer = [0; 16];
+        assert_eq!(alice.read(&mut buffer).await.unwrap(), 0);
+        let (status, _) = http(admin, &kick).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn the_dashboard_shows_joins_and_leaves() {
+        let mut config = Config::default();
+        config.admin.enabled = true;
+        config.admin.bind = "127.0.0.1:0".to_string();
+        config.admin.token = Some("secret".to_string());
+
+        let (server, connect) = piped(Server::builder().config(config)).await;
+        let admin = server.admin_addr().unwrap();
+
+        // the page itself holds nothing, it asks for the token
+        let (status, body) = http(admin, "GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("<canvas"));
+
+        let (alice, reply) = pipe(&connect, "REG alice\n").await;
+        assert_eq!(reply, "OK\n");
+        drop(alice);
+
+        let stats = "GET /stats HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+
+        // leaving is noticed by the connection task, give it a moment
+        let mut recent = serde_json::Value::Null;
+        for _ in 0..50 {
+            let (status, body) = http(admin, stats).await;
+            assert_eq!(status, "HTTP/1.1 200 OK");
+
+            let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+            recent = stats["recent"].clone();
+
+            if recent.as_array().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(recent[0]["nickname"], "alice");
+        assert_eq!(recent[0]["joined"], true);
+        assert_eq!(recent[1]["joined"], false);
+
+        server.shutdown();
     }
 }
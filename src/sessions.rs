@@ -0,0 +1,323 @@
+use crate::audit::Event;
+use crate::hooks::Verdict;
+use crate::offline::QueueError;
+use crate::presence::{self, Status};
+use crate::rooms;
+use crate::server::{
+    send_bytes, send_denied, send_error_response, send_response, Connection, ServerState,
+};
+use crate::transport::Writer;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+// what a dropped connection leaves behind for RESUME
+struct Session {
+    nickname: String,
+    rooms: HashSet<String>,
+    status: Status,
+    status_text: Option<String>,
+    watching: HashSet<String>,
+    public_key: Option<String>,
+    fingerprint: Option<String>,
+    registered_at: SystemTime,
+    // (from, payload) of messages sent to the nickname while it was away
+    pending: Vec<(String, String)>,
+    expires: Instant,
+}
+
+/// Session tokens of registered connections, and the sessions of dropped
+/// ones waiting out their grace window.
+#[derive(Default)]
+pub struct Sessions {
+    tokens: Mutex<HashMap<std::net::SocketAddr, String>>,
+    parked: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    /// A fresh token for a connection that just registered. Tokens are
+    /// single use, RESUME hands out a new one.
+    pub async fn issue(&self, addr: std::net::SocketAddr) -> String {
+        let token: String = rand::random::<[u8; 32]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        self.tokens.lock().await.insert(addr, token.clone());
+        token
+    }
+
+    /// Takes back the token of a connection, so it can't be resumed once
+    /// it drops.
+    pub async fn forget(&self, addr: std::net::SocketAddr) {
+        self.tokens.lock().await.remove(&addr);
+    }
+
+    /// Keeps what a dropped connection had for `grace`, if it was given a
+    /// token.
+    pub async fn park(&self, conn: &Connection, grace: Duration) {
+        let Some(token) = self.tokens.lock().await.remove(&conn.addr) else {
+            return;
+        };
+
+        self.parked.lock().await.insert(
+            token,
+            Session {
+                nickname: conn.nickname.clone(),
+                rooms: conn.rooms.clone(),
+                status: conn.status,
+                status_text: conn.status_text.clone(),
+                watching: conn.watching.clone(),
+                public_key: conn.public_key.clone(),
+                fingerprint: conn.fingerprint.clone(),
+                registered_at: conn.registered_at,
+                pending: Vec::new(),
+                expires: Instant::now() + grace,
+            },
+        );
+    }
+
+    /// Whether `nickname` is held for a dropped connection to resume.
+    pub async fn is_reserved(&self, nickname: &str) -> bool {
+        let mut parked = self.parked.lock().await;
+
+        parked.retain(|_, s| s.expires > Instant::now());
+        parked.values().any(|s| s.nickname == nickname)
+    }
+
+    /// Holds a message for a dropped nickname, `UnknownNickname` when
+    /// nobody is waiting to resume it.
+    pub async fn hold(
+        &self,
+        nickname: &str,
+        from: &str,
+        payload: &str,
+        max: usize,
+    ) -> Result<(), QueueError> {
+        let mut parked = self.parked.lock().await;
+
+        let session = parked
+            .values_mut()
+            .find(|s| s.nickname == nickname && s.expires > Instant::now())
+            .ok_or(QueueError::UnknownNickname)?;
+
+        if session.pending.len() >= max {
+            return Err(QueueError::Full);
+        }
+
+        session
+            .pending
+            .push((from.to_string(), payload.to_string()));
+        Ok(())
+    }
+
+    /// Throws away every parked session, returning their nicknames.
+    pub async fn drain(&self) -> Vec<String> {
+        self.tokens.lock().await.clear();
+
+        self.parked
+            .lock()
+            .await
+            .drain()
+            .map(|(_, s)| s.nickname)
+            .collect()
+    }
+
+    /// Throws away whatever is parked for `nickname`. False if nothing was.
+    pub async fn release(&self, nickname: &str) -> bool {
+        let mut parked = self.parked.lock().await;
+        let before = parked.len();
+
+        parked.retain(|_, s| s.nickname != nickname);
+        parked.len() < before
+    }
+
+    // the nickname a live session is parked under, left where it is
+    async fn peek(&self, token: &str) -> Option<String> {
+        self.parked
+            .lock()
+            .await
+            .get(token)
+            .filter(|s| s.expires > Instant::now())
+            .map(|s| s.nickname.clone())
+    }
+
+    // puts back a session taken for a RESUME that failed after all
+    async fn restore(&self, token: &str, session: Session) {
+        self.parked.lock().await.insert(token.to_string(), session);
+    }
+
+    async fn take(&self, token: &str) -> Option<Session> {
+        self.parked
+            .lock()
+            .await
+            .remove(token)
+            .filter(|s| s.expires > Instant::now())
+    }
+}
+
+/// `RESUME <token>` registers again as the nickname a dropped connection
+/// had, with its rooms, status and watches, and answers `OK <new token>`.
+/// Messages sent to the nickname in the meantime follow the `OK`.
+pub async fn handle_resume(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    token: &str,
+) {
+    if !state.config.resume.enabled {
+        send_error_response(socket, "NO_RESUME").await;
+        return;
+    }
+
+    if state.connections.contains(addr) {
+        send_error_response(socket, "ALR_REG").await;
+        return;
+    }
+
+    if state.is_banned(addr.ip()).await {
+        send_error_response(socket, "BANNED").await;
+        return;
+    }
+
+    // a wrong token uses up nothing, only a live session can be taken;
+    // it stays parked until nothing else can refuse it
+    let Some(nickname) = state.sessions.peek(token).await else {
+        send_error_response(socket, "BAD_TOKEN").await;
+        return;
+    };
+
+    if let Verdict::Deny(reason) = state.hooks.on_register(addr, &nickname).await {
+        send_denied(socket, &reason).await;
+        return;
+    }
+
+    if let Some(cluster) = &state.cluster {
+        match cluster.claim(&nickname).await {
+            Ok(true) => {}
+            Ok(false) => {
+                send_error_response(socket, "TKN").await;
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(component = "cluster", error = %e, "Failed to claim nickname");
+                send_error_response(socket, "UNAVAILABLE").await;
+                return;
+            }
+        }
+    }
+
+    // gone if it expired or another RESUME took it meanwhile
+    let Some(session) = state.sessions.take(token).await else {
+        send_error_response(socket, "BAD_TOKEN").await;
+        return;
+    };
+
+    let conn = Connection {
+        socket: socket.clone(),
+        addr,
+        nickname: session.nickname.clone(),
+        rooms: session.rooms.clone(),
+        status: session.status,
+        status_text: session.status_text.clone(),
+        watching: session.watching.clone(),
+        public_key: session.public_key.clone(),
+        fingerprint: session.fingerprint.clone(),
+        registered_at: session.registered_at,
+        last_activity: Instant::now(),
+        queued: Arc::default(),
+        last_typing: None,
+    };
+
+    // reserved nicknames can't be taken, but check rather than clobber
+    if !state.connections.insert(conn.clone()) {
+        state.sessions.restore(token, session).await;
+        send_error_response(socket, "TKN").await;
+        return;
+    }
+
+    let token = state.sessions.issue(addr).await;
+    send_response(socket.clone(), format!("OK {}", token).as_str(), true).await;
+    state.metrics.registered(&conn.nickname);
+
+    for (from, payload) in session.pending {
+        let line = format!("MSG {} {}\n", from, payload);
+
+        if send_bytes(socket.clone(), line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    crate::messages::deliver_queued(socket.clone(), state.clone(), &conn.nickname).await;
+
+    rooms::rejoin_all(&conn, state.clone()).await;
+
+    let text = conn.status_text.as_deref();
+    presence::notify_watchers(state.clone(), &conn.nickname, conn.status.as_str(), text).await;
+    crate::federation::announce(&state, &conn.nickname).await;
+
+    if let Some(cluster) = &state.cluster {
+        cluster
+            .set_presence(&conn.nickname, conn.status.as_str())
+            .await;
+    }
+
+    if let Some(dht) = state.dht.clone() {
+        let nickname = conn.nickname.clone();
+
+        tokio::spawn(async move { dht.announce(&nickname, addr).await });
+    }
+
+    tracing::Span::current().record("nickname", conn.nickname.as_str());
+    tracing::info!(nickname = %conn.nickname, "Resumed");
+    state.audit.record(Event::Resume {
+        nickname: &conn.nickname,
+        addr,
+    });
+}
+
+/// `GHOST <nick> <password>` frees a nickname for whoever holds its
+/// `[auth.users]` credential: a connection still holding it is
+/// disconnected and a session parked for RESUME is thrown away. Once `OK`
+/// is back the nickname can be registered. Nicknames that go by the shared
+/// token have no owner and can't be ghosted, `ERR NO_AUTH`.
+pub async fn handle_ghost(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    nickname: &str,
+    password: &str,
+) {
+    // without a credential of its own nobody is more the owner than
+    // anyone else who has the shared token
+    if !state.has_own_credential(nickname) {
+        send_error_response(socket, "NO_AUTH").await;
+        return;
+    }
+
+    if let Err(error) = state
+        .authenticate(addr.ip(), nickname, Some(password))
+        .await
+    {
+        send_error_response(socket, error).await;
+        return;
+    }
+
+    // ghosting oneself would only end this connection
+    if state.connections.addr_of(nickname) == Some(addr) {
+        send_error_response(socket, "ALR_REG").await;
+        return;
+    }
+
+    let parked = state.sessions.release(nickname).await;
+    let evicted = state.evict(nickname, "GHOSTED").await;
+
+    if !parked && !evicted {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    }
+
+    tracing::info!(%nickname, "Ghosted");
+    state.audit.record(Event::Ghost { nickname, addr });
+    send_response(socket, "OK", true).await;
+}
@@ -0,0 +1,84 @@
+use crate::server::{
+    get_connection_by_addr, get_connection_by_nickname, send_error_response, send_framed_to,
+    send_response, ServerState,
+};
+use crate::transport::Writer;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// relayed as opaque payloads, the server never looks inside
+const COMMANDS: [&str; 3] = ["SDP_OFFER", "SDP_ANSWER", "ICE_CAND"];
+
+/// A signaling header whose payload is still being read.
+pub struct Frame {
+    command: &'static str,
+    target: String,
+    // payload bytes still expected
+    pub len: usize,
+    // set for payloads over the limit, which are skipped instead of kept
+    pub discard: bool,
+}
+
+/// Recognizes `<SDP_OFFER|SDP_ANSWER|ICE_CAND> <nick> <len>` header lines.
+/// The next `len` bytes after the newline are the payload, taken verbatim
+/// so SDP blobs keep their newlines and spacing.
+pub fn parse_header(line: &[u8], max_payload: usize) -> Option<Result<Frame, &'static str>> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+
+    let first = words.next()?;
+    let command = COMMANDS.iter().find(|c| **c == first)?;
+
+    let (Some(target), Some(len)) = (words.next(), words.next()) else {
+        return Some(Err("NIL_ARG"));
+    };
+
+    let Ok(len) = len.parse::<usize>() else {
+        return Some(Err("BAD_LEN"));
+    };
+
+    Some(Ok(Frame {
+        command,
+        target: target.to_string(),
+        len,
+        discard: len > max_payload,
+    }))
+}
+
+/// Passes a complete payload on to the target as
+/// `<command> <from> <len>\n<payload>`.
+pub async fn deliver(
+    socket: Arc<Mutex<Writer>>,
+    addr: std::net::SocketAddr,
+    state: Arc<ServerState>,
+    frame: Frame,
+    payload: Vec<u8>,
+) {
+    if frame.discard {
+        send_error_response(socket, "TOO_LONG").await;
+        return;
+    }
+
+    let Some(conn) = get_connection_by_addr(addr, state.clone()).await else {
+        send_error_response(socket, "NOT_REG").await;
+        return;
+    };
+
+    let Some(target) = get_connection_by_nickname(&frame.target, state.clone()).await else {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    };
+
+    let header = format!("{} {} {}", frame.command, conn.nickname, payload.len());
+
+    // a target too slow to keep up is cut off with ERR SLOW like anywhere
+    if send_framed_to(state, &target, &header, &payload)
+        .await
+        .is_err()
+    {
+        send_error_response(socket, "NO_NICK").await;
+        return;
+    }
+
+    send_response(socket, "OK", true).await;
+}
@@ -0,0 +1,86 @@
+//! The `[tcp]` options, set on every accepted socket before anything is
+//! read from it.
+
+use crate::config::TcpConfig;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Applies `config` to `stream`.
+pub fn configure(stream: &TcpStream, config: &TcpConfig) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+
+    socket.set_tcp_nodelay(config.nodelay)?;
+
+    if config.keepalive_seconds > 0 {
+        socket.set_tcp_keepalive(&keepalive(config))?;
+    }
+
+    if let Some(seconds) = config.linger_seconds {
+        socket.set_linger(Some(Duration::from_secs(seconds)))?;
+    }
+
+    Ok(())
+}
+
+fn keepalive(config: &TcpConfig) -> TcpKeepalive {
+    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(config.keepalive_seconds));
+
+    // not every platform lets the probes be tuned
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let keepalive = match config.keepalive_interval_seconds {
+        0 => keepalive,
+        seconds => keepalive.with_interval(Duration::from_secs(seconds)),
+    };
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let keepalive = match config.keepalive_retries {
+        0 => keepalive,
+        retries => keepalive.with_retries(retries),
+    };
+
+    keepalive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn options_reach_the_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let config = TcpConfig {
+            nodelay: true,
+            keepalive_seconds: 30,
+            keepalive_interval_seconds: 5,
+            keepalive_retries: 3,
+            linger_seconds: Some(2),
+        };
+        configure(&stream, &config).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(2)));
+
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                socket.tcp_keepalive_time().unwrap(),
+                Duration::from_secs(30)
+            );
+            assert_eq!(
+                socket.tcp_keepalive_interval().unwrap(),
+                Duration::from_secs(5)
+            );
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+        }
+    }
+}
@@ -0,0 +1,696 @@
+use crate::caps::Caps;
+use crate::compression::{self, Compression, Mode};
+use crate::config::{NoiseConfig, TcpConfig};
+use crate::tcp;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+    ReadHalf, WriteHalf,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// the largest noise message, and the tag every transport message carries
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// A connected byte stream from any kind of listener. The server only
+/// needs to read, write, and know who is on the other end.
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Applies the `[tcp]` options, which streams that aren't plain TCP
+    /// have no use for.
+    fn configure(&self, _config: &TcpConfig) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PeerStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn configure(&self, config: &TcpConfig) -> io::Result<()> {
+        tcp::configure(self, config)
+    }
+}
+
+impl PeerStream for Box<dyn PeerStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn configure(&self, config: &TcpConfig) -> io::Result<()> {
+        (**self).configure(config)
+    }
+}
+
+/// Pairs a stream with the address to know its peer by, for streams that
+/// don't carry one themselves, such as TLS or WebSocket wrappers, or Unix
+/// sockets given a made-up address.
+pub struct WithAddr<S> {
+    stream: S,
+    addr: SocketAddr,
+}
+
+impl<S> WithAddr<S> {
+    pub fn new(stream: S, addr: SocketAddr) -> Self {
+        WithAddr { stream, addr }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static> PeerStream for WithAddr<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WithAddr<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WithAddr<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Something connections are accepted from. [`Server`](crate::Server) runs
+/// the same command loop whatever the transport is.
+pub trait Transport: Send + Sync + 'static {
+    type Stream: PeerStream;
+
+    fn accept(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<TcpStream> {
+        let (stream, _) = TcpListener::accept(self).await?;
+        Ok(stream)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+// buffered so the first byte can be looked at without taking it
+type Stream = BufReader<Box<dyn PeerStream>>;
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(NOISE_PARAMS.parse().expect("valid noise params"))
+}
+
+/// Makes a fresh static keypair for `[noise] private_key`, as base64
+/// private and public keys.
+pub fn generate_keypair() -> io::Result<(String, String)> {
+    let keypair = builder().generate_keypair().map_err(noise_error)?;
+
+    Ok((
+        BASE64.encode(keypair.private),
+        BASE64.encode(keypair.public),
+    ))
+}
+
+fn decode(key: &str) -> io::Result<Vec<u8>> {
+    BASE64
+        .decode(key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_keys(keys: &[String]) -> io::Result<Vec<Vec<u8>>> {
+    keys.iter().map(|k| decode(k)).collect()
+}
+
+/// The server's static Noise key and who may connect with it.
+pub struct Noise {
+    config: NoiseConfig,
+    private_key: Vec<u8>,
+    // only set when the key was generated at startup
+    pub generated_public_key: Option<String>,
+    // swapped on reload, only later handshakes see the change
+    allowed_keys: std::sync::RwLock<Vec<Vec<u8>>>,
+}
+
+impl Noise {
+    pub fn load(config: &NoiseConfig) -> io::Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let (private_key, generated_public_key) = match &config.private_key {
+            Some(key) => (decode(key)?, None),
+            None => {
+                let keypair = builder().generate_keypair().map_err(noise_error)?;
+                (keypair.private, Some(BASE64.encode(keypair.public)))
+            }
+        };
+
+        if private_key.len() != KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "noise private_key is not a 32 byte key",
+            ));
+        }
+
+        let allowed_keys = decode_keys(&config.allowed_keys)?;
+
+        Ok(Some(Noise {
+            config: config.clone(),
+            private_key,
+            generated_public_key,
+            allowed_keys: std::sync::RwLock::new(allowed_keys),
+        }))
+    }
+
+    /// Lets only `keys` connect from the next handshake on, anyone if
+    /// empty. Nothing changes if one of them isn't base64.
+    pub fn set_allowed_keys(&self, keys: &[String]) -> io::Result<()> {
+        let keys = decode_keys(keys)?;
+        *self.allowed_keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+
+        Ok(())
+    }
+
+    // XX as the responder: -> e, <- e ee s es, -> s se
+    async fn handshake(&self, stream: &mut Stream) -> io::Result<StatelessTransportState> {
+        let mut handshake: HandshakeState = builder()
+            .local_private_key(&self.private_key)
+            .map_err(noise_error)?
+            .build_responder()
+            .map_err(noise_error)?;
+
+        let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+
+        let message = read_frame(stream)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        handshake
+            .read_message(&message, &mut buffer)
+            .map_err(noise_error)?;
+
+        let n = handshake
+            .write_message(&[], &mut buffer)
+            .map_err(noise_error)?;
+        write_frame(stream, &buffer[..n]).await?;
+
+        let message = read_frame(stream)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        handshake
+            .read_message(&message, &mut buffer)
+            .map_err(noise_error)?;
+
+        let allowed = {
+            let allowed_keys = self.allowed_keys.read().unwrap_or_else(|e| e.into_inner());
+            let remote = handshake.get_remote_static().unwrap_or_default();
+
+            allowed_keys.is_empty() || allowed_keys.iter().any(|k| k.as_slice() == remote)
+        };
+
+        if !allowed {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "static key not allowed",
+            ));
+        }
+
+        handshake
+            .into_stateless_transport_mode()
+            .map_err(noise_error)
+    }
+}
+
+// noise messages go over tcp with a two byte big-endian length in front;
+// None when the stream ends cleanly before a new frame
+async fn read_frame<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame).await?;
+
+    Ok(Some(frame))
+}
+
+async fn write_frame<W: AsyncWriteExt + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    let mut data = Vec::with_capacity(frame.len() + 2);
+    data.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+    data.extend_from_slice(frame);
+
+    writer.write_all(&data).await
+}
+
+// the bytes on the wire, plain or encrypted
+enum RawReader {
+    Plain(ReadHalf<Stream>),
+    Noise {
+        half: ReadHalf<Stream>,
+        session: Arc<StatelessTransportState>,
+        nonce: u64,
+        // decrypted bytes not handed out yet
+        plaintext: Vec<u8>,
+    },
+}
+
+impl RawReader {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (half, session, nonce, plaintext) = match self {
+            RawReader::Plain(half) => return half.read(buf).await,
+            RawReader::Noise {
+                half,
+                session,
+                nonce,
+                plaintext,
+            } => (half, session, nonce, plaintext),
+        };
+
+        // empty messages are allowed, keep going until there is something
+        while plaintext.is_empty() {
+            let Some(message) = read_frame(half).await? else {
+                return Ok(0);
+            };
+
+            let mut decrypted = vec![0; message.len()];
+            let n = session
+                .read_message(*nonce, &message, &mut decrypted)
+                .map_err(noise_error)?;
+            *nonce += 1;
+
+            decrypted.truncate(n);
+            *plaintext = decrypted;
+        }
+
+        let n = buf.len().min(plaintext.len());
+        buf[..n].copy_from_slice(&plaintext[..n]);
+        plaintext.drain(..n);
+
+        Ok(n)
+    }
+}
+
+/// The read side of a connection, decrypted and decompressed. The command
+/// loop only ever sees plaintext.
+pub struct Reader {
+    raw: RawReader,
+    compression: Mode,
+    // compressed frames not complete yet
+    encoded: Vec<u8>,
+    // what came out of them, not handed out yet
+    decoded: Vec<u8>,
+}
+
+impl Reader {
+    fn new(raw: RawReader, compression: Mode) -> Self {
+        Reader {
+            raw,
+            compression,
+            encoded: Vec::new(),
+            decoded: Vec::new(),
+        }
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.decoded.is_empty() {
+                let n = buf.len().min(self.decoded.len());
+                buf[..n].copy_from_slice(&self.decoded[..n]);
+                self.decoded.drain(..n);
+
+                return Ok(n);
+            }
+
+            let compression = self.compression.get();
+
+            if compression == Compression::None {
+                // whatever was read past a `CAP compress=none` is plain
+                if self.encoded.is_empty() {
+                    return self.raw.read(buf).await;
+                }
+
+                std::mem::swap(&mut self.encoded, &mut self.decoded);
+                continue;
+            }
+
+            compression::decode(compression, &mut self.encoded, &mut self.decoded)?;
+
+            if !self.decoded.is_empty() {
+                continue;
+            }
+
+            let mut chunk = [0; 4096];
+            let n = self.raw.read(&mut chunk).await?;
+
+            // a partial frame at the end is lost with the connection
+            if n == 0 {
+                return Ok(0);
+            }
+
+            self.encoded.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+enum RawWriter {
+    Plain(WriteHalf<Stream>),
+    Noise {
+        half: WriteHalf<Stream>,
+        session: Arc<StatelessTransportState>,
+        nonce: u64,
+    },
+}
+
+impl RawWriter {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let (half, session, nonce) = match self {
+            RawWriter::Plain(half) => return half.write_all(data).await,
+            RawWriter::Noise {
+                half,
+                session,
+                nonce,
+            } => (half, session, nonce),
+        };
+
+        for chunk in data.chunks(MAX_NOISE_MESSAGE - TAG_LEN) {
+            let mut message = vec![0; chunk.len() + TAG_LEN];
+            let n = session
+                .write_message(*nonce, chunk, &mut message)
+                .map_err(noise_error)?;
+            *nonce += 1;
+
+            write_frame(half, &message[..n]).await?;
+        }
+
+        Ok(())
+    }
+
+    fn half(&mut self) -> &mut WriteHalf<Stream> {
+        match self {
+            RawWriter::Plain(half) | RawWriter::Noise { half, .. } => half,
+        }
+    }
+}
+
+/// The write side of a connection, compressing once `CAP compress` has
+/// turned it on and encrypting when it is a Noise session.
+pub struct Writer {
+    raw: RawWriter,
+    compression: Mode,
+    caps: Caps,
+    // writes smaller than this are framed but not compressed
+    min_size: usize,
+}
+
+impl Writer {
+    fn new(raw: RawWriter, compression: Mode) -> Self {
+        Writer {
+            raw,
+            compression,
+            caps: Caps::default(),
+            min_size: 0,
+        }
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.compression.get() {
+            Compression::None => self.raw.write_all(data).await,
+            compression => {
+                let frames = compression::encode(compression, data, self.min_size)?;
+                self.raw.write_all(&frames).await
+            }
+        }
+    }
+
+    /// Writes whole protocol lines, the way the connection's `CAP`s say.
+    pub async fn write_lines(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = self.caps.render(data);
+        self.write_all(&data).await
+    }
+
+    /// What the connection turned on with `CAP`.
+    pub fn caps(&self) -> &Caps {
+        &self.caps
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression.get()
+    }
+
+    /// Switches compression for both directions of the connection, from
+    /// the next write and the next read on.
+    pub fn set_compression(&mut self, compression: Compression, min_size: usize) {
+        self.compression.set(compression);
+        self.min_size = min_size;
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.raw.half().flush().await
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.raw.half().shutdown().await
+    }
+}
+
+/// Turns an accepted stream into a reader and writer. With `[noise]`
+/// enabled, a connection whose first byte is zero is taken as the start of
+/// a handshake, since a handshake message begins with its two byte length
+/// and no command line ever starts with a zero byte. Waiting for that byte
+/// and the handshake after it gives up after `[noise]
+/// handshake_timeout_seconds`.
+pub async fn accept<S: PeerStream>(
+    stream: S,
+    noise: Option<&Noise>,
+) -> io::Result<(Reader, Writer)> {
+    let stream: Stream = BufReader::new(Box::new(stream));
+
+    let Some(noise) = noise else {
+        return Ok(plain(stream));
+    };
+
+    let timeout = Duration::from_secs(noise.config.handshake_timeout_seconds);
+
+    tokio::time::timeout(timeout, accept_noise(stream, noise))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no handshake in time"))?
+}
+
+async fn accept_noise(mut stream: Stream, noise: &Noise) -> io::Result<(Reader, Writer)> {
+    let is_handshake = stream.fill_buf().await?.first() == Some(&0);
+
+    if !is_handshake {
+        if noise.config.required {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "plaintext connections are not allowed",
+            ));
+        }
+
+        return Ok(plain(stream));
+    }
+
+    let session = Arc::new(noise.handshake(&mut stream).await?);
+    let (reader, writer) = tokio::io::split(stream);
+
+    let reader = RawReader::Noise {
+        half: reader,
+        session: session.clone(),
+        nonce: 0,
+        plaintext: Vec::new(),
+    };
+    let writer = RawWriter::Noise {
+        half: writer,
+        session,
+        nonce: 0,
+    };
+
+    Ok(pair(reader, writer))
+}
+
+fn plain(stream: Stream) -> (Reader, Writer) {
+    let (reader, writer) = tokio::io::split(stream);
+    pair(RawReader::Plain(reader), RawWriter::Plain(writer))
+}
+
+fn pair(reader: RawReader, writer: RawWriter) -> (Reader, Writer) {
+    let compression = Mode::default();
+
+    (
+        Reader::new(reader, compression.clone()),
+        Writer::new(writer, compression),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // connects, runs the initiator side of XX and returns the session
+    async fn noise_client(
+        addr: std::net::SocketAddr,
+        private_key: &[u8],
+    ) -> io::Result<(TcpStream, snow::TransportState)> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut handshake = builder()
+            .local_private_key(private_key)
+            .map_err(noise_error)?
+            .build_initiator()
+            .map_err(noise_error)?;
+        let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+
+        let n = handshake
+            .write_message(&[], &mut buffer)
+            .map_err(noise_error)?;
+        write_frame(&mut stream, &buffer[..n]).await?;
+
+        let message = read_frame(&mut stream)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        handshake
+            .read_message(&message, &mut buffer)
+            .map_err(noise_error)?;
+
+        let n = handshake
+            .write_message(&[], &mut buffer)
+            .map_err(noise_error)?;
+        write_frame(&mut stream, &buffer[..n]).await?;
+
+        let session = handshake.into_transport_mode().map_err(noise_error)?;
+        Ok((stream, session))
+    }
+
+    async fn server(config: NoiseConfig) -> (TcpListener, Noise) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let noise = Noise::load(&config).unwrap().unwrap();
+
+        (listener, noise)
+    }
+
+    fn enabled() -> NoiseConfig {
+        NoiseConfig {
+            enabled: true,
+            ..NoiseConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn noise_session_round_trips() {
+        let (listener, noise) = server(enabled()).await;
+        let addr = listener.local_addr().unwrap();
+        let key = builder().generate_keypair().unwrap();
+
+        let client = tokio::spawn(async move {
+            let (mut stream, mut session) = noise_client(addr, &key.private).await.unwrap();
+            let mut message = vec![0; 64];
+
+            let n = session.write_message(b"REG alice\n", &mut message).unwrap();
+            write_frame(&mut stream, &message[..n]).await.unwrap();
+
+            let reply = read_frame(&mut stream).await.unwrap().unwrap();
+            let n = session.read_message(&reply, &mut message).unwrap();
+            message.truncate(n);
+            message
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = accept(stream, Some(&noise)).await.unwrap();
+
+        let mut line = [0; 64];
+        let n = reader.read(&mut line).await.unwrap();
+        assert_eq!(&line[..n], b"REG alice\n");
+
+        writer.write_all(b"OK\n").await.unwrap();
+        assert_eq!(client.await.unwrap(), b"OK\n");
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_refused_when_noise_is_required() {
+        let (listener, noise) = server(NoiseConfig {
+            required: true,
+            ..enabled()
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"HELLO\n").await.unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(accept(stream, Some(&noise)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_static_keys_are_refused() {
+        let allowed = builder().generate_keypair().unwrap();
+        let (listener, noise) = server(NoiseConfig {
+            allowed_keys: vec![BASE64.encode(&allowed.public)],
+            ..enabled()
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+        let stranger = builder().generate_keypair().unwrap();
+
+        let client = tokio::spawn(async move { noise_client(addr, &stranger.private).await });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(accept(stream, Some(&noise)).await.is_err());
+
+        drop(client.await);
+    }
+
+    #[tokio::test]
+    async fn silent_connections_time_out() {
+        let (listener, noise) = server(NoiseConfig {
+            handshake_timeout_seconds: 1,
+            ..enabled()
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let error = accept(stream, Some(&noise)).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,54 @@
+mod support;
+
+use p2p_rs::config::Config;
+use std::path::Path;
+use std::time::Duration;
+use support::TestServer;
+
+// every event written so far, across however many files
+fn events(directory: &Path) -> Vec<serde_json::Value> {
+    let mut events = Vec::new();
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+        let contents = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+
+        events.extend(
+            contents
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()),
+        );
+    }
+
+    events
+}
+
+#[tokio::test]
+async fn registrations_and_disconnects_are_audited() {
+    let directory = std::env::temp_dir().join(format!("p2p-audit-test-{}", std::process::id()));
+
+    let mut config = Config::default();
+    config.audit.path = Some(directory.join("audit.jsonl"));
+    let server = TestServer::with_config(config).await;
+
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    alice.close().await;
+
+    // the disconnect is noticed on the server's own time
+    let mut seen = Vec::new();
+
+    for _ in 0..50 {
+        seen = events(&directory);
+
+        if seen.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    let kinds: Vec<&str> = seen.iter().map(|e| e["event"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["register", "disconnect"]);
+    assert!(seen.iter().all(|e| e["nickname"] == "alice"));
+    assert_eq!(seen[0]["addr"], seen[1]["addr"]);
+}
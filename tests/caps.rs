@@ -0,0 +1,73 @@
+mod support;
+
+use support::TestServer;
+
+#[tokio::test]
+async fn capabilities_are_listed_and_requested() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+
+    alice.send("CAP LS").await;
+    alice
+        .expect("CAP LS compress=zstd,gzip json acks presence")
+        .await;
+    alice.expect("OK").await;
+
+    alice.send("CAP LIST").await;
+    alice.expect("CAP LIST acks presence").await;
+    alice.expect("OK").await;
+
+    // all or nothing
+    assert_eq!(alice.request("CAP REQ -acks sasl").await, "ERR BAD_CAP");
+    assert_eq!(alice.request("CAP REQ").await, "ERR BAD_CAP");
+    assert_eq!(alice.request("CAP REQ -acks -presence").await, "OK");
+
+    alice.send("CAP LIST").await;
+    alice.expect("CAP LIST").await;
+    alice.expect("OK").await;
+}
+
+#[tokio::test]
+async fn turned_off_notices_never_arrive() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+    assert_eq!(alice.request("CAP REQ -acks").await, "OK");
+
+    assert_eq!(alice.request("MSGID a1 bob hello").await, "OK");
+    bob.expect("MSGID 1 alice hello").await;
+    assert_eq!(bob.request("ACK 1").await, "OK");
+
+    // the ACK would have come before this
+    assert_eq!(bob.request("MSG alice done").await, "OK");
+    alice.expect("MSG bob done").await;
+}
+
+#[tokio::test]
+async fn json_lines_both_ways() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    // the OK is the last plain line
+    assert_eq!(bob.request("CAP REQ json").await, "OK");
+
+    assert_eq!(alice.request("MSG bob hello  there").await, "OK");
+    bob.expect(r#"{"command":"MSG","params":["alice"],"text":"hello  there"}"#)
+        .await;
+
+    bob.send(r#"{"command":"MSG","params":["alice"],"text":"hi"}"#)
+        .await;
+    bob.expect(r#"{"command":"OK","params":[]}"#).await;
+    alice.expect("MSG bob hi").await;
+
+    bob.send("MSG alice hi").await;
+    bob.expect(r#"{"command":"ERR","params":["BAD_JSON"]}"#)
+        .await;
+}
@@ -0,0 +1,117 @@
+mod support;
+
+use p2p_rs::cluster::{MemoryStore, Store};
+use p2p_rs::config::{ClusterConfig, Config};
+use p2p_rs::Server;
+use std::time::Duration;
+use support::TestServer;
+
+// a node of the cluster around `store`
+async fn node(name: &str, store: &MemoryStore) -> TestServer {
+    let config = Config {
+        cluster: ClusterConfig {
+            enabled: true,
+            node: Some(name.to_string()),
+            ..ClusterConfig::default()
+        },
+        ..Config::default()
+    };
+
+    TestServer::with_builder(
+        Server::builder()
+            .config(config)
+            .cluster_store(store.clone()),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn nodes_share_nicknames_and_messages() {
+    let store = MemoryStore::default();
+    let a = node("a", &store).await;
+    let b = node("b", &store).await;
+
+    let mut alice = a.connect().await;
+    let mut bob = b.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    // a nickname is taken on every node
+    let mut other = b.connect().await;
+    assert_eq!(other.request("REG alice").await, "ERR TKN");
+
+    assert_eq!(bob.request("MSG alice hi from b").await, "OK");
+    alice.expect("MSG bob hi from b").await;
+
+    assert_eq!(alice.request("STATUS away lunch").await, "OK");
+    bob.send("WHOIS alice").await;
+    bob.expect("WHOIS alice node a").await;
+    bob.expect("WHOIS alice status away").await;
+    bob.expect("OK").await;
+
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+    assert_eq!(bob.request("JOIN #lobby").await, "OK");
+    assert_eq!(bob.request("RMSG #lobby hello all").await, "OK");
+    alice.expect("RMSG #lobby bob hello all").await;
+
+    // leaving gives the nickname back to the whole cluster
+    alice.close().await;
+
+    for _ in 0..100 {
+        if other.request("REG alice").await == "OK" {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("alice was never released");
+}
+
+#[tokio::test]
+async fn a_lost_claim_evicts_the_local_holder() {
+    let store = MemoryStore::default();
+    let config = Config {
+        cluster: ClusterConfig {
+            enabled: true,
+            node: Some("a".to_string()),
+            claim_seconds: 1,
+            ..ClusterConfig::default()
+        },
+        ..Config::default()
+    };
+    let a = TestServer::with_builder(
+        Server::builder()
+            .config(config)
+            .cluster_store(store.clone()),
+    )
+    .await;
+
+    let mut alice = a.connect().await;
+    alice.register("alice").await;
+
+    // as if the claim lapsed and another node took it
+    store.release("alice", "a").await.unwrap();
+    assert!(store
+        .claim("alice", "b", Duration::from_secs(60))
+        .await
+        .unwrap());
+
+    alice.skip_to("ERR NICK_TAKEN").await;
+    assert_eq!(alice.recv().await, None);
+    assert_eq!(store.owner("alice").await.unwrap().as_deref(), Some("b"));
+}
+
+#[tokio::test]
+async fn shutting_down_releases_claims() {
+    let store = MemoryStore::default();
+    let a = node("a", &store).await;
+
+    let mut alice = a.connect().await;
+    alice.register("alice").await;
+    assert_eq!(store.owner("alice").await.unwrap().as_deref(), Some("a"));
+
+    a.shutdown();
+    alice.skip_to("ERR SHUTDOWN").await;
+    assert_eq!(store.owner("alice").await.unwrap(), None);
+}
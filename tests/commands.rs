@@ -0,0 +1,47 @@
+mod support;
+
+use support::TestServer;
+
+#[tokio::test]
+async fn payloads_arrive_as_sent() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request(r#"MSG bob  "hi"   there"#).await, "OK");
+    bob.expect(r#"MSG alice "hi"   there"#).await;
+}
+
+#[tokio::test]
+async fn quoted_arguments_are_passed_on_quoted() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(
+        alice.request(r#"FILE_OFFER bob "my notes.txt" 12"#).await,
+        "OK 1"
+    );
+    bob.expect(r#"FILE_OFFER 1 alice "my notes.txt" 12"#).await;
+}
+
+#[tokio::test]
+async fn malformed_lines_say_what_is_wrong() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+
+    assert_eq!(alice.request(r#"REG "alice"#).await, "ERR BAD_QUOTE");
+    assert_eq!(alice.request(r#"REG "alice smith""#).await, "ERR BAD_NICK");
+    alice.register("alice").await;
+
+    assert_eq!(alice.request("MSG bob").await, "ERR NIL_MSG");
+    assert_eq!(alice.request("JOIN #a #b").await, "ERR TOO_MANY_ARGS");
+    assert_eq!(alice.request(r##"JOIN "#a b""##).await, "ERR BAD_ROOM");
+    assert_eq!(alice.request("NOPE").await, "ERR UNK_CMD");
+}
@@ -0,0 +1,58 @@
+mod support;
+
+use p2p_rs::config::{CompressionConfig, Config};
+use support::TestServer;
+
+#[tokio::test]
+async fn compressed_and_plain_connections_talk() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut carol = server.connect().await;
+
+    // before and after registering both work
+    alice.compress("zstd").await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+    bob.compress("gzip").await;
+    carol.register("carol").await;
+
+    // big enough to be compressed on the way in and out, short of TOO_LONG
+    let big = "all work and no play ".repeat(40);
+    let big = big.trim_end();
+
+    assert_eq!(alice.request(&format!("MSG bob {}", big)).await, "OK");
+    bob.expect(&format!("MSG alice {}", big)).await;
+
+    assert_eq!(bob.request(&format!("MSG carol {}", big)).await, "OK");
+    carol.expect(&format!("MSG bob {}", big)).await;
+
+    assert_eq!(carol.request("MSG alice hi").await, "OK");
+    alice.expect("MSG carol hi").await;
+
+    // and it can be turned off again
+    alice.compress("none").await;
+    assert_eq!(alice.request("MSG bob bye").await, "OK");
+    bob.expect("MSG alice bye").await;
+}
+
+#[tokio::test]
+async fn unknown_or_disabled_compression_is_refused() {
+    let server = TestServer::with_config(Config {
+        compression: CompressionConfig {
+            enabled: false,
+            ..CompressionConfig::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let mut alice = server.connect().await;
+
+    assert_eq!(alice.request("CAP compress=lz4").await, "ERR BAD_CAP");
+    assert_eq!(alice.request("CAP sasl").await, "ERR BAD_CAP");
+    assert_eq!(alice.request("CAP").await, "ERR NIL_CAP");
+    assert_eq!(alice.request("CAP compress=zstd").await, "ERR NO_COMPRESS");
+
+    // still plain
+    alice.register("alice").await;
+}
@@ -0,0 +1,170 @@
+mod support;
+
+use p2p_rs::config::{Config, FederationConfig};
+use std::time::Duration;
+use support::{TestClient, TestServer};
+
+const SECRET: &str = "s3cret";
+
+async fn linked_to(servers: &[&TestServer]) -> TestServer {
+    TestServer::with_config(Config {
+        federation: FederationConfig {
+            secret: Some(SECRET.to_string()),
+            links: servers.iter().map(|s| s.addr.to_string()).collect(),
+            retry_seconds: 1,
+            ..FederationConfig::default()
+        },
+        ..Config::default()
+    })
+    .await
+}
+
+// sends until the nickname is reachable, links come up in the background
+async fn send_until_delivered(client: &mut TestClient, line: &str) {
+    for _ in 0..100 {
+        if client.request(line).await == "OK" {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("{:?} was never delivered", line);
+}
+
+#[tokio::test]
+async fn messages_cross_the_link() {
+    let a = linked_to(&[]).await;
+    let b = linked_to(&[&a]).await;
+
+    let mut alice = a.connect().await;
+    let mut bob = b.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    send_until_delivered(&mut bob, "MSG alice hi from b").await;
+    alice.expect("MSG bob hi from b").await;
+
+    assert_eq!(alice.request("MSG bob hi from a").await, "OK");
+    bob.expect("MSG alice hi from a").await;
+
+    // nicknames are taken across the link
+    let mut other = a.connect().await;
+    assert_eq!(other.request("REG bob").await, "ERR TKN");
+
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+    assert_eq!(bob.request("JOIN #lobby").await, "OK");
+    assert_eq!(bob.request("RMSG #lobby hello all").await, "OK");
+    alice.expect("RMSG #lobby bob hello all").await;
+
+    // and given back when their owner leaves
+    bob.close().await;
+
+    for _ in 0..100 {
+        if other.request("REG bob").await == "OK" {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("bob was never released");
+}
+
+#[tokio::test]
+async fn loops_deliver_once() {
+    // a triangle, every line has two ways to go
+    let a = linked_to(&[]).await;
+    let b = linked_to(&[&a]).await;
+    let c = linked_to(&[&a, &b]).await;
+
+    let mut alice = a.connect().await;
+    let mut carol = c.connect().await;
+    alice.register("alice").await;
+    carol.register("carol").await;
+
+    send_until_delivered(&mut carol, "MSG alice ping").await;
+    alice.expect("MSG carol ping").await;
+
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+    assert_eq!(carol.request("JOIN #lobby").await, "OK");
+
+    // wait for the whole triangle to be up before counting
+    let mut bob = b.connect().await;
+    bob.register("bob").await;
+    send_until_delivered(&mut carol, "MSG bob ping").await;
+    bob.expect("MSG carol ping").await;
+    send_until_delivered(&mut bob, "MSG alice ping").await;
+    alice.expect("MSG bob ping").await;
+
+    assert_eq!(carol.request("RMSG #lobby once").await, "OK");
+    assert_eq!(carol.request("MSG alice done").await, "OK");
+
+    alice.expect("RMSG #lobby carol once").await;
+    alice.expect("MSG carol done").await;
+}
+
+#[tokio::test]
+async fn links_need_the_secret() {
+    let a = linked_to(&[]).await;
+    let mut intruder = a.connect().await;
+
+    assert_eq!(intruder.request("LINK evil wrong").await, "ERR AUTH");
+    assert_eq!(intruder.recv().await, None);
+
+    let mut server = a.connect().await;
+    let addr = a.addr.to_string();
+    assert_eq!(
+        server.request(&format!("LINK {} {}", addr, SECRET)).await,
+        "ERR LOOP"
+    );
+
+    let mut server = a.connect().await;
+    assert_eq!(
+        server.request(&format!("LINK other {}", SECRET)).await,
+        format!("OK {}", addr)
+    );
+}
+
+#[tokio::test]
+async fn links_that_stop_reading_are_cut() {
+    let mut config = Config {
+        federation: FederationConfig {
+            secret: Some(SECRET.to_string()),
+            ..FederationConfig::default()
+        },
+        ..Config::default()
+    };
+    config.outbound.write_timeout_seconds = 1;
+    let a = TestServer::with_config(config).await;
+
+    let mut stalled = a.connect().await;
+    assert!(stalled
+        .request(&format!("LINK stalled {}", SECRET))
+        .await
+        .starts_with("OK "));
+
+    let mut alice = a.connect().await;
+    alice.register("alice").await;
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+
+    // room messages go to every link, until this one's buffers are full
+    let payload = "x".repeat(900);
+    let line = format!("RMSG #lobby {}", payload);
+
+    for _ in 0..20_000 {
+        let started = std::time::Instant::now();
+        assert_eq!(alice.request(&line).await, "OK");
+
+        if started.elapsed() >= Duration::from_millis(900) {
+            break;
+        }
+    }
+
+    // what did make it out is still there to read, then the link is gone
+    while stalled.recv().await.is_some() {}
+
+    let started = std::time::Instant::now();
+    assert_eq!(alice.request(&line).await, "OK");
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
@@ -0,0 +1,143 @@
+mod support;
+
+use p2p_rs::config::{BandwidthConfig, Config, FilesConfig};
+use support::{TestClient, TestServer};
+
+// alice offering bob a five byte file, and what bob got to accept it
+async fn offered(server: &TestServer) -> (TestClient, TestClient) {
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request("FILE_OFFER bob notes.txt 5").await, "OK 1");
+    bob.expect("FILE_OFFER 1 alice notes.txt 5").await;
+
+    (alice, bob)
+}
+
+// offered and accepted, with the token alice was told
+async fn accepted(server: &TestServer) -> (TestClient, TestClient) {
+    let (mut alice, mut bob) = offered(server).await;
+
+    let accepted = bob.request("FILE_ACCEPT 1").await;
+    let token = accepted.strip_prefix("OK ").unwrap();
+    alice.expect(&format!("FILE_ACCEPT 1 {}", token)).await;
+
+    (alice, bob)
+}
+
+#[tokio::test]
+async fn accepted_files_are_relayed_until_done() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = accepted(&server).await;
+
+    // "hello"
+    assert_eq!(alice.request("FILE_CHUNK 1 aGVsbG8=").await, "OK");
+    bob.expect("FILE_CHUNK 1 aGVsbG8=").await;
+
+    assert_eq!(bob.request("FILE_ACK 1").await, "OK");
+    bob.expect("FILE_DONE 1").await;
+    alice.expect("FILE_ACK 1").await;
+    alice.expect("FILE_DONE 1").await;
+}
+
+#[tokio::test]
+async fn rejected_files_are_forgotten() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = offered(&server).await;
+
+    assert_eq!(bob.request("FILE_REJECT 1").await, "OK");
+    alice.expect("FILE_REJECT 1").await;
+
+    assert_eq!(alice.request("FILE_CHUNK 1 aGVsbG8=").await, "ERR BAD_ID");
+    assert_eq!(bob.request("FILE_ACCEPT 1").await, "ERR BAD_ID");
+}
+
+#[tokio::test]
+async fn chunks_wait_for_a_free_window_slot() {
+    let server = TestServer::with_config(Config {
+        files: FilesConfig {
+            window: 1,
+            ..FilesConfig::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let (mut alice, mut bob) = accepted(&server).await;
+
+    // "he", then "llo" before and after bob acks the first
+    assert_eq!(alice.request("FILE_CHUNK 1 aGU=").await, "OK");
+    bob.expect("FILE_CHUNK 1 aGU=").await;
+    assert_eq!(alice.request("FILE_CHUNK 1 bGxv").await, "ERR FILE_WINDOW");
+
+    assert_eq!(bob.request("FILE_ACK 1").await, "OK");
+    alice.expect("FILE_ACK 1").await;
+    assert_eq!(alice.request("FILE_CHUNK 1 bGxv").await, "OK");
+    bob.expect("FILE_CHUNK 1 bGxv").await;
+}
+
+#[tokio::test]
+async fn throttled_chunks_hold_up_no_commands() {
+    let server = TestServer::with_config(Config {
+        bandwidth: BandwidthConfig {
+            connection_bytes_per_second: 1,
+            burst_bytes: 8,
+            ..BandwidthConfig::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request("FILE_OFFER bob notes.txt 11").await, "OK 1");
+    bob.expect("FILE_OFFER 1 alice notes.txt 11").await;
+    assert!(bob.request("FILE_ACCEPT 1").await.starts_with("OK "));
+    assert!(alice.recv().await.unwrap().starts_with("FILE_ACCEPT 1 "));
+
+    // "hello" fits the burst, "hello!" waits out the next eight seconds
+    assert_eq!(alice.request("FILE_CHUNK 1 aGVsbG8=").await, "OK");
+    bob.expect("FILE_CHUNK 1 aGVsbG8=").await;
+    assert_eq!(alice.request("FILE_CHUNK 1 aGVsbG8h").await, "OK");
+
+    assert!(alice.request("HELLO").await.starts_with("HELLO "));
+    assert_eq!(alice.request("FILE_CANCEL 1").await, "OK");
+    bob.expect("FILE_CANCEL 1").await;
+}
+
+#[tokio::test]
+async fn chunks_past_the_offered_size_are_refused() {
+    let server = TestServer::start().await;
+    let (mut alice, _bob) = accepted(&server).await;
+
+    // "hello!" is one byte more than offered
+    assert_eq!(
+        alice.request("FILE_CHUNK 1 aGVsbG8h").await,
+        "ERR FILE_OVERFLOW"
+    );
+}
+
+#[tokio::test]
+async fn resuming_takes_the_token_from_the_accept() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = offered(&server).await;
+
+    let accepted = bob.request("FILE_ACCEPT 1").await;
+    let token = accepted.strip_prefix("OK ").unwrap().to_string();
+    alice.expect(&format!("FILE_ACCEPT 1 {}", token)).await;
+
+    bob.close().await;
+    alice.expect("FILE_INTERRUPTED 1").await;
+
+    // taking the nickname isn't enough to take the transfer
+    let mut bob = server.connect().await;
+    bob.register("bob").await;
+    assert_eq!(bob.request("FILE_RESUME 1 0 wrong").await, "ERR BAD_ID");
+
+    let resume = format!("FILE_RESUME 1 0 {}", token);
+    assert_eq!(bob.request(&resume).await, "OK");
+    alice.expect("FILE_RESUME 1 0").await;
+}
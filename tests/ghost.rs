@@ -0,0 +1,111 @@
+mod support;
+
+use async_trait::async_trait;
+use p2p_rs::config::Config;
+use p2p_rs::hooks::{Hooks, Verdict};
+use p2p_rs::Server;
+use std::net::SocketAddr;
+use support::TestServer;
+
+fn with_users() -> Config {
+    let mut config = Config::default();
+    config.auth.enabled = true;
+    config
+        .auth
+        .users
+        .insert("alice".to_string(), "pw".to_string());
+    config
+}
+
+#[tokio::test]
+async fn ghost_frees_a_nickname_for_its_owner() {
+    let server = TestServer::with_config(with_users()).await;
+    let mut stale = server.connect().await;
+    let mut alice = server.connect().await;
+
+    assert_eq!(stale.request("REG alice pw").await, "OK");
+    assert_eq!(alice.request("REG alice pw").await, "ERR TKN");
+
+    assert_eq!(alice.request("GHOST alice wrong").await, "ERR AUTH");
+    assert_eq!(alice.request("GHOST bob pw").await, "ERR NO_AUTH");
+    assert_eq!(alice.request("GHOST alice pw").await, "OK");
+    stale.expect("ERR GHOSTED").await;
+
+    assert_eq!(alice.request("REG alice pw").await, "OK");
+    assert_eq!(alice.request("GHOST alice pw").await, "ERR ALR_REG");
+}
+
+#[tokio::test]
+async fn ghost_needs_auth() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+
+    assert_eq!(alice.request("GHOST alice pw").await, "ERR NO_AUTH");
+}
+
+#[tokio::test]
+async fn idle_holders_give_way() {
+    let mut config = Config::default();
+    config.nicknames.idle_release_seconds = 1;
+    let server = TestServer::with_config(config).await;
+    let mut stale = server.connect().await;
+    let mut alice = server.connect().await;
+
+    stale.register("alice").await;
+    assert_eq!(alice.request("REG alice").await, "ERR TKN");
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(alice.request("REG alice").await, "OK");
+    stale.expect("ERR GHOSTED").await;
+}
+
+// lets only the first registration through
+#[derive(Default)]
+struct FirstOnly(std::sync::atomic::AtomicBool);
+
+#[async_trait]
+impl Hooks for FirstOnly {
+    async fn on_register(&self, _addr: SocketAddr, _nickname: &str) -> Verdict {
+        match self.0.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            false => Verdict::Allow,
+            true => Verdict::Deny("NOPE".to_string()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_refused_reg_leaves_the_idle_holder_be() {
+    let mut config = Config::default();
+    config.nicknames.idle_release_seconds = 1;
+    let builder = Server::builder().config(config).hooks(FirstOnly::default());
+    let server = TestServer::with_builder(builder).await;
+    let mut stale = server.connect().await;
+    let mut alice = server.connect().await;
+
+    stale.register("alice").await;
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(alice.request("REG alice").await, "ERR DENIED NOPE");
+
+    // nothing came before the reply, no ERR GHOSTED
+    assert_eq!(stale.request("REG alice").await, "ERR ALR_REG");
+}
+
+#[tokio::test]
+async fn the_shared_token_ghosts_nobody() {
+    let mut config = with_users();
+    config.auth.token = Some("shared".to_string());
+    let server = TestServer::with_config(config).await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let mut mallory = server.connect().await;
+
+    assert_eq!(alice.request("REG alice pw").await, "OK");
+    assert_eq!(bob.request("REG bob shared").await, "OK");
+
+    assert_eq!(mallory.request("GHOST alice shared").await, "ERR AUTH");
+    assert_eq!(mallory.request("GHOST bob shared").await, "ERR NO_AUTH");
+
+    // both are still registered
+    assert_eq!(alice.request("REG alice pw").await, "ERR ALR_REG");
+    assert_eq!(bob.request("REG bob shared").await, "ERR ALR_REG");
+}
@@ -0,0 +1,115 @@
+mod support;
+
+use p2p_rs::config::{Config, HistoryConfig};
+use support::{TestClient, TestServer};
+
+// `HISTORY <room> <unix time> <nick> <payload>` without its time
+async fn history_line(client: &mut TestClient) -> String {
+    let line = client.recv().await.unwrap();
+    let mut parts = line.splitn(4, ' ');
+
+    assert_eq!(parts.next(), Some("HISTORY"));
+    let room = parts.next().unwrap();
+    assert!(parts.next().unwrap().parse::<u64>().unwrap() > 0);
+
+    format!("{} {}", room, parts.next().unwrap())
+}
+
+async fn server(size: usize, replay_on_join: usize) -> TestServer {
+    TestServer::with_config(Config {
+        history: HistoryConfig {
+            size,
+            replay_on_join,
+            ..HistoryConfig::default()
+        },
+        ..Config::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn history_keeps_the_last_messages() {
+    let server = server(2, 0).await;
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+
+    for text in ["one", "two", "three"] {
+        assert_eq!(alice.request(&format!("RMSG #lobby {}", text)).await, "OK");
+    }
+
+    alice.send("HISTORY #lobby").await;
+    assert_eq!(history_line(&mut alice).await, "#lobby alice two");
+    assert_eq!(history_line(&mut alice).await, "#lobby alice three");
+    alice.expect("OK").await;
+
+    alice.send("HISTORY #lobby 1").await;
+    assert_eq!(history_line(&mut alice).await, "#lobby alice three");
+    alice.expect("OK").await;
+
+    assert_eq!(alice.request("HISTORY #lobby x").await, "ERR BAD_NUM");
+    assert_eq!(alice.request("HISTORY #elsewhere").await, "ERR NOT_IN_ROOM");
+}
+
+#[tokio::test]
+async fn joining_replays_at_most_replay_on_join() {
+    let server = server(10, 2).await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+
+    for text in ["one", "two", "three"] {
+        assert_eq!(alice.request(&format!("RMSG #lobby {}", text)).await, "OK");
+    }
+
+    assert_eq!(bob.request("JOIN #lobby").await, "OK");
+    assert_eq!(history_line(&mut bob).await, "#lobby alice two");
+    assert_eq!(history_line(&mut bob).await, "#lobby alice three");
+
+    // nothing more was replayed
+    assert_eq!(bob.request("RMSG #lobby hi").await, "OK");
+}
+
+#[tokio::test]
+async fn history_outlives_the_last_member() {
+    let server = server(10, 10).await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+    assert_eq!(alice.request("RMSG #lobby anyone?").await, "OK");
+    assert_eq!(alice.request("PART #lobby").await, "OK");
+
+    assert_eq!(bob.request("JOIN #lobby").await, "OK");
+    assert_eq!(history_line(&mut bob).await, "#lobby alice anyone?");
+}
+
+#[tokio::test]
+async fn the_quietest_room_is_forgotten_first() {
+    let server = TestServer::with_config(Config {
+        history: HistoryConfig {
+            replay_on_join: 10,
+            max_rooms: 1,
+            ..HistoryConfig::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    for room in ["#a", "#b"] {
+        assert_eq!(alice.request(&format!("JOIN {}", room)).await, "OK");
+        assert_eq!(alice.request(&format!("RMSG {} hi", room)).await, "OK");
+    }
+
+    alice.send("HISTORY #a").await;
+    alice.expect("OK").await;
+    alice.send("HISTORY #b").await;
+    assert_eq!(history_line(&mut alice).await, "#b alice hi");
+    alice.expect("OK").await;
+}
@@ -0,0 +1,27 @@
+mod support;
+
+use p2p_rs::config::Config;
+use support::{TestClient, TestServer};
+
+#[tokio::test]
+async fn every_listener_shares_one_server() {
+    let mut config = Config::default();
+    config.listen.addrs = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+
+    let server = TestServer::listening(config).await;
+    let addrs = server.addrs();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+
+    let mut alice = TestClient::connect(addrs[0]).await;
+    let mut bob = TestClient::connect(addrs[1]).await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    // one set of nicknames, whichever port they came in on
+    let mut other = TestClient::connect(addrs[1]).await;
+    assert_eq!(other.request("REG alice").await, "ERR TKN");
+    assert_eq!(alice.request("MSG bob hi").await, "OK");
+    bob.expect("MSG alice hi").await;
+}
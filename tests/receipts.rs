@@ -0,0 +1,32 @@
+mod support;
+
+use support::TestServer;
+
+#[tokio::test]
+async fn senders_learn_what_was_read() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request("MSGID a1 bob hello").await, "OK");
+    bob.expect("MSGID 1 alice hello").await;
+
+    assert_eq!(bob.request("ACK 1").await, "OK");
+    alice.expect("ACK a1 bob").await;
+
+    // only the recipient, and only once
+    assert_eq!(alice.request("READ 1").await, "ERR BAD_ID");
+    assert_eq!(bob.request("READ 1").await, "OK");
+    alice.expect("READ a1 bob").await;
+    assert_eq!(bob.request("READ 1").await, "ERR BAD_ID");
+
+    // read straight away, which acknowledges it too
+    assert_eq!(alice.request("MSGID a2 bob again").await, "OK");
+    bob.expect("MSGID 2 alice again").await;
+    assert_eq!(bob.request("READ 2").await, "OK");
+    alice.expect("ACK a2 bob").await;
+    alice.expect("READ a2 bob").await;
+}
@@ -0,0 +1,110 @@
+mod support;
+
+use support::TestServer;
+
+#[tokio::test]
+async fn registering_takes_the_nickname() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+
+    assert_eq!(alice.request("REG alice").await, "OK");
+    assert_eq!(alice.request("REG alice").await, "ERR ALR_REG");
+
+    alice.send("LIST").await;
+    alice.expect("LIST alice - online").await;
+    alice.expect("OK").await;
+}
+
+#[tokio::test]
+async fn taken_nicknames_are_refused() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut other = server.connect().await;
+
+    alice.register("alice").await;
+    assert_eq!(other.request("REG alice").await, "ERR TKN");
+
+    // the refused connection can still pick another one
+    other.register("bob").await;
+}
+
+#[tokio::test]
+async fn disconnecting_cleans_up() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+    assert_eq!(bob.request("JOIN #lobby").await, "OK");
+    alice.expect("JOIN #lobby bob").await;
+
+    alice.close().await;
+
+    // the room hears about it and nobody sees alice anymore
+    bob.expect("PART #lobby alice").await;
+    bob.send("LIST").await;
+    bob.expect("LIST bob - online").await;
+    bob.expect("OK").await;
+
+    // and the nickname is free again
+    let mut again = server.connect().await;
+    again.register("alice").await;
+}
+
+#[tokio::test]
+async fn concurrent_registrations() {
+    let server = TestServer::start().await;
+
+    let mut tasks = Vec::new();
+
+    for i in 0..20 {
+        let mut client = server.connect().await;
+
+        tasks.push(tokio::spawn(async move {
+            client.register(&format!("peer{}", i)).await;
+            client
+        }));
+    }
+
+    let mut clients = Vec::new();
+    for task in tasks {
+        clients.push(task.await.unwrap());
+    }
+
+    clients[0].send("LIST").await;
+    for _ in 0..20 {
+        assert!(clients[0].recv().await.unwrap().starts_with("LIST peer"));
+    }
+    clients[0].expect("OK").await;
+}
+
+#[tokio::test]
+async fn one_nickname_goes_to_one_connection() {
+    let server = TestServer::start().await;
+
+    let mut tasks = Vec::new();
+
+    for _ in 0..20 {
+        let mut client = server.connect().await;
+
+        tasks.push(tokio::spawn(async move {
+            let reply = client.request("REG alice").await;
+            (reply, client)
+        }));
+    }
+
+    let mut replies = Vec::new();
+    // kept open, a closed winner would give the nickname back
+    let mut clients = Vec::new();
+
+    for task in tasks {
+        let (reply, client) = task.await.unwrap();
+        replies.push(reply);
+        clients.push(client);
+    }
+
+    assert_eq!(replies.iter().filter(|r| *r == "OK").count(), 1);
+    assert!(replies.iter().all(|r| r == "OK" || r == "ERR TKN"));
+}
@@ -0,0 +1,62 @@
+mod support;
+
+use std::time::Duration;
+use support::{TestClient, TestServer};
+
+// alice opening a relay session with bob, and each side's token
+async fn opened(server: &TestServer) -> (TestClient, TestClient, String, String) {
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    let reply = alice.request("RELAY_OPEN bob").await;
+    let (id, alice_token) = reply
+        .strip_prefix("OK ")
+        .and_then(|rest| rest.split_once(' '))
+        .unwrap();
+
+    let pushed = bob.recv().await.unwrap();
+    let bob_token = pushed
+        .strip_prefix(&format!("RELAY_OPEN {} alice ", id))
+        .unwrap();
+
+    (alice, bob, alice_token.to_string(), bob_token.to_string())
+}
+
+#[tokio::test]
+async fn paired_sides_reach_each_other() {
+    let server = TestServer::start().await;
+    let (_alice, _bob, alice_token, bob_token) = opened(&server).await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.send(&format!("RELAY {}", alice_token)).await;
+    bob.send(&format!("RELAY {}", bob_token)).await;
+    alice.expect("OK").await;
+    bob.expect("OK").await;
+
+    // no longer commands, just bytes
+    alice.send("HELLO bob").await;
+    bob.expect("HELLO bob").await;
+    bob.send("HELLO alice").await;
+    alice.expect("HELLO alice").await;
+}
+
+#[tokio::test]
+async fn the_same_side_cannot_attach_twice() {
+    let server = TestServer::start().await;
+    let (_alice, _bob, alice_token, _) = opened(&server).await;
+
+    let mut first = server.connect().await;
+    first.send(&format!("RELAY {}", alice_token)).await;
+    // waiting sides hear nothing, give it time to be parked
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut second = server.connect().await;
+    let attach = format!("RELAY {}", alice_token);
+    assert_eq!(second.request(&attach).await, "ERR BAD_TOKEN");
+
+    let mut stranger = server.connect().await;
+    assert_eq!(stranger.request("RELAY nope").await, "ERR BAD_TOKEN");
+}
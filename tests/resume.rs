@@ -0,0 +1,59 @@
+mod support;
+
+use async_trait::async_trait;
+use p2p_rs::config::Config;
+use p2p_rs::hooks::{Hooks, Verdict};
+use p2p_rs::Server;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use support::TestServer;
+
+// refuses every registration while closed
+#[derive(Clone, Default)]
+struct Gate(Arc<AtomicBool>);
+
+#[async_trait]
+impl Hooks for Gate {
+    async fn on_register(&self, _addr: SocketAddr, _nickname: &str) -> Verdict {
+        match self.0.load(Ordering::Relaxed) {
+            true => Verdict::Deny("CLOSED".to_string()),
+            false => Verdict::Allow,
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_refused_resume_keeps_the_session() {
+    let mut config = Config::default();
+    config.resume.enabled = true;
+    let gate = Gate::default();
+    let server =
+        TestServer::with_builder(Server::builder().config(config).hooks(gate.clone())).await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    let reply = alice.request("REG alice").await;
+    let token = reply.strip_prefix("OK ").unwrap().to_string();
+    bob.request("REG bob").await;
+
+    alice.close().await;
+    while bob.request("WHOIS alice").await != "ERR NO_NICK" {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(bob.request("MSG alice still there?").await, "OK QUEUED");
+
+    gate.0.store(true, Ordering::Relaxed);
+    let resume = format!("RESUME {}", token);
+    let mut alice = server.connect().await;
+    assert_eq!(alice.request(&resume).await, "ERR DENIED CLOSED");
+
+    // still reserved, and still holding what was sent meanwhile
+    gate.0.store(false, Ordering::Relaxed);
+    let mut mallory = server.connect().await;
+    assert_eq!(mallory.request("REG alice").await, "ERR TKN");
+
+    assert!(alice.request(&resume).await.starts_with("OK "));
+    alice.expect("MSG bob still there?").await;
+}
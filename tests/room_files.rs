@@ -0,0 +1,79 @@
+mod support;
+
+use support::{TestClient, TestServer};
+
+async fn member(server: &TestServer, nickname: &str) -> TestClient {
+    let mut client = server.connect().await;
+    client.register(nickname).await;
+    assert_eq!(client.request("JOIN #lobby").await, "OK");
+
+    client
+}
+
+#[tokio::test]
+async fn one_upload_reaches_every_acceptor() {
+    let server = TestServer::start().await;
+    let mut alice = member(&server, "alice").await;
+    let mut bob = member(&server, "bob").await;
+    let mut carol = member(&server, "carol").await;
+    let mut dave = member(&server, "dave").await;
+
+    // after the others joining
+    alice.send("FILE_OFFER #lobby notes.txt 5").await;
+    alice.skip_to("OK 1").await;
+    for client in [&mut bob, &mut carol, &mut dave] {
+        client
+            .skip_to("FILE_OFFER 1 alice notes.txt 5 #lobby")
+            .await;
+    }
+
+    assert_eq!(bob.request("FILE_ACCEPT 1").await, "OK");
+    alice.expect("FILE_ACCEPT 1 bob").await;
+    assert_eq!(carol.request("FILE_ACCEPT 1").await, "OK");
+    alice.expect("FILE_ACCEPT 1 carol").await;
+    assert_eq!(dave.request("FILE_REJECT 1").await, "OK");
+    alice.expect("FILE_REJECT 1 dave").await;
+
+    // "hello", sent once
+    assert_eq!(alice.request("FILE_CHUNK 1 aGVsbG8=").await, "OK");
+    bob.expect("FILE_CHUNK 1 aGVsbG8=").await;
+    carol.expect("FILE_CHUNK 1 aGVsbG8=").await;
+
+    // the sender hears about it once the slower one has it too
+    assert_eq!(bob.request("FILE_ACK 1").await, "OK");
+    assert_eq!(carol.request("FILE_ACK 1").await, "OK");
+    alice.expect("FILE_ACK 1").await;
+    alice.expect("FILE_DONE 1").await;
+    bob.expect("FILE_DONE 1").await;
+    carol.expect("FILE_DONE 1").await;
+}
+
+#[tokio::test]
+async fn the_first_chunk_closes_the_offer() {
+    let server = TestServer::start().await;
+    let mut alice = member(&server, "alice").await;
+    let mut bob = member(&server, "bob").await;
+    let mut carol = member(&server, "carol").await;
+
+    alice.send("FILE_OFFER #lobby a.bin 10").await;
+    alice.skip_to("OK 1").await;
+    bob.skip_to("FILE_OFFER 1 alice a.bin 10 #lobby").await;
+    carol.skip_to("FILE_OFFER 1 alice a.bin 10 #lobby").await;
+
+    assert_eq!(
+        alice.request("FILE_CHUNK 1 aGVsbG8=").await,
+        "ERR NOT_ACCEPTED"
+    );
+    assert_eq!(bob.request("FILE_ACCEPT 1").await, "OK");
+    alice.expect("FILE_ACCEPT 1 bob").await;
+    assert_eq!(alice.request("FILE_CHUNK 1 aGVsbG8=").await, "OK");
+
+    carol.expect("FILE_CANCEL 1").await;
+    assert_eq!(carol.request("FILE_ACCEPT 1").await, "ERR BAD_ID");
+
+    // the last acceptor leaving ends it
+    bob.expect("FILE_CHUNK 1 aGVsbG8=").await;
+    assert_eq!(bob.request("FILE_CANCEL 1").await, "OK");
+    alice.expect("FILE_CANCEL 1 bob").await;
+    alice.expect("FILE_CANCEL 1").await;
+}
@@ -0,0 +1,39 @@
+mod support;
+
+use async_trait::async_trait;
+use p2p_rs::hooks::Hooks;
+use p2p_rs::Server;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use support::TestServer;
+
+#[derive(Clone, Default)]
+struct Departures(Arc<Mutex<Vec<String>>>);
+
+#[async_trait]
+impl Hooks for Departures {
+    async fn on_disconnect(&self, _addr: SocketAddr, nickname: &str) {
+        self.0.lock().unwrap().push(nickname.to_string());
+    }
+}
+
+#[tokio::test]
+async fn shutting_down_lets_everyone_leave() {
+    let departures = Departures::default();
+    let server = TestServer::with_builder(Server::builder().hooks(departures.clone())).await;
+
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    server.shutdown();
+
+    alice.skip_to("ERR SHUTDOWN").await;
+    bob.skip_to("ERR SHUTDOWN").await;
+    assert_eq!(alice.recv().await, None);
+
+    let mut left = departures.0.lock().unwrap().clone();
+    left.sort();
+    assert_eq!(left, ["alice", "bob"]);
+}
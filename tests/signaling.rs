@@ -0,0 +1,59 @@
+mod support;
+
+use p2p_rs::config::{Config, SignalingConfig};
+use support::TestServer;
+
+// an SDP blob keeps its newlines; `send` ends it with the last one
+const OFFER: &str = "SDP_OFFER bob 8\nv=0\ns=-";
+
+#[tokio::test]
+async fn payloads_arrive_as_they_were_sent() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request(OFFER).await, "OK");
+    bob.expect("SDP_OFFER alice 8").await;
+    bob.expect("v=0").await;
+    bob.expect("s=-").await;
+
+    assert_eq!(bob.request("ICE_CAND alice 0").await, "OK");
+    alice.expect("ICE_CAND bob 0").await;
+}
+
+#[tokio::test]
+async fn payloads_past_the_limit_are_skipped() {
+    let server = TestServer::with_config(Config {
+        signaling: SignalingConfig { max_payload: 4 },
+        ..Config::default()
+    })
+    .await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request(OFFER).await, "ERR TOO_LONG");
+
+    // the payload was read past, not taken as commands
+    assert_eq!(alice.request("MSG bob hi").await, "OK");
+    bob.expect("MSG alice hi").await;
+}
+
+#[tokio::test]
+async fn json_clients_get_a_json_header_then_the_payload() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+    assert_eq!(bob.request("CAP REQ json").await, "OK");
+
+    assert_eq!(alice.request(OFFER).await, "OK");
+    bob.expect(r#"{"command":"SDP_OFFER","params":["alice","8"]}"#)
+        .await;
+    bob.expect("v=0").await;
+    bob.expect("s=-").await;
+}
@@ -0,0 +1,192 @@
+//! A server on an ephemeral port and a scripted client that talks to it
+//! line by line, for the integration tests.
+
+// each test file uses its own share of this
+#![allow(dead_code)]
+
+use p2p_rs::compression::{self, Compression};
+use p2p_rs::config::Config;
+use p2p_rs::{Server, ServerBuilder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+// long enough for a loaded CI machine, short enough to fail fast
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running server, shut down when dropped.
+pub struct TestServer {
+    server: Arc<Server>,
+    pub addr: SocketAddr,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        TestServer::with_config(Config::default()).await
+    }
+
+    pub async fn with_config(config: Config) -> TestServer {
+        TestServer::with_builder(Server::builder().config(config)).await
+    }
+
+    /// For servers that need more than a config, bound to a free port.
+    pub async fn with_builder(builder: ServerBuilder) -> TestServer {
+        TestServer::bound(builder.bind("127.0.0.1:0")).await
+    }
+
+    /// Listening where `[listen]` says, see [`addrs`](TestServer::addrs).
+    pub async fn listening(config: Config) -> TestServer {
+        TestServer::bound(Server::builder().config(config)).await
+    }
+
+    async fn bound(builder: ServerBuilder) -> TestServer {
+        let server = builder.build().await.expect("failed to build the server");
+
+        let addr = server.local_addr().unwrap();
+        let server = Arc::new(server);
+
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+
+        TestServer { server, addr }
+    }
+
+    pub async fn connect(&self) -> TestClient {
+        TestClient::connect(self.addr).await
+    }
+
+    /// Every address the server accepts connections on.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.server.local_addrs().unwrap()
+    }
+
+    pub fn shutdown(&self) {
+        self.server.shutdown();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.shutdown();
+    }
+}
+
+/// A raw protocol client. Every read times out, so a missing reply fails
+/// the test instead of hanging it.
+pub struct TestClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    compression: Compression,
+    // frames not complete yet and the bytes they held, once compressing
+    encoded: Vec<u8>,
+    decoded: Vec<u8>,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> TestClient {
+        let stream = TcpStream::connect(addr).await.expect("failed to connect");
+        let (reader, writer) = stream.into_split();
+
+        TestClient {
+            reader: BufReader::new(reader),
+            writer,
+            compression: Compression::None,
+            encoded: Vec::new(),
+            decoded: Vec::new(),
+        }
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        let mut data = format!("{}\n", line).into_bytes();
+
+        if self.compression != Compression::None {
+            // compress everything worth it, the server's threshold is its own
+            data = compression::encode(self.compression, &data, 64).unwrap();
+        }
+
+        self.writer.write_all(&data).await.expect("failed to send");
+    }
+
+    /// Agrees on `CAP compress=<name>` and talks that way from then on.
+    pub async fn compress(&mut self, name: &str) {
+        assert_eq!(self.request(&format!("CAP compress={}", name)).await, "OK");
+        self.compression = Compression::parse(name).unwrap();
+    }
+
+    /// The next line without its newline, `None` once the server hangs up.
+    pub async fn recv(&mut self) -> Option<String> {
+        if self.compression != Compression::None {
+            return tokio::time::timeout(TIMEOUT, self.recv_framed())
+                .await
+                .expect("timed out waiting for a line");
+        }
+
+        let mut line = String::new();
+
+        let read = tokio::time::timeout(TIMEOUT, self.reader.read_line(&mut line))
+            .await
+            .expect("timed out waiting for a line");
+
+        match read {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+        }
+    }
+
+    async fn recv_framed(&mut self) -> Option<String> {
+        loop {
+            if let Some(end) = self.decoded.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.decoded.drain(..=end).collect();
+                let line = String::from_utf8(line).unwrap();
+
+                return Some(line.trim_end_matches(['\r', '\n']).to_string());
+            }
+
+            let mut chunk = [0; 4096];
+            let n = self.reader.read(&mut chunk).await.ok()?;
+
+            if n == 0 {
+                return None;
+            }
+
+            self.encoded.extend_from_slice(&chunk[..n]);
+            compression::decode(self.compression, &mut self.encoded, &mut self.decoded).unwrap();
+        }
+    }
+
+    /// Sends `line` and returns the first line of the reply.
+    pub async fn request(&mut self, line: &str) -> String {
+        self.send(line).await;
+        self.recv().await.expect("connection closed")
+    }
+
+    pub async fn expect(&mut self, expected: &str) {
+        assert_eq!(self.recv().await.as_deref(), Some(expected));
+    }
+
+    pub async fn register(&mut self, nickname: &str) {
+        assert_eq!(self.request(&format!("REG {}", nickname)).await, "OK");
+    }
+
+    /// Skips lines until one equal to `expected`, for replies with pushed
+    /// lines in between.
+    pub async fn skip_to(&mut self, expected: &str) {
+        loop {
+            match self.recv().await {
+                Some(line) if line == expected => return,
+                Some(_) => {}
+                None => panic!("connection closed before {:?}", expected),
+            }
+        }
+    }
+
+    /// Hangs up, which the server sees as a disconnect.
+    pub async fn close(mut self) {
+        let _ = self.writer.shutdown().await;
+    }
+}
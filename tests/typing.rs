@@ -0,0 +1,49 @@
+mod support;
+
+use p2p_rs::config::Config;
+use support::TestServer;
+
+#[tokio::test]
+async fn typing_reaches_peers_and_rooms() {
+    let mut config = Config::default();
+    config.typing.interval_seconds = 0;
+
+    let server = TestServer::with_config(config).await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request("TYPING bob").await, "OK");
+    bob.expect("TYPING alice").await;
+
+    assert_eq!(alice.request("JOIN #lobby").await, "OK");
+    assert_eq!(bob.request("JOIN #lobby").await, "OK");
+    alice.expect("JOIN #lobby bob").await;
+
+    assert_eq!(bob.request("TYPING #lobby").await, "OK");
+    alice.expect("TYPING #lobby bob").await;
+
+    // never queued for later
+    assert_eq!(alice.request("TYPING carol").await, "ERR NO_NICK");
+    assert_eq!(alice.request("TYPING #elsewhere").await, "ERR NOT_IN_ROOM");
+}
+
+#[tokio::test]
+async fn typing_is_throttled() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+
+    alice.register("alice").await;
+    bob.register("bob").await;
+
+    assert_eq!(alice.request("TYPING bob").await, "OK");
+    assert_eq!(alice.request("TYPING bob").await, "OK");
+    assert_eq!(alice.request("MSG bob done").await, "OK");
+
+    // the second one was dropped
+    bob.expect("TYPING alice").await;
+    bob.expect("MSG alice done").await;
+}
@@ -0,0 +1,62 @@
+mod support;
+
+use p2p_rs::config::{Config, WhoisConfig};
+use support::{TestClient, TestServer};
+
+// the fields of a WHOIS reply, up to its OK
+async fn whois(client: &mut TestClient, nickname: &str) -> Vec<String> {
+    client.send(&format!("WHOIS {}", nickname)).await;
+
+    let prefix = format!("WHOIS {} ", nickname);
+    let mut fields = Vec::new();
+
+    loop {
+        let line = client.recv().await.unwrap();
+
+        if line == "OK" {
+            return fields;
+        }
+
+        fields.push(line.strip_prefix(&prefix).unwrap().to_string());
+    }
+}
+
+#[tokio::test]
+async fn whois_describes_a_connection() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect().await;
+    let mut bob = server.connect().await;
+    alice.register("alice").await;
+    bob.register("bob").await;
+    assert_eq!(bob.request("JOIN #b").await, "OK");
+    assert_eq!(bob.request("JOIN #a").await, "OK");
+
+    let fields = whois(&mut alice, "bob").await;
+    let names: Vec<&str> = fields
+        .iter()
+        .map(|f| f.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(names, ["registered", "addr", "idle", "rooms", "status"]);
+
+    assert!(fields[0]["registered ".len()..].parse::<u64>().unwrap() > 0);
+    assert!(fields[1].starts_with("addr 127.0.0.1:"));
+    assert_eq!(fields[3], "rooms #a #b");
+    assert_eq!(fields[4], "status online");
+
+    assert_eq!(alice.request("WHOIS carol").await, "ERR NO_NICK");
+}
+
+#[tokio::test]
+async fn addresses_can_be_left_out() {
+    let server = TestServer::with_config(Config {
+        whois: WhoisConfig { hide_address: true },
+        ..Config::default()
+    })
+    .await;
+    let mut alice = server.connect().await;
+    alice.register("alice").await;
+
+    let fields = whois(&mut alice, "alice").await;
+    assert!(fields.iter().all(|f| !f.starts_with("addr ")));
+    assert_eq!(fields.last().unwrap(), "status online");
+}